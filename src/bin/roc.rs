@@ -4,95 +4,581 @@
 extern crate clap;
 
 use rodata::convert::{Converter, json::JsonConverter, xml::XmlConverter, csv::CsvConverter};
-use rodata::model::{EntitySetQuery, FunctionQuery, EntityIndividualQuery};
+use rodata::model::{EntitySetQuery, FunctionQuery, EntityIndividualQuery, MetadataQuery, ExpandOption, EntityWriteQuery, Entity, EntityImportQuery, ImportMode, ActionQuery, ProtocolVersion, DeltaQuery, ParallelOptions, Checkpoint, Token, Value, ValuePath};
+use rodata::error::Error;
+use rodata::filter::literal::Literal;
+use rodata::metadata::format::{ModelFormatter, markdown::MarkdownFormatter, xsd::XsdFormatter, json_schema::JsonSchemaFormatter};
 use rodata::provider::entity_set::EntitySetIterator;
 use rodata::provider::entity_individual::EntityIndividualLoader;
 use rodata::provider::function::FunctionCaller;
+use rodata::provider::action::ActionCaller;
+use rodata::provider::metadata::MetadataLoader;
+use rodata::provider::entity_writer::EntityWriter;
+use rodata::provider::entity_import::{EntityImporter, ImportInput, RowError};
+use rodata::reader::{FileReader, InputFormat, CsvTable};
+use rodata::service::retry::RetryPolicy;
+use rodata::service::client::{ODataClient, ClientConfig, ClientIdentity};
+use rodata::service::auth::{SharedAuthenticator, BearerTokenAuthenticator, TokenSourceAuthenticator, ClientCredentialsAuthenticator};
 use rodata::writer::FileWriter;
+use rodata::entity_stream::filter::{Pipeline, TransformTokens, EntityAttributeFilter, LimitEntitiesFilter, RenameAttributesFilter, DropNullsFilter, EntityPredicateFilter};
+use std::sync::Arc;
+use futures::channel::mpsc::{channel, Receiver};
+use futures::stream::StreamExt;
 use clap::ArgMatches;
 
 //"https://services.odata.org/v4/TripPinServiceRW/People"
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn main() {
 
     let options = clap_app!(rodata =>
         (version: "0.1")
         (author: "Gregor :kelko: Karzelek")
         (about: "Rust OData Client")
+        (@arg connect_timeout: --("connect-timeout") +takes_value +global "Longest time in seconds to connect to the service")
+        (@arg timeout: --timeout +takes_value +global "Longest time in seconds for a whole request, until the last byte of the response body is received. Not an idle timeout: large pages need a limit long enough to stream them")
+        (@arg proxy: --proxy +takes_value +global "Proxy for all requests, e.g. http://proxy.example.org:3128 (default: HTTP_PROXY/HTTPS_PROXY)")
+        (@arg no_proxy: --("no-proxy") +takes_value +global "Comma separated hosts connected to without proxy (--proxy or of the environment), replacing NO_PROXY")
+        (@arg ca_cert: --("ca-cert") +takes_value +global +multiple number_of_values(1) "Additional root certificates (PEM or DER) to trust, e.g. of a private CA")
+        (@arg client_cert: --("client-cert") +takes_value +global "Client certificate: PKCS#12 archive, or PEM certificate along with --client-key")
+        (@arg client_key: --("client-key") +takes_value +global requires[client_cert] "PEM file of the (PKCS#8) private key of --client-cert")
+        (@arg client_cert_password: --("client-cert-password") +takes_value +global requires[client_cert] "Password of the PKCS#12 archive of --client-cert")
+        (@arg insecure: --insecure +global "Accept any certificate of the service. For development only")
+        (@arg user_agent: --("user-agent") +takes_value +global "User-Agent header of the requests")
+        (@arg header: -H --header +takes_value +global +multiple number_of_values(1) "Additional header sent with every request: `Name: Value`")
+        (@arg username: -u --username +takes_value +global "Username")
+        (@arg password: -p --password +takes_value +global "Password")
+        (@arg bearer_token: --("bearer-token") +takes_value +global "Static bearer token to authenticate with")
+        (@arg token_file: --("token-file") +takes_value +global "File containing the bearer token (re-read when the service rejects it)")
+        (@arg token_env: --("token-env") +takes_value +global "Environment variable containing the bearer token")
+        (@arg oauth_token_url: --("oauth-token-url") +takes_value +global requires[oauth_client_id oauth_client_secret] "OAuth2 token endpoint for the client credentials flow")
+        (@arg oauth_client_id: --("oauth-client-id") +takes_value +global "OAuth2 client id")
+        (@arg oauth_client_secret: --("oauth-client-secret") +takes_value +global "OAuth2 client secret")
+        (@arg oauth_scope: --("oauth-scope") +takes_value +global "OAuth2 scope to request")
         (@subcommand entityset =>
             (about: "Loads an OData Entity Set. Can select output columns, filter and/or sort")
             (@arg select: --select +takes_value "List of fields to query (a.k.a. $select)")
             (@arg filter: --filter +takes_value "Filter for the query (a.k.a $filter)")
             (@arg order: --order-by +takes_value "List of fields to use for ordering/sorting (a.k.a $orderby)")
-            (@arg username: -u --username +takes_value "Username")
-            (@arg password: -p --password +takes_value "Password")
+            (@arg expand: --expand +takes_value "Related entities to include, with nested options: `Trips($select=Name;$expand=PlanItems)` (a.k.a $expand)")
+            (@arg top: --top +takes_value "Maximum number of entities to load (a.k.a $top)")
+            (@arg skip: --skip +takes_value "Number of entities to skip (a.k.a $skip)")
+            (@arg search: --search +takes_value "Free-text search expression (a.k.a $search)")
+            (@arg count: --count "Request the total count of matching entities and report it on stderr (a.k.a $count)")
+            (@arg count_only: --("count-only") "Only output the total count of matching entities")
+            (@arg odata_version: --("odata-version") +takes_value "OData version of the service (2, 4; default: detected)")
+            (@arg delta: --delta +takes_value conflicts_with[count_only] "State file of change tracking: the first run loads all entities and stores the delta link, later runs only load the changes (marked in the field @change)")
+            (@arg parallel: --parallel +takes_value conflicts_with[count count_only delta] "Number of windows ($skip/$top) loaded at the same time. Requires $count support of the service")
+            (@arg windows: --windows +takes_value requires[parallel] "Number of windows the entities are split into with --parallel (default: 4 per parallel load)")
+            (@arg unordered: --unordered requires[parallel] "Output the entities of the windows as they arrive, instead of in the order of the service")
+            (@arg max_page_size: --("max-page-size") +takes_value "Ask the service for pages of at most this many entities (Prefer: odata.maxpagesize)")
+            (@arg max_pages: --("max-pages") +takes_value conflicts_with[delta parallel] "Stop after this many pages, without following the next link of the last one")
+            (@arg max_rows: --("max-rows") +takes_value conflicts_with[delta parallel checkpoint] "Stop after this many entities, dropping the rest of the page (unlike --top, the service does not know about it)")
+            (@arg checkpoint: --checkpoint +takes_value requires[output] conflicts_with[count count_only delta parallel] "File to store the progress in after each page (next link and size of the output), to continue an interrupted load with --resume")
+            (@arg resume: --resume requires[checkpoint] "Continue from the checkpoint file, appending to the output. Without a checkpoint file, the load starts from the beginning")
+            (@arg transform: --transform +takes_value +multiple number_of_values(1) conflicts_with[checkpoint delta] "Steps applied to the entities before the output, in order (also separated by `|`): select:<attributes>, drop:<attributes>, rename:<attribute>=<name>, drop-nulls (not for CSV), limit:<n>, where:<attribute>=<value> (or !=). Attributes are separated by `,`, nested ones written as Address/City")
+            (@arg retries: --retries +takes_value "Number of times a page request is repeated after 429, 503, timeouts or connections lost before the response (default: 3)")
+            (@arg retry_max_wait: --("retry-max-wait") +takes_value "Longest wait in seconds before repeating a request, also limiting Retry-After (default: 60)")
             (@arg format: -f --format +takes_value "Format (csv, xml, json; default: csv)")
             (@arg output: -o --output +takes_value "File name of the Ouput. `-` for stdout (default)")
             (@arg ENTITYSETURL: +required "The full URL to the OData entityset (without $filter, $select, etc.)")
         )
         (@subcommand entity =>
             (about: "Loads a single OData Entity.")
-            (@arg username: -u --username +takes_value "Username")
-            (@arg password: -p --password +takes_value "Password")
+            (@arg expand: --expand +takes_value "Related entities to include, with nested options: `Trips($select=Name;$expand=PlanItems)` (a.k.a $expand)")
+            (@arg odata_version: --("odata-version") +takes_value "OData version of the service (2, 4; default: detected)")
             (@arg format: -f --format +takes_value "Format (csv, xml, json; default: csv)")
             (@arg output: -o --output +takes_value "File name of the Ouput. `-` for stdout (default)")
             (@arg ENTITYURL: +required "The full URL to the OData entity (including ID parameters!)")
         )
-        /*(@subcommand model =>
+        (@subcommand model =>
             (about: "Loads the model of the OData service.")
-            (@arg username: -u --username +takes_value "Username")
-            (@arg password: -p --password +takes_value "Password")
-            (@arg format: -f --format +takes_value "Format (markdown, xsd; default: markdown)")
+            (@arg format: -f --format +takes_value "Format (markdown, xsd, json; default: markdown)")
             (@arg output: -o --output +takes_value "File name of the Ouput. `-` for stdout (default)")
-            (@arg ENTITYURL: +required "The full URL to the OData entityset")
-            (@arg ID: +required "The identifier of the entity to fetch'")
-        )*/
+            (@arg SERVICEURL: +required "The full URL to the OData service (or directly to its $metadata)")
+        )
         (@subcommand function =>
             (about: "Calls an OData function.")
             (@arg select: -s --select +takes_value "List of fields to include in the export. Separate with comma")
-            (@arg username: -u --username +takes_value "Username")
-            (@arg password: -p --password +takes_value "Password")
+            (@arg param: --param +takes_value +multiple number_of_values(1) "Parameter of the function: `name=value`, the value in the literal syntax of OData. Strings in single quotes (`City='Bern'`, `Zip='01234'`), numbers (`42`, `-1.5`), `true`, `false`, `null`, GUIDs, dates (`2021-03-31`) and timestamps (`2021-03-31T12:00:00Z`) without. Anything else unquoted, like numbers with leading zeros, is rejected")
+            (@arg format: -f --format +takes_value "Format (csv, xml, json; default: csv)")
+            (@arg output: -o --output +takes_value "File name of the Output. `-` for stdout (default)")
+            (@arg FUNCTIONURL: +required "The full URL to the OData function (parameters either in the URL or with --param)")
+        )
+        (@subcommand action =>
+            (about: "Invokes an OData action (bound or unbound).")
+            (@arg param: --param +takes_value +multiple number_of_values(1) "Parameter of the action: `name=value`. Values are JSON, strings in double quotes (`Count=3`, `Name=\"a\"`, `Tags=[\"a\"]`)")
+            (@arg input: -i --input +takes_value "JSON file with the parameters as object. `-` for stdin")
             (@arg format: -f --format +takes_value "Format (csv, xml, json; default: csv)")
             (@arg output: -o --output +takes_value "File name of the Output. `-` for stdout (default)")
-            (@arg FUNCTIONURL: +required "The full URL to the OData function (including all function parameters!)")
+            (@arg ACTIONURL: +required "The full URL to the OData action, for bound actions including the entity: `.../People('russellwhyte')/NS.ShareTrip`")
+        )
+        (@subcommand create =>
+            (about: "Creates OData Entities, read from a JSON or CSV file.")
+            (@arg input: -i --input +takes_value +required "File with the entities to create. JSON: an object, an array or `{\"value\": [...]}`; CSV: as written by `entityset -f csv`. `-` for stdin")
+            (@arg input_format: --("input-format") +takes_value "Format of the input (csv, json; default: by file extension, json)")
+            (@arg return_representation: --("return-representation") "Ask the service to respond with the created entities and write them (as JSON) to the output")
+            (@arg output: -o --output +takes_value "File name of the Output. `-` for stdout (default)")
+            (@arg ENTITYSETURL: +required "The full URL to the OData entityset")
+        )
+        (@subcommand update =>
+            (about: "Updates an OData Entity with the properties read from a JSON or CSV file.")
+            (@arg input: -i --input +takes_value +required "File with the properties to update, a single entity as JSON or CSV. `-` for stdin")
+            (@arg input_format: --("input-format") +takes_value "Format of the input (csv, json; default: by file extension, json)")
+            (@arg put: --put "Replace the entity as a whole (PUT, creates it if the service supports upserts), instead of only changing the given properties (PATCH)")
+            (@arg if_match: --("if-match") +takes_value "ETag the entity is expected to have, `*` for any (default: `@odata.etag` of the input)")
+            (@arg return_representation: --("return-representation") "Ask the service to respond with the updated entity and write it (as JSON) to the output")
+            (@arg output: -o --output +takes_value "File name of the Output. `-` for stdout (default)")
+            (@arg ENTITYURL: +required "The full URL to the OData entity (including ID parameters!)")
+        )
+        (@subcommand delete =>
+            (about: "Deletes an OData Entity.")
+            (@arg if_match: --("if-match") +takes_value "ETag the entity is expected to have, `*` for any")
+            (@arg ENTITYURL: +required "The full URL to the OData entity (including ID parameters!)")
+        )
+        (@subcommand import =>
+            (about: "Imports a CSV or JSON file into an OData Entity Set. CSV values are converted to the property types of the $metadata")
+            (@arg input: -i --input +takes_value +required "File with the entities to import. CSV: as written by `entityset -f csv`; JSON: an object, an array or `{\"value\": [...]}`. `-` for stdin")
+            (@arg input_format: --("input-format") +takes_value "Format of the input (csv, json; default: by file extension, json)")
+            (@arg update: --update "Update (PATCH) the entities identified by the key columns, instead of creating them (POST)")
+            (@arg batch_size: --("batch-size") +takes_value "Number of rows sent per $batch request, each in its own change set (default: 1, a request per row)")
+            (@arg error_report: --("error-report") +takes_value "CSV file to write the failed rows (row number and error) to. Default: stderr")
+            (@arg dry_run: --("dry-run") "Only convert the rows and write the requests which would be sent to the output")
+            (@arg output: -o --output +takes_value "File name of the Output (for --dry-run). `-` for stdout (default)")
+            (@arg ENTITYSETURL: +required "The full URL to the OData entityset")
         )
     ).get_matches();
 
 
-    match options.subcommand() {
+    let result = match options.subcommand() {
         ("entityset", entity_options) => load_entity_set(entity_options.expect("Missing required entity set parameters")).await,
         ("entity", entity_options) => load_individual_entity(entity_options.expect("Missing required single entity parameters")).await,
         ("function", function_options) => call_function(function_options.expect("Missing required function call parameters")).await,
+        ("action", action_options) => call_action(action_options.expect("Missing required action call parameters")).await,
+        ("model", model_options) => load_model(model_options.expect("Missing required model parameters")).await,
+        ("create", create_options) => create_entities(create_options.expect("Missing required create parameters")).await,
+        ("update", update_options) => update_entity(update_options.expect("Missing required update parameters")).await,
+        ("delete", delete_options) => delete_entity(delete_options.expect("Missing required delete parameters")).await,
+        ("import", import_options) => import_entities(import_options.expect("Missing required import parameters")).await,
         _ => panic!("Invalid call")
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
     }
 }
 
 async fn load_entity_set(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let output_format = options.value_of("format").map(|value| value.to_string().to_lowercase());
+    let client = load_client(options)?;
     let query = EntitySetQuery {
         entityset_url: options.value_of("ENTITYSETURL").expect("Missing required parameter ENTITYSETURL").to_string(),
         select: options.value_of("select").map(|value| value.to_string()),
         filters: options.value_of("filter").map(|value| value.to_string()),
         order_by: options.value_of("order").map(|value| value.to_string()),
+        expand: parse_expand(options)?,
+        top: parse_number(options, "top")?,
+        skip: parse_number(options, "skip")?,
+        search: options.value_of("search").map(|value| value.to_string()),
+        count: options.is_present("count"),
+        max_page_size: parse_number(options, "max_page_size")?,
+        max_pages: parse_number(options, "max_pages")?,
+        max_rows: parse_number(options, "max_rows")?,
+        version: parse_version(options)?,
+        track_changes: false,
+        retry: parse_retry_policy(options)?,
         username: options.value_of("username").map(|value| value.to_string()),
-        password: options.value_of("password").map(|value| value.to_string())
+        password: options.value_of("password").map(|value| value.to_string()),
+        authenticator: load_authenticator(options, &client),
+        client: Some(client)
     };
     let out_file = options.value_of_os("output").unwrap_or(std::ffi::OsStr::new("-"));
+    let report_paging = query.max_page_size.is_some() || query.max_pages.is_some() || query.max_rows.is_some();
+    let transforms = parse_transforms(options)?;
+
+    if let Some(state_file) = options.value_of_os("delta") {
+        return load_entity_set_changes(query, std::path::Path::new(state_file), output_format, out_file).await;
+    }
+
+    if let Some(checkpoint_file) = options.value_of_os("checkpoint") {
+        return load_entity_set_with_checkpoints(query, std::path::Path::new(checkpoint_file), options.is_present("resume"), output_format, out_file, report_paging).await;
+    }
 
     let entity_iterator = EntitySetIterator::new();
-    let odata_receiver = entity_iterator.iterate_entity_set(query);
+    let mut writer = FileWriter::new(out_file)?;
+
+    if options.is_present("count_only") {
+        let count = entity_iterator.count_entity_set(query).await?;
+
+        let (mut output_sender, output_receiver) = FileWriter::setup_channel();
+        output_sender.try_send(Ok(format!("{}\n", count)))?;
+        output_sender.disconnect();
+        writer.write(output_receiver).await?;
+
+        return Ok(());
+    }
+
+    if let Some(concurrency) = parse_number(options, "parallel")? {
+        let mut parallel_options = ParallelOptions::new(concurrency);
+        parallel_options.windows = parse_number(options, "windows")?.unwrap_or(parallel_options.windows);
+        parallel_options.ordered = !options.is_present("unordered");
+
+        let odata_receiver = transform_tokens(entity_iterator.iterate_entity_set_parallel(query, parallel_options), transforms);
+        let converter = load_result_converter(output_format);
+
+        let (output_sender, output_receiver) = FileWriter::setup_channel();
+        converter.convert(odata_receiver, output_sender);
+        writer.write(output_receiver).await?;
+
+        return Ok(());
+    }
+
+    let (odata_receiver, count_receiver, checkpoint_receiver) = entity_iterator.iterate_entity_set_with_progress(query);
+    let odata_receiver = transform_tokens(odata_receiver, transforms);
 
     let converter = load_result_converter(output_format);
-    let mut writer = FileWriter::new(out_file);
 
     let (output_sender, output_receiver) = FileWriter::setup_channel();
     converter.convert(odata_receiver, output_sender);
-    writer.write(output_receiver).await;
+    writer.write(output_receiver).await?;
+
+    if let Ok(count) = count_receiver.await {
+        eprintln!("Total count: {}", count);
+    }
+
+    if let (true, Ok(checkpoint)) = (report_paging, checkpoint_receiver.await) {
+        report_pages(&checkpoint);
+    }
+
+    Ok(())
+}
+
+/// how many pages and entities were loaded, and whether a limit left some
+fn report_pages(checkpoint: &Checkpoint) {
+    let pages = if checkpoint.pages == 1 { "page" } else { "pages" };
+    let remaining = if checkpoint.is_complete() { "" } else { ", more are available" };
+    eprintln!("Fetched {} {}, {} rows{}", checkpoint.pages, pages, checkpoint.entities, remaining);
+}
+
+/// loads all entities the first time, only the changes since the previous run afterwards. The delta link is kept in the state file
+async fn load_entity_set_changes(query: EntitySetQuery, state_file: &std::path::Path, output_format: Option<String>, out_file: &std::ffi::OsStr) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let entityset_url = query.entityset_url.clone();
+    let entity_iterator = EntitySetIterator::new();
+
+    let (odata_receiver, delta_receiver) = match read_delta_link(state_file, &entityset_url)? {
+        Some(delta_link) => entity_iterator.iterate_changes(DeltaQuery {
+            delta_link,
+            retry: query.retry,
+            username: query.username,
+            password: query.password,
+            authenticator: query.authenticator,
+            client: query.client
+        }),
+        None => entity_iterator.iterate_entity_set_with_delta_link(query)
+    };
+
+    let converter = load_result_converter(output_format);
+    let mut writer = FileWriter::new(out_file)?;
+
+    let (output_sender, output_receiver) = FileWriter::setup_channel();
+    converter.convert(odata_receiver, output_sender);
+    writer.write(output_receiver).await?;
+
+    // only stored once all changes are written, a failed run is repeated from the same delta link
+    let delta_link = delta_receiver.await
+        .map_err(|_| Error::Protocol("The service did not return a delta link (@odata.deltaLink), it may not support change tracking".to_owned()))?;
+    let state = serde_json::json!({ "entityset_url": entityset_url, "delta_link": delta_link });
+    write_state(state_file, &state)?;
+
+    Ok(())
+}
+
+/// the delta link of the previous run, if there was one
+fn read_delta_link(state_file: &std::path::Path, entityset_url: &str) -> Result<Option<String>, Error> {
+    if !state_file.exists() {
+        return Ok(None);
+    }
+
+    let state = match serde_json::from_str::<Entity>(FileReader::new(state_file.as_os_str())?.content()) {
+        Ok(state) => state,
+        Err(err) => return Err(Error::Decode { message: format!("Invalid state file: {}", err), offset: None })
+    };
+
+    if state.get("entityset_url").and_then(Entity::as_str) != Some(entityset_url) {
+        return Err(Error::InvalidQuery(format!("The state file {} belongs to another entity set", state_file.display())));
+    }
+
+    match state.get("delta_link").and_then(Entity::as_str) {
+        Some(delta_link) => Ok(Some(delta_link.to_owned())),
+        None => Err(Error::InvalidQuery(format!("The state file {} contains no delta link", state_file.display())))
+    }
+}
+
+/// loads the entities page by page, storing the next link and the size of the output in the checkpoint file after each one.
+/// When resuming, the output is cut back to that size and continued. The checkpoint file is removed once all pages are written,
+/// with `max_pages` it is kept for the next run
+async fn load_entity_set_with_checkpoints(query: EntitySetQuery, checkpoint_file: &std::path::Path, resume: bool, output_format: Option<String>, out_file: &std::ffi::OsStr, report_paging: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let entityset_url = query.entityset_url.clone();
+    let resumed = if resume { read_checkpoint(checkpoint_file, &entityset_url)? } else { None };
+
+    let (mut checkpoint, mut writer) = match resumed {
+        Some((checkpoint, output_offset)) => (Some(checkpoint), FileWriter::continuing(out_file, output_offset)?),
+        None => (None, FileWriter::new(out_file)?)
+    };
+
+    // the pages are converted one by one, within the root array (or list) written around them
+    if checkpoint.is_none() {
+        write_tokens(vec![Token { path: ValuePath::new(), value: Value::StartArray }], load_result_converter(output_format.clone()), &mut writer).await?;
+    }
+
+    // the pages of this run
+    let page_limit = query.max_pages.map(|max_pages| max_pages + checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.pages));
+
+    loop {
+        let (pages, entities) = checkpoint.as_ref().map_or((0, 0), |checkpoint| (checkpoint.pages, checkpoint.entities));
+        if checkpoint.as_ref().is_some_and(Checkpoint::is_complete) || page_limit.is_some_and(|page_limit| pages >= page_limit) {
+            break;
+        }
+
+        let (page, checkpoint_receiver) = EntitySetIterator::new().stream_page(query.clone(), checkpoint.take());
+
+        let (token_sender, token_receiver) = channel::<Result<Token, Error>>(10_000);
+        tokio::spawn(page.filter(|token| futures::future::ready(!matches!(token, Ok(token) if token.path.is_empty()))).map(Ok).forward(token_sender));
+
+        // the header belongs in front of the first entity only
+        let converter : Box<dyn Converter> = match output_format.as_deref() {
+            Some("xml") | Some("json") => load_result_converter(output_format.clone()),
+            _ if entities > 0 => Box::new(CsvConverter::new().without_header()),
+            _ => Box::new(CsvConverter::new())
+        };
+
+        let (output_sender, output_receiver) = FileWriter::setup_channel();
+        converter.convert(token_receiver, output_sender);
+        writer.write(output_receiver).await?;
+
+        let page_checkpoint = checkpoint_receiver.await.map_err(|_| Error::Protocol("The page was not loaded completely".to_owned()))?;
+        write_checkpoint(checkpoint_file, &entityset_url, &page_checkpoint, writer.position())?;
+        checkpoint = Some(page_checkpoint);
+    }
+
+    write_tokens(vec![Token { path: ValuePath::new(), value: Value::EndArray }], load_result_converter(output_format), &mut writer).await?;
+
+    if let Some(checkpoint) = checkpoint {
+        if report_paging {
+            report_pages(&checkpoint);
+        }
+
+        // a load stopped by --max-pages goes on with the next run
+        if checkpoint.is_complete() {
+            std::fs::remove_file(checkpoint_file)?;
+        }
+    }
 
     Ok(())
 }
 
+async fn write_tokens(tokens: Vec<Token>, converter: Box<dyn Converter>, writer: &mut FileWriter) -> Result<(), Error> {
+    let (mut token_sender, token_receiver) = channel::<Result<Token, Error>>(tokens.len());
+    for token in tokens {
+        let _ = token_sender.try_send(Ok(token));
+    }
+    token_sender.disconnect();
+
+    let (output_sender, output_receiver) = FileWriter::setup_channel();
+    converter.convert(token_receiver, output_sender);
+    writer.write(output_receiver).await
+}
+
+/// the checkpoint and the size of the output when it was stored, if there is a checkpoint file
+fn read_checkpoint(checkpoint_file: &std::path::Path, entityset_url: &str) -> Result<Option<(Checkpoint, u64)>, Error> {
+    if !checkpoint_file.exists() {
+        return Ok(None);
+    }
+
+    let state = match serde_json::from_str::<Entity>(FileReader::new(checkpoint_file.as_os_str())?.content()) {
+        Ok(state) => state,
+        Err(err) => return Err(Error::Decode { message: format!("Invalid checkpoint file: {}", err), offset: None })
+    };
+
+    if state.get("entityset_url").and_then(Entity::as_str) != Some(entityset_url) {
+        return Err(Error::InvalidQuery(format!("The checkpoint file {} belongs to another entity set", checkpoint_file.display())));
+    }
+
+    let next_link = state.get("next_link").and_then(Entity::as_str).map(|next_link| next_link.to_owned());
+    let number = |key: &str| state.get(key).and_then(Entity::as_u64);
+    match (number("pages"), number("entities"), number("output_offset")) {
+        (Some(pages), Some(entities), Some(output_offset)) => Ok(Some((Checkpoint::new(next_link, pages as usize, entities as usize), output_offset))),
+        _ => Err(Error::InvalidQuery(format!("The checkpoint file {} contains no progress", checkpoint_file.display())))
+    }
+}
+
+/// replaces the checkpoint file at once, an interruption leaves the previous checkpoint
+fn write_checkpoint(checkpoint_file: &std::path::Path, entityset_url: &str, checkpoint: &Checkpoint, output_offset: u64) -> Result<(), Error> {
+    let state = serde_json::json!({
+        "entityset_url": entityset_url,
+        "next_link": checkpoint.next_link,
+        "pages": checkpoint.pages,
+        "entities": checkpoint.entities,
+        "output_offset": output_offset
+    });
+
+    write_state(checkpoint_file, &state)
+}
+
+/// replaces the file at once, an interrupted write leaves the previous state in place
+fn write_state(state_file: &std::path::Path, state: &Entity) -> Result<(), Error> {
+    let mut temporary_file = state_file.as_os_str().to_owned();
+    temporary_file.push(".tmp");
+    std::fs::write(&temporary_file, format!("{:#}\n", state))?;
+    std::fs::rename(&temporary_file, state_file)?;
+
+    Ok(())
+}
+
+/// the steps of --transform, in order: `select:Name,Address/City | drop-nulls | limit:10`
+fn parse_transforms(options: &ArgMatches<'_>) -> Result<Pipeline, Error> {
+    // the columns of CSV are taken from the first entity, the values of the others must not leave gaps
+    let writes_csv = !matches!(options.value_of("format").map(|value| value.to_lowercase()).as_deref(), Some("json") | Some("xml"));
+    let mut pipeline = Pipeline::new();
+    for step in options.values_of("transform").into_iter().flatten().flat_map(|steps| steps.split('|')) {
+        let (name, argument) = match step.split_once(':') {
+            Some((name, argument)) => (name.trim(), argument.trim()),
+            None => (step.trim(), "")
+        };
+
+        pipeline = match name {
+            "select" => pipeline.then(EntityAttributeFilter::from_stringified_list(argument, true)),
+            "drop" => pipeline.then(EntityAttributeFilter::from_stringified_list(argument, false)),
+            "rename" => pipeline.then(RenameAttributesFilter::from_stringified_list(argument)?),
+            "drop-nulls" if writes_csv => return Err(Error::InvalidQuery("drop-nulls of --transform can not be written as CSV, the values would not match the columns. Use -f json or -f xml".to_owned())),
+            "drop-nulls" => pipeline.then(DropNullsFilter::new()),
+            "limit" => {
+                let limit = argument.parse::<usize>().map_err(|_| Error::InvalidQuery(format!("Invalid limit '{}' of --transform", argument)))?;
+                pipeline.then(LimitEntitiesFilter::new(limit))
+            },
+            "where" => pipeline.then(parse_condition(argument)?),
+            "" => pipeline,
+            _ => return Err(Error::InvalidQuery(format!("Unknown step '{}' of --transform, expected select, drop, rename, drop-nulls, limit or where", name)))
+        };
+    }
+
+    Ok(pipeline)
+}
+
+/// `<attribute>=<value>` or `<attribute>!=<value>`. Strings are compared by their content, everything else as JSON (`true`, `42`, `null`)
+fn parse_condition(condition: &str) -> Result<EntityPredicateFilter, Error> {
+    let (attribute, expected, equal) = match condition.split_once("!=") {
+        Some((attribute, expected)) => (attribute, expected, false),
+        None => match condition.split_once('=') {
+            Some((attribute, expected)) => (attribute, expected, true),
+            None => return Err(Error::InvalidQuery(format!("Invalid condition '{}', expected <attribute>=<value> or <attribute>!=<value>", condition)))
+        }
+    };
+
+    let pointer : String = attribute.split(['.', '/']).map(|key| format!("/{}", key.trim())).collect();
+    let expected = expected.trim().to_owned();
+    let expected_json = serde_json::from_str::<Entity>(&expected).ok();
+
+    Ok(EntityPredicateFilter::new(move |entity: &Entity| {
+        let matches = match entity.pointer(&pointer) {
+            Some(Entity::String(value)) => *value == expected,
+            Some(value) => Some(value) == expected_json.as_ref(),
+            None => expected == "null"
+        };
+
+        matches == equal
+    }))
+}
+
+/// the tokens after the steps of --transform
+fn transform_tokens(tokens: Receiver<Result<Token, Error>>, transforms: Pipeline) -> Receiver<Result<Token, Error>> {
+    if transforms.is_empty() {
+        return tokens;
+    }
+
+    tokens.transform(transforms).into_receiver(10_000)
+}
+
+fn parse_number(options: &ArgMatches<'_>, name: &str) -> Result<Option<usize>, Error> {
+    match options.value_of(name) {
+        Some(value) => value.parse::<usize>().map(Some).map_err(|_| Error::InvalidQuery(format!("Invalid number '{}' for --{}", value, name.replace('_', "-")))),
+        None => Ok(None)
+    }
+}
+
+fn parse_retry_policy(options: &ArgMatches<'_>) -> Result<RetryPolicy, Error> {
+    let mut retry = RetryPolicy::new(parse_number(options, "retries")?.unwrap_or(3) as u32);
+    if let Some(max_wait) = parse_number(options, "retry_max_wait")? {
+        retry.max_wait = std::time::Duration::from_secs(max_wait as u64);
+    }
+
+    Ok(retry)
+}
+
+fn parse_expand(options: &ArgMatches<'_>) -> Result<Vec<ExpandOption>, Error> {
+    match options.value_of("expand") {
+        Some(expand) => ExpandOption::parse_list(expand),
+        None => Ok(vec![])
+    }
+}
+
+fn parse_version(options: &ArgMatches<'_>) -> Result<Option<ProtocolVersion>, Error> {
+    options.value_of("odata_version").map(|version| version.parse()).transpose()
+}
+
+fn load_authenticator(options: &ArgMatches<'_>, client: &ODataClient) -> Option<SharedAuthenticator> {
+    if let Some(token_url) = options.value_of("oauth_token_url") {
+        return Some(Arc::new(ClientCredentialsAuthenticator::new(
+            token_url.to_string(),
+            options.value_of("oauth_client_id").expect("--oauth-client-id is required for --oauth-token-url").to_string(),
+            options.value_of("oauth_client_secret").expect("--oauth-client-secret is required for --oauth-token-url").to_string(),
+            options.value_of("oauth_scope").map(|value| value.to_string())
+        ).with_client(client.clone())));
+    }
+
+    if let Some(token) = options.value_of("bearer_token") {
+        return Some(Arc::new(BearerTokenAuthenticator::new(token.to_string())));
+    }
+
+    if let Some(token_file) = options.value_of_os("token_file") {
+        return Some(Arc::new(TokenSourceAuthenticator::from_file(token_file)));
+    }
+
+    if let Some(variable) = options.value_of("token_env") {
+        return Some(Arc::new(TokenSourceAuthenticator::from_environment(variable)));
+    }
+
+    None
+}
+
+/// the HTTP client configured by the global options
+fn load_client(options: &ArgMatches<'_>) -> Result<ODataClient, Error> {
+    let seconds = |name: &str| parse_number(options, name).map(|value| value.map(|seconds| std::time::Duration::from_secs(seconds as u64)));
+
+    let identity = options.value_of_os("client_cert").map(|certificate| match options.value_of_os("client_key") {
+        Some(key) => ClientIdentity::Pem { certificate: certificate.into(), key: key.into() },
+        None => ClientIdentity::Pkcs12 { path: certificate.into(), password: options.value_of("client_cert_password").map(|value| value.to_string()) }
+    });
+
+    let headers = options.values_of("header").map(|values| values.map(|header| match header.split_once(':') {
+        Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+        None => Err(Error::InvalidQuery(format!("Invalid header '{}', expected `Name: Value`", header)))
+    }).collect::<Result<Vec<_>, Error>>()).transpose()?;
+
+    ODataClient::new(&ClientConfig {
+        connect_timeout: seconds("connect_timeout")?,
+        timeout: seconds("timeout")?,
+        proxy: options.value_of("proxy").map(|value| value.to_string()),
+        no_proxy: options.value_of("no_proxy").map(|value| value.to_string()),
+        root_certificates: options.values_of_os("ca_cert").map(|values| values.map(|value| value.into()).collect()).unwrap_or_default(),
+        identity,
+        accept_invalid_certificates: options.is_present("insecure"),
+        user_agent: Some(options.value_of("user_agent").unwrap_or(concat!("roc/", env!("CARGO_PKG_VERSION"))).to_string()),
+        headers: headers.unwrap_or_default()
+    })
+}
+
 fn load_result_converter(output_format: Option<String>) -> Box<dyn Converter> {
     if let Some(format_value) = output_format {
         match format_value.as_str() {
@@ -107,10 +593,15 @@ fn load_result_converter(output_format: Option<String>) -> Box<dyn Converter> {
 
 async fn load_individual_entity(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let output_format = options.value_of("format").map(|value| value.to_string().to_lowercase());
+    let client = load_client(options)?;
     let query = EntityIndividualQuery {
         entity_url: options.value_of("ENTITYURL").expect("Missing required parameter ENTITYURL").to_string(),
+        expand: parse_expand(options)?,
+        version: parse_version(options)?,
         username: options.value_of("username").map(|value| value.to_string()),
-        password: options.value_of("password").map(|value| value.to_string())
+        password: options.value_of("password").map(|value| value.to_string()),
+        authenticator: load_authenticator(options, &client),
+        client: Some(client)
     };
     let out_file = options.value_of_os("output").unwrap_or(std::ffi::OsStr::new("-"));
 
@@ -118,21 +609,25 @@ async fn load_individual_entity(options: &ArgMatches<'_>) -> Result<(), Box<dyn
     let odata_receiver = entity_loader.load_individual(query);
 
     let converter = load_result_converter(output_format);
-    let mut writer = FileWriter::new(out_file);
+    let mut writer = FileWriter::new(out_file)?;
 
     let (output_sender, output_receiver) = FileWriter::setup_channel();
     converter.convert(odata_receiver, output_sender);
-    writer.write(output_receiver).await;
+    writer.write(output_receiver).await?;
 
     Ok(())
 }
 
 async fn call_function(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let output_format = options.value_of("format").map(|value| value.to_string().to_lowercase());
+    let client = load_client(options)?;
     let query = FunctionQuery {
         function_url: options.value_of("FUNCTIONURL").expect("Missing required parameter FUNCTIONURL").to_string(),
+        parameters: parse_function_parameters(options)?,
         username: options.value_of("username").map(|value| value.to_string()),
-        password: options.value_of("password").map(|value| value.to_string())
+        password: options.value_of("password").map(|value| value.to_string()),
+        authenticator: load_authenticator(options, &client),
+        client: Some(client)
     };
     let out_file = options.value_of_os("output").unwrap_or(std::ffi::OsStr::new("-"));
 
@@ -140,11 +635,275 @@ async fn call_function(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::erro
     let odata_receiver = function_caller.call_function(query);
     
     let converter = load_result_converter(output_format);
-    let mut writer = FileWriter::new(out_file);
+    let mut writer = FileWriter::new(out_file)?;
 
     let (output_sender, output_receiver) = FileWriter::setup_channel();
     converter.convert(odata_receiver, output_sender);
-    writer.write(output_receiver).await;
+    writer.write(output_receiver).await?;
+
+    Ok(())
+}
+
+async fn call_action(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let output_format = options.value_of("format").map(|value| value.to_string().to_lowercase());
+    let client = load_client(options)?;
+    let query = ActionQuery {
+        action_url: options.value_of("ACTIONURL").expect("Missing required parameter ACTIONURL").to_string(),
+        parameters: read_action_parameters(options)?,
+        username: options.value_of("username").map(|value| value.to_string()),
+        password: options.value_of("password").map(|value| value.to_string()),
+        authenticator: load_authenticator(options, &client),
+        client: Some(client)
+    };
+    let out_file = options.value_of_os("output").unwrap_or(std::ffi::OsStr::new("-"));
+
+    let action_caller = ActionCaller::new();
+    let odata_receiver = action_caller.call_action(query);
+
+    let converter = load_result_converter(output_format);
+    let mut writer = FileWriter::new(out_file)?;
+
+    let (output_sender, output_receiver) = FileWriter::setup_channel();
+    converter.convert(odata_receiver, output_sender);
+    writer.write(output_receiver).await?;
+
+    Ok(())
+}
+
+fn parse_function_parameters(options: &ArgMatches<'_>) -> Result<Vec<(String, Literal)>, Error> {
+    options.values_of("param").into_iter().flatten().map(|param| {
+        let (name, value) = param.split_once('=').ok_or_else(|| Error::InvalidQuery(format!("Invalid parameter '{}', expected name=value", param)))?;
+        let value = Literal::parse(value).map_err(|err| Error::InvalidQuery(format!("Invalid parameter '{}': {}", param, err)))?;
+        Ok((name.trim().to_owned(), value))
+    }).collect()
+}
+
+/// the parameters of the input file, overridden by the ones given with `--param`
+fn read_action_parameters(options: &ArgMatches<'_>) -> Result<serde_json::Map<String, Entity>, Error> {
+    let mut parameters = match options.value_of_os("input") {
+        Some(in_file) => match serde_json::from_str::<Entity>(FileReader::new(in_file)?.content()) {
+            Ok(Entity::Object(parameters)) => parameters,
+            Ok(other) => return Err(Error::InvalidQuery(format!("Expected an object with the parameters, found '{}'", other))),
+            Err(err) => return Err(Error::Decode { message: format!("Invalid JSON: {}", err), offset: None })
+        },
+        None => serde_json::Map::new()
+    };
+
+    for param in options.values_of("param").into_iter().flatten() {
+        let (name, value) = param.split_once('=').ok_or_else(|| Error::InvalidQuery(format!("Invalid parameter '{}', expected name=value", param)))?;
+        let value = serde_json::from_str::<Entity>(value).map_err(|err| Error::InvalidQuery(format!("Invalid parameter '{}', expected a JSON value, strings in double quotes: {}", param, err)))?;
+        parameters.insert(name.trim().to_owned(), value);
+    }
+
+    Ok(parameters)
+}
+
+fn load_model_formatter(output_format: Option<String>) -> Box<dyn ModelFormatter> {
+    if let Some(format_value) = output_format {
+        match format_value.as_str() {
+            "xsd" => return Box::new(XsdFormatter::new()),
+            "json" => return Box::new(JsonSchemaFormatter::new()),
+            _ => ()
+        };
+    }
+
+    Box::new(MarkdownFormatter::new())
+}
+
+async fn load_model(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let output_format = options.value_of("format").map(|value| value.to_string().to_lowercase());
+    let client = load_client(options)?;
+    let query = MetadataQuery {
+        service_url: options.value_of("SERVICEURL").expect("Missing required parameter SERVICEURL").to_string(),
+        username: options.value_of("username").map(|value| value.to_string()),
+        password: options.value_of("password").map(|value| value.to_string()),
+        authenticator: load_authenticator(options, &client),
+        client: Some(client)
+    };
+    let out_file = options.value_of_os("output").unwrap_or(std::ffi::OsStr::new("-"));
+
+    let metadata_loader = MetadataLoader::new();
+    let metadata = metadata_loader.load_metadata(query).await?;
+
+    let formatter = load_model_formatter(output_format);
+    let mut writer = FileWriter::new(out_file)?;
+
+    let (mut output_sender, output_receiver) = FileWriter::setup_channel();
+    output_sender.try_send(Ok(formatter.format(&metadata)))?;
+    output_sender.disconnect();
+    writer.write(output_receiver).await?;
+
+    Ok(())
+}
+
+fn read_input(options: &ArgMatches<'_>) -> Result<Vec<Entity>, Error> {
+    let in_file = options.value_of_os("input").expect("Missing required parameter input");
+    let input_format = InputFormat::determine(options.value_of("input_format"), in_file)?;
+
+    FileReader::new(in_file)?.read_entities(input_format)
+}
+
+fn build_write_query(options: &ArgMatches<'_>, entity_url: &str, payload: Option<Entity>, client: &ODataClient) -> EntityWriteQuery {
+    EntityWriteQuery {
+        entity_url: entity_url.to_string(),
+        payload,
+        if_match: options.value_of("if_match").map(|value| value.to_string()),
+        return_representation: options.is_present("return_representation"),
+        username: options.value_of("username").map(|value| value.to_string()),
+        password: options.value_of("password").map(|value| value.to_string()),
+        authenticator: load_authenticator(options, client),
+        client: Some(client.clone())
+    }
+}
+
+async fn write_entity_output(options: &ArgMatches<'_>, content: Option<Entity>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let content = match content {
+        Some(content) => content,
+        None => return Ok(())
+    };
+
+    let out_file = options.value_of_os("output").unwrap_or(std::ffi::OsStr::new("-"));
+    let mut writer = FileWriter::new(out_file)?;
+
+    let (mut output_sender, output_receiver) = FileWriter::setup_channel();
+    output_sender.try_send(Ok(format!("{}\n", content)))?;
+    output_sender.disconnect();
+    writer.write(output_receiver).await?;
+
+    Ok(())
+}
+
+async fn create_entities(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let entity_set_url = options.value_of("ENTITYSETURL").expect("Missing required parameter ENTITYSETURL");
+    let entities = read_input(options)?;
+    let client = load_client(options)?;
+    let mut created = vec![];
+
+    for entity in entities {
+        let result = EntityWriter::new().create(build_write_query(options, entity_set_url, Some(entity), &client)).await?;
+        eprintln!("Created: {}", result.location.as_deref().unwrap_or_else(|| result.status.as_str()));
+
+        if let Some(entity) = result.entity {
+            created.push(entity);
+        }
+    }
+
+    let output = if options.is_present("return_representation") { Some(Entity::Array(created)) } else { None };
+    write_entity_output(options, output).await
+}
+
+async fn update_entity(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let entity_url = options.value_of("ENTITYURL").expect("Missing required parameter ENTITYURL");
+    let mut entities = read_input(options)?;
+    let client = load_client(options)?;
+    if entities.len() != 1 {
+        return Err(Error::InvalidQuery(format!("Expected exactly one entity as input, found {}", entities.len())).into());
+    }
+
+    let query = build_write_query(options, entity_url, entities.pop(), &client);
+    let entity_writer = EntityWriter::new();
+    let result = if options.is_present("put") {
+        entity_writer.replace(query).await?
+    } else {
+        entity_writer.update(query).await?
+    };
+
+    match &result.etag {
+        Some(etag) => eprintln!("Updated: {} (ETag: {})", entity_url, etag),
+        None => eprintln!("Updated: {}", entity_url)
+    }
+
+    write_entity_output(options, result.entity).await
+}
+
+async fn delete_entity(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let entity_url = options.value_of("ENTITYURL").expect("Missing required parameter ENTITYURL");
+    let client = load_client(options)?;
+    EntityWriter::new().delete(build_write_query(options, entity_url, None, &client)).await?;
+    eprintln!("Deleted: {}", entity_url);
+
+    Ok(())
+}
+
+async fn import_entities(options: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = load_client(options)?;
+    let query = EntityImportQuery {
+        entityset_url: options.value_of("ENTITYSETURL").expect("Missing required parameter ENTITYSETURL").to_string(),
+        mode: if options.is_present("update") { ImportMode::Update } else { ImportMode::Create },
+        batch_size: parse_number(options, "batch_size")?,
+        username: options.value_of("username").map(|value| value.to_string()),
+        password: options.value_of("password").map(|value| value.to_string()),
+        authenticator: load_authenticator(options, &client),
+        client: Some(client)
+    };
+
+    let in_file = options.value_of_os("input").expect("Missing required parameter input");
+    let reader = FileReader::new(in_file)?;
+    let input = match InputFormat::determine(options.value_of("input_format"), in_file)? {
+        InputFormat::Csv => ImportInput::Csv(CsvTable::parse(reader.content(), ';')?),
+        format => ImportInput::Entities(reader.read_entities(format)?)
+    };
+
+    let importer = EntityImporter::new();
+    let (requests, mut failed) = importer.prepare(&query, input).await?;
+    let total = requests.len() + failed.len();
+
+    let imported = if options.is_present("dry_run") {
+        let out_file = options.value_of_os("output").unwrap_or(std::ffi::OsStr::new("-"));
+        let mut writer = FileWriter::new(out_file)?;
+
+        let (mut output_sender, output_receiver) = FileWriter::setup_channel();
+        for request in &requests {
+            output_sender.try_send(Ok(format!("{} {} {}\n", request.method, request.url, request.payload)))?;
+        }
+        output_sender.disconnect();
+        writer.write(output_receiver).await?;
+
+        0
+    } else {
+        let report = importer.execute(&query, requests).await;
+        failed.extend(report.failed);
+        report.imported
+    };
+
+    failed.sort_by_key(|row_error| row_error.row);
+    report_failed_rows(options, &failed).await?;
+
+    if options.is_present("dry_run") {
+        eprintln!("Checked {} rows, {} failed", total, failed.len());
+    } else {
+        eprintln!("Imported {} of {} rows, {} failed", imported, total, failed.len());
+    }
+
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn report_failed_rows(options: &ArgMatches<'_>, failed: &[RowError]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let report_file = match options.value_of_os("error_report") {
+        Some(report_file) => report_file,
+        None => {
+            for row_error in failed {
+                eprintln!("Row {}: {}", row_error.row, row_error.error);
+            }
+
+            return Ok(());
+        }
+    };
+
+    // the same dialect as written by the CSV converter
+    let mut writer = FileWriter::new(report_file)?;
+    let (mut output_sender, output_receiver) = FileWriter::setup_channel();
+    output_sender.try_send(Ok("Row;Error\r\n".to_owned()))?;
+    for row_error in failed {
+        let message = row_error.error.to_string().replace('"', "\"\"").replace('\n', " ");
+        output_sender.try_send(Ok(format!("{};\"{}\"\r\n", row_error.row, message)))?;
+    }
+    output_sender.disconnect();
+    writer.write(output_receiver).await?;
 
     Ok(())
 }
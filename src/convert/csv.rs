@@ -1,32 +1,54 @@
-﻿use std::collections::HashSet;
-use futures::channel::mpsc::{Sender, Receiver};
+﻿use futures::channel::mpsc::Receiver;
 use futures::stream::StreamExt;
 use futures::executor::block_on;
-use crate::model::{Token, ValuePath, ValuePosition, Value};
-use crate::convert::{ Converter, send_line_to_writer };
+use crate::model::{Token, ValuePosition, Value};
+use crate::error::Error;
+use crate::writer::OutputSender;
+use crate::convert::{ Converter, send_line_to_writer, send_error_to_writer };
 
 pub struct CsvConverter<'a> {
     delimiter: &'a str,
-    newline: &'a str
+    newline: &'a str,
+    /// writes the names of the columns before the first row
+    header: bool
 }
 
 impl<'a> CsvConverter<'a> {
     pub fn new() -> CsvConverter<'a> {
-        CsvConverter { delimiter: ";", newline: "\r\n" }
+        CsvConverter { delimiter: ";", newline: "\r\n", header: true }
+    }
+
+    /// only writes the rows, e.g. when appending to an existing file
+    pub fn without_header(mut self) -> CsvConverter<'a> {
+        self.header = false;
+        self
     }
 }
 
 impl<'a> Converter for CsvConverter<'a> {
-    fn convert(&self, entity_stream : Receiver<Token>, mut output: Sender<Box<String>>) {
+    fn convert(&self, entity_stream : Receiver<Result<Token, Error>>, mut output: OutputSender) {
         let newline = self.newline.to_owned();
         let delimiter = self.delimiter.to_owned();
+        let header = self.header;
         
         tokio::spawn(async move {
             let mut heavylifter = HeavyliftConverter::new(&delimiter, &newline, &mut output);
-            let mut first = true;
+            let mut first = header;
+            let mut failed = false;
 
             let running_foreach = entity_stream.for_each(move |next_object| {
-                let object_ready = heavylifter.buffer_object(next_object);
+                if failed {
+                    return futures::future::ready(());
+                }
+
+                let object_ready = match next_object.and_then(|token| heavylifter.buffer_object(token)) {
+                    Ok(object_ready) => object_ready,
+                    Err(err) => {
+                        failed = true;
+                        send_error_to_writer(err, heavylifter.output);
+                        return futures::future::ready(());
+                    }
+                };
 
                 if !object_ready {
                     return futures::future::ready(());
@@ -50,20 +72,22 @@ impl<'a> Converter for CsvConverter<'a> {
 
 struct HeavyliftConverter<'a> {
     single_object: bool,
-    output: &'a mut Sender<Box<String>>,
+    output: &'a mut OutputSender,
     delimiter: &'a str,
     newline: &'a str,
     object_in_progress: std::vec::Vec<(String,String)>,
-    value_in_progress: std::vec::Vec<String>,
-    known_entities: HashSet<String>,
+    /// the JSON of the complex value (or collection) in progress
+    value_in_progress: String,
+    /// per open object or array of the complex value: whether it has a member already
+    has_members: std::vec::Vec<bool>,
 }
 
 impl<'a> HeavyliftConverter<'a> {
-    fn new(delimiter: &'a str, newline: &'a str, output: &'a mut Sender<Box<String>>) -> Self {
-        HeavyliftConverter { delimiter, newline, output, object_in_progress: vec![], value_in_progress: vec![], known_entities: HashSet::<String>::new(), single_object: false }
+    fn new(delimiter: &'a str, newline: &'a str, output: &'a mut OutputSender) -> Self {
+        HeavyliftConverter { delimiter, newline, output, object_in_progress: vec![], value_in_progress: String::new(), has_members: vec![], single_object: false }
     }
 
-    fn buffer_object(&mut self, token: Token) -> bool {
+    fn buffer_object(&mut self, token: Token) -> Result<bool, Error> {
         match token.path.current_level() {
             0 => {
                 match token.value {
@@ -71,10 +95,13 @@ impl<'a> HeavyliftConverter<'a> {
                         self.single_object = true;
                         self.object_in_progress = vec![];
                     },
-                    Value::EndObject => return true,
+                    Value::EndObject => return Ok(true),
                     Value::StartArray => self.single_object = false,
                     Value::EndArray => (),
-                    _ => panic!("Invalid token '{}' at {}", token.value, token.path)
+                    value => {
+                        self.buffer_single_value(value);
+                        return Ok(true);
+                    }
                 }
             },
             1 => {
@@ -86,8 +113,12 @@ impl<'a> HeavyliftConverter<'a> {
                         Value::StartObject => {
                             self.object_in_progress = vec![];
                         }
-                        Value::EndObject => return true,
-                        _ => panic!("Invalid token '{}' at {}", token.value, token.path)
+                        Value::EndObject => return Ok(true),
+                        Value::StartArray | Value::EndArray => return Err(Error::Converter(format!("Invalid token '{}' at {}", token.value, token.path))),
+                        value => {
+                            self.buffer_single_value(value);
+                            return Ok(true);
+                        }
                     };
                 }                
             },
@@ -104,7 +135,7 @@ impl<'a> HeavyliftConverter<'a> {
             }
         }
 
-        false
+        Ok(false)
     }
 
     fn buffer_content(&mut self, token: Token) {
@@ -115,100 +146,74 @@ impl<'a> HeavyliftConverter<'a> {
                 Value::Number(value) => self.object_in_progress.push((key, value)),
                 Value::String(value) => self.object_in_progress.push((key, format!("\"{}\"", value))),
                 Value::None => self.object_in_progress.push((key, "".to_owned())),
-                Value::StartArray | Value::StartObject => self.start_complex_value(),
+                Value::StartArray => self.start_complex_value("["),
+                Value::StartObject => self.start_complex_value("{"),
                 Value::EndObject | Value::EndArray => {
-                    let value = self.finish_complex_value();
+                    // complex values (e.g. expanded navigation properties) are written as JSON, which may contain the delimiter
+                    let value = format!("\"{}\"", self.finish_complex_value(&token.value).replace('"', "\"\""));
                     self.object_in_progress.push((key, value))
                 }
             };
         }
     }
 
-    fn start_complex_value(&mut self) {
-        self.value_in_progress =  vec![];
-        self.known_entities = HashSet::<String>::new();
-    }
+    /// single values and collections of primitive values are written with one column `value`
+    fn buffer_single_value(&mut self, value: Value) {
+        let formatted = match value {
+            Value::Boolean(true) => "true".to_owned(),
+            Value::Boolean(false) => "false".to_owned(),
+            Value::Number(value) => value,
+            Value::String(value) => format!("\"{}\"", value),
+            _ => "".to_owned()
+        };
 
-    fn build_complex_value(&mut self, token: Token) {
-        match token.value {
-            Value::Boolean(true) => self.push_valuepart(&token.path, "true"),
-            Value::Boolean(false) => self.push_valuepart(&token.path, "false"),
-            Value::Number(value) => self.push_valuepart(&token.path, value),
-            Value::String(value) => self.push_valuepart(&token.path, value),
-            Value::None => self.push_valuepart(&token.path, "null"),
-            Value::StartArray | Value::EndArray => (),
-            Value::StartObject => self.push_valuepart(&token.path, "("),
-            Value::EndObject => {
-                self.value_in_progress.push(")".to_owned());
-                self.finished_object_as_valuepart(&token.path);
-            }
-        }
+        self.object_in_progress = vec![("value".to_owned(), formatted)];
     }
 
-    fn push_valuepart<T>(&mut self, path: &ValuePath, value: T) where T : Into<String> {
-        match path.top_most().expect("There has to be some path to come here") {
-            ValuePosition::Index(index) => {
-                let stringified = self.build_array_valuepart(index, value);
-                self.value_in_progress.push(stringified);
-
-            },
-            ValuePosition::Key(key_value) => {
-                let stringified = self.build_object_valuepart(path, &key_value, value);
-                self.value_in_progress.push(stringified);
-            }
-        }
-    }
-    
-    fn valuepart_needs_array_separator(&self, index: usize) -> bool {
-        index > 0
+    fn start_complex_value(&mut self, opening: &str) {
+        self.value_in_progress = opening.to_owned();
+        self.has_members = vec![false];
     }
 
-    fn build_array_valuepart<T>(&mut self, index: usize, value: T)  -> String
-    where T: Into<String>{
-        let prefix = if self.valuepart_needs_array_separator(index) {
-            " / "
-        } else {
-            ""
+    fn build_complex_value(&mut self, token: Token) {
+        let opens_value = matches!(token.value, Value::StartArray | Value::StartObject);
+        let value = match token.value {
+            Value::EndArray | Value::EndObject => {
+                self.has_members.pop();
+                self.value_in_progress.push_str(if token.value == Value::EndArray { "]" } else { "}" });
+                return;
+            },
+            Value::Boolean(true) => "true".to_owned(),
+            Value::Boolean(false) => "false".to_owned(),
+            Value::Number(value) => value,
+            Value::String(value) => json_string(&value),
+            Value::None => "null".to_owned(),
+            Value::StartArray => "[".to_owned(),
+            Value::StartObject => "{".to_owned()
         };
 
-        
-        format!("{}{}", prefix, value.into())
-    }
-
-    fn valuepart_needs_object_separator(&self, path: &ValuePath) -> bool {
-        if let Some(parent) = path.parent() {
-            return self.known_entities.contains(&parent.get_path_string())
+        if let Some(has_members) = self.has_members.last_mut() {
+            if *has_members {
+                self.value_in_progress.push(',');
+            }
+            *has_members = true;
         }
 
-        false
-    }
-
-    fn processed_object_key_in_valuepart(&mut self, path: &ValuePath) {
-        if let Some(parent) = path.parent() {
-            self.known_entities.insert(parent.get_path_string());
+        if let Some(ValuePosition::Key(key)) = token.path.top_most() {
+            self.value_in_progress.push_str(&json_string(&key));
+            self.value_in_progress.push(':');
         }
-    }
 
-    fn finished_object_as_valuepart(&mut self, path: &ValuePath) {
-        self.known_entities.remove(&path.get_path_string());
-    }
-
-    fn build_object_valuepart<T>(&mut self, path: &ValuePath, key: &String, value: T) -> String
-    where T: Into<String>{
-        let prefix = if self.valuepart_needs_object_separator(path) {
-            " / "
-        } else {
-            ""
-        };
-
-        let value = format!("{}{}: {}", prefix, key, value.into());
-        self.processed_object_key_in_valuepart(path);
-
-        return value;
+        self.value_in_progress.push_str(&value);
+        if opens_value {
+            self.has_members.push(false);
+        }
     }
 
-    fn finish_complex_value(&mut self) -> String{
-        return self.value_in_progress.join("")
+    fn finish_complex_value(&mut self, closing: &Value) -> String {
+        self.value_in_progress.push_str(if *closing == Value::EndArray { "]" } else { "}" });
+        self.has_members.clear();
+        std::mem::take(&mut self.value_in_progress)
     }
 
     fn format_header(&self) -> String {
@@ -227,3 +232,8 @@ impl<'a> HeavyliftConverter<'a> {
         send_line_to_writer(self.format_value_line(), &mut self.output, self.newline);
     }
 }
+
+/// the tokens carry strings and keys JSON-escaped already
+fn json_string(value: &str) -> String {
+    format!("\"{}\"", value)
+}
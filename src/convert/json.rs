@@ -1,9 +1,11 @@
 use std::collections::HashSet;
-use futures::channel::mpsc::{Sender, Receiver};
+use futures::channel::mpsc::Receiver;
 use futures::stream::StreamExt;
 use futures::executor::block_on;
-use crate::convert::{Converter, send_message_to_writer};
+use crate::convert::{Converter, send_message_to_writer, send_error_to_writer};
 use crate::model::{Token, Value, ValuePath, ValuePosition};
+use crate::error::Error;
+use crate::writer::OutputSender;
 
 enum ProcessableTokenValue {
     ArrayFinishedString(&'static str),
@@ -35,11 +37,22 @@ impl JsonConverter {
 }
 
 impl Converter for JsonConverter {
-    fn convert(&self, entity_stream : Receiver<Token>, mut output: Sender<Box<String>>) {
+    fn convert(&self, entity_stream : Receiver<Result<Token, Error>>, mut output: OutputSender) {
         tokio::spawn(async move {
             let mut heavylifter = HeavyliftConverter::new(&mut output);
-            let running_foreach = entity_stream.for_each(move |next_object : Token| {
-                heavylifter.forward_json(&next_object);
+            let mut failed = false;
+            let running_foreach = entity_stream.for_each(move |next_object| {
+                if failed {
+                    return futures::future::ready(());
+                }
+
+                match next_object {
+                    Ok(token) => heavylifter.forward_json(&token),
+                    Err(err) => {
+                        failed = true;
+                        send_error_to_writer(err, heavylifter.output);
+                    }
+                }
             
                 futures::future::ready(())
             });
@@ -52,11 +65,11 @@ impl Converter for JsonConverter {
 
 struct HeavyliftConverter<'a> {
     known_entities: HashSet<String>,
-    output: &'a mut Sender<Box<String>>
+    output: &'a mut OutputSender
 }
 
 impl<'a> HeavyliftConverter<'a> {
-    fn new(output: &'a mut Sender<Box<String>>) -> Self {
+    fn new(output: &'a mut OutputSender) -> Self {
         HeavyliftConverter { known_entities: HashSet::<String>::new(), output}
     }
 
@@ -3,28 +3,40 @@ pub mod xml;
 pub mod json;
 
 use std::{thread, time};
-use futures::channel::mpsc::{Sender, Receiver};
+use futures::channel::mpsc::Receiver;
 use crate::model::Token;
+use crate::error::Error;
+use crate::writer::OutputSender;
 
 pub trait Converter {
-    fn convert(&self, entity_stream : Receiver<Token>, output : Sender<Box<String>>);
+    /// converts the tokens into the output format. An error in the entity stream (or while converting) is passed on to the output, which ends with it
+    fn convert(&self, entity_stream : Receiver<Result<Token, Error>>, output : OutputSender);
 }
 
 
-pub(crate) fn send_line_to_writer<T>(line: T, sender: &mut Sender<Box<String>>, newline: &str) 
+pub(crate) fn send_line_to_writer<T>(line: T, sender: &mut OutputSender, newline: &str) 
 where T: Into<String> {
     let line_content = line.into();
     send_message_to_writer(format!("{}{}", line_content, newline), sender);
 }
 
-pub(crate) fn send_message_to_writer<T>(message: T, sender: &mut Sender<Box<String>>)
+pub(crate) fn send_message_to_writer<T>(message: T, sender: &mut OutputSender)
 where T: Into<String> {
     let message_content = message.into();
+    send_to_writer(Ok(message_content), sender);
+}
+
+pub(crate) fn send_error_to_writer(error: Error, sender: &mut OutputSender) {
+    send_to_writer(Err(error), sender);
+}
 
-    let mut message_pointer = Some(Box::new(message_content));
+fn send_to_writer(message: Result<String, Error>, sender: &mut OutputSender) {
+    let mut message_pointer = Some(message);
     while message_pointer.is_some() {
         match sender.try_send(message_pointer.take().unwrap()) {
             Ok(_) => (),
+            // the writer gave up
+            Err(e) if e.is_disconnected() => (),
             Err(e) => {
                 message_pointer = Some(e.into_inner());
                 thread::sleep(time::Duration::from_millis(50));
@@ -1,8 +1,10 @@
-use futures::channel::mpsc::{Sender, Receiver};
+use futures::channel::mpsc::Receiver;
 use futures::stream::StreamExt;
 use futures::executor::block_on;
-use crate::convert::{Converter, send_message_to_writer};
+use crate::convert::{Converter, send_message_to_writer, send_error_to_writer};
 use crate::model::{Token, Value, ValuePosition};
+use crate::error::Error;
+use crate::writer::OutputSender;
 
 pub struct XmlConverter {
 }
@@ -12,7 +14,7 @@ impl XmlConverter {
         XmlConverter {}
     }
 
-    fn stream_as_xml(token: &Token, output: &mut Sender<Box<String>>) {
+    fn stream_as_xml(token: &Token, output: &mut OutputSender) {
         match &token.value {
             Value::None => {
                 match token.path.top_most() {
@@ -70,10 +72,21 @@ impl XmlConverter {
 }
 
 impl Converter for XmlConverter {
-    fn convert(&self, entity_stream : Receiver<Token>, mut output: Sender<Box<String>>) {
+    fn convert(&self, entity_stream : Receiver<Result<Token, Error>>, mut output: OutputSender) {
         tokio::spawn(async move {
-            let running_foreach = entity_stream.for_each(move |next_object : Token| {
-                XmlConverter::stream_as_xml(&next_object, &mut output);
+            let mut failed = false;
+            let running_foreach = entity_stream.for_each(move |next_object| {
+                if failed {
+                    return futures::future::ready(());
+                }
+
+                match next_object {
+                    Ok(token) => XmlConverter::stream_as_xml(&token, &mut output),
+                    Err(err) => {
+                        failed = true;
+                        send_error_to_writer(err, &mut output);
+                    }
+                }
 
                 futures::future::ready(())
             });
@@ -1,75 +1,535 @@
-﻿/*
-use futures::Stream;
-use futures::task::{Context, Poll};
-use tokio::macros::support::Pin;
-use crate::entity_stream::ODataObject;
-use std::rc::Rc;
-use std::borrow::Borrow;
-use std::ops::Deref;
+﻿use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::ready;
+use futures::stream::{Stream, StreamExt};
+use futures::channel::mpsc::{channel, Receiver};
+use crate::model::{Entity, Token, Value, ValuePath, ValuePosition};
+use crate::error::Error;
+use crate::service::token_deserializer::from_tokens;
 
-pub struct EntityAttributeFilter<TInner> where TInner : futures::stream::Stream<Item=ODataObject> + Send + Sync  {
-    whitelist: Vec<String>,
-    inner: Rc<TInner>
+/// changes the tokens of a stream on their way from a provider to a `Converter`, e.g. dropping attributes or whole entities.
+/// The entities are the elements of a top level array, or a single object
+pub trait TokenTransformer {
+    /// the tokens to pass on for the token, none to drop it
+    fn push(&mut self, token: Token) -> Result<Vec<Token>, Error>;
+
+    /// nothing is passed on any more, the rest of the stream is not read
+    fn is_done(&self) -> bool {
+        false
+    }
 }
 
-impl<TInner> EntityAttributeFilter<TInner>  where TInner : futures::stream::Stream<Item=ODataObject> + Send + Sync {
-    pub fn new(inner: TInner, whitelist: Vec<String>) -> EntityAttributeFilter<TInner> {
-        EntityAttributeFilter { inner: Rc::new(inner), whitelist }
+/// the transformers applied one after the other, each to the tokens passed on by the previous one
+#[derive(Default)]
+pub struct Pipeline {
+    transformers: Vec<Box<dyn TokenTransformer + Send>>
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// adds the transformer at the end of the pipeline
+    pub fn then<T: TokenTransformer + Send + 'static>(mut self, transformer: T) -> Pipeline {
+        self.transformers.push(Box::new(transformer));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transformers.is_empty()
     }
+}
+
+impl TokenTransformer for Pipeline {
+    fn push(&mut self, token: Token) -> Result<Vec<Token>, Error> {
+        let mut tokens = vec![token];
+        for transformer in self.transformers.iter_mut() {
+            let mut passed = vec![];
+            for token in tokens {
+                if transformer.is_done() {
+                    break;
+                }
+
+                passed.extend(transformer.push(token)?);
+            }
 
-    pub fn from_stringified_whitelist(inner: TInner, concatted_field_list: String) -> EntityAttributeFilter<TInner> {
-        EntityAttributeFilter::new(inner, EntityAttributeFilter::<TInner>::split_whitelist(concatted_field_list))
+            tokens = passed;
+        }
+
+        Ok(tokens)
     }
 
-    fn split_whitelist(concatted_field_list: String) -> Vec<String> {
-        concatted_field_list.split(",").map(|value| value.to_string()).collect()
+    fn is_done(&self) -> bool {
+        self.transformers.iter().any(|transformer| transformer.is_done())
     }
 }
 
-impl<TInner : futures::stream::Stream> Stream for EntityAttributeFilter<TInner> where TInner : futures::stream::Stream<Item=ODataObject> + Send + Sync   {
-    type Item = ODataObject;
+/// the tokens of the stream, after the transformer. Errors of the stream are passed on as they are
+pub struct Transformed<S, T> {
+    tokens: S,
+    transformer: T,
+    pending: VecDeque<Token>,
+    finished: bool
+}
+
+impl<S, T> Transformed<S, T>
+where S: Stream<Item = Result<Token, Error>> + Unpin + Send + 'static, T: TokenTransformer + Unpin + Send + 'static {
+    pub fn new(tokens: S, transformer: T) -> Transformed<S, T> {
+        Transformed { tokens, transformer, pending: VecDeque::new(), finished: false }
+    }
+
+    /// the channel based API (e.g. for a `Converter`): the stream is driven by a spawned task, until it ends or the receiver is dropped
+    pub fn into_receiver(self, buffer_size: usize) -> Receiver<Result<Token, Error>> {
+        let (sender, receiver) = channel::<Result<Token, Error>>(buffer_size);
+        tokio::spawn(async move {
+            let _ = self.map(Ok).forward(sender).await;
+        });
+
+        receiver
+    }
+}
+
+impl<S, T> Stream for Transformed<S, T>
+where S: Stream<Item = Result<Token, Error>> + Unpin, T: TokenTransformer + Unpin {
+    type Item = Result<Token, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let inner = Pin::new(self.inner.deref());
-        let next = inner.poll_next(cx);
-
-        next.map(|object_option| -> Option<ODataObject> {
-            object_option.map(|stream_object| -> ODataObject {
-                let mut result = stream_object.clone();
-                for key in stream_object.keys() {
-                    if !self.whitelist.contains(key) {
-                        result.remove(key);
+        let this = &mut *self;
+
+        loop {
+            if let Some(token) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(token)));
+            }
+
+            if this.finished {
+                return Poll::Ready(None);
+            }
+
+            match ready!(this.tokens.poll_next_unpin(cx)) {
+                Some(Ok(token)) => match this.transformer.push(token) {
+                    Ok(tokens) => {
+                        this.pending.extend(tokens);
+                        this.finished = this.transformer.is_done();
+                    },
+                    Err(err) => {
+                        this.finished = true;
+                        return Poll::Ready(Some(Err(err)));
                     }
-                }
+                },
+                // the error ends the stream anyway
+                Some(Err(err)) => {
+                    this.finished = true;
+                    return Poll::Ready(Some(Err(err)));
+                },
+                None => this.finished = true
+            }
+        }
+    }
+}
 
-                result
-            })
-        })
+/// `transform` for every stream of tokens, e.g. a `TokenStream` or the receiver of an iterator
+pub trait TransformTokens: Stream<Item = Result<Token, Error>> + Sized {
+    fn transform<T>(self, transformer: T) -> Transformed<Self, T>
+    where Self: Unpin + Send + 'static, T: TokenTransformer + Unpin + Send + 'static {
+        Transformed::new(self, transformer)
+    }
+}
+
+impl<S: Stream<Item = Result<Token, Error>>> TransformTokens for S {}
+
+/// a path of keys within the entities, separated by `.` or `/`: `Address/City`
+pub fn parse_attribute_path(path: &str) -> ValuePath {
+    let steps : Vec<ValuePosition> = path.split(['.', '/'])
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(|key| ValuePosition::Key(key.to_owned()))
+        .collect();
+
+    ValuePath::from(steps)
+}
+
+/// the nesting level of the entities: 1 for the elements of a top level array, 0 for a single entity. Known with the first token
+#[derive(Default)]
+struct EntityLevel {
+    level: Option<usize>
+}
+
+impl EntityLevel {
+    fn of(&mut self, token: &Token) -> usize {
+        *self.level.get_or_insert(if token.path.is_empty() && token.value == Value::StartArray { 1 } else { 0 })
+    }
+
+    /// the keys of the path within the entity, without the indexes of arrays: `Trips[1].Name` becomes `Trips.Name`
+    fn attribute_path(&mut self, token: &Token) -> ValuePath {
+        let level = self.of(token);
+        let steps : Vec<ValuePosition> = token.path.iter().skip(level).cloned().collect();
+        ValuePath::from(steps).build_key_path()
+    }
+}
+
+/// keeps only the listed attributes of the entities (with everything within them), or drops them
+pub struct EntityAttributeFilter {
+    paths: Vec<ValuePath>,
+    keep: bool,
+    entity_level: EntityLevel
+}
+
+impl EntityAttributeFilter {
+    /// keeps the attributes (and the objects leading to them) only
+    pub fn select(paths: Vec<ValuePath>) -> EntityAttributeFilter {
+        EntityAttributeFilter { paths, keep: true, entity_level: EntityLevel::default() }
+    }
+
+    pub fn drop(paths: Vec<ValuePath>) -> EntityAttributeFilter {
+        EntityAttributeFilter { paths, keep: false, entity_level: EntityLevel::default() }
+    }
+
+    /// the paths are separated by `,`: `Name,Address/City`
+    pub fn from_stringified_list(concatted_paths: &str, keep: bool) -> EntityAttributeFilter {
+        let paths = concatted_paths.split(',').map(parse_attribute_path).filter(|path| !path.is_empty()).collect();
+        EntityAttributeFilter { paths, keep, entity_level: EntityLevel::default() }
+    }
+}
+
+impl TokenTransformer for EntityAttributeFilter {
+    fn push(&mut self, token: Token) -> Result<Vec<Token>, Error> {
+        // the entities themselves (and the array around them)
+        let attribute_path = self.entity_level.attribute_path(&token);
+        if attribute_path.is_empty() {
+            return Ok(vec![token]);
+        }
+
+        let passes = if self.keep {
+            self.paths.iter().any(|path| attribute_path.starts_with(path) || path.starts_with(&attribute_path))
+        } else {
+            !self.paths.iter().any(|path| attribute_path.starts_with(path))
+        };
+
+        Ok(if passes { vec![token] } else { vec![] })
     }
 }
 
-pub struct LimitEntitiesFilter<TInner> where TInner : futures::stream::Stream<Item=ODataObject> + Send + Sync {
+/// passes on the first entities only and closes the top level array after them
+pub struct LimitEntitiesFilter {
     limit: usize,
     current: usize,
-    inner: TInner
+    entity_level: EntityLevel
 }
 
-impl<TInner> LimitEntitiesFilter<TInner> where TInner : futures::stream::Stream<Item=ODataObject> + Send + Sync {
-    pub fn new(inner: TInner, limit: usize) -> LimitEntitiesFilter<TInner> {
-        LimitEntitiesFilter { inner, limit, current: 0 }
+impl LimitEntitiesFilter {
+    pub fn new(limit: usize) -> LimitEntitiesFilter {
+        LimitEntitiesFilter { limit, current: 0, entity_level: EntityLevel::default() }
     }
 }
 
-impl<TInner: futures::stream::Stream> Stream for LimitEntitiesFilter<TInner> where TInner : futures::stream::Stream<Item=ODataObject> + Send + Sync  {
-    type Item = ODataObject;
+impl TokenTransformer for LimitEntitiesFilter {
+    fn push(&mut self, token: Token) -> Result<Vec<Token>, Error> {
+        if self.is_done() {
+            return Ok(vec![]);
+        }
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if self.limit == self.current {
-            return Poll::Ready(None);
+        // a single entity is not limited
+        if self.entity_level.of(&token) == 0 {
+            return Ok(vec![token]);
         }
 
+        let level = token.path.current_level();
+        if level == 0 && token.value == Value::StartArray && self.limit == 0 {
+            self.current = 1;
+            return Ok(vec![token, Token { path: ValuePath::new(), value: Value::EndArray }]);
+        }
+
+        if level != 1 || matches!(token.value, Value::StartObject | Value::StartArray) {
+            return Ok(vec![token]);
+        }
+
+        // the entity is complete
         self.current += 1;
-        self.as_mut().inner.poll_next(cx)
+        if self.current < self.limit {
+            return Ok(vec![token]);
+        }
+
+        Ok(vec![token, Token { path: ValuePath::new(), value: Value::EndArray }])
+    }
+
+    fn is_done(&self) -> bool {
+        self.current >= self.limit.max(1)
+    }
+}
+
+/// gives attributes of the entities other names, e.g. `Address/City` becomes `Address/Town`
+pub struct RenameAttributesFilter {
+    renames: Vec<(ValuePath, String)>,
+    entity_level: EntityLevel
+}
+
+impl RenameAttributesFilter {
+    /// the attributes with their new name, the last key of the path is renamed
+    pub fn new(renames: Vec<(ValuePath, String)>) -> RenameAttributesFilter {
+        RenameAttributesFilter { renames, entity_level: EntityLevel::default() }
+    }
+
+    /// the renames are separated by `,`: `Name=FullName,Address/City=Town`
+    pub fn from_stringified_list(concatted_renames: &str) -> Result<RenameAttributesFilter, Error> {
+        let renames = concatted_renames.split(',')
+            .map(|rename| match rename.split_once('=') {
+                Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Ok((parse_attribute_path(from), to.trim().to_owned())),
+                _ => Err(Error::InvalidQuery(format!("Invalid rename '{}', expected <attribute>=<new name>", rename)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(RenameAttributesFilter::new(renames))
+    }
+}
+
+impl TokenTransformer for RenameAttributesFilter {
+    fn push(&mut self, mut token: Token) -> Result<Vec<Token>, Error> {
+        let entity_level = self.entity_level.of(&token);
+        for (from, to) in &self.renames {
+            rename_key(&mut token.path, entity_level, from, to);
+        }
+
+        Ok(vec![token])
+    }
+}
+
+/// renames the key at the end of `from`, if the keys of the path (from the level on) start with it
+fn rename_key(path: &mut ValuePath, level: usize, from: &ValuePath, to: &str) {
+    let mut matched = 0;
+    for current in level..path.current_level() {
+        if let Some(ValuePosition::Key(key)) = path.get(current) {
+            match from.get(matched) {
+                Some(ValuePosition::Key(from_key)) if from_key == key => matched += 1,
+                _ => return
+            }
+
+            if matched == from.current_level() {
+                path.replace(current, ValuePosition::Key(to.to_owned()));
+                return;
+            }
+        }
+    }
+}
+
+/// drops the attributes without value (`null`). Elements of arrays are kept, they are numbered
+#[derive(Default)]
+pub struct DropNullsFilter {
+}
+
+impl DropNullsFilter {
+    pub fn new() -> DropNullsFilter {
+        DropNullsFilter {}
+    }
+}
+
+impl TokenTransformer for DropNullsFilter {
+    fn push(&mut self, token: Token) -> Result<Vec<Token>, Error> {
+        if token.value == Value::None && matches!(token.path.top_most(), Some(ValuePosition::Key(_))) {
+            return Ok(vec![]);
+        }
+
+        Ok(vec![token])
+    }
+}
+
+/// passes on the entities matching the predicate only, evaluated on the client. The remaining entities are numbered anew
+pub struct EntityPredicateFilter {
+    predicate: Box<dyn Fn(&Entity) -> bool + Send>,
+    entity_level: EntityLevel,
+    /// the tokens of the current entity
+    entity: Vec<Token>,
+    /// the index of the next entity passed on
+    next_index: Option<usize>
+}
+
+impl EntityPredicateFilter {
+    pub fn new<P: Fn(&Entity) -> bool + Send + 'static>(predicate: P) -> EntityPredicateFilter {
+        EntityPredicateFilter { predicate: Box::new(predicate), entity_level: EntityLevel::default(), entity: vec![], next_index: None }
+    }
+}
+
+impl TokenTransformer for EntityPredicateFilter {
+    fn push(&mut self, token: Token) -> Result<Vec<Token>, Error> {
+        let entity_level = self.entity_level.of(&token);
+        let level = token.path.current_level();
+        if level < entity_level {
+            return Ok(vec![token]);
+        }
+
+        let completes_entity = level == entity_level && !matches!(token.value, Value::StartObject | Value::StartArray);
+        self.entity.push(token);
+        if !completes_entity {
+            return Ok(vec![]);
+        }
+
+        let mut tokens = std::mem::take(&mut self.entity);
+        // counted from the first entity, dropped or not
+        let index = match tokens[0].path.get(0) {
+            Some(ValuePosition::Index(index)) => *self.next_index.get_or_insert(*index),
+            _ => 0
+        };
+
+        if !(self.predicate)(&from_tokens::<Entity>(tokens.clone())?) {
+            return Ok(vec![]);
+        }
+
+        if entity_level == 1 {
+            for token in tokens.iter_mut() {
+                token.path.replace(0, ValuePosition::Index(index));
+            }
+
+            self.next_index = Some(index + 1);
+        }
+
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::json_stream::token::escape;
+
+    /// the tokens of the value as a provider streams them: strings and keys JSON-escaped
+    fn tokens(value: &Entity) -> Vec<Token> {
+        fn push(value: &Entity, path: &ValuePath, tokens: &mut Vec<Token>) {
+            let token = |value: Value| Token { path: path.clone(), value };
+            let child = |position: ValuePosition| {
+                let mut child = path.clone();
+                child.push(position);
+                child
+            };
+
+            match value {
+                Entity::Object(properties) => {
+                    tokens.push(token(Value::StartObject));
+                    for (name, property) in properties {
+                        push(property, &child(ValuePosition::Key(escape(name))), tokens);
+                    }
+                    tokens.push(token(Value::EndObject));
+                },
+                Entity::Array(items) => {
+                    tokens.push(token(Value::StartArray));
+                    for (index, item) in items.iter().enumerate() {
+                        push(item, &child(ValuePosition::Index(index)), tokens);
+                    }
+                    tokens.push(token(Value::EndArray));
+                },
+                Entity::String(text) => tokens.push(token(Value::String(escape(text)))),
+                Entity::Number(number) => tokens.push(token(Value::Number(number.to_string()))),
+                Entity::Bool(boolean) => tokens.push(token(Value::Boolean(*boolean))),
+                Entity::Null => tokens.push(token(Value::None))
+            }
+        }
+
+        let mut tokens = vec![];
+        push(value, &ValuePath::new(), &mut tokens);
+        tokens
+    }
+
+    /// the entities passed on by the transformer
+    fn transform<T: TokenTransformer>(mut transformer: T, entities: Entity) -> Entity {
+        let mut passed = vec![];
+        for token in tokens(&entities) {
+            if transformer.is_done() {
+                break;
+            }
+
+            passed.extend(transformer.push(token).expect("failed to transform"));
+        }
+
+        from_tokens(passed).expect("invalid tokens")
+    }
+
+    fn people() -> Entity {
+        json!([
+            { "Id": 1, "Name": "Russell", "Address": { "City": "Bern", "Zip": "3000" }, "Note": null },
+            { "Id": 2, "Name": "Scott \"Scotty\"", "Address": { "City": "Basel", "Zip": null }, "Note": "new" },
+            { "Id": 3, "Name": "Ronald", "Address": null, "Note": null }
+        ])
+    }
+
+    #[test]
+    fn selects_attributes() {
+        let selected = transform(EntityAttributeFilter::from_stringified_list("Id, Address/City", true), people());
+
+        assert_eq!(selected, json!([
+            { "Id": 1, "Address": { "City": "Bern" } },
+            { "Id": 2, "Address": { "City": "Basel" } },
+            { "Id": 3, "Address": null }
+        ]));
+    }
+
+    #[test]
+    fn drops_attributes() {
+        let dropped = transform(EntityAttributeFilter::from_stringified_list("Name,Address.Zip", false), json!({ "Id": 1, "Name": "Russell", "Address": { "City": "Bern", "Zip": "3000" } }));
+
+        assert_eq!(dropped, json!({ "Id": 1, "Address": { "City": "Bern" } }));
+    }
+
+    #[test]
+    fn limits_entities() {
+        let ids = |entities: Entity| entities.as_array().map(|entities| entities.iter().map(|entity| entity["Id"].clone()).collect::<Vec<_>>()).unwrap_or_default();
+
+        assert_eq!(ids(transform(LimitEntitiesFilter::new(2), people())), vec![json!(1), json!(2)]);
+        assert_eq!(ids(transform(LimitEntitiesFilter::new(5), people())), vec![json!(1), json!(2), json!(3)]);
+        assert_eq!(transform(LimitEntitiesFilter::new(0), people()), json!([]));
+
+        let mut limit = LimitEntitiesFilter::new(1);
+        assert!(!limit.is_done());
+        for token in tokens(&people()) {
+            limit.push(token).expect("failed to limit");
+        }
+        assert!(limit.is_done());
+    }
+
+    #[test]
+    fn renames_attributes() {
+        let renamer = RenameAttributesFilter::from_stringified_list("Address/City=Town, Name=FullName").expect("invalid renames");
+        let renamed = transform(renamer, json!([{ "Name": "Russell", "City": "Bern", "Address": { "City": "Bern" } }]));
+
+        assert_eq!(renamed, json!([{ "FullName": "Russell", "City": "Bern", "Address": { "Town": "Bern" } }]));
+        assert!(RenameAttributesFilter::from_stringified_list("Name").is_err());
+    }
+
+    #[test]
+    fn drops_null_attributes() {
+        let without_nulls = transform(DropNullsFilter::new(), json!([{ "Id": 1, "Note": null, "Address": { "Zip": null }, "Tags": [null, "a"] }]));
+
+        assert_eq!(without_nulls, json!([{ "Id": 1, "Address": {}, "Tags": [null, "a"] }]));
+    }
+
+    #[test]
+    fn keeps_matching_entities() {
+        let named = |name: &'static str| EntityPredicateFilter::new(move |entity: &Entity| entity["Name"] == name);
+
+        // compared with the unescaped text, numbered anew
+        let matching = transform(named("Scott \"Scotty\""), people());
+        assert_eq!(matching, json!([{ "Id": 2, "Name": "Scott \"Scotty\"", "Address": { "City": "Basel", "Zip": null }, "Note": "new" }]));
+
+        let single = json!({ "Id": 1, "Name": "Russell" });
+        assert_eq!(transform(named("Russell"), single.clone()), single);
+    }
+
+    #[test]
+    fn applies_the_steps_in_order() {
+        let pipeline = Pipeline::new()
+            .then(EntityPredicateFilter::new(|entity: &Entity| entity["Note"].is_null()))
+            .then(RenameAttributesFilter::from_stringified_list("Id=Number").expect("invalid renames"))
+            .then(EntityAttributeFilter::from_stringified_list("Number", true))
+            .then(LimitEntitiesFilter::new(1));
+
+        assert_eq!(transform(pipeline, people()), json!([{ "Number": 1 }]));
+    }
+
+    #[tokio::test]
+    async fn transforms_streams() {
+        let stream = futures::stream::iter(tokens(&people()).into_iter().map(Ok));
+        let passed : Vec<Token> = stream.transform(LimitEntitiesFilter::new(1)).map(|token| token.expect("failed token")).collect().await;
+
+        assert_eq!(from_tokens::<Entity>(passed).expect("invalid tokens"), json!([people()[0]]));
     }
 }
-*/
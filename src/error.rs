@@ -0,0 +1,136 @@
+use reqwest::StatusCode;
+use crate::json_stream::decode::DecodeError;
+
+/// everything that can go wrong when talking to an OData service
+#[derive(Debug)]
+pub enum Error {
+    /// the service responded with an error status code, if possible with the OData error it sent
+    Http { status: StatusCode, url: String, service_error: Option<Box<ServiceError>> },
+    /// the request could not be sent or the response could not be received
+    Transport(reqwest::Error),
+    /// the response is not well-formed JSON/XML. `offset` is the position (in bytes) in the response, if known
+    Decode { message: String, offset: Option<usize> },
+    /// the response is well-formed, but not structured as expected from an OData service
+    Protocol(String),
+    /// no credentials could be obtained (token file, token endpoint, ...)
+    Authentication(String),
+    /// the request could not be built from the query: invalid URL, `$expand`, `$filter`, ...
+    InvalidQuery(String),
+    /// the loaded content could not be converted into the output format
+    Converter(String),
+    /// an entity could not be deserialized into the requested type
+    Deserialize(String),
+    Io(std::io::Error)
+}
+
+/// the error object an OData service sends along with an error status code (`{ "error": { "code": ..., "message": ... } }`)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceError {
+    pub code: String,
+    pub message: String,
+    pub target: Option<String>,
+    pub details: Vec<ServiceError>,
+    /// service specific debug information, e.g. a stack trace
+    pub inner_error: Option<serde_json::Value>
+}
+
+impl Error {
+    pub(crate) fn decode<T: Into<String>>(message: T, offset: Option<usize>) -> Error {
+        Error::Decode { message: message.into(), offset }
+    }
+
+    /// the HTTP status, for errors responded by the service
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Http { status, .. } => Some(*status),
+            Error::Transport(err) => err.status(),
+            _ => None
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http { status, url, service_error: Some(service_error) } => write!(f, "{} ({}): {}", status, url, service_error),
+            Error::Http { status, url, service_error: None } => write!(f, "{} ({})", status, url),
+            Error::Transport(err) => write!(f, "Request failed: {}", err),
+            Error::Decode { message, offset: Some(offset) } => write!(f, "Decode-Error at byte {}: {}", offset, message),
+            Error::Decode { message, offset: None } => write!(f, "Decode-Error: {}", message),
+            Error::Protocol(message) => write!(f, "Unexpected response: {}", message),
+            Error::Authentication(message) => write!(f, "Authentication failed: {}", message),
+            Error::InvalidQuery(message) => f.write_str(message),
+            Error::Converter(message) => write!(f, "Conversion failed: {}", message),
+            Error::Deserialize(message) => write!(f, "Deserialization failed: {}", message),
+            Error::Io(err) => write!(f, "I/O-Error: {}", err)
+        }
+    }
+}
+
+impl std::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.code.is_empty() {
+            f.write_str(&self.message)?;
+        } else {
+            write!(f, "[{}] {}", self.code, self.message)?;
+        }
+
+        if let Some(target) = &self.target {
+            write!(f, " (target: {})", target)?;
+        }
+
+        for detail in &self.details {
+            write!(f, "\n  - {}", detail)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: std::fmt::Display>(message: T) -> Self {
+        Error::Deserialize(message.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(source_error: reqwest::Error) -> Self {
+        Error::Transport(source_error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source_error: std::io::Error) -> Self {
+        Error::Io(source_error)
+    }
+}
+
+impl From<crate::json_stream::stream::Error> for Error {
+    fn from(source_error: crate::json_stream::stream::Error) -> Self {
+        match source_error {
+            crate::json_stream::stream::Error::IoError(err) => Error::Io(err),
+            crate::json_stream::stream::Error::TransportError(err) => Error::Transport(err),
+            crate::json_stream::stream::Error::DecodeError(content, offset) => {
+                let message = match content {
+                    DecodeError::InvalidUnicodeEscape(payload) => format!("String contains a sequence {:x} which is an invalid unicode code point", payload),
+                    DecodeError::NeedsMore => "Needs more".to_owned(),
+                    DecodeError::UnexpectedEndOfStream => "More input needed to finish parse, but input bytes marked as end of stream".to_owned(),
+                    DecodeError::InvalidUtf8 => "Invalid UTF-8".to_owned(),
+                    DecodeError::UnexpectedByte(payload) => format!("found an invalid byte: {:x}", payload)
+                };
+
+                Error::Decode { message, offset: Some(offset) }
+            }
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::filter::literal::Literal;
+use crate::filter::validation::FilterValidator;
+use crate::metadata::csdl::ServiceMetadata;
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparisonOperator {
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterOrEqual,
+    LessThan,
+    LessOrEqual
+}
+
+impl ComparisonOperator {
+    fn keyword(&self) -> &'static str {
+        match self {
+            ComparisonOperator::Equal => "eq",
+            ComparisonOperator::NotEqual => "ne",
+            ComparisonOperator::GreaterThan => "gt",
+            ComparisonOperator::GreaterOrEqual => "ge",
+            ComparisonOperator::LessThan => "lt",
+            ComparisonOperator::LessOrEqual => "le"
+        }
+    }
+
+    /// `eq` and `ne` work for every type, the others need an ordered type
+    pub fn is_ordering(&self) -> bool {
+        !matches!(self, ComparisonOperator::Equal | ComparisonOperator::NotEqual)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOperator {
+    And,
+    Or
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo
+}
+
+impl ArithmeticOperator {
+    fn keyword(&self) -> &'static str {
+        match self {
+            ArithmeticOperator::Add => "add",
+            ArithmeticOperator::Subtract => "sub",
+            ArithmeticOperator::Multiply => "mul",
+            ArithmeticOperator::Divide => "div",
+            ArithmeticOperator::Modulo => "mod"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LambdaOperator {
+    Any,
+    All
+}
+
+/// a typed `$filter` expression. Build it with `property`, `literal` and the combinators, and render it with `to_string()`:
+/// `property("Name").starts_with("Ru").and(property("Age").gt(30))` becomes `startswith(Name,'Ru') and Age gt 30`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    /// a property path, segments separated by `/`. Inside a lambda the path starts with the lambda variable
+    Property(String),
+    Literal(Literal),
+    Comparison { left: Box<Expression>, operator: ComparisonOperator, right: Box<Expression> },
+    Logical { left: Box<Expression>, operator: LogicalOperator, right: Box<Expression> },
+    Not(Box<Expression>),
+    Arithmetic { left: Box<Expression>, operator: ArithmeticOperator, right: Box<Expression> },
+    Negate(Box<Expression>),
+    /// a built-in function like `contains`, `tolower` or `year`
+    Function { name: String, arguments: Vec<Expression> },
+    In { operand: Box<Expression>, values: Vec<Expression> },
+    /// `any`/`all` on a collection; `any` without predicate checks for a non-empty collection
+    Lambda { collection: String, operator: LambdaOperator, variable: Option<String>, predicate: Option<Box<Expression>> }
+}
+
+pub fn property<T: Into<String>>(path: T) -> Expression {
+    Expression::Property(path.into())
+}
+
+pub fn literal<T: Into<Literal>>(value: T) -> Expression {
+    Expression::Literal(value.into())
+}
+
+/// the current date and time of the service (`now()`)
+pub fn now() -> Expression {
+    Expression::Function { name: "now".to_owned(), arguments: vec![] }
+}
+
+impl Expression {
+    pub fn eq<T: Into<Expression>>(self, other: T) -> Expression {
+        self.compare(ComparisonOperator::Equal, other)
+    }
+
+    pub fn ne<T: Into<Expression>>(self, other: T) -> Expression {
+        self.compare(ComparisonOperator::NotEqual, other)
+    }
+
+    pub fn gt<T: Into<Expression>>(self, other: T) -> Expression {
+        self.compare(ComparisonOperator::GreaterThan, other)
+    }
+
+    pub fn ge<T: Into<Expression>>(self, other: T) -> Expression {
+        self.compare(ComparisonOperator::GreaterOrEqual, other)
+    }
+
+    pub fn lt<T: Into<Expression>>(self, other: T) -> Expression {
+        self.compare(ComparisonOperator::LessThan, other)
+    }
+
+    pub fn le<T: Into<Expression>>(self, other: T) -> Expression {
+        self.compare(ComparisonOperator::LessOrEqual, other)
+    }
+
+    pub fn compare<T: Into<Expression>>(self, operator: ComparisonOperator, other: T) -> Expression {
+        Expression::Comparison { left: Box::new(self), operator, right: Box::new(other.into()) }
+    }
+
+    pub fn and(self, other: Expression) -> Expression {
+        Expression::Logical { left: Box::new(self), operator: LogicalOperator::And, right: Box::new(other) }
+    }
+
+    pub fn or(self, other: Expression) -> Expression {
+        Expression::Logical { left: Box::new(self), operator: LogicalOperator::Or, right: Box::new(other) }
+    }
+
+    pub fn is_in<T: Into<Expression>, I: IntoIterator<Item = T>>(self, values: I) -> Expression {
+        Expression::In { operand: Box::new(self), values: values.into_iter().map(Into::into).collect() }
+    }
+
+    pub fn contains<T: Into<Expression>>(self, other: T) -> Expression {
+        Self::function("contains", vec![self, other.into()])
+    }
+
+    pub fn starts_with<T: Into<Expression>>(self, other: T) -> Expression {
+        Self::function("startswith", vec![self, other.into()])
+    }
+
+    pub fn ends_with<T: Into<Expression>>(self, other: T) -> Expression {
+        Self::function("endswith", vec![self, other.into()])
+    }
+
+    pub fn index_of<T: Into<Expression>>(self, other: T) -> Expression {
+        Self::function("indexof", vec![self, other.into()])
+    }
+
+    pub fn length(self) -> Expression {
+        Self::function("length", vec![self])
+    }
+
+    pub fn to_lower(self) -> Expression {
+        Self::function("tolower", vec![self])
+    }
+
+    pub fn to_upper(self) -> Expression {
+        Self::function("toupper", vec![self])
+    }
+
+    pub fn trim(self) -> Expression {
+        Self::function("trim", vec![self])
+    }
+
+    pub fn year(self) -> Expression {
+        Self::function("year", vec![self])
+    }
+
+    pub fn month(self) -> Expression {
+        Self::function("month", vec![self])
+    }
+
+    pub fn day(self) -> Expression {
+        Self::function("day", vec![self])
+    }
+
+    pub fn hour(self) -> Expression {
+        Self::function("hour", vec![self])
+    }
+
+    pub fn minute(self) -> Expression {
+        Self::function("minute", vec![self])
+    }
+
+    pub fn second(self) -> Expression {
+        Self::function("second", vec![self])
+    }
+
+    /// the date part of a timestamp
+    pub fn date(self) -> Expression {
+        Self::function("date", vec![self])
+    }
+
+    /// the time part of a timestamp
+    pub fn time(self) -> Expression {
+        Self::function("time", vec![self])
+    }
+
+    pub fn function<T: Into<String>>(name: T, arguments: Vec<Expression>) -> Expression {
+        Expression::Function { name: name.into(), arguments }
+    }
+
+    /// `collection/any(variable:predicate)`, the predicate refers to the elements via `property("variable/...")`
+    pub fn any<C: Into<String>, V: Into<String>>(collection: C, variable: V, predicate: Expression) -> Expression {
+        Expression::Lambda { collection: collection.into(), operator: LambdaOperator::Any, variable: Some(variable.into()), predicate: Some(Box::new(predicate)) }
+    }
+
+    /// `collection/any()`, true if the collection is not empty
+    pub fn not_empty<C: Into<String>>(collection: C) -> Expression {
+        Expression::Lambda { collection: collection.into(), operator: LambdaOperator::Any, variable: None, predicate: None }
+    }
+
+    pub fn all<C: Into<String>, V: Into<String>>(collection: C, variable: V, predicate: Expression) -> Expression {
+        Expression::Lambda { collection: collection.into(), operator: LambdaOperator::All, variable: Some(variable.into()), predicate: Some(Box::new(predicate)) }
+    }
+
+    /// checks that all properties exist on the entity type of the entity set and the types of the operands fit together
+    pub fn validate(&self, metadata: &ServiceMetadata, entity_set_name: &str) -> Result<(), Error> {
+        FilterValidator::for_entity_set(metadata, entity_set_name)?.validate(self)
+    }
+
+    /// binding strength of the operators, to know when parentheses are needed
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Logical { operator: LogicalOperator::Or, .. } => 1,
+            Expression::Logical { operator: LogicalOperator::And, .. } => 2,
+            Expression::Comparison { .. } | Expression::In { .. } => 3,
+            Expression::Arithmetic { operator: ArithmeticOperator::Add, .. } | Expression::Arithmetic { operator: ArithmeticOperator::Subtract, .. } => 4,
+            Expression::Arithmetic { .. } => 5,
+            Expression::Not(_) | Expression::Negate(_) => 6,
+            _ => 7
+        }
+    }
+
+    fn write_operand(&self, f: &mut std::fmt::Formatter<'_>, operand: &Expression, is_right: bool) -> std::fmt::Result {
+        // operators are left associative, so an operand on the right of the same precedence needs parentheses as well.
+        // Comparisons are always grouped, as OData binds `gt`/`lt`/... stronger than `eq`/`ne`
+        let same_precedence = operand.precedence() == self.precedence();
+        let needs_parentheses = operand.precedence() < self.precedence() || (same_precedence && (is_right || self.precedence() == 3));
+
+        if needs_parentheses {
+            write!(f, "({})", operand)
+        } else {
+            write!(f, "{}", operand)
+        }
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Property(path) => f.write_str(path),
+            Expression::Literal(value) => write!(f, "{}", value),
+            Expression::Comparison { left, operator, right } => {
+                self.write_operand(f, left, false)?;
+                write!(f, " {} ", operator.keyword())?;
+                self.write_operand(f, right, true)
+            },
+            Expression::Logical { left, operator, right } => {
+                self.write_operand(f, left, false)?;
+                f.write_str(if *operator == LogicalOperator::And { " and " } else { " or " })?;
+                self.write_operand(f, right, true)
+            },
+            Expression::Arithmetic { left, operator, right } => {
+                self.write_operand(f, left, false)?;
+                write!(f, " {} ", operator.keyword())?;
+                self.write_operand(f, right, true)
+            },
+            Expression::Not(operand) => {
+                f.write_str("not ")?;
+                self.write_operand(f, operand, true)
+            },
+            Expression::Negate(operand) => {
+                f.write_str("-")?;
+                self.write_operand(f, operand, true)
+            },
+            Expression::Function { name, arguments } => {
+                let arguments : Vec<String> = arguments.iter().map(|argument| argument.to_string()).collect();
+                write!(f, "{}({})", name, arguments.join(","))
+            },
+            Expression::In { operand, values } => {
+                self.write_operand(f, operand, false)?;
+                let values : Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                write!(f, " in ({})", values.join(","))
+            },
+            Expression::Lambda { collection, operator, variable, predicate } => {
+                let keyword = if *operator == LambdaOperator::Any { "any" } else { "all" };
+                match (variable, predicate) {
+                    (Some(variable), Some(predicate)) => write!(f, "{}/{}({}:{})", collection, keyword, variable, predicate),
+                    _ => write!(f, "{}/{}()", collection, keyword)
+                }
+            }
+        }
+    }
+}
+
+impl From<Literal> for Expression {
+    fn from(value: Literal) -> Self {
+        Expression::Literal(value)
+    }
+}
+
+impl From<bool> for Expression {
+    fn from(value: bool) -> Self {
+        literal(value)
+    }
+}
+
+impl From<i32> for Expression {
+    fn from(value: i32) -> Self {
+        literal(value)
+    }
+}
+
+impl From<i64> for Expression {
+    fn from(value: i64) -> Self {
+        literal(value)
+    }
+}
+
+impl From<u32> for Expression {
+    fn from(value: u32) -> Self {
+        literal(value)
+    }
+}
+
+impl From<f64> for Expression {
+    fn from(value: f64) -> Self {
+        literal(value)
+    }
+}
+
+/// plain strings are string literals, use `property` to refer to a property
+impl From<&str> for Expression {
+    fn from(value: &str) -> Self {
+        literal(value)
+    }
+}
+
+impl From<String> for Expression {
+    fn from(value: String) -> Self {
+        literal(value)
+    }
+}
+
+impl From<std::time::SystemTime> for Expression {
+    fn from(value: std::time::SystemTime) -> Self {
+        literal(value)
+    }
+}
+
+impl std::ops::Not for Expression {
+    type Output = Expression;
+
+    fn not(self) -> Expression {
+        Expression::Not(Box::new(self))
+    }
+}
+
+impl std::ops::Neg for Expression {
+    type Output = Expression;
+
+    fn neg(self) -> Expression {
+        Expression::Negate(Box::new(self))
+    }
+}
+
+macro_rules! arithmetic_operator {
+    ($trait_name:ident, $method:ident, $operator:expr) => {
+        impl<T: Into<Expression>> std::ops::$trait_name<T> for Expression {
+            type Output = Expression;
+
+            fn $method(self, other: T) -> Expression {
+                Expression::Arithmetic { left: Box::new(self), operator: $operator, right: Box::new(other.into()) }
+            }
+        }
+    };
+}
+
+arithmetic_operator!(Add, add, ArithmeticOperator::Add);
+arithmetic_operator!(Sub, sub, ArithmeticOperator::Subtract);
+arithmetic_operator!(Mul, mul, ArithmeticOperator::Multiply);
+arithmetic_operator!(Div, div, ArithmeticOperator::Divide);
+arithmetic_operator!(Rem, rem, ArithmeticOperator::Modulo);
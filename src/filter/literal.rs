@@ -0,0 +1,343 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::error::Error;
+use crate::model::ProtocolVersion;
+
+/// a constant value in a `$filter` expression, rendered in the OData (V4) literal syntax
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    /// kept as text, to not lose any precision
+    Decimal(String),
+    Double(f64),
+    String(String),
+    Guid(String),
+    Date(String),
+    DateTimeOffset(String),
+    TimeOfDay(String),
+    Duration(String),
+    Enum { type_name: String, member: String }
+}
+
+impl Literal {
+    pub fn decimal<T: Into<String>>(value: T) -> Result<Literal, Error> {
+        let value = value.into();
+        let digits = value.strip_prefix('-').unwrap_or(&value);
+        let mut parts = digits.splitn(2, '.');
+        let integral = parts.next().unwrap_or_default();
+        let fraction = parts.next();
+
+        let is_number = !integral.is_empty() && integral.chars().all(|c| c.is_ascii_digit())
+            && fraction.map(|fraction| !fraction.is_empty() && fraction.chars().all(|c| c.is_ascii_digit())).unwrap_or(true);
+
+        if !is_number {
+            return Err(Error::InvalidQuery(format!("'{}' is not a valid decimal", value)));
+        }
+
+        Ok(Literal::Decimal(value))
+    }
+
+    pub fn guid<T: Into<String>>(value: T) -> Result<Literal, Error> {
+        Self::checked(value.into(), "xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx", "GUID", Literal::Guid)
+    }
+
+    /// a date like `2021-03-31`
+    pub fn date<T: Into<String>>(value: T) -> Result<Literal, Error> {
+        Self::checked(value.into(), "dddd-dd-dd", "date", Literal::Date)
+    }
+
+    /// a timestamp like `2021-03-31T12:00:00Z` or `2021-03-31T12:00:00.123+02:00`
+    pub fn date_time_offset<T: Into<String>>(value: T) -> Result<Literal, Error> {
+        let value = value.into();
+        let is_valid = value.len() > 16 && value.is_char_boundary(16)
+            && matches_pattern(&value[..16], "dddd-dd-ddTdd:dd")
+            && (value.ends_with('Z') || value[16..].contains('+') || value[16..].contains('-'));
+
+        if !is_valid {
+            return Err(Error::InvalidQuery(format!("'{}' is not a valid timestamp, expected e.g. 2021-03-31T12:00:00Z", value)));
+        }
+
+        Ok(Literal::DateTimeOffset(value))
+    }
+
+    /// a time like `12:30` or `12:30:15.5`
+    pub fn time_of_day<T: Into<String>>(value: T) -> Result<Literal, Error> {
+        let value = value.into();
+        if value.len() < 5 || !value.is_char_boundary(5) || !matches_pattern(&value[..5], "dd:dd") {
+            return Err(Error::InvalidQuery(format!("'{}' is not a valid time of day, expected e.g. 12:30:00", value)));
+        }
+
+        Ok(Literal::TimeOfDay(value))
+    }
+
+    /// an ISO 8601 duration like `P1DT2H`
+    pub fn duration<T: Into<String>>(value: T) -> Result<Literal, Error> {
+        let value = value.into();
+        let digits = value.strip_prefix('-').unwrap_or(&value);
+        if !digits.starts_with('P') || digits.len() < 3 {
+            return Err(Error::InvalidQuery(format!("'{}' is not a valid duration, expected e.g. P1DT2H", value)));
+        }
+
+        Ok(Literal::Duration(value))
+    }
+
+    /// a member of an enum type, with the qualified name of the type, e.g. `Microsoft.OData.SampleService.Models.TripPin.PersonGender`
+    pub fn enum_member<T: Into<String>, M: Into<String>>(type_name: T, member: M) -> Literal {
+        Literal::Enum { type_name: type_name.into(), member: member.into() }
+    }
+
+    /// reads a literal given as text (e.g. on the command line): strings in single quotes (`'Bern'`, `'01234'`, `'it''s'`),
+    /// `null`, `true`, `false`, numbers (`42`, `-1.5`), GUIDs, dates (`2021-03-31`) and timestamps (`2021-03-31T12:00:00Z`) without.
+    /// Nothing is taken as string unless quoted, numbers with leading zeros are rejected as they would lose them
+    pub fn parse(text: &str) -> Result<Literal, Error> {
+        if text.len() >= 2 && text.starts_with('\'') && text.ends_with('\'') {
+            return Ok(Literal::String(text[1..text.len() - 1].replace("''", "'")));
+        }
+
+        match text {
+            "null" => return Ok(Literal::Null),
+            "true" => return Ok(Literal::Boolean(true)),
+            "false" => return Ok(Literal::Boolean(false)),
+            _ => ()
+        }
+
+        let digits = text.strip_prefix('-').unwrap_or(text);
+        let has_leading_zero = digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.");
+        if !has_leading_zero {
+            if let Ok(value) = text.parse::<i64>() {
+                return Ok(Literal::Integer(value));
+            }
+
+            if let Ok(decimal) = Literal::decimal(text) {
+                return Ok(decimal);
+            }
+        }
+
+        Literal::guid(text)
+            .or_else(|_| Literal::date(text))
+            .or_else(|_| Literal::date_time_offset(text))
+            .map_err(|_| Error::InvalidQuery(format!("'{}' is not a literal, strings are enclosed in single quotes: {}", text, quote(text))))
+    }
+
+    /// the EDM type of the literal. `None` for `null`, which matches every type
+    pub fn type_name(&self) -> Option<String> {
+        let type_name = match self {
+            Literal::Null => return None,
+            Literal::Boolean(_) => "Edm.Boolean",
+            Literal::Integer(_) => "Edm.Int64",
+            Literal::Decimal(_) => "Edm.Decimal",
+            Literal::Double(_) => "Edm.Double",
+            Literal::String(_) => "Edm.String",
+            Literal::Guid(_) => "Edm.Guid",
+            Literal::Date(_) => "Edm.Date",
+            Literal::DateTimeOffset(_) => "Edm.DateTimeOffset",
+            Literal::TimeOfDay(_) => "Edm.TimeOfDay",
+            Literal::Duration(_) => "Edm.Duration",
+            Literal::Enum { type_name, .. } => type_name
+        };
+
+        Some(type_name.to_owned())
+    }
+
+    /// the literal in the syntax of the version, for a value of the type. V2 marks the type of most literals:
+    /// `guid'…'`, `datetime'…'`, `datetimeoffset'…'`, `time'…'`, `1.5M` and `5L` (only for `Edm.Int64`, so the type is needed)
+    pub fn format_for_version(&self, type_name: &str, version: ProtocolVersion) -> String {
+        if version == ProtocolVersion::V4 {
+            return self.to_string();
+        }
+
+        match self {
+            Literal::Integer(value) if type_name == "Edm.Int64" => format!("{}L", value),
+            Literal::Decimal(value) => format!("{}M", value),
+            Literal::Double(value) if value.is_finite() => format!("{}d", self),
+            Literal::Guid(value) => format!("guid{}", quote(value)),
+            Literal::Date(value) => format!("datetime'{}T00:00:00'", value),
+            // `Edm.DateTime` has no time zone
+            Literal::DateTimeOffset(value) if type_name == "Edm.DateTime" => format!("datetime{}", quote(value.trim_end_matches('Z'))),
+            Literal::DateTimeOffset(value) => format!("datetimeoffset{}", quote(value)),
+            Literal::Duration(value) => format!("time{}", quote(value)),
+            _ => self.to_string()
+        }
+    }
+
+    fn checked(value: String, pattern: &str, description: &str, literal: fn(String) -> Literal) -> Result<Literal, Error> {
+        if !matches_pattern(&value, pattern) {
+            return Err(Error::InvalidQuery(format!("'{}' is not a valid {}", value, description)));
+        }
+
+        Ok(literal(value))
+    }
+}
+
+/// `d` stands for a decimal digit, `x` for a hex digit, every other character must match exactly
+fn matches_pattern(value: &str, pattern: &str) -> bool {
+    value.len() == pattern.len() && value.chars().zip(pattern.chars()).all(|(actual, expected)| match expected {
+        'd' => actual.is_ascii_digit(),
+        'x' => actual.is_ascii_hexdigit(),
+        _ => actual == expected
+    })
+}
+
+/// string literals are enclosed in single quotes, which are escaped by doubling them
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+impl std::fmt::Display for Literal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Literal::Null => f.write_str("null"),
+            Literal::Boolean(value) => write!(f, "{}", value),
+            Literal::Integer(value) => write!(f, "{}", value),
+            Literal::Decimal(value) => f.write_str(value),
+            Literal::Double(value) => {
+                if value.is_nan() {
+                    f.write_str("NaN")
+                } else if value.is_infinite() {
+                    f.write_str(if *value > 0.0 { "INF" } else { "-INF" })
+                } else {
+                    // Debug keeps the decimal point (`1.0`) and uses the exponent notation for large numbers
+                    write!(f, "{:?}", value)
+                }
+            },
+            Literal::String(value) => f.write_str(&quote(value)),
+            Literal::Guid(value) | Literal::Date(value) | Literal::DateTimeOffset(value) | Literal::TimeOfDay(value) => f.write_str(value),
+            Literal::Duration(value) => write!(f, "duration{}", quote(value)),
+            Literal::Enum { type_name, member } => write!(f, "{}{}", type_name, quote(member))
+        }
+    }
+}
+
+impl From<bool> for Literal {
+    fn from(value: bool) -> Self {
+        Literal::Boolean(value)
+    }
+}
+
+impl From<i32> for Literal {
+    fn from(value: i32) -> Self {
+        Literal::Integer(value as i64)
+    }
+}
+
+impl From<i64> for Literal {
+    fn from(value: i64) -> Self {
+        Literal::Integer(value)
+    }
+}
+
+impl From<u32> for Literal {
+    fn from(value: u32) -> Self {
+        Literal::Integer(value as i64)
+    }
+}
+
+impl From<f64> for Literal {
+    fn from(value: f64) -> Self {
+        Literal::Double(value)
+    }
+}
+
+impl From<&str> for Literal {
+    fn from(value: &str) -> Self {
+        Literal::String(value.to_owned())
+    }
+}
+
+impl From<String> for Literal {
+    fn from(value: String) -> Self {
+        Literal::String(value)
+    }
+}
+
+impl<T: Into<Literal>> From<Option<T>> for Literal {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or(Literal::Null)
+    }
+}
+
+/// timestamps are rendered in UTC, with millisecond precision if needed
+impl From<SystemTime> for Literal {
+    fn from(value: SystemTime) -> Self {
+        let (seconds, millis) = match value.duration_since(UNIX_EPOCH) {
+            Ok(duration) => (duration.as_secs() as i64, duration.subsec_millis()),
+            Err(err) => {
+                let before = err.duration();
+                let millis = before.subsec_millis();
+                if millis == 0 {
+                    (-(before.as_secs() as i64), 0)
+                } else {
+                    (-(before.as_secs() as i64) - 1, 1000 - millis)
+                }
+            }
+        };
+
+        let days = seconds.div_euclid(86_400);
+        let seconds_of_day = seconds.rem_euclid(86_400);
+        let (year, month, day) = civil_from_days(days);
+        let time = format!("{:02}:{:02}:{:02}", seconds_of_day / 3600, seconds_of_day % 3600 / 60, seconds_of_day % 60);
+
+        if millis == 0 {
+            Literal::DateTimeOffset(format!("{:04}-{:02}-{:02}T{}Z", year, month, day, time))
+        } else {
+            Literal::DateTimeOffset(format!("{:04}-{:02}-{:02}T{}.{:03}Z", year, month, day, time, millis))
+        }
+    }
+}
+
+/// converts days since 1970-01-01 into a (proleptic gregorian) date, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_literals() {
+        let parse = |text: &str| Literal::parse(text).ok();
+
+        assert_eq!(parse("42"), Some(Literal::Integer(42)));
+        assert_eq!(parse("-0.5"), Some(Literal::Decimal("-0.5".to_owned())));
+        assert_eq!(parse("0"), Some(Literal::Integer(0)));
+        assert_eq!(parse("null"), Some(Literal::Null));
+        assert_eq!(parse("false"), Some(Literal::Boolean(false)));
+        assert_eq!(parse("01234567-89ab-cdef-0123-456789abcdef"), Some(Literal::Guid("01234567-89ab-cdef-0123-456789abcdef".to_owned())));
+        assert_eq!(parse("2021-03-31"), Some(Literal::Date("2021-03-31".to_owned())));
+        assert_eq!(parse("2021-03-31T12:00:00Z"), Some(Literal::DateTimeOffset("2021-03-31T12:00:00Z".to_owned())));
+    }
+
+    #[test]
+    fn parses_only_quoted_strings() {
+        let parse = |text: &str| Literal::parse(text).ok();
+
+        assert_eq!(parse("'01234'"), Some(Literal::String("01234".to_owned())));
+        assert_eq!(parse("'it''s'"), Some(Literal::String("it's".to_owned())));
+        assert_eq!(parse("''"), Some(Literal::String(String::new())));
+        assert_eq!(parse("01234"), None);
+        assert_eq!(parse("-007.5"), None);
+        assert_eq!(parse("Bern"), None);
+        assert_eq!(parse("'Bern"), None);
+        assert_eq!(parse("aaaaaaaaaaaaaaaé"), None);
+    }
+
+    #[test]
+    fn rejects_text_with_multibyte_characters() {
+        assert!(Literal::date_time_offset("2021-03-31T12:0é:00Z").is_err());
+        assert!(Literal::time_of_day("12:3é").is_err());
+        assert!(Literal::time_of_day("1234é").is_err());
+        assert!(Literal::guid("é1234567-89ab-cdef-0123-456789abcde").is_err());
+    }
+}
@@ -0,0 +1,3 @@
+pub mod expression;
+pub mod literal;
+pub mod validation;
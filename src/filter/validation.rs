@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use crate::filter::expression::Expression;
+use crate::filter::literal::Literal;
+use crate::metadata::csdl::{element_type_name, is_collection_type, Property, NavigationProperty, ServiceMetadata};
+use crate::error::Error;
+
+const NUMERIC_TYPES: [&str; 8] = ["Edm.Byte", "Edm.SByte", "Edm.Int16", "Edm.Int32", "Edm.Int64", "Edm.Decimal", "Edm.Single", "Edm.Double"];
+const TEMPORAL_TYPES: [&str; 5] = ["Edm.Date", "Edm.DateTime", "Edm.DateTimeOffset", "Edm.TimeOfDay", "Edm.Time"];
+
+/// checks a `$filter` expression against the `$metadata` of the service, before sending it.
+/// Types that can't be determined (open types, unknown functions, `null`) are not checked
+pub struct FilterValidator<'a> {
+    metadata: &'a ServiceMetadata,
+    root_type: String,
+    /// lambda variables in scope, with the (element) type they stand for
+    variables: HashMap<String, String>
+}
+
+impl<'a> FilterValidator<'a> {
+    pub fn new(metadata: &'a ServiceMetadata, entity_type_name: &str) -> FilterValidator<'a> {
+        FilterValidator { metadata, root_type: metadata.normalize_type_name(entity_type_name), variables: HashMap::new() }
+    }
+
+    pub fn for_entity_set(metadata: &'a ServiceMetadata, entity_set_name: &str) -> Result<FilterValidator<'a>, Error> {
+        let entity_set = metadata.find_entity_set(entity_set_name)
+            .ok_or_else(|| Error::InvalidQuery(format!("Entity set '{}' not found in $metadata", entity_set_name)))?;
+
+        Ok(Self::new(metadata, &entity_set.entity_type))
+    }
+
+    pub fn validate(&mut self, expression: &Expression) -> Result<(), Error> {
+        match self.infer_type(expression)? {
+            Some(type_name) if type_name != "Edm.Boolean" => Err(Error::InvalidQuery(format!("Filter '{}' is of type {}, expected a boolean expression", expression, type_name))),
+            _ => Ok(())
+        }
+    }
+
+    fn infer_type(&mut self, expression: &Expression) -> Result<Option<String>, Error> {
+        match expression {
+            Expression::Property(path) => self.resolve_path(path),
+            Expression::Literal(value) => self.literal_type(value),
+            Expression::Comparison { left, operator, right } => {
+                let left_type = self.infer_type(left)?;
+                let right_type = self.infer_type(right)?;
+                self.check_comparable(expression, left_type.as_deref(), right_type.as_deref(), operator.is_ordering())?;
+
+                Ok(Some("Edm.Boolean".to_owned()))
+            },
+            Expression::Logical { left, right, .. } => {
+                self.expect_boolean(left)?;
+                self.expect_boolean(right)?;
+
+                Ok(Some("Edm.Boolean".to_owned()))
+            },
+            Expression::Not(operand) => {
+                self.expect_boolean(operand)?;
+
+                Ok(Some("Edm.Boolean".to_owned()))
+            },
+            Expression::Arithmetic { left, right, .. } => {
+                let left_type = self.infer_type(left)?;
+                let right_type = self.infer_type(right)?;
+
+                for (operand, operand_type) in [(left, &left_type), (right, &right_type)] {
+                    if let Some(operand_type) = operand_type {
+                        if !is_numeric(operand_type) && !is_temporal(operand_type) && operand_type != "Edm.Duration" {
+                            return Err(Error::InvalidQuery(format!("'{}' is of type {} and can't be used in arithmetic", operand, operand_type)));
+                        }
+                    }
+                }
+
+                Ok(left_type.or(right_type))
+            },
+            Expression::Negate(operand) => self.infer_type(operand),
+            Expression::Function { name, arguments } => self.function_type(expression, name, arguments),
+            Expression::In { operand, values } => {
+                let operand_type = self.infer_type(operand)?;
+                for value in values {
+                    let value_type = self.infer_type(value)?;
+                    self.check_comparable(expression, operand_type.as_deref(), value_type.as_deref(), false)?;
+                }
+
+                Ok(Some("Edm.Boolean".to_owned()))
+            },
+            Expression::Lambda { collection, variable, predicate, .. } => {
+                let collection_type = self.resolve_path(collection)?;
+
+                if let Some(collection_type) = &collection_type {
+                    if !is_collection_type(collection_type) {
+                        return Err(Error::InvalidQuery(format!("'{}' is not a collection, any/all can't be applied", collection)));
+                    }
+                }
+
+                if let (Some(variable), Some(predicate)) = (variable, predicate) {
+                    let element_type = collection_type.as_deref().map(|type_name| element_type_name(type_name).to_owned());
+                    let shadowed = match element_type {
+                        Some(element_type) => self.variables.insert(variable.clone(), element_type),
+                        None => self.variables.remove(variable)
+                    };
+
+                    let result = self.expect_boolean(predicate);
+
+                    match shadowed {
+                        Some(previous) => self.variables.insert(variable.clone(), previous),
+                        None => self.variables.remove(variable)
+                    };
+                    result?;
+                }
+
+                Ok(Some("Edm.Boolean".to_owned()))
+            }
+        }
+    }
+
+    fn expect_boolean(&mut self, expression: &Expression) -> Result<(), Error> {
+        match self.infer_type(expression)? {
+            Some(type_name) if type_name != "Edm.Boolean" => Err(Error::InvalidQuery(format!("'{}' is of type {}, expected a boolean expression", expression, type_name))),
+            _ => Ok(())
+        }
+    }
+
+    fn literal_type(&self, value: &Literal) -> Result<Option<String>, Error> {
+        if let Literal::Enum { type_name, member } = value {
+            let enum_type = self.metadata.find_enum_type(type_name)
+                .ok_or_else(|| Error::InvalidQuery(format!("Enum type '{}' not found in $metadata", type_name)))?;
+
+            if !enum_type.members.iter().any(|candidate| &candidate.name == member) {
+                return Err(Error::InvalidQuery(format!("'{}' is not a member of enum type '{}'", member, type_name)));
+            }
+
+            return Ok(Some(self.metadata.normalize_type_name(type_name)));
+        }
+
+        Ok(value.type_name())
+    }
+
+    /// follows a property path through complex types and navigation properties, returns the type of the last segment
+    fn resolve_path(&self, path: &str) -> Result<Option<String>, Error> {
+        let mut segments = path.split('/').peekable();
+        let mut current_type = match segments.peek().and_then(|first| self.variables.get(*first)) {
+            Some(variable_type) => {
+                segments.next();
+                variable_type.clone()
+            },
+            None => self.root_type.clone()
+        };
+
+        for segment in segments {
+            if is_collection_type(&current_type) {
+                return Err(Error::InvalidQuery(format!("'{}' in '{}' refers to a collection, use any/all to filter on its elements", segment, path)));
+            }
+
+            let (properties, navigation_properties, open_type) = match self.structured_type(&current_type) {
+                Some(members) => members,
+                // primitive types have no properties
+                None => return Err(Error::InvalidQuery(format!("Type {} has no property '{}' (in '{}')", current_type, segment, path)))
+            };
+
+            if let Some(property) = properties.iter().find(|property| property.name == segment) {
+                current_type = self.metadata.normalize_type_name(&property.type_name);
+            } else if let Some(navigation) = navigation_properties.iter().find(|navigation| navigation.name == segment) {
+                current_type = self.metadata.normalize_type_name(&navigation.type_name);
+            } else if open_type {
+                // dynamic properties of open types can be of any type
+                return Ok(None);
+            } else {
+                return Err(Error::InvalidQuery(format!("Property '{}' not found on type {} (in '{}')", segment, current_type, path)));
+            }
+        }
+
+        Ok(Some(current_type))
+    }
+
+    /// the properties (including inherited ones) of an entity or complex type
+    fn structured_type(&self, type_name: &str) -> Option<(Vec<&'a Property>, Vec<&'a NavigationProperty>, bool)> {
+        let metadata = self.metadata;
+        let mut properties = vec![];
+        let mut navigation_properties = vec![];
+        let mut open_type = false;
+        let mut next_type = Some(type_name.to_owned());
+        let mut found = false;
+
+        while let Some(current) = next_type.take() {
+            if let Some(entity_type) = metadata.find_entity_type(&current) {
+                properties.extend(entity_type.properties.iter());
+                navigation_properties.extend(entity_type.navigation_properties.iter());
+                open_type |= entity_type.open_type;
+                next_type = entity_type.base_type.clone();
+            } else if let Some(complex_type) = metadata.find_complex_type(&current) {
+                properties.extend(complex_type.properties.iter());
+                navigation_properties.extend(complex_type.navigation_properties.iter());
+                open_type |= complex_type.open_type;
+                next_type = complex_type.base_type.clone();
+            } else {
+                break;
+            }
+
+            found = true;
+        }
+
+        if found { Some((properties, navigation_properties, open_type)) } else { None }
+    }
+
+    fn check_comparable(&self, expression: &Expression, left: Option<&str>, right: Option<&str>, ordering: bool) -> Result<(), Error> {
+        let (left, right) = match (left, right) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return Ok(())
+        };
+
+        let compatible = left == right
+            || (is_numeric(left) && is_numeric(right))
+            || (is_temporal(left) && is_temporal(right))
+            // V2 services use strings for some types, e.g. Edm.Guid keys are compared against string literals
+            || (left == "Edm.String" || right == "Edm.String") && (left == "Edm.Guid" || right == "Edm.Guid");
+
+        if !compatible {
+            return Err(Error::InvalidQuery(format!("Can't compare {} with {} in '{}'", left, right, expression)));
+        }
+
+        let is_structured = self.structured_type(left).is_some() || is_collection_type(left);
+        if is_structured || (ordering && left == "Edm.Boolean") {
+            let operator = if ordering { "ordered" } else { "compared" };
+            return Err(Error::InvalidQuery(format!("Values of type {} can't be {} in '{}'", left, operator, expression)));
+        }
+
+        Ok(())
+    }
+
+    fn function_type(&mut self, expression: &Expression, name: &str, arguments: &[Expression]) -> Result<Option<String>, Error> {
+        let mut argument_types = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            argument_types.push(self.infer_type(argument)?);
+        }
+
+        let (expected, result) : (&[&[&str]], Option<&str>) = match name {
+            "contains" | "startswith" | "endswith" => (&[&["Edm.String"], &["Edm.String"]], Some("Edm.Boolean")),
+            "indexof" => (&[&["Edm.String"], &["Edm.String"]], Some("Edm.Int32")),
+            "length" => (&[&["Edm.String"]], Some("Edm.Int32")),
+            "tolower" | "toupper" | "trim" => (&[&["Edm.String"]], Some("Edm.String")),
+            "year" | "month" | "day" => (&[&["Edm.Date", "Edm.DateTime", "Edm.DateTimeOffset"]], Some("Edm.Int32")),
+            "hour" | "minute" | "second" => (&[&["Edm.TimeOfDay", "Edm.Time", "Edm.DateTime", "Edm.DateTimeOffset"]], Some("Edm.Int32")),
+            "date" => (&[&["Edm.DateTime", "Edm.DateTimeOffset"]], Some("Edm.Date")),
+            "time" => (&[&["Edm.DateTime", "Edm.DateTimeOffset"]], Some("Edm.TimeOfDay")),
+            "now" => (&[], Some("Edm.DateTimeOffset")),
+            // unknown (e.g. vendor specific) functions are passed through unchecked
+            _ => return Ok(None)
+        };
+
+        if expected.len() != arguments.len() {
+            return Err(Error::InvalidQuery(format!("{}() expects {} argument(s), got {} in '{}'", name, expected.len(), arguments.len(), expression)));
+        }
+
+        for ((argument, argument_type), allowed) in arguments.iter().zip(argument_types.iter()).zip(expected.iter()) {
+            if let Some(argument_type) = argument_type {
+                if !allowed.contains(&argument_type.as_str()) {
+                    return Err(Error::InvalidQuery(format!("{}() can't be applied to '{}' of type {}", name, argument, argument_type)));
+                }
+            }
+        }
+
+        Ok(result.map(|type_name| type_name.to_owned()))
+    }
+}
+
+fn is_numeric(type_name: &str) -> bool {
+    NUMERIC_TYPES.contains(&type_name)
+}
+
+fn is_temporal(type_name: &str) -> bool {
+    TEMPORAL_TYPES.contains(&type_name)
+}
@@ -15,8 +15,10 @@ use async_trait::async_trait;
 pub enum Error {
     /// Propagated error from the underlying Read object
     IoError(IoError),
-    /// Error that occurred during Json decoding.
-    DecodeError(DecodeError),
+    /// The response could not be (completely) received
+    TransportError(reqwest::Error),
+    /// Error that occurred during Json decoding, with the position (in bytes) in the stream.
+    DecodeError(DecodeError, usize),
 }
 
 impl From<IoError> for Error {
@@ -26,8 +28,9 @@ impl From<IoError> for Error {
 }
 
 impl From<DecodeError> for Error {
+    /// the position is filled in by `Stream::advance`
     fn from(d: DecodeError) -> Error {
-        Error::DecodeError(d)
+        Error::DecodeError(d, 0)
     }
 }
 
@@ -129,6 +132,8 @@ pub struct Stream<R> {
     buffer: Vec<u8>,
     indices: StreamIndices,
     decoder: JsonDecoder,
+    /// number of bytes already dropped from the buffer
+    consumed: usize,
     curr_token: Option<DerefJsonToken>,
     seen_eof: bool,
     src: R,
@@ -191,8 +196,14 @@ where
 {
     /// Advance to the next token.
     async fn advance(&mut self) -> Result<(), Error> {
-        self.curr_token = self.advance_impl().await?;
-        Ok(())
+        match self.advance_impl().await {
+            Ok(token) => {
+                self.curr_token = token;
+                Ok(())
+            },
+            Err(Error::DecodeError(error, _)) => Err(Error::DecodeError(error, self.position())),
+            Err(error) => Err(error)
+        }
     }
 
     /// Get the current token, or None if the stream is exhausted.
@@ -229,13 +240,19 @@ where
                 end: 0,
             },
             decoder: JsonDecoder::new(),
+            consumed: 0,
             curr_token: None,
             seen_eof: false,
             src,
         })
     }
 
-    async fn ensure_bytes(&mut self) -> Result<(), IoError> {
+    /// the position (in bytes) of the current token in the stream
+    pub fn position(&self) -> usize {
+        self.consumed + self.indices.start
+    }
+
+    async fn ensure_bytes(&mut self) -> Result<(), Error> {
         
         if self.indices.scanned >= self.indices.end {
             
@@ -250,6 +267,7 @@ where
                 }
                 self.indices.end = to_move;
                 self.indices.scanned -= self.indices.start;
+                self.consumed += self.indices.start;
                 self.indices.start = 0;
             }
 
@@ -261,7 +279,12 @@ where
                 self.buffer.len()
             );
 
-            if let Some(Ok(chunk_bytes)) = self.src.next().await {
+            let next_chunk = self.src.next().await;
+            if let Some(Err(err)) = next_chunk {
+                return Err(Error::TransportError(err));
+            }
+
+            if let Some(Ok(chunk_bytes)) = next_chunk {
                 let chunk_length = chunk_bytes.len();
                 let calculated_end = self.indices.scanned + chunk_length;
 
@@ -302,7 +325,7 @@ where
                 &mut self.indices,
                 &mut self.decoder,
             ) {
-                Err(Error::DecodeError(DecodeError::NeedsMore)) => {
+                Err(Error::DecodeError(DecodeError::NeedsMore, _)) => {
                     assert!(!self.seen_eof, "Cannot return NeedsMore if we've seen eof.");
                 }
                 n => {
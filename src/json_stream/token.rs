@@ -138,6 +138,12 @@ unsafe fn unescape(s: &str) -> String {
     res
 }
 
+/// the raw text of a JSON string with the given content (without the enclosing quotes), as the tokens of strings carry it
+pub fn escape(text: &str) -> String {
+    let quoted = serde_json::Value::String(text.to_owned()).to_string();
+    quoted[1..quoted.len() - 1].to_owned()
+}
+
 impl Into<String> for JsonString<'_> {
     fn into(self) -> String {
         // self.raw must be a valid set of escaped JSON string utf-8 bytes.
@@ -1,6 +1,11 @@
 pub mod convert;
+pub mod entity_stream;
+pub mod error;
+pub mod filter;
+pub mod metadata;
 pub mod model;
 pub mod provider;
 pub mod service;
 pub mod json_stream;
+pub mod reader;
 pub mod writer;
\ No newline at end of file
@@ -0,0 +1,297 @@
+use crate::model::ProtocolVersion;
+
+#[derive(Default, Debug, Clone)]
+pub struct ServiceMetadata {
+    pub version: String,
+    pub schemas: Vec<Schema>
+}
+
+impl ServiceMetadata {
+    /// the protocol the service speaks: V2 for the versions before 4.0 (`1.0` to `3.0`), V4 otherwise
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        match self.version.split('.').next().and_then(|major| major.parse::<u32>().ok()) {
+            Some(major) if major < 4 => ProtocolVersion::V2,
+            _ => ProtocolVersion::V4
+        }
+    }
+
+    pub fn entity_containers(&self) -> impl Iterator<Item = &EntityContainer> {
+        self.schemas.iter().flat_map(|schema| schema.entity_containers.iter())
+    }
+
+    pub fn find_entity_set(&self, name: &str) -> Option<&EntitySet> {
+        self.entity_containers().flat_map(|container| container.entity_sets.iter()).find(|entity_set| entity_set.name == name)
+    }
+
+    pub fn find_entity_type(&self, qualified_name: &str) -> Option<&EntityType> {
+        let (schema, name) = self.resolve_schema(qualified_name)?;
+        schema.entity_types.iter().find(|entity_type| entity_type.name == name)
+    }
+
+    pub fn find_complex_type(&self, qualified_name: &str) -> Option<&ComplexType> {
+        let (schema, name) = self.resolve_schema(qualified_name)?;
+        schema.complex_types.iter().find(|complex_type| complex_type.name == name)
+    }
+
+    pub fn find_enum_type(&self, qualified_name: &str) -> Option<&EnumType> {
+        let (schema, name) = self.resolve_schema(qualified_name)?;
+        schema.enum_types.iter().find(|enum_type| enum_type.name == name)
+    }
+
+    /// all properties of an entity type, including the ones inherited from its base types
+    pub fn entity_type_properties<'a>(&'a self, entity_type: &'a EntityType) -> Vec<&'a Property> {
+        let mut properties = match entity_type.base_type.as_ref().and_then(|base| self.find_entity_type(base)) {
+            Some(base_type) => self.entity_type_properties(base_type),
+            None => vec![]
+        };
+
+        properties.extend(entity_type.properties.iter());
+        properties
+    }
+
+    /// the key property names of an entity type, looking up the base types if it does not declare a key itself
+    pub fn entity_type_key(&self, entity_type: &EntityType) -> Vec<String> {
+        if !entity_type.key.is_empty() {
+            return entity_type.key.clone();
+        }
+
+        match entity_type.base_type.as_ref().and_then(|base| self.find_entity_type(base)) {
+            Some(base_type) => self.entity_type_key(base_type),
+            None => vec![]
+        }
+    }
+
+    /// looks up the declared type of a property of the entity type behind the given entity set
+    pub fn find_property_type(&self, entity_set_name: &str, property_name: &str) -> Option<&Property> {
+        let entity_set = self.find_entity_set(entity_set_name)?;
+        let entity_type = self.find_entity_type(&entity_set.entity_type)?;
+
+        self.entity_type_properties(entity_type).into_iter().find(|property| property.name == property_name)
+    }
+
+    /// replaces a schema alias in a qualified type name with the namespace of the schema
+    pub fn normalize_type_name(&self, qualified_name: &str) -> String {
+        let type_name = element_type_name(qualified_name);
+        let normalized = match self.resolve_schema(type_name) {
+            Some((schema, name)) => format!("{}.{}", schema.namespace, name),
+            None => type_name.to_owned()
+        };
+
+        if is_collection_type(qualified_name) {
+            format!("Collection({})", normalized)
+        } else {
+            normalized
+        }
+    }
+
+    fn resolve_schema<'a, 'b>(&'a self, qualified_name: &'b str) -> Option<(&'a Schema, &'b str)> {
+        let type_name = element_type_name(qualified_name);
+        let separator = type_name.rfind('.')?;
+        let (namespace, name) = (&type_name[..separator], &type_name[separator + 1..]);
+
+        self.schemas.iter()
+            .find(|schema| schema.namespace == namespace || schema.alias.as_deref() == Some(namespace))
+            .map(|schema| (schema, name))
+    }
+}
+
+/// strips a surrounding `Collection(...)` from a type name
+pub fn element_type_name(type_name: &str) -> &str {
+    if is_collection_type(type_name) {
+        &type_name["Collection(".len()..type_name.len() - 1]
+    } else {
+        type_name
+    }
+}
+
+pub fn is_collection_type(type_name: &str) -> bool {
+    type_name.starts_with("Collection(") && type_name.ends_with(')')
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Schema {
+    pub namespace: String,
+    pub alias: Option<String>,
+    pub entity_types: Vec<EntityType>,
+    pub complex_types: Vec<ComplexType>,
+    pub enum_types: Vec<EnumType>,
+    pub associations: Vec<Association>,
+    pub functions: Vec<Operation>,
+    pub actions: Vec<Operation>,
+    pub entity_containers: Vec<EntityContainer>,
+    pub annotations: Vec<AnnotationTarget>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct EntityType {
+    pub name: String,
+    pub base_type: Option<String>,
+    pub is_abstract: bool,
+    pub open_type: bool,
+    pub has_stream: bool,
+    pub key: Vec<String>,
+    pub properties: Vec<Property>,
+    pub navigation_properties: Vec<NavigationProperty>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ComplexType {
+    pub name: String,
+    pub base_type: Option<String>,
+    pub is_abstract: bool,
+    pub open_type: bool,
+    pub properties: Vec<Property>,
+    pub navigation_properties: Vec<NavigationProperty>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    pub type_name: String,
+    pub nullable: bool,
+    pub max_length: Option<String>,
+    pub precision: Option<String>,
+    pub scale: Option<String>,
+    pub default_value: Option<String>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct NavigationProperty {
+    pub name: String,
+    /// the target type; for OData V2 services it is resolved from the association after parsing
+    pub type_name: String,
+    pub nullable: bool,
+    pub partner: Option<String>,
+    pub contains_target: bool,
+    /// V2 only: the qualified name of the association
+    pub relationship: Option<String>,
+    /// V2 only: the association end this navigation leads to
+    pub to_role: Option<String>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct EnumType {
+    pub name: String,
+    pub underlying_type: Option<String>,
+    pub is_flags: bool,
+    pub members: Vec<EnumMember>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct EnumMember {
+    pub name: String,
+    pub value: Option<String>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Association {
+    pub name: String,
+    pub ends: Vec<AssociationEnd>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct AssociationEnd {
+    pub role: String,
+    pub type_name: String,
+    pub multiplicity: String
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    #[default]
+    Function,
+    Action
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub name: String,
+    pub is_bound: bool,
+    pub is_composable: bool,
+    pub entity_set_path: Option<String>,
+    pub parameters: Vec<Parameter>,
+    pub return_type: Option<ReturnType>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub type_name: String,
+    pub nullable: bool
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ReturnType {
+    pub type_name: String,
+    pub nullable: bool
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct EntityContainer {
+    pub name: String,
+    pub is_default: bool,
+    pub entity_sets: Vec<EntitySet>,
+    pub singletons: Vec<Singleton>,
+    pub function_imports: Vec<OperationImport>,
+    pub action_imports: Vec<OperationImport>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct EntitySet {
+    pub name: String,
+    pub entity_type: String,
+    pub navigation_bindings: Vec<NavigationBinding>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Singleton {
+    pub name: String,
+    pub type_name: String,
+    pub navigation_bindings: Vec<NavigationBinding>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct NavigationBinding {
+    pub path: String,
+    pub target: String
+}
+
+/// a `FunctionImport` or `ActionImport` of the entity container.
+/// OData V2 declares the parameters and return type directly at the import, V4 references an `operation` of the schema
+#[derive(Default, Debug, Clone)]
+pub struct OperationImport {
+    pub kind: OperationKind,
+    pub name: String,
+    pub operation: Option<String>,
+    pub entity_set: Option<String>,
+    pub http_method: Option<String>,
+    pub parameters: Vec<Parameter>,
+    pub return_type: Option<ReturnType>,
+    pub annotations: Vec<Annotation>
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Annotation {
+    pub term: String,
+    pub qualifier: Option<String>,
+    /// the value of simple constant or path expressions; `None` for records and collections
+    pub value: Option<String>
+}
+
+/// an external `Annotations` element, applying terms to the model element given by `target`
+#[derive(Default, Debug, Clone)]
+pub struct AnnotationTarget {
+    pub target: String,
+    pub qualifier: Option<String>,
+    pub annotations: Vec<Annotation>
+}
@@ -0,0 +1,130 @@
+use serde_json::{json, Map, Value};
+use crate::metadata::csdl::*;
+use crate::metadata::format::ModelFormatter;
+
+#[derive(Default)]
+pub struct JsonSchemaFormatter {
+}
+
+impl JsonSchemaFormatter {
+    pub fn new() -> JsonSchemaFormatter {
+        JsonSchemaFormatter {}
+    }
+
+    fn primitive_schema(type_name: &str) -> Option<Value> {
+        let schema = match type_name {
+            "Edm.String" => json!({ "type": "string" }),
+            "Edm.Boolean" => json!({ "type": "boolean" }),
+            "Edm.Byte" | "Edm.SByte" | "Edm.Int16" | "Edm.Int32" | "Edm.Int64" => json!({ "type": "integer" }),
+            "Edm.Decimal" | "Edm.Single" | "Edm.Double" => json!({ "type": "number" }),
+            "Edm.Date" => json!({ "type": "string", "format": "date" }),
+            "Edm.DateTime" | "Edm.DateTimeOffset" => json!({ "type": "string", "format": "date-time" }),
+            "Edm.TimeOfDay" => json!({ "type": "string", "format": "time" }),
+            "Edm.Time" | "Edm.Duration" => json!({ "type": "string", "format": "duration" }),
+            "Edm.Guid" => json!({ "type": "string", "format": "uuid" }),
+            "Edm.Binary" | "Edm.Stream" => json!({ "type": "string", "contentEncoding": "base64" }),
+            _ => return None
+        };
+
+        Some(schema)
+    }
+
+    fn type_schema(metadata: &ServiceMetadata, type_name: &str, nullable: bool) -> Value {
+        let element_type = element_type_name(type_name);
+        let element_schema = match Self::primitive_schema(element_type) {
+            Some(mut schema) => {
+                if nullable {
+                    let primitive = schema["type"].clone();
+                    schema["type"] = json!([primitive, "null"]);
+                }
+                schema
+            },
+            None => {
+                let reference = json!({ "$ref": format!("#/definitions/{}", metadata.normalize_type_name(element_type)) });
+                if nullable {
+                    json!({ "anyOf": [reference, { "type": "null" }] })
+                } else {
+                    reference
+                }
+            }
+        };
+
+        if is_collection_type(type_name) {
+            json!({ "type": "array", "items": element_schema })
+        } else {
+            element_schema
+        }
+    }
+
+    fn structured_type_schema(metadata: &ServiceMetadata, base_type: &Option<String>, properties: &[Property], navigation_properties: &[NavigationProperty], key: &[String], open_type: bool) -> Value {
+        let mut property_schemas = Map::new();
+        let mut required = vec![];
+
+        for property in properties {
+            property_schemas.insert(property.name.clone(), Self::type_schema(metadata, &property.type_name, property.nullable));
+            if !property.nullable || key.contains(&property.name) {
+                required.push(Value::String(property.name.clone()));
+            }
+        }
+
+        for navigation in navigation_properties {
+            property_schemas.insert(navigation.name.clone(), Self::type_schema(metadata, &navigation.type_name, navigation.nullable));
+        }
+
+        let mut schema = json!({
+            "type": "object",
+            "properties": property_schemas,
+            "additionalProperties": open_type
+        });
+
+        if !required.is_empty() {
+            schema["required"] = Value::Array(required);
+        }
+
+        match base_type {
+            Some(base_type) => json!({ "allOf": [{ "$ref": format!("#/definitions/{}", metadata.normalize_type_name(base_type)) }, schema] }),
+            None => schema
+        }
+    }
+}
+
+impl ModelFormatter for JsonSchemaFormatter {
+    fn format(&self, metadata: &ServiceMetadata) -> String {
+        let mut definitions = Map::new();
+
+        for schema in &metadata.schemas {
+            for entity_type in &schema.entity_types {
+                definitions.insert(format!("{}.{}", schema.namespace, entity_type.name),
+                    Self::structured_type_schema(metadata, &entity_type.base_type, &entity_type.properties, &entity_type.navigation_properties, &entity_type.key, entity_type.open_type));
+            }
+
+            for complex_type in &schema.complex_types {
+                definitions.insert(format!("{}.{}", schema.namespace, complex_type.name),
+                    Self::structured_type_schema(metadata, &complex_type.base_type, &complex_type.properties, &complex_type.navigation_properties, &[], complex_type.open_type));
+            }
+
+            for enum_type in &schema.enum_types {
+                let members : Vec<Value> = enum_type.members.iter().map(|member| Value::String(member.name.clone())).collect();
+                definitions.insert(format!("{}.{}", schema.namespace, enum_type.name), json!({ "type": "string", "enum": members }));
+            }
+        }
+
+        let mut entity_sets = Map::new();
+        for entity_set in metadata.entity_containers().flat_map(|container| container.entity_sets.iter()) {
+            entity_sets.insert(entity_set.name.clone(), json!({
+                "type": "array",
+                "items": { "$ref": format!("#/definitions/{}", metadata.normalize_type_name(&entity_set.entity_type)) }
+            }));
+        }
+
+        let document = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "title": "OData Service Model",
+            "type": "object",
+            "properties": entity_sets,
+            "definitions": definitions
+        });
+
+        serde_json::to_string_pretty(&document).unwrap_or_default()
+    }
+}
@@ -0,0 +1,250 @@
+use crate::metadata::csdl::*;
+use crate::metadata::format::ModelFormatter;
+
+#[derive(Default)]
+pub struct MarkdownFormatter {
+}
+
+impl MarkdownFormatter {
+    pub fn new() -> MarkdownFormatter {
+        MarkdownFormatter {}
+    }
+
+    fn escape(value: &str) -> String {
+        value.replace('|', "\\|").replace('\n', " ")
+    }
+
+    fn yes_no(value: bool) -> &'static str {
+        if value {
+            "yes"
+        } else {
+            "no"
+        }
+    }
+
+    fn format_annotations(annotations: &[Annotation]) -> String {
+        annotations.iter().map(|annotation| {
+            let term = match &annotation.qualifier {
+                Some(qualifier) => format!("{}#{}", annotation.term, qualifier),
+                None => annotation.term.clone()
+            };
+
+            match &annotation.value {
+                Some(value) => format!("{} = {}", term, Self::escape(value)),
+                None => term
+            }
+        }).collect::<Vec<String>>().join("<br>")
+    }
+
+    fn format_annotation_list(output: &mut String, annotations: &[Annotation]) {
+        if annotations.is_empty() {
+            return;
+        }
+
+        output.push_str("Annotations:\n\n");
+        for annotation in annotations {
+            output.push_str(&format!("* `{}`", annotation.term));
+            if let Some(qualifier) = &annotation.qualifier {
+                output.push_str(&format!(" (qualifier `{}`)", qualifier));
+            }
+            if let Some(value) = &annotation.value {
+                output.push_str(&format!(": {}", value));
+            }
+            output.push('\n');
+        }
+        output.push('\n');
+    }
+
+    fn format_properties(output: &mut String, properties: &[Property], key: &[String]) {
+        if properties.is_empty() {
+            return;
+        }
+
+        output.push_str("| Property | Type | Key | Nullable | Facets | Annotations |\n");
+        output.push_str("|----------|------|-----|----------|--------|-------------|\n");
+        for property in properties {
+            let mut facets = vec![];
+            if let Some(max_length) = &property.max_length {
+                facets.push(format!("MaxLength={}", max_length));
+            }
+            if let Some(precision) = &property.precision {
+                facets.push(format!("Precision={}", precision));
+            }
+            if let Some(scale) = &property.scale {
+                facets.push(format!("Scale={}", scale));
+            }
+            if let Some(default_value) = &property.default_value {
+                facets.push(format!("Default={}", default_value));
+            }
+
+            output.push_str(&format!("| {} | `{}` | {} | {} | {} | {} |\n",
+                property.name,
+                property.type_name,
+                Self::yes_no(key.contains(&property.name)),
+                Self::yes_no(property.nullable),
+                Self::escape(&facets.join(", ")),
+                Self::format_annotations(&property.annotations)));
+        }
+        output.push('\n');
+    }
+
+    fn format_navigation_properties(output: &mut String, navigation_properties: &[NavigationProperty]) {
+        if navigation_properties.is_empty() {
+            return;
+        }
+
+        output.push_str("| Navigation Property | Type | Nullable | Partner | Contains Target |\n");
+        output.push_str("|---------------------|------|----------|---------|-----------------|\n");
+        for navigation in navigation_properties {
+            output.push_str(&format!("| {} | `{}` | {} | {} | {} |\n",
+                navigation.name,
+                navigation.type_name,
+                Self::yes_no(navigation.nullable),
+                navigation.partner.as_deref().unwrap_or(""),
+                Self::yes_no(navigation.contains_target)));
+        }
+        output.push('\n');
+    }
+
+    fn format_parameters(output: &mut String, parameters: &[Parameter], return_type: &Option<ReturnType>) {
+        if !parameters.is_empty() {
+            output.push_str("| Parameter | Type | Nullable |\n");
+            output.push_str("|-----------|------|----------|\n");
+            for parameter in parameters {
+                output.push_str(&format!("| {} | `{}` | {} |\n", parameter.name, parameter.type_name, Self::yes_no(parameter.nullable)));
+            }
+            output.push('\n');
+        }
+
+        if let Some(return_type) = return_type {
+            output.push_str(&format!("Returns: `{}`\n\n", return_type.type_name));
+        }
+    }
+
+    fn format_container(output: &mut String, container: &EntityContainer) {
+        output.push_str(&format!("### Entity Container `{}`\n\n", container.name));
+
+        if !container.entity_sets.is_empty() {
+            output.push_str("| Entity Set | Entity Type | Navigation Bindings | Annotations |\n");
+            output.push_str("|------------|-------------|---------------------|-------------|\n");
+            for entity_set in &container.entity_sets {
+                let bindings = entity_set.navigation_bindings.iter().map(|binding| format!("{} → {}", binding.path, binding.target)).collect::<Vec<String>>().join("<br>");
+                output.push_str(&format!("| {} | `{}` | {} | {} |\n", entity_set.name, entity_set.entity_type, bindings, Self::format_annotations(&entity_set.annotations)));
+            }
+            output.push('\n');
+        }
+
+        if !container.singletons.is_empty() {
+            output.push_str("| Singleton | Type |\n");
+            output.push_str("|-----------|------|\n");
+            for singleton in &container.singletons {
+                output.push_str(&format!("| {} | `{}` |\n", singleton.name, singleton.type_name));
+            }
+            output.push('\n');
+        }
+
+        for operation_import in container.function_imports.iter().chain(container.action_imports.iter()) {
+            let kind = match operation_import.kind {
+                OperationKind::Function => "Function Import",
+                OperationKind::Action => "Action Import"
+            };
+
+            output.push_str(&format!("#### {} `{}`\n\n", kind, operation_import.name));
+            if let Some(operation) = &operation_import.operation {
+                output.push_str(&format!("Operation: `{}`\n\n", operation));
+            }
+            if let Some(entity_set) = &operation_import.entity_set {
+                output.push_str(&format!("Entity Set: `{}`\n\n", entity_set));
+            }
+            if let Some(http_method) = &operation_import.http_method {
+                output.push_str(&format!("HTTP Method: `{}`\n\n", http_method));
+            }
+            Self::format_parameters(output, &operation_import.parameters, &operation_import.return_type);
+        }
+    }
+
+    fn format_schema(output: &mut String, metadata: &ServiceMetadata, schema: &Schema) {
+        output.push_str(&format!("## Schema `{}`\n\n", schema.namespace));
+        if let Some(alias) = &schema.alias {
+            output.push_str(&format!("Alias: `{}`\n\n", alias));
+        }
+
+        for container in &schema.entity_containers {
+            Self::format_container(output, container);
+        }
+
+        for entity_type in &schema.entity_types {
+            output.push_str(&format!("### Entity Type `{}`\n\n", entity_type.name));
+            if let Some(base_type) = &entity_type.base_type {
+                output.push_str(&format!("Base Type: `{}`\n\n", base_type));
+            }
+            if entity_type.is_abstract || entity_type.open_type || entity_type.has_stream {
+                let mut flags = vec![];
+                if entity_type.is_abstract { flags.push("abstract"); }
+                if entity_type.open_type { flags.push("open type"); }
+                if entity_type.has_stream { flags.push("media entity"); }
+                output.push_str(&format!("Flags: {}\n\n", flags.join(", ")));
+            }
+
+            Self::format_annotation_list(output, &entity_type.annotations);
+            Self::format_properties(output, &entity_type.properties, &metadata.entity_type_key(entity_type));
+            Self::format_navigation_properties(output, &entity_type.navigation_properties);
+        }
+
+        for complex_type in &schema.complex_types {
+            output.push_str(&format!("### Complex Type `{}`\n\n", complex_type.name));
+            if let Some(base_type) = &complex_type.base_type {
+                output.push_str(&format!("Base Type: `{}`\n\n", base_type));
+            }
+
+            Self::format_annotation_list(output, &complex_type.annotations);
+            Self::format_properties(output, &complex_type.properties, &[]);
+            Self::format_navigation_properties(output, &complex_type.navigation_properties);
+        }
+
+        for enum_type in &schema.enum_types {
+            output.push_str(&format!("### Enum Type `{}`\n\n", enum_type.name));
+            if enum_type.is_flags {
+                output.push_str("Flags: yes\n\n");
+            }
+
+            output.push_str("| Member | Value |\n");
+            output.push_str("|--------|-------|\n");
+            for member in &enum_type.members {
+                output.push_str(&format!("| {} | {} |\n", member.name, member.value.as_deref().unwrap_or("")));
+            }
+            output.push('\n');
+        }
+
+        for operation in schema.functions.iter().chain(schema.actions.iter()) {
+            let kind = match operation.kind {
+                OperationKind::Function => "Function",
+                OperationKind::Action => "Action"
+            };
+
+            let bound = if operation.is_bound { " (bound)" } else { "" };
+            output.push_str(&format!("### {} `{}`{}\n\n", kind, operation.name, bound));
+            Self::format_annotation_list(output, &operation.annotations);
+            Self::format_parameters(output, &operation.parameters, &operation.return_type);
+        }
+
+        for target in &schema.annotations {
+            output.push_str(&format!("### Annotations for `{}`\n\n", target.target));
+            Self::format_annotation_list(output, &target.annotations);
+        }
+    }
+}
+
+impl ModelFormatter for MarkdownFormatter {
+    fn format(&self, metadata: &ServiceMetadata) -> String {
+        let mut output = String::with_capacity(4096);
+        output.push_str("# OData Service Model\n\n");
+        output.push_str(&format!("OData Version: {}\n\n", metadata.version));
+
+        for schema in &metadata.schemas {
+            Self::format_schema(&mut output, metadata, schema);
+        }
+
+        output
+    }
+}
@@ -0,0 +1,9 @@
+pub mod markdown;
+pub mod xsd;
+pub mod json_schema;
+
+use crate::metadata::csdl::ServiceMetadata;
+
+pub trait ModelFormatter {
+    fn format(&self, metadata: &ServiceMetadata) -> String;
+}
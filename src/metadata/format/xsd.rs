@@ -0,0 +1,149 @@
+use crate::metadata::csdl::*;
+use crate::metadata::format::ModelFormatter;
+
+#[derive(Default)]
+pub struct XsdFormatter {
+}
+
+impl XsdFormatter {
+    pub fn new() -> XsdFormatter {
+        XsdFormatter {}
+    }
+
+    fn escape(value: &str) -> String {
+        value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+    }
+
+    fn xsd_type(metadata: &ServiceMetadata, type_name: &str) -> String {
+        let element_type = element_type_name(type_name);
+        let primitive = match element_type {
+            "Edm.String" => "xs:string",
+            "Edm.Boolean" => "xs:boolean",
+            "Edm.Byte" => "xs:unsignedByte",
+            "Edm.SByte" => "xs:byte",
+            "Edm.Int16" => "xs:short",
+            "Edm.Int32" => "xs:int",
+            "Edm.Int64" => "xs:long",
+            "Edm.Decimal" => "xs:decimal",
+            "Edm.Single" => "xs:float",
+            "Edm.Double" => "xs:double",
+            "Edm.Date" => "xs:date",
+            "Edm.DateTime" | "Edm.DateTimeOffset" => "xs:dateTime",
+            "Edm.TimeOfDay" => "xs:time",
+            "Edm.Time" | "Edm.Duration" => "xs:duration",
+            "Edm.Binary" | "Edm.Stream" => "xs:base64Binary",
+            "Edm.Guid" => "tns:Guid",
+            _ => ""
+        };
+
+        if !primitive.is_empty() {
+            return primitive.to_owned();
+        }
+
+        let is_known_type = metadata.find_entity_type(element_type).is_some()
+            || metadata.find_complex_type(element_type).is_some()
+            || metadata.find_enum_type(element_type).is_some();
+
+        if is_known_type {
+            format!("tns:{}", Self::local_type_name(&metadata.normalize_type_name(element_type)))
+        } else {
+            "xs:anyType".to_owned()
+        }
+    }
+
+    /// all schemas are merged into one XSD namespace, so the types are named by their qualified name with '_' instead of '.'
+    fn local_type_name(qualified_name: &str) -> String {
+        qualified_name.replace('.', "_")
+    }
+
+    fn format_property(output: &mut String, metadata: &ServiceMetadata, name: &str, type_name: &str, nullable: bool, is_key: bool) {
+        let min_occurs = if nullable && !is_key { " minOccurs=\"0\"" } else { "" };
+        let nillable = if nullable && !is_key { " nillable=\"true\"" } else { "" };
+        let max_occurs = if is_collection_type(type_name) { " maxOccurs=\"unbounded\"" } else { "" };
+
+        output.push_str(&format!("      <xs:element name=\"{}\" type=\"{}\"{}{}{} />\n",
+            Self::escape(name), Self::xsd_type(metadata, type_name), min_occurs, max_occurs, nillable));
+    }
+
+    fn format_structured_type(output: &mut String, metadata: &ServiceMetadata, qualified_name: &str, base_type: &Option<String>, properties: &[Property], navigation_properties: &[NavigationProperty], key: &[String]) {
+        output.push_str(&format!("  <xs:complexType name=\"{}\">\n", Self::local_type_name(qualified_name)));
+
+        let content_indent = if base_type.is_some() { "    " } else { "" };
+        if let Some(base_type) = base_type {
+            output.push_str("    <xs:complexContent>\n");
+            output.push_str(&format!("      <xs:extension base=\"tns:{}\">\n", Self::local_type_name(&metadata.normalize_type_name(base_type))));
+        }
+
+        output.push_str(&format!("{}    <xs:sequence>\n", content_indent));
+        for property in properties {
+            output.push_str(content_indent);
+            Self::format_property(output, metadata, &property.name, &property.type_name, property.nullable, key.contains(&property.name));
+        }
+        for navigation in navigation_properties {
+            output.push_str(content_indent);
+            // navigation properties are only contained when expanded
+            Self::format_property(output, metadata, &navigation.name, &navigation.type_name, true, false);
+        }
+        output.push_str(&format!("{}    </xs:sequence>\n", content_indent));
+
+        if base_type.is_some() {
+            output.push_str("      </xs:extension>\n");
+            output.push_str("    </xs:complexContent>\n");
+        }
+
+        output.push_str("  </xs:complexType>\n");
+    }
+
+    fn format_enum_type(output: &mut String, qualified_name: &str, enum_type: &EnumType) {
+        output.push_str(&format!("  <xs:simpleType name=\"{}\">\n", Self::local_type_name(qualified_name)));
+        output.push_str("    <xs:restriction base=\"xs:string\">\n");
+        for member in &enum_type.members {
+            output.push_str(&format!("      <xs:enumeration value=\"{}\" />\n", Self::escape(&member.name)));
+        }
+        output.push_str("    </xs:restriction>\n");
+        output.push_str("  </xs:simpleType>\n");
+    }
+}
+
+impl ModelFormatter for XsdFormatter {
+    fn format(&self, metadata: &ServiceMetadata) -> String {
+        let mut output = String::with_capacity(4096);
+        output.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        output.push_str("<xs:schema xmlns:xs=\"http://www.w3.org/2001/XMLSchema\" xmlns:tns=\"urn:rodata:model\" targetNamespace=\"urn:rodata:model\" elementFormDefault=\"qualified\">\n");
+        output.push_str("  <xs:simpleType name=\"Guid\">\n");
+        output.push_str("    <xs:restriction base=\"xs:string\">\n");
+        output.push_str("      <xs:pattern value=\"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}\" />\n");
+        output.push_str("    </xs:restriction>\n");
+        output.push_str("  </xs:simpleType>\n");
+
+        for schema in &metadata.schemas {
+            for entity_type in &schema.entity_types {
+                let qualified_name = format!("{}.{}", schema.namespace, entity_type.name);
+                Self::format_structured_type(&mut output, metadata, &qualified_name, &entity_type.base_type, &entity_type.properties, &entity_type.navigation_properties, &entity_type.key);
+            }
+
+            for complex_type in &schema.complex_types {
+                let qualified_name = format!("{}.{}", schema.namespace, complex_type.name);
+                Self::format_structured_type(&mut output, metadata, &qualified_name, &complex_type.base_type, &complex_type.properties, &complex_type.navigation_properties, &[]);
+            }
+
+            for enum_type in &schema.enum_types {
+                let qualified_name = format!("{}.{}", schema.namespace, enum_type.name);
+                Self::format_enum_type(&mut output, &qualified_name, enum_type);
+            }
+        }
+
+        for entity_set in metadata.entity_containers().flat_map(|container| container.entity_sets.iter()) {
+            output.push_str(&format!("  <xs:element name=\"{}\">\n", Self::escape(&entity_set.name)));
+            output.push_str("    <xs:complexType>\n");
+            output.push_str("      <xs:sequence>\n");
+            output.push_str(&format!("        <xs:element name=\"object\" type=\"{}\" minOccurs=\"0\" maxOccurs=\"unbounded\" />\n", Self::xsd_type(metadata, &entity_set.entity_type)));
+            output.push_str("      </xs:sequence>\n");
+            output.push_str("    </xs:complexType>\n");
+            output.push_str("  </xs:element>\n");
+        }
+
+        output.push_str("</xs:schema>\n");
+        output
+    }
+}
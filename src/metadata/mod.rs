@@ -0,0 +1,3 @@
+pub mod csdl;
+pub mod parser;
+pub mod format;
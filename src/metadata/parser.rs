@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use quick_xml::Reader;
+use quick_xml::events::{Event, BytesStart};
+use crate::error::Error;
+use crate::metadata::csdl::*;
+
+/// one open element of the CSDL document, while the parser is inside of it
+enum Context {
+    Schema(Schema),
+    EntityType(EntityType),
+    ComplexType(ComplexType),
+    EnumType(EnumType),
+    Property(Property),
+    NavigationProperty(NavigationProperty),
+    Association(Association),
+    Operation(Operation),
+    EntityContainer(EntityContainer),
+    EntitySet(EntitySet),
+    Singleton(Singleton),
+    OperationImport(OperationImport),
+    AnnotationTarget(AnnotationTarget),
+    Annotation(Annotation),
+    /// elements of no interest (or handled entirely via their attributes)
+    Other
+}
+
+pub struct MetadataParser {
+    version: String,
+    schemas: Vec<Schema>,
+    stack: Vec<Context>
+}
+
+impl MetadataParser {
+    pub fn parse(content: &str) -> Result<ServiceMetadata, Error> {
+        let mut parser = MetadataParser { version: String::new(), schemas: vec![], stack: vec![] };
+        let mut reader = Reader::from_str(content);
+        reader.trim_text(true);
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(element)) => parser.open_element(&reader, &element)?,
+                Ok(Event::Empty(element)) => {
+                    parser.open_element(&reader, &element)?;
+                    parser.close_element();
+                },
+                Ok(Event::End(_)) => parser.close_element(),
+                Ok(Event::Text(text)) => {
+                    let text = text.unescape().map_err(|err| Self::xml_error(&reader, err))?;
+                    parser.apply_text(text.into_owned());
+                },
+                Ok(Event::Eof) => break,
+                Ok(_) => (),
+                Err(err) => return Err(Self::xml_error(&reader, err))
+            }
+        }
+
+        if parser.schemas.is_empty() {
+            return Err(Error::Protocol("Invalid $metadata document: no Schema found".to_owned()));
+        }
+
+        let mut metadata = ServiceMetadata { version: parser.version, schemas: parser.schemas };
+        Self::resolve_association_types(&mut metadata);
+
+        Ok(metadata)
+    }
+
+    fn xml_error<E: std::fmt::Display>(reader: &Reader<&[u8]>, err: E) -> Error {
+        Error::decode(format!("Invalid $metadata document: {}", err), Some(reader.buffer_position()))
+    }
+
+    fn open_element(&mut self, reader: &Reader<&[u8]>, element: &BytesStart<'_>) -> Result<(), Error> {
+        let mut attributes = HashMap::<String, String>::new();
+        let mut vendor_annotations = vec![];
+
+        for attribute in element.attributes() {
+            let attribute = attribute.map_err(|err| Self::xml_error(reader, err))?;
+            let key = reader.decoder().decode(attribute.key.as_ref()).map_err(|err| Self::xml_error(reader, err))?.into_owned();
+            let value = attribute.decode_and_unescape_value(reader).map_err(|err| Self::xml_error(reader, err))?.into_owned();
+
+            // vendor specific attributes like SAP's `sap:label` are kept as annotations
+            if key.starts_with("sap:") {
+                vendor_annotations.push(Annotation { term: key.clone(), qualifier: None, value: Some(value.clone()) });
+            }
+
+            attributes.insert(key, value);
+        }
+
+        let name = reader.decoder().decode(element.local_name().as_ref()).map_err(|err| Self::xml_error(reader, err))?.into_owned();
+        let context = self.build_context(&name, &attributes, vendor_annotations);
+        self.stack.push(context);
+
+        Ok(())
+    }
+
+    fn build_context(&mut self, element_name: &str, attributes: &HashMap<String, String>, annotations: Vec<Annotation>) -> Context {
+        let attribute = |key: &str| attributes.get(key).cloned();
+        let name = || attribute("Name").unwrap_or_default();
+        let flag = |key: &str, default: bool| attributes.get(key).map(|value| value == "true").unwrap_or(default);
+
+        match element_name {
+            "Edmx" => {
+                self.version = attribute("Version").unwrap_or_default();
+                Context::Other
+            },
+            "DataServices" => {
+                if let Some(version) = attribute("m:DataServiceVersion") {
+                    self.version = version;
+                }
+                Context::Other
+            },
+            "Schema" => Context::Schema(Schema { namespace: attribute("Namespace").unwrap_or_default(), alias: attribute("Alias"), ..Default::default() }),
+            "EntityType" => Context::EntityType(EntityType {
+                name: name(),
+                base_type: attribute("BaseType"),
+                is_abstract: flag("Abstract", false),
+                open_type: flag("OpenType", false),
+                has_stream: flag("HasStream", false) || flag("m:HasStream", false),
+                annotations,
+                ..Default::default()
+            }),
+            "ComplexType" => Context::ComplexType(ComplexType {
+                name: name(),
+                base_type: attribute("BaseType"),
+                is_abstract: flag("Abstract", false),
+                open_type: flag("OpenType", false),
+                annotations,
+                ..Default::default()
+            }),
+            "EnumType" => Context::EnumType(EnumType { name: name(), underlying_type: attribute("UnderlyingType"), is_flags: flag("IsFlags", false), annotations, ..Default::default() }),
+            "Member" => {
+                if let Some(Context::EnumType(enum_type)) = self.stack.last_mut() {
+                    enum_type.members.push(EnumMember { name: name(), value: attribute("Value") });
+                }
+                Context::Other
+            },
+            "PropertyRef" => {
+                // nested within <Key>, which itself is nested within the entity type
+                let entity_type = self.stack.iter_mut().rev().find_map(|context| match context {
+                    Context::EntityType(entity_type) => Some(entity_type),
+                    _ => None
+                });
+
+                if let Some(entity_type) = entity_type {
+                    entity_type.key.push(name());
+                }
+                Context::Other
+            },
+            "Property" => Context::Property(Property {
+                name: name(),
+                type_name: attribute("Type").unwrap_or_default(),
+                nullable: flag("Nullable", true),
+                max_length: attribute("MaxLength"),
+                precision: attribute("Precision"),
+                scale: attribute("Scale"),
+                default_value: attribute("DefaultValue"),
+                annotations
+            }),
+            "NavigationProperty" => Context::NavigationProperty(NavigationProperty {
+                name: name(),
+                type_name: attribute("Type").unwrap_or_default(),
+                nullable: flag("Nullable", true),
+                partner: attribute("Partner"),
+                contains_target: flag("ContainsTarget", false),
+                relationship: attribute("Relationship"),
+                to_role: attribute("ToRole"),
+                annotations
+            }),
+            "Association" => Context::Association(Association { name: name(), ends: vec![] }),
+            "End" => {
+                if let Some(Context::Association(association)) = self.stack.last_mut() {
+                    association.ends.push(AssociationEnd {
+                        role: attribute("Role").unwrap_or_default(),
+                        type_name: attribute("Type").unwrap_or_default(),
+                        multiplicity: attribute("Multiplicity").unwrap_or_default()
+                    });
+                }
+                Context::Other
+            },
+            "Function" | "Action" => Context::Operation(Operation {
+                kind: if element_name == "Action" { OperationKind::Action } else { OperationKind::Function },
+                name: name(),
+                is_bound: flag("IsBound", false),
+                is_composable: flag("IsComposable", false),
+                entity_set_path: attribute("EntitySetPath"),
+                annotations,
+                ..Default::default()
+            }),
+            "Parameter" => {
+                let parameter = Parameter { name: name(), type_name: attribute("Type").unwrap_or_default(), nullable: flag("Nullable", true) };
+                match self.stack.last_mut() {
+                    Some(Context::Operation(operation)) => operation.parameters.push(parameter),
+                    Some(Context::OperationImport(operation_import)) => operation_import.parameters.push(parameter),
+                    _ => ()
+                }
+                Context::Other
+            },
+            "ReturnType" => {
+                let return_type = ReturnType { type_name: attribute("Type").unwrap_or_default(), nullable: flag("Nullable", true) };
+                if let Some(Context::Operation(operation)) = self.stack.last_mut() {
+                    operation.return_type = Some(return_type);
+                }
+                Context::Other
+            },
+            "EntityContainer" => Context::EntityContainer(EntityContainer {
+                name: name(),
+                is_default: flag("m:IsDefaultEntityContainer", false),
+                annotations,
+                ..Default::default()
+            }),
+            "EntitySet" => Context::EntitySet(EntitySet { name: name(), entity_type: attribute("EntityType").unwrap_or_default(), annotations, ..Default::default() }),
+            "Singleton" => Context::Singleton(Singleton { name: name(), type_name: attribute("Type").unwrap_or_default(), annotations, ..Default::default() }),
+            "NavigationPropertyBinding" => {
+                let binding = NavigationBinding { path: attribute("Path").unwrap_or_default(), target: attribute("Target").unwrap_or_default() };
+                match self.stack.last_mut() {
+                    Some(Context::EntitySet(entity_set)) => entity_set.navigation_bindings.push(binding),
+                    Some(Context::Singleton(singleton)) => singleton.navigation_bindings.push(binding),
+                    _ => ()
+                }
+                Context::Other
+            },
+            "FunctionImport" | "ActionImport" => Context::OperationImport(OperationImport {
+                kind: if element_name == "ActionImport" { OperationKind::Action } else { OperationKind::Function },
+                name: name(),
+                operation: attribute("Function").or_else(|| attribute("Action")),
+                entity_set: attribute("EntitySet"),
+                http_method: attribute("m:HttpMethod"),
+                // V2 declares the return type as attribute of the import itself
+                return_type: attribute("ReturnType").map(|type_name| ReturnType { type_name, nullable: true }),
+                annotations,
+                ..Default::default()
+            }),
+            "Annotations" => Context::AnnotationTarget(AnnotationTarget { target: attribute("Target").unwrap_or_default(), qualifier: attribute("Qualifier"), annotations: vec![] }),
+            "Annotation" => Context::Annotation(Annotation {
+                term: attribute("Term").unwrap_or_default(),
+                qualifier: attribute("Qualifier"),
+                value: Self::annotation_attribute_value(attributes)
+            }),
+            _ => Context::Other
+        }
+    }
+
+    fn annotation_attribute_value(attributes: &HashMap<String, String>) -> Option<String> {
+        const CONSTANT_EXPRESSIONS: [&str; 16] = [
+            "String", "Bool", "Int", "Decimal", "Float", "Date", "DateTimeOffset", "TimeOfDay", "Duration", "Guid",
+            "EnumMember", "Binary", "Path", "PropertyPath", "NavigationPropertyPath", "AnnotationPath"
+        ];
+
+        CONSTANT_EXPRESSIONS.iter().find_map(|expression| attributes.get(*expression).cloned())
+    }
+
+    fn apply_text(&mut self, text: String) {
+        // constant expressions in element notation, e.g. <Annotation Term="..."><String>text</String></Annotation>
+        if let [.., Context::Annotation(annotation), Context::Other] = self.stack.as_mut_slice() {
+            if annotation.value.is_none() {
+                annotation.value = Some(text);
+            }
+        }
+    }
+
+    fn close_element(&mut self) {
+        let finished = match self.stack.pop() {
+            Some(context) => context,
+            None => return
+        };
+
+        match (finished, self.stack.last_mut()) {
+            (Context::Schema(schema), _) => self.schemas.push(schema),
+            (Context::EntityType(entity_type), Some(Context::Schema(schema))) => schema.entity_types.push(entity_type),
+            (Context::ComplexType(complex_type), Some(Context::Schema(schema))) => schema.complex_types.push(complex_type),
+            (Context::EnumType(enum_type), Some(Context::Schema(schema))) => schema.enum_types.push(enum_type),
+            (Context::Association(association), Some(Context::Schema(schema))) => schema.associations.push(association),
+            (Context::Operation(operation), Some(Context::Schema(schema))) => match operation.kind {
+                OperationKind::Function => schema.functions.push(operation),
+                OperationKind::Action => schema.actions.push(operation)
+            },
+            (Context::EntityContainer(container), Some(Context::Schema(schema))) => schema.entity_containers.push(container),
+            (Context::AnnotationTarget(target), Some(Context::Schema(schema))) => schema.annotations.push(target),
+            (Context::Property(property), Some(Context::EntityType(entity_type))) => entity_type.properties.push(property),
+            (Context::Property(property), Some(Context::ComplexType(complex_type))) => complex_type.properties.push(property),
+            (Context::NavigationProperty(navigation), Some(Context::EntityType(entity_type))) => entity_type.navigation_properties.push(navigation),
+            (Context::NavigationProperty(navigation), Some(Context::ComplexType(complex_type))) => complex_type.navigation_properties.push(navigation),
+            (Context::EntitySet(entity_set), Some(Context::EntityContainer(container))) => container.entity_sets.push(entity_set),
+            (Context::Singleton(singleton), Some(Context::EntityContainer(container))) => container.singletons.push(singleton),
+            (Context::OperationImport(operation_import), Some(Context::EntityContainer(container))) => match operation_import.kind {
+                OperationKind::Function => container.function_imports.push(operation_import),
+                OperationKind::Action => container.action_imports.push(operation_import)
+            },
+            (Context::Annotation(annotation), Some(parent)) => Self::attach_annotation(parent, annotation),
+            _ => ()
+        }
+    }
+
+    fn attach_annotation(parent: &mut Context, annotation: Annotation) {
+        match parent {
+            Context::EntityType(entity_type) => entity_type.annotations.push(annotation),
+            Context::ComplexType(complex_type) => complex_type.annotations.push(annotation),
+            Context::EnumType(enum_type) => enum_type.annotations.push(annotation),
+            Context::Property(property) => property.annotations.push(annotation),
+            Context::NavigationProperty(navigation) => navigation.annotations.push(annotation),
+            Context::Operation(operation) => operation.annotations.push(annotation),
+            Context::EntityContainer(container) => container.annotations.push(annotation),
+            Context::EntitySet(entity_set) => entity_set.annotations.push(annotation),
+            Context::Singleton(singleton) => singleton.annotations.push(annotation),
+            Context::OperationImport(operation_import) => operation_import.annotations.push(annotation),
+            Context::AnnotationTarget(target) => target.annotations.push(annotation),
+            _ => ()
+        }
+    }
+
+    /// OData V2 navigation properties only name their association, the target type is declared at the association end
+    fn resolve_association_types(metadata: &mut ServiceMetadata) {
+        let mut association_ends = HashMap::<(String, String), AssociationEnd>::new();
+        for schema in &metadata.schemas {
+            for association in &schema.associations {
+                for end in &association.ends {
+                    association_ends.insert((format!("{}.{}", schema.namespace, association.name), end.role.clone()), end.clone());
+                    if let Some(alias) = &schema.alias {
+                        association_ends.insert((format!("{}.{}", alias, association.name), end.role.clone()), end.clone());
+                    }
+                }
+            }
+        }
+
+        let navigation_properties = metadata.schemas.iter_mut()
+            .flat_map(|schema| schema.entity_types.iter_mut())
+            .flat_map(|entity_type| entity_type.navigation_properties.iter_mut());
+
+        for navigation in navigation_properties {
+            if !navigation.type_name.is_empty() {
+                continue;
+            }
+
+            if let (Some(relationship), Some(to_role)) = (&navigation.relationship, &navigation.to_role) {
+                if let Some(end) = association_ends.get(&(relationship.clone(), to_role.clone())) {
+                    navigation.type_name = if end.multiplicity == "*" {
+                        format!("Collection({})", end.type_name)
+                    } else {
+                        end.type_name.clone()
+                    };
+                    navigation.nullable = end.multiplicity != "1";
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,40 @@
-﻿#[derive(Default,Debug)]
+﻿use url::Url;
+use crate::error::Error;
+use crate::filter::literal::Literal;
+use crate::service::auth::SharedAuthenticator;
+use crate::service::client::ODataClient;
+use crate::service::retry::RetryPolicy;
+use crate::service::url::{build_query_url, parse_url};
+
+#[derive(Default,Debug,Clone)]
 pub struct EntitySetQuery
 {
     pub entityset_url: String,
     pub select: Option<String>,
     pub filters: Option<String>,
     pub order_by: Option<String>,
+    pub expand: Vec<ExpandOption>,
+    pub top: Option<usize>,
+    pub skip: Option<usize>,
+    pub search: Option<String>,
+    pub count: bool,
+    /// asks the service for pages of at most this many entities (`Prefer: odata.maxpagesize`), it may still send larger ones
+    pub max_page_size: Option<usize>,
+    /// stops following the next links after this many pages
+    pub max_pages: Option<usize>,
+    /// stops after this many entities, the rest of the page is dropped (and the checkpoint is `truncated`). Unlike `top`, the service does not know about it
+    pub max_rows: Option<usize>,
+    /// the protocol version of the service, detected if not given
+    pub version: Option<ProtocolVersion>,
+    /// asks the service for a delta link (`Prefer: odata.track-changes`) and marks the entities as `added`
+    pub track_changes: bool,
+    /// repeats the requests of pages failing temporarily, none by default
+    pub retry: RetryPolicy,
     pub username: Option<String>,
-    pub password: Option<String>
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    /// the HTTP client to send the requests with, the shared one if not given
+    pub client: Option<ODataClient>
 }
 
 impl EntitySetQuery {
@@ -15,30 +43,286 @@ impl EntitySetQuery {
     }
 
     pub fn has_options(&self) -> bool {
-        self.select.is_some() || self.filters.is_some() || self.order_by.is_some()
+        self.select.is_some() || self.filters.is_some() || self.order_by.is_some() || !self.expand.is_empty()
+            || self.top.is_some() || self.skip.is_some() || self.search.is_some() || self.count
     }
 }
 
+/// loads an entity set in windows of `$skip`/`$top` at the same time, see `EntitySetIterator::stream_entity_set_parallel`
+#[derive(Debug, Clone, Copy)]
+pub struct ParallelOptions
+{
+    /// the number of windows the entities are split into
+    pub windows: usize,
+    /// the number of windows loaded at the same time
+    pub concurrency: usize,
+    /// keeps the order of the service, the windows loaded ahead are buffered meanwhile
+    pub ordered: bool
+}
+
+impl ParallelOptions {
+    /// four windows per concurrent load, so windows of slower pages do not hold up the others for long
+    pub fn new(concurrency: usize) -> ParallelOptions {
+        ParallelOptions { windows: concurrency * 4, concurrency, ordered: true }
+    }
+}
+
+impl Default for ParallelOptions {
+    fn default() -> Self {
+        ParallelOptions::new(4)
+    }
+}
+
+/// how far the pages of an entity set are loaded: to continue from there later on (see `EntitySetIterator::stream_page`),
+/// or to report where a limited load stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint
+{
+    /// the (absolute) link of the next page, `None` once the last page is loaded
+    pub next_link: Option<String>,
+    /// the number of pages loaded so far
+    pub pages: usize,
+    /// the number of entities loaded so far, which is the index of the next one
+    pub entities: usize,
+    /// a row limit dropped the rest of the last page. The next link would skip those entities, so there is none to continue with
+    pub truncated: bool
+}
+
+impl Checkpoint {
+    pub fn new(next_link: Option<String>, pages: usize, entities: usize) -> Checkpoint {
+        Checkpoint { next_link, pages, entities, truncated: false }
+    }
+
+    /// the checkpoint of a load stopped by a row limit within a page, which can not be continued
+    pub fn truncated(pages: usize, entities: usize) -> Checkpoint {
+        Checkpoint { next_link: None, pages, entities, truncated: true }
+    }
+
+    /// all pages are loaded
+    pub fn is_complete(&self) -> bool {
+        self.next_link.is_none() && !self.truncated
+    }
+}
+
+/// loads the changes since the delta link was returned, see `EntitySetIterator::stream_changes`
+#[derive(Default,Debug)]
+pub struct DeltaQuery
+{
+    /// the `@odata.deltaLink` of an earlier load
+    pub delta_link: String,
+    pub retry: RetryPolicy,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
+}
+
+impl DeltaQuery {
+    pub fn new(delta_link: String) -> DeltaQuery {
+        DeltaQuery { delta_link, ..Default::default() }
+    }
+}
+
+/// one navigation property to expand (a.k.a. `$expand`), including the query options applied to the related entities
+#[derive(Default,Debug,Clone,PartialEq,Eq)]
+pub struct ExpandOption
+{
+    pub navigation_property: String,
+    pub select: Option<String>,
+    pub filters: Option<String>,
+    pub order_by: Option<String>,
+    pub top: Option<usize>,
+    pub skip: Option<usize>,
+    pub count: bool,
+    pub levels: Option<String>,
+    pub expand: Vec<ExpandOption>
+}
+
+impl ExpandOption {
+    pub fn new(navigation_property: String) -> ExpandOption {
+        ExpandOption { navigation_property, ..Default::default() }
+    }
+
+    /// parses the value of an `$expand`, e.g. `Trips($select=Name;$expand=PlanItems),Friends`
+    pub fn parse_list(expand: &str) -> Result<Vec<ExpandOption>, Error> {
+        split_top_level(expand, ',').into_iter()
+            .filter(|item| !item.trim().is_empty())
+            .map(|item| ExpandOption::parse(item.trim()))
+            .collect()
+    }
+
+    pub fn parse(item: &str) -> Result<ExpandOption, Error> {
+        let (navigation_property, nested_options) = match item.find('(') {
+            Some(start) => {
+                if !item.ends_with(')') {
+                    return Err(Error::InvalidQuery(format!("Invalid $expand item '{}': missing closing parenthesis", item)));
+                }
+
+                (&item[..start], Some(&item[start + 1..item.len() - 1]))
+            },
+            None => (item, None)
+        };
+
+        let mut expand_option = ExpandOption::new(navigation_property.trim().to_owned());
+        if expand_option.navigation_property.is_empty() {
+            return Err(Error::InvalidQuery(format!("Invalid $expand item '{}': missing navigation property", item)));
+        }
+
+        for option in nested_options.map(|options| split_top_level(options, ';')).unwrap_or_default() {
+            let (name, value) = match option.find('=') {
+                Some(separator) => (option[..separator].trim(), option[separator + 1..].trim()),
+                None => return Err(Error::InvalidQuery(format!("Invalid option '{}' in $expand of {}", option, expand_option.navigation_property)))
+            };
+
+            let parse_number = |value: &str| value.parse::<usize>().map_err(|_| Error::InvalidQuery(format!("Invalid number '{}' for {} in $expand of {}", value, name, navigation_property)));
+
+            match name {
+                "$select" => expand_option.select = Some(value.to_owned()),
+                "$filter" => expand_option.filters = Some(value.to_owned()),
+                "$orderby" => expand_option.order_by = Some(value.to_owned()),
+                "$top" => expand_option.top = Some(parse_number(value)?),
+                "$skip" => expand_option.skip = Some(parse_number(value)?),
+                "$count" => expand_option.count = value == "true",
+                "$levels" => expand_option.levels = Some(value.to_owned()),
+                "$expand" => expand_option.expand = ExpandOption::parse_list(value)?,
+                _ => return Err(Error::InvalidQuery(format!("Unsupported option '{}' in $expand of {}", name, expand_option.navigation_property)))
+            }
+        }
+
+        Ok(expand_option)
+    }
+
+    pub fn format_list(expand: &[ExpandOption]) -> String {
+        expand.iter().map(|item| item.to_string()).collect::<Vec<String>>().join(",")
+    }
+
+    /// V2 only knows paths (`Trips/PlanItems`), without any nested query options
+    pub fn format_paths(expand: &[ExpandOption]) -> Result<String, Error> {
+        let mut paths = vec![];
+        for item in expand {
+            if item.select.is_some() || item.filters.is_some() || item.order_by.is_some() || item.top.is_some() || item.skip.is_some() || item.count || item.levels.is_some() {
+                return Err(Error::InvalidQuery(format!("OData V2 does not support query options in $expand of {}", item.navigation_property)));
+            }
+
+            if item.expand.is_empty() {
+                paths.push(item.navigation_property.clone());
+            } else {
+                for nested in ExpandOption::format_paths(&item.expand)?.split(',') {
+                    paths.push(format!("{}/{}", item.navigation_property, nested));
+                }
+            }
+        }
+
+        Ok(paths.join(","))
+    }
+
+    pub fn format_for_version(expand: &[ExpandOption], version: ProtocolVersion) -> Result<String, Error> {
+        match version {
+            ProtocolVersion::V2 => ExpandOption::format_paths(expand),
+            ProtocolVersion::V4 => Ok(ExpandOption::format_list(expand))
+        }
+    }
+}
+
+impl std::fmt::Display for ExpandOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut nested_options = vec![];
+        if let Some(select) = &self.select {
+            nested_options.push(format!("$select={}", select));
+        }
+        if let Some(filters) = &self.filters {
+            nested_options.push(format!("$filter={}", filters));
+        }
+        if let Some(order_by) = &self.order_by {
+            nested_options.push(format!("$orderby={}", order_by));
+        }
+        if let Some(top) = self.top {
+            nested_options.push(format!("$top={}", top));
+        }
+        if let Some(skip) = self.skip {
+            nested_options.push(format!("$skip={}", skip));
+        }
+        if self.count {
+            nested_options.push("$count=true".to_owned());
+        }
+        if let Some(levels) = &self.levels {
+            nested_options.push(format!("$levels={}", levels));
+        }
+        if !self.expand.is_empty() {
+            nested_options.push(format!("$expand={}", ExpandOption::format_list(&self.expand)));
+        }
+
+        f.write_str(&self.navigation_property)?;
+        if !nested_options.is_empty() {
+            f.write_fmt(format_args!("({})", nested_options.join(";")))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// splits at the separator, but not inside of parentheses or quoted string literals
+fn split_top_level(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut start = 0;
+
+    for (index, character) in value.char_indices() {
+        match character {
+            '\'' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => depth = depth.saturating_sub(1),
+            _ if character == separator && depth == 0 && !in_string => {
+                parts.push(&value[start..index]);
+                start = index + character.len_utf8();
+            },
+            _ => ()
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts
+}
+
 #[derive(Default,Debug)]
 pub struct EntityIndividualQuery
 {
     pub entity_url: String,
+    pub expand: Vec<ExpandOption>,
+    /// the protocol version of the service, V4 syntax is used if not given
+    pub version: Option<ProtocolVersion>,
     pub username: Option<String>,
-    pub password: Option<String>
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
 }
 
 impl EntityIndividualQuery {
     pub fn new(entity_url: String) -> EntityIndividualQuery {
         EntityIndividualQuery { entity_url, ..Default::default() }
     }
+
+    pub fn full_url(&self) -> Result<Url, Error> {
+        let mut options = Vec::<(&str, String)>::new();
+        if !self.expand.is_empty() {
+            options.push(("$expand", ExpandOption::format_for_version(&self.expand, self.version.unwrap_or_default())?));
+        }
+
+        build_query_url(&self.entity_url, &options)
+    }
 }
 
 #[derive(Default,Debug)]
 pub struct FunctionQuery
 {
     pub function_url: String,
+    /// the parameters, added to the URL: `GetNearestAirport(lat=33.0,lon=-118.0)`
+    pub parameters: Vec<(String, Literal)>,
     pub username: Option<String>,
-    pub password: Option<String>
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
 }
 
 impl FunctionQuery {
@@ -47,6 +331,185 @@ impl FunctionQuery {
     }
 }
 
+/// invokes an action: unbound (`.../ResetDataSource`) or bound to an entity (`.../People('russellwhyte')/NS.ShareTrip`)
+#[derive(Default,Debug)]
+pub struct ActionQuery
+{
+    pub action_url: String,
+    /// the parameters, sent as JSON object in the body
+    pub parameters: serde_json::Map<String, Entity>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
+}
+
+impl ActionQuery {
+    pub fn new(action_url: String) -> ActionQuery {
+        ActionQuery { action_url, ..Default::default() }
+    }
+}
+
+#[derive(Default,Debug)]
+pub struct MetadataQuery
+{
+    pub service_url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
+}
+
+impl MetadataQuery {
+    pub fn new(service_url: String) -> MetadataQuery {
+        MetadataQuery { service_url, ..Default::default() }
+    }
+
+    /// the `$metadata` document of the service. Query parameters of the service URL (e.g. `sap-client`) are kept
+    pub fn metadata_url(&self) -> Result<Url, Error> {
+        let mut url = parse_url(&self.service_url)?;
+        if url.path().ends_with("$metadata") {
+            return Ok(url);
+        }
+
+        url.path_segments_mut()
+            .map_err(|_| Error::InvalidQuery(format!("Invalid service URL '{}'", self.service_url)))?
+            .pop_if_empty()
+            .push("$metadata");
+
+        Ok(url)
+    }
+}
+
+/// creates, updates or deletes an entity. `entity_url` is the entity set (to create an entity),
+/// the entity itself (to update or delete it) or a navigation property (to change its links)
+#[derive(Default,Debug)]
+pub struct EntityWriteQuery
+{
+    pub entity_url: String,
+    pub payload: Option<Entity>,
+    /// the ETag the entity is expected to have (`If-Match`), `*` for any. Taken from `@odata.etag` of the payload if not set
+    pub if_match: Option<String>,
+    /// asks the service to respond with the created/updated entity (`Prefer: return=representation`)
+    pub return_representation: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
+}
+
+impl EntityWriteQuery {
+    pub fn new(entity_url: String) -> EntityWriteQuery {
+        EntityWriteQuery { entity_url, ..Default::default() }
+    }
+
+    pub fn with_payload(entity_url: String, payload: Entity) -> EntityWriteQuery {
+        EntityWriteQuery { entity_url, payload: Some(payload), ..Default::default() }
+    }
+
+    /// the ETag to send as `If-Match`
+    pub fn etag(&self) -> Option<String> {
+        self.if_match.clone().or_else(|| self.payload.as_ref()
+            .and_then(|payload| payload.get("@odata.etag"))
+            .and_then(|etag| etag.as_str())
+            .map(|etag| etag.to_owned()))
+    }
+}
+
+/// how imported rows are written to the entity set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImportMode {
+    /// `POST` every row as a new entity
+    #[default]
+    Create,
+    /// `PATCH` the entity identified by the key properties of the row
+    Update
+}
+
+/// loads rows (e.g. of a CSV file) into an entity set
+#[derive(Default,Debug)]
+pub struct EntityImportQuery
+{
+    pub entityset_url: String,
+    pub mode: ImportMode,
+    /// number of rows sent per `$batch` request, each in its own change set. Without (or with 1), every row is a request of its own
+    pub batch_size: Option<usize>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
+}
+
+impl EntityImportQuery {
+    pub fn new(entityset_url: String) -> EntityImportQuery {
+        EntityImportQuery { entityset_url, ..Default::default() }
+    }
+}
+
+/// the response of the service to a create, update or delete
+#[derive(Debug)]
+pub struct WriteResult
+{
+    pub status: reqwest::StatusCode,
+    /// the new ETag of the entity
+    pub etag: Option<String>,
+    /// the URL of a created entity (`Location` or `OData-EntityId`)
+    pub location: Option<String>,
+    /// the entity, if the service responded with it
+    pub entity: Option<Entity>
+}
+
+/// the version of the OData protocol a service speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    /// V2 and V3 with the verbose JSON format: `{ "d": { "results": [...], "__next": ... } }`, `$inlinecount`
+    V2,
+    /// V4 (and the V3 JSON light format): `{ "value": [...], "@odata.nextLink": ... }`, `$count`
+    #[default]
+    V4
+}
+
+impl std::str::FromStr for ProtocolVersion {
+    type Err = Error;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version.trim_start_matches(['v', 'V']) {
+            "2" | "2.0" | "3" | "3.0" => Ok(ProtocolVersion::V2),
+            "4" | "4.0" | "4.01" => Ok(ProtocolVersion::V4),
+            _ => Err(Error::InvalidQuery(format!("Unknown OData version '{}', expected 2 or 4", version)))
+        }
+    }
+}
+
+/// the format of a `$batch` request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BatchFormat {
+    /// `multipart/mixed`, understood by V2 and V4 services
+    #[default]
+    Multipart,
+    /// the JSON format of OData 4.01
+    Json
+}
+
+/// sends several requests to a service at once (`$batch`)
+#[derive(Default,Debug)]
+pub struct BatchQuery
+{
+    /// the service root, the requests of the batch are relative to it
+    pub service_url: String,
+    pub format: BatchFormat,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub authenticator: Option<SharedAuthenticator>,
+    pub client: Option<ODataClient>
+}
+
+impl BatchQuery {
+    pub fn new(service_url: String) -> BatchQuery {
+        BatchQuery { service_url, ..Default::default() }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub enum ValuePosition {
     Key(String),
@@ -155,61 +618,38 @@ impl ValuePath {
         self.steps.iter()
     }
 
-    pub fn reset(&mut self) {
-        self.steps.clear();
+    pub fn starts_with(&self, prefix: &ValuePath) -> bool {
+        self.steps.starts_with(&prefix.steps)
     }
-}
 
-impl std::fmt::Debug for ValuePath {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.get_path_string())
+    /// the step at the level, if any
+    pub fn get(&self, level: usize) -> Option<&ValuePosition> {
+        self.steps.get(level)
     }
-}
 
-impl std::fmt::Display for ValuePath {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.get_path_string())
+    pub fn remove(&mut self, level: usize) -> ValuePosition {
+        self.steps.remove(level)
     }
-}
-
-#[derive(Clone, PartialEq, Eq)]
-pub struct MyError {
-    pub(crate) message: String
-}
 
-impl std::fmt::Debug for MyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.message)
+    /// puts the position in place of the step at the level, returning the previous one
+    pub fn replace(&mut self, level: usize, position: ValuePosition) -> ValuePosition {
+        std::mem::replace(&mut self.steps[level], position)
     }
-}
 
-impl std::fmt::Display for MyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.message)
+    pub fn reset(&mut self) {
+        self.steps.clear();
     }
 }
 
-impl From<reqwest::Error> for MyError {
-    fn from(_: reqwest::Error) -> Self { 
-        MyError{ message: "Some reqwest stuff went wrong".to_owned()}
+impl std::fmt::Debug for ValuePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.get_path_string())
     }
 }
 
-impl From<crate::json_stream::stream::Error> for MyError {
-
-    fn from(source_error: crate::json_stream::stream::Error) -> Self {
-        match source_error {
-            crate::json_stream::stream::Error::IoError(_) => MyError { message: "I/O-Error occurred".to_owned() },
-            crate::json_stream::stream::Error::DecodeError(content) => {
-                match content {
-                    crate::json_stream::decode::DecodeError::InvalidUnicodeEscape(payload) => MyError { message: format!("Decode-Error: String contains a sequence {:x} which is an invalid unicode code point", payload).to_owned() },
-                    crate::json_stream::decode::DecodeError::NeedsMore => MyError { message: "Decode-Error: Needs more".to_owned() },
-                    crate::json_stream::decode::DecodeError::UnexpectedEndOfStream => MyError { message: "Decode-Error: More input needed to finish parse, but input bytes marked as end of stream".to_owned() },
-                    crate::json_stream::decode::DecodeError::InvalidUtf8 => MyError { message: "Decode-Error: Invalid UTF-8".to_owned() },
-                    crate::json_stream::decode::DecodeError::UnexpectedByte(payload) => MyError { message: format!("Decode-Error: found an invalid byte: {:x}", payload).to_owned() },
-                }
-            }
-        }
+impl std::fmt::Display for ValuePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.get_path_string())
     }
 }
 
@@ -250,6 +690,9 @@ impl std::fmt::Display for Value {
 }
 
 
+/// an entity (or any other value) combined from its tokens, see `TokenStream::entities`
+pub type Entity = serde_json::Value;
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct Token {
     pub path: ValuePath,
@@ -0,0 +1,63 @@
+use futures::channel::mpsc::{Sender, Receiver};
+use reqwest::{Method, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use crate::model::{ActionQuery, Entity, Token};
+use crate::error::Error;
+use crate::service::url::{parse_url, RequestContent, SingleUrlCaller};
+use crate::service::auth::resolve_authenticator;
+use crate::service::entity_stream::{EntityStreamer, RootEntityType};
+use crate::service::token_stream::TokenStream;
+use crate::provider::function::FunctionResultCollector;
+use crate::service::protocol::version_from_headers;
+
+/// invokes actions (`POST` with the parameters as JSON body)
+#[derive(Default)]
+pub struct ActionCaller {}
+impl ActionCaller {
+    const BUFFER_SIZE: usize = 1_000_000;
+
+    pub fn new() -> ActionCaller {
+        ActionCaller {}
+    }
+
+    /// streams the result of the action: the elements of a returned collection, or the single returned value, complex value or entity.
+    /// Actions without a result (`204 No Content`) produce no tokens. Errors are sent as last item of the stream
+    pub fn stream_action<T: Into<ActionQuery>>(self, query: T) -> TokenStream {
+        let action_query = query.into();
+        let action_url = match parse_url(&action_query.action_url) {
+            Ok(url) => url,
+            Err(err) => return TokenStream::failed(err)
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        let body = Entity::Object(action_query.parameters).to_string();
+        let content = RequestContent { method: Method::POST, headers, body: Some(body) };
+        let url_caller = SingleUrlCaller::new(action_url, resolve_authenticator(action_query.authenticator, action_query.username, action_query.password))
+            .with_client(action_query.client);
+
+        TokenStream::new(|sender| Self::call(url_caller, content, sender))
+    }
+
+    /// `stream_action`, driven by a spawned task. Errors are sent as last item of the stream
+    pub fn call_action<T: Into<ActionQuery>>(self, query: T) -> Receiver<Result<Token, Error>> {
+        self.stream_action(query).into_receiver(ActionCaller::BUFFER_SIZE)
+    }
+
+    async fn call(url_caller: SingleUrlCaller, content: RequestContent, sender: Sender<Result<Token, Error>>) {
+        let result = match url_caller.send(&content).await {
+            Ok(response) if response.status() == StatusCode::NO_CONTENT || response.content_length() == Some(0) => Ok(()),
+            Ok(response) => {
+                let version = version_from_headers(response.headers()).unwrap_or_default();
+                FunctionResultCollector::new(sender.clone(), version).stream_odata_object(response.bytes_stream()).await
+            },
+            Err(err) => Err(err)
+        };
+
+        if let Err(err) = result {
+            EntityStreamer::new(sender, RootEntityType::Value).fail(err).await;
+        }
+    }
+}
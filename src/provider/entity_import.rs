@@ -0,0 +1,331 @@
+use futures::stream::{self, StreamExt};
+use reqwest::Method;
+use serde_json::Map;
+use crate::model::{BatchQuery, Entity, EntityImportQuery, EntityWriteQuery, ImportMode, MetadataQuery, WriteResult};
+use crate::error::Error;
+use crate::filter::literal::Literal;
+use crate::metadata::csdl::{is_collection_type, EntityType, Property, ServiceMetadata};
+use crate::provider::entity_writer::EntityWriter;
+use crate::provider::metadata::MetadataLoader;
+use crate::reader::{CsvField, CsvTable};
+use crate::service::auth::{resolve_authenticator, SharedAuthenticator};
+use crate::service::batch::{BatchBuilder, BatchOperation};
+use crate::service::client::ODataClient;
+use crate::service::url::parse_url;
+
+const INTEGER_TYPES: [&str; 5] = ["Edm.Byte", "Edm.SByte", "Edm.Int16", "Edm.Int32", "Edm.Int64"];
+const FLOATING_TYPES: [&str; 3] = ["Edm.Decimal", "Edm.Single", "Edm.Double"];
+
+/// the rows to import: cells of a CSV file (converted by the property types) or entities read from JSON
+pub enum ImportInput {
+    Csv(CsvTable),
+    Entities(Vec<Entity>)
+}
+
+/// one write request, prepared from a row
+#[derive(Debug)]
+pub struct ImportRequest {
+    /// the number of the row in the input, starting with 1
+    pub row: usize,
+    pub method: Method,
+    pub url: String,
+    pub payload: Entity
+}
+
+#[derive(Debug)]
+pub struct RowError {
+    pub row: usize,
+    pub error: Error
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub failed: Vec<RowError>
+}
+
+impl ImportReport {
+    fn add(&mut self, row: usize, result: Result<(), Error>) {
+        match result {
+            Ok(_) => self.imported += 1,
+            Err(error) => self.failed.push(RowError { row, error })
+        }
+    }
+}
+
+/// writes rows into an entity set. Values are converted into the types the `$metadata` of the service declares for the properties
+#[derive(Default)]
+pub struct EntityImporter {}
+impl EntityImporter {
+    pub fn new() -> EntityImporter {
+        EntityImporter {}
+    }
+
+    /// converts the rows into requests, without sending them. Rows which can't be converted are reported as errors,
+    /// columns not matching any property fail the import as a whole
+    pub async fn prepare(&self, query: &EntityImportQuery, input: ImportInput) -> Result<(Vec<ImportRequest>, Vec<RowError>), Error> {
+        let (service_url, entity_set_name) = split_entity_set_url(&query.entityset_url)?;
+        let metadata = MetadataLoader::new().load_metadata(MetadataQuery {
+            service_url,
+            username: query.username.clone(),
+            password: query.password.clone(),
+            authenticator: query.authenticator.clone(),
+            client: query.client.clone()
+        }).await?;
+
+        let entity_type = metadata.find_entity_set(&entity_set_name)
+            .and_then(|entity_set| metadata.find_entity_type(&entity_set.entity_type))
+            .ok_or_else(|| Error::InvalidQuery(format!("Entity set '{}' not found in $metadata", entity_set_name)))?;
+
+        let entities : Vec<Result<Entity, Error>> = match input {
+            ImportInput::Csv(table) => {
+                let columns = map_columns(&metadata, entity_type, &table.header)?;
+                table.rows.iter().map(|row| convert_row(&metadata, &columns, row)).collect()
+            },
+            ImportInput::Entities(entities) => entities.into_iter().map(Ok).collect()
+        };
+
+        let mut requests = vec![];
+        let mut errors = vec![];
+        for (index, entity) in entities.into_iter().enumerate() {
+            let row = index + 1;
+            match entity.and_then(|entity| self.build_request(&metadata, entity_type, query, row, entity)) {
+                Ok(request) => requests.push(request),
+                Err(error) => errors.push(RowError { row, error })
+            }
+        }
+
+        Ok((requests, errors))
+    }
+
+    /// sends the requests one after the other, or `batch_size` of them per `$batch` request.
+    /// Every row of a batch is a change set of its own, so failed rows neither roll back the others nor stop the import
+    pub async fn execute(&self, query: &EntityImportQuery, requests: Vec<ImportRequest>) -> ImportReport {
+        let authenticator = resolve_authenticator(query.authenticator.clone(), query.username.clone(), query.password.clone());
+        let batch_size = query.batch_size.unwrap_or(1).max(1);
+        let mut report = ImportReport::default();
+
+        if batch_size == 1 {
+            for request in requests {
+                let (row, result) = Self::send(request, authenticator.clone(), query.client.clone()).await;
+                report.add(row, result.map(|_| ()));
+            }
+
+            return report;
+        }
+
+        let mut batches = stream::iter(requests).chunks(batch_size);
+        while let Some(batch) = batches.next().await {
+            for (row, result) in Self::send_batch(query, batch, authenticator.clone()).await {
+                report.add(row, result);
+            }
+        }
+
+        report
+    }
+
+    /// the requests as one `$batch`, returns the result of each row
+    async fn send_batch(query: &EntityImportQuery, requests: Vec<ImportRequest>, authenticator: Option<SharedAuthenticator>) -> Vec<(usize, Result<(), Error>)> {
+        let service_url = split_entity_set_url(&query.entityset_url).map(|(service_url, _)| service_url).unwrap_or_default();
+        let mut builder = BatchBuilder::new(BatchQuery { authenticator, client: query.client.clone(), ..BatchQuery::new(service_url.clone()) });
+        let rows : Vec<usize> = requests.iter().map(|request| request.row).collect();
+
+        for request in requests {
+            let url = request.url.strip_prefix(&service_url).unwrap_or(&request.url).trim_start_matches('/').to_owned();
+            let operation = if request.method == Method::PATCH {
+                BatchOperation::update(&url, request.payload)
+            } else {
+                BatchOperation::create(&url, request.payload)
+            };
+
+            builder.add_change_set(vec![operation]);
+        }
+
+        match builder.send().await {
+            Ok(results) => rows.into_iter().zip(results).map(|(row, batch_result)| (row, batch_result.result.map(|_| ()))).collect(),
+            Err(error) => rows.into_iter().map(|row| (row, Err(batch_failure(&error)))).collect()
+        }
+    }
+
+    async fn send(request: ImportRequest, authenticator: Option<SharedAuthenticator>, client: Option<ODataClient>) -> (usize, Result<WriteResult, Error>) {
+        let write_query = EntityWriteQuery { authenticator, client, ..EntityWriteQuery::with_payload(request.url, request.payload) };
+        let result = if request.method == Method::PATCH {
+            EntityWriter::new().update(write_query).await
+        } else {
+            EntityWriter::new().create(write_query).await
+        };
+
+        (request.row, result)
+    }
+
+    fn build_request(&self, metadata: &ServiceMetadata, entity_type: &EntityType, query: &EntityImportQuery, row: usize, entity: Entity) -> Result<ImportRequest, Error> {
+        match query.mode {
+            ImportMode::Create => Ok(ImportRequest { row, method: Method::POST, url: query.entityset_url.clone(), payload: entity }),
+            ImportMode::Update => {
+                let mut properties = match entity {
+                    Entity::Object(properties) => properties,
+                    other => return Err(Error::InvalidQuery(format!("Expected an object, found '{}'", other)))
+                };
+
+                // the key identifies the entity, it is not changed
+                let key_properties = metadata.entity_type_key(entity_type);
+                let mut key_values = Map::new();
+                for key_property in &key_properties {
+                    match properties.remove(key_property) {
+                        Some(value) => key_values.insert(key_property.clone(), value),
+                        None => return Err(Error::InvalidQuery(format!("Key property '{}' is missing", key_property)))
+                    };
+                }
+
+                let url = entity_url(&query.entityset_url, &key_predicate(metadata, entity_type, &key_values)?)?;
+                Ok(ImportRequest { row, method: Method::PATCH, url, payload: Entity::Object(properties) })
+            }
+        }
+    }
+}
+
+/// the error of a `$batch` request which failed as a whole, for each of its rows
+fn batch_failure(error: &Error) -> Error {
+    match error {
+        Error::Http { status, url, service_error } => Error::Http { status: *status, url: url.clone(), service_error: service_error.clone() },
+        other => Error::Protocol(format!("The $batch request failed: {}", other))
+    }
+}
+
+/// the service URL (to load the `$metadata` from) and the name of the entity set
+fn split_entity_set_url(entityset_url: &str) -> Result<(String, String), Error> {
+    let mut url = parse_url(entityset_url)?;
+    let entity_set_name = last_segment(&url)
+        .ok_or_else(|| Error::InvalidQuery(format!("No entity set in URL '{}'", entityset_url)))?;
+
+    url.path_segments_mut()
+        .map_err(|_| Error::InvalidQuery(format!("Invalid entity set URL '{}'", entityset_url)))?
+        .pop_if_empty()
+        .pop();
+
+    Ok((url.to_string(), entity_set_name))
+}
+
+fn last_segment(url: &url::Url) -> Option<String> {
+    url.path_segments()
+        .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+        .map(|segment| segment.to_owned())
+}
+
+/// the URL of the entity with the given key predicate, e.g. `People('russellwhyte')`
+fn entity_url(entityset_url: &str, key_predicate: &str) -> Result<String, Error> {
+    let mut url = parse_url(entityset_url)?;
+    let entity_set_name = last_segment(&url).unwrap_or_default();
+
+    url.path_segments_mut()
+        .map_err(|_| Error::InvalidQuery(format!("Invalid entity set URL '{}'", entityset_url)))?
+        .pop_if_empty()
+        .pop()
+        .push(&format!("{}({})", entity_set_name, key_predicate));
+
+    Ok(url.to_string())
+}
+
+/// `'key'` for single keys, `Name1=value1,Name2=value2` for composite ones. The literals are written for the version of the service
+fn key_predicate(metadata: &ServiceMetadata, entity_type: &EntityType, key_values: &Map<String, Entity>) -> Result<String, Error> {
+    let properties = metadata.entity_type_properties(entity_type);
+    let version = metadata.protocol_version();
+    let mut parts = vec![];
+
+    for (name, value) in key_values {
+        let type_name = properties.iter().find(|property| &property.name == name)
+            .map(|property| metadata.normalize_type_name(&property.type_name))
+            .unwrap_or_default();
+
+        let text = match value {
+            Entity::String(text) => text.clone(),
+            Entity::Number(number) => number.to_string(),
+            other => return Err(Error::InvalidQuery(format!("Invalid value '{}' for key property '{}'", other, name)))
+        };
+
+        let literal = match type_name.as_str() {
+            "Edm.String" | "" => Literal::String(text),
+            "Edm.Guid" => Literal::guid(text)?,
+            "Edm.Date" => Literal::date(text)?,
+            "Edm.DateTimeOffset" => Literal::date_time_offset(text)?,
+            // V2 only, without time zone
+            "Edm.DateTime" => Literal::DateTimeOffset(text),
+            "Edm.Decimal" => Literal::decimal(text)?,
+            type_name if INTEGER_TYPES.contains(&type_name) => Literal::Integer(text.parse().map_err(|_| Error::InvalidQuery(format!("Invalid value '{}' for key property '{}'", text, name)))?),
+            type_name if metadata.find_enum_type(type_name).is_some() => Literal::enum_member(type_name, text),
+            _ => Literal::String(text)
+        };
+
+        parts.push((name, literal.format_for_version(&type_name, version)));
+    }
+
+    if let [(_, literal)] = parts.as_slice() {
+        return Ok(literal.clone());
+    }
+
+    Ok(parts.iter().map(|(name, literal)| format!("{}={}", name, literal)).collect::<Vec<String>>().join(","))
+}
+
+/// the property for each column. Columns of open types without a declared property keep their inferred type
+fn map_columns<'a>(metadata: &'a ServiceMetadata, entity_type: &'a EntityType, header: &[String]) -> Result<Vec<(String, Option<&'a Property>)>, Error> {
+    let properties = metadata.entity_type_properties(entity_type);
+
+    header.iter().map(|column| {
+        match properties.iter().find(|property| &property.name == column) {
+            Some(property) => Ok((column.clone(), Some(*property))),
+            None if entity_type.open_type => Ok((column.clone(), None)),
+            None => Err(Error::InvalidQuery(format!("Column '{}' is not a property of {}", column, entity_type.name)))
+        }
+    }).collect()
+}
+
+fn convert_row(metadata: &ServiceMetadata, columns: &[(String, Option<&Property>)], row: &[CsvField]) -> Result<Entity, Error> {
+    let mut properties = Map::new();
+    for ((column, property), field) in columns.iter().zip(row.iter()) {
+        let value = match property {
+            Some(property) => coerce_field(metadata, property, field)?,
+            None => field.infer_value()
+        };
+
+        properties.insert(column.clone(), value);
+    }
+
+    Ok(Entity::Object(properties))
+}
+
+/// converts the text of a cell into the JSON value for the type of the property. Empty cells are `null`
+pub fn coerce_field(metadata: &ServiceMetadata, property: &Property, field: &CsvField) -> Result<Entity, Error> {
+    if field.text.is_empty() && !field.quoted {
+        return Ok(Entity::Null);
+    }
+
+    let type_name = metadata.normalize_type_name(&property.type_name);
+    let invalid = || Error::InvalidQuery(format!("Invalid value '{}' for property '{}' of type {}", field.text, property.name, type_name));
+    let text = field.text.as_str();
+
+    if is_collection_type(&type_name) || metadata.find_complex_type(&type_name).is_some() {
+        return serde_json::from_str(text).map_err(|_| invalid());
+    }
+
+    match type_name.as_str() {
+        "Edm.Boolean" => match text.to_lowercase().as_str() {
+            "true" | "1" => Ok(Entity::Bool(true)),
+            "false" | "0" => Ok(Entity::Bool(false)),
+            _ => Err(invalid())
+        },
+        type_name if INTEGER_TYPES.contains(&type_name) => text.parse::<i64>().map(Entity::from).map_err(|_| invalid()),
+        type_name if FLOATING_TYPES.contains(&type_name) => match text {
+            "NaN" | "INF" | "-INF" => Ok(Entity::String(text.to_owned())),
+            _ => text.parse::<serde_json::Number>().map(Entity::Number).map_err(|_| invalid())
+        },
+        type_name => {
+            if let Some(enum_type) = metadata.find_enum_type(type_name) {
+                if !enum_type.members.iter().any(|member| member.name == text) && text.parse::<i64>().is_err() {
+                    return Err(invalid());
+                }
+            }
+
+            Ok(Entity::String(text.to_owned()))
+        }
+    }
+}
@@ -1,9 +1,16 @@
-use futures::channel::mpsc::{ channel, Sender, Receiver};
-use futures::stream::Stream;
+use futures::channel::mpsc::{Sender, Receiver};
+use futures::stream::{Stream, StreamExt};
 use bytes::Bytes;
-use crate::model::{EntityIndividualQuery, Token, MyError};
-use crate::service::url::SingleUrlCaller;
+use crate::model::{EntityIndividualQuery, ProtocolVersion, Token};
+use crate::error::Error;
+use crate::service::url::{RequestContent, SingleUrlCaller, JSON_OR_ATOM};
+use crate::service::auth::resolve_authenticator;
 use crate::service::entity_stream::{EntityStreamer, RootEntityType};
+use crate::service::protocol::version_from_headers;
+use crate::service::atom_stream::{is_atom, AtomParser};
+use crate::service::token_stream::TokenStream;
+use serde::de::DeserializeOwned;
+use crate::json_stream::token::JsonToken;
 use crate::json_stream::stream::TokenIterator;
 
 pub struct EntityIndividualLoader {}
@@ -14,38 +21,49 @@ impl EntityIndividualLoader {
         EntityIndividualLoader {}
     }
 
-    pub fn load_individual<T: Into<EntityIndividualQuery>>(self, query: T) -> Receiver<Token> {
+    /// streams the entity. Errors are sent as last item of the stream
+    pub fn stream_individual<T: Into<EntityIndividualQuery>>(self, query: T) -> TokenStream {
         let entity_individual_query = query.into();
-        let url_caller = SingleUrlCaller::new(entity_individual_query.entity_url, entity_individual_query.username, entity_individual_query.password);
-        let (sender, receiver) = channel::<Token>(EntityIndividualLoader::BUFFER_SIZE);
+        let full_url = match entity_individual_query.full_url() {
+            Ok(url) => url,
+            Err(err) => return TokenStream::failed(err)
+        };
 
-        self.run_in_background(url_caller, sender);
-        
-        return receiver;
+        let url_caller = SingleUrlCaller::new(full_url, resolve_authenticator(entity_individual_query.authenticator, entity_individual_query.username, entity_individual_query.password))
+            .with_client(entity_individual_query.client);
+
+        let version = entity_individual_query.version;
+        TokenStream::new(move |sender| Self::load(url_caller, version, sender))
     }
 
-    fn run_in_background(&self, url_caller: SingleUrlCaller, sender: Sender<Token>) {
-        tokio::spawn(async move {
-            let mut reader = EntityReader::new(sender);
-
-            match url_caller.call().await {
-                Ok(response) => {
-                    match reader.stream_odata_object(response).await {
-                        Ok(_) => (),
-                        Err(err) => {
-                            eprintln!("{}", err.message);
-                            return futures::future::ready(());
-                        }
-                    }
-                },
-                Err(err) => {
-                    eprintln!("{}", err.message);
-                    return futures::future::ready(());
-                }
-            }
+    /// loads the entity, deserialized into `T`
+    pub async fn entity<T: DeserializeOwned, Q: Into<EntityIndividualQuery>>(self, query: Q) -> Result<T, Error> {
+        let mut entities = self.stream_individual(query).deserialize::<T>();
+        entities.next().await.unwrap_or_else(|| Err(Error::Protocol("The service did not return an entity".to_owned())))
+    }
 
-            futures::future::ready(())
-        });
+    /// `stream_individual`, driven by a spawned task. Errors are sent as last item of the stream
+    pub fn load_individual<T: Into<EntityIndividualQuery>>(self, query: T) -> Receiver<Result<Token, Error>> {
+        self.stream_individual(query).into_receiver(EntityIndividualLoader::BUFFER_SIZE)
+    }
+
+    async fn load(url_caller: SingleUrlCaller, version: Option<ProtocolVersion>, sender: Sender<Result<Token, Error>>) {
+        let mut reader = EntityReader::new(sender);
+        reader.stream.begin().await;
+
+        let result = match url_caller.send(&RequestContent::get_accepting(JSON_OR_ATOM)).await {
+            Ok(response) if is_atom(response.headers()) => AtomParser::new().stream_response(response.bytes_stream(), &mut reader.stream).await.map(|_| ()),
+            Ok(response) => {
+                let version = version.or_else(|| version_from_headers(response.headers())).unwrap_or_default();
+                reader.stream_odata_object(response.bytes_stream(), version).await
+            },
+            Err(err) => Err(err)
+        };
+
+        match result {
+            Ok(_) => reader.stream.finish().await,
+            Err(err) => reader.stream.fail(err).await
+        }
     }
 }
 
@@ -54,16 +72,32 @@ struct EntityReader {
 }
 
 impl EntityReader {
-    fn new(sender: Sender<Token>) -> Self {
+    fn new(sender: Sender<Result<Token, Error>>) -> Self {
         EntityReader { stream: EntityStreamer::new(sender, RootEntityType::Object) }
     }
 
-    async fn stream_odata_object<T>(&mut self, odata_response: T) -> Result<(), MyError>
+    async fn stream_odata_object<T>(&mut self, odata_response: T, version: ProtocolVersion) -> Result<(), Error>
     where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
 
         let mut stream = crate::json_stream::stream::Stream::from_stream(odata_response)?;
         stream.advance().await?; // start of object (should not be inspected by `stream_content`)
         stream.advance().await?; // first entry in the object
+
+        if version == ProtocolVersion::V2 {
+            // the entity is wrapped into `{ "d": { ... } }`
+            let key = match stream.get() {
+                Some(JsonToken::JsKey(key)) => key.into_raw_str(),
+                other => return Err(Error::Protocol(format!("Invalid top level JSON structure of response: {:?}", other)))
+            };
+
+            if key != "d" {
+                return Err(Error::Protocol(format!("Expected key 'd' in V2 response, found '{}'", key)));
+            }
+
+            stream.advance().await?; // start of the entity
+            stream.advance().await?; // first entry in the entity
+            self.stream.use_protocol(version);
+        }
         self.stream.stream_content(&mut stream).await?;
 
         return Ok(());
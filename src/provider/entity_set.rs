@@ -1,114 +1,561 @@
-use futures::stream::Stream;
+use futures::stream::{self, Stream, StreamExt};
+use futures::sink::SinkExt;
+use futures::future::{self, Either};
 use futures::channel::mpsc::{ channel, Sender, Receiver};
+use futures::channel::oneshot;
 use bytes::Bytes;
-use crate::model::{EntitySetQuery, MyError, Token};
-use crate::service::url::MultiUrlCaller;
-use crate::service::entity_stream::{EntityStreamer, RootEntityType};
+use serde::de::DeserializeOwned;
+use crate::model::{Checkpoint, DeltaQuery, EntitySetQuery, ExpandOption, ParallelOptions, ProtocolVersion, Token, Value, ValuePath, ValuePosition};
+use crate::error::Error;
+use url::Url;
+use crate::service::url::{build_query_url, parse_url, MultiUrlCaller};
+use crate::service::auth::{resolve_authenticator, SharedAuthenticator};
+use crate::service::entity_stream::{skip_value, EntityStreamer, RootEntityType};
+use crate::service::protocol::detect_version;
+use crate::service::atom_stream::{is_atom, AtomParser};
+use crate::service::delta::ChangeType;
+use crate::service::token_stream::{EntityStream, TokenStream};
 use crate::json_stream::token::JsonToken;
 use crate::json_stream::stream::TokenIterator;
 
 pub struct EntitySetIterator {}
 impl EntitySetIterator {
     const BUFFER_SIZE: usize = 1_000_000;
+    /// tokens buffered per window when loading in parallel
+    const WINDOW_BUFFER_SIZE: usize = 100_000;
 
     pub fn new() -> EntitySetIterator {
         EntitySetIterator {}
     }
 
-    pub fn iterate_entity_set<T: Into<EntitySetQuery>>(self, query: T) -> Receiver<Token> {
+    /// streams the entities of the entity set, following the next links. Errors are sent as last item of the stream
+    pub fn stream_entity_set<T: Into<EntitySetQuery>>(self, query: T) -> TokenStream {
+        self.stream_pages(query.into(), None, None, None)
+    }
+
+    /// streams the entities of the entity set, deserialized into `T`
+    pub fn entity_set<T: DeserializeOwned, Q: Into<EntitySetQuery>>(self, query: Q) -> EntityStream<TokenStream, T> {
+        self.stream_entity_set(query).deserialize()
+    }
+
+    /// like `stream_entity_set`, but additionally reports the total count (`@odata.count`) of the entity set,
+    /// as soon as the service sent it. Requires `count` to be set on the query.
+    pub fn stream_entity_set_with_count<T: Into<EntitySetQuery>>(self, query: T) -> (TokenStream, oneshot::Receiver<u64>) {
+        let (count_sender, count_receiver) = oneshot::channel::<u64>();
+        (self.stream_pages(query.into(), Some(count_sender), None, None), count_receiver)
+    }
+
+    /// like `stream_entity_set_with_count`, additionally reporting the checkpoint after the last page loaded:
+    /// how many pages and entities were loaded, and the next link if the load stopped at `max_pages`.
+    /// If `max_rows` dropped the rest of a page, the checkpoint is `truncated` instead, without a next link to continue with
+    pub fn stream_entity_set_with_progress<T: Into<EntitySetQuery>>(self, query: T) -> (TokenStream, oneshot::Receiver<u64>, oneshot::Receiver<Checkpoint>) {
+        let (count_sender, count_receiver) = oneshot::channel::<u64>();
+        let (checkpoint_sender, checkpoint_receiver) = oneshot::channel::<Checkpoint>();
+        (self.stream_pages(query.into(), Some(count_sender), None, Some(checkpoint_sender)), count_receiver, checkpoint_receiver)
+    }
+
+    /// like `stream_entity_set`, with `track_changes` set: the entities are marked as `added` (see `CHANGE_FIELD`)
+    /// and the `@odata.deltaLink` is reported after the last page, to load the changes from later on
+    pub fn stream_entity_set_with_delta_link<T: Into<EntitySetQuery>>(self, query: T) -> (TokenStream, oneshot::Receiver<String>) {
+        let mut entity_set_query = query.into();
+        entity_set_query.track_changes = true;
+
+        let (delta_sender, delta_receiver) = oneshot::channel::<String>();
+        (self.stream_pages(entity_set_query, None, Some(delta_sender), None), delta_receiver)
+    }
+
+    /// streams the entities changed since the delta link was returned, marked as `changed` or `removed` (see `CHANGE_FIELD`).
+    /// The next delta link is reported after the last page
+    pub fn stream_changes<T: Into<DeltaQuery>>(self, query: T) -> (TokenStream, oneshot::Receiver<String>) {
+        let delta_query = query.into();
+        let (delta_sender, delta_receiver) = oneshot::channel::<String>();
+        (TokenStream::new(|sender| Self::load_changes(delta_query, sender, delta_sender)), delta_receiver)
+    }
+
+    /// like `stream_entity_set`, but splits the entities into windows of `$skip`/`$top` which are loaded at the same time.
+    /// The total count is requested first, so the service has to support `$count`, `$skip` and `$top`.
+    /// Without `order_by`, the windows rely on the service returning the entities in a stable order (usually by key)
+    pub fn stream_entity_set_parallel<T: Into<EntitySetQuery>>(self, query: T, options: ParallelOptions) -> TokenStream {
+        let entity_set_query = query.into();
+        TokenStream::new(|sender| Self::load_windows(entity_set_query, options, sender))
+    }
+
+    /// streams a single page of the entity set: the first one, or the next one of the checkpoint, numbering the entities on from there.
+    /// The checkpoint after the page is reported once its entities are streamed, to load the following page with
+    pub fn stream_page<T: Into<EntitySetQuery>>(self, query: T, checkpoint: Option<Checkpoint>) -> (TokenStream, oneshot::Receiver<Checkpoint>) {
         let entity_set_query = query.into();
-        let multi_caller = MultiUrlCaller::new(self.build_full_url(&entity_set_query), entity_set_query.username, entity_set_query.password);
-        let (sender, receiver) = channel::<Token>(EntitySetIterator::BUFFER_SIZE);
+        let (checkpoint_sender, checkpoint_receiver) = oneshot::channel::<Checkpoint>();
+        (TokenStream::new(|sender| Self::load_page(entity_set_query, checkpoint, sender, checkpoint_sender)), checkpoint_receiver)
+    }
 
-        self.run_in_background(multi_caller, sender);
-        return receiver;
+    /// `stream_entity_set`, driven by a spawned task. Errors are sent as last item of the stream
+    pub fn iterate_entity_set<T: Into<EntitySetQuery>>(self, query: T) -> Receiver<Result<Token, Error>> {
+        self.stream_entity_set(query).into_receiver(EntitySetIterator::BUFFER_SIZE)
     }
 
-    fn build_full_url(&self, query: &EntitySetQuery) -> String {
-        let mut full_url = String::with_capacity(128);
-        full_url.push_str(&query.entityset_url);
+    /// `stream_entity_set_with_count`, driven by a spawned task
+    pub fn iterate_entity_set_with_count<T: Into<EntitySetQuery>>(self, query: T) -> (Receiver<Result<Token, Error>>, oneshot::Receiver<u64>) {
+        let (stream, count_receiver) = self.stream_entity_set_with_count(query);
+        (stream.into_receiver(EntitySetIterator::BUFFER_SIZE), count_receiver)
+    }
 
-        if !query.has_options() {
-            return full_url;
+    /// `stream_entity_set_with_progress`, driven by a spawned task
+    pub fn iterate_entity_set_with_progress<T: Into<EntitySetQuery>>(self, query: T) -> (Receiver<Result<Token, Error>>, oneshot::Receiver<u64>, oneshot::Receiver<Checkpoint>) {
+        let (stream, count_receiver, checkpoint_receiver) = self.stream_entity_set_with_progress(query);
+        (stream.into_receiver(EntitySetIterator::BUFFER_SIZE), count_receiver, checkpoint_receiver)
+    }
+
+    /// `stream_entity_set_parallel`, driven by a spawned task
+    pub fn iterate_entity_set_parallel<T: Into<EntitySetQuery>>(self, query: T, options: ParallelOptions) -> Receiver<Result<Token, Error>> {
+        self.stream_entity_set_parallel(query, options).into_receiver(EntitySetIterator::BUFFER_SIZE)
+    }
+
+    /// `stream_entity_set_with_delta_link`, driven by a spawned task
+    pub fn iterate_entity_set_with_delta_link<T: Into<EntitySetQuery>>(self, query: T) -> (Receiver<Result<Token, Error>>, oneshot::Receiver<String>) {
+        let (stream, delta_receiver) = self.stream_entity_set_with_delta_link(query);
+        (stream.into_receiver(EntitySetIterator::BUFFER_SIZE), delta_receiver)
+    }
+
+    /// `stream_changes`, driven by a spawned task
+    pub fn iterate_changes<T: Into<DeltaQuery>>(self, query: T) -> (Receiver<Result<Token, Error>>, oneshot::Receiver<String>) {
+        let (stream, delta_receiver) = self.stream_changes(query);
+        (stream.into_receiver(EntitySetIterator::BUFFER_SIZE), delta_receiver)
+    }
+
+    /// only determines the number of entities matching the query, without loading them
+    pub async fn count_entity_set<T: Into<EntitySetQuery>>(self, query: T) -> Result<u64, Error> {
+        let mut entity_set_query = query.into();
+        entity_set_query.top = Some(0);
+        entity_set_query.skip = None;
+        entity_set_query.count = true;
+
+        let authenticator = resolve_authenticator(entity_set_query.authenticator.clone(), entity_set_query.username.clone(), entity_set_query.password.clone());
+        let version = Self::resolve_version(&entity_set_query, &authenticator).await?;
+        Self::load_count(&entity_set_query, version, authenticator).await
+    }
+
+    /// requests the count of a query asking for no entities (`$top=0`)
+    async fn load_count(count_query: &EntitySetQuery, version: ProtocolVersion, authenticator: Option<SharedAuthenticator>) -> Result<u64, Error> {
+        let multi_caller = MultiUrlCaller::new(Self::build_full_url(count_query, version)?, authenticator)
+            .retrying(count_query.retry)
+            .with_client(count_query.client.clone());
+        let (sender, _receiver) = channel::<Result<Token, Error>>(EntitySetIterator::BUFFER_SIZE);
+        let mut collector = EntityCollector::new(sender);
+        collector.stream.use_protocol(version);
+
+        if let Some(response) = multi_caller.next(&Some(multi_caller.starting_link_marker())).await? {
+            collector.stream_response(response).await?;
         }
 
-        let mut ampersand_necessary = false;
-        full_url.push('?');
+        collector.count.ok_or_else(|| Error::Protocol("The service did not return a count (@odata.count)".to_owned()))
+    }
+
+    /// the version of the service: as given by the query, or detected if the query options differ between the versions
+    async fn resolve_version(query: &EntitySetQuery, authenticator: &Option<SharedAuthenticator>) -> Result<ProtocolVersion, Error> {
+        let has_nested_expand = query.expand.iter().any(|item| item.to_string() != item.navigation_property);
 
-        if let Some(filter) = &query.filters
-        {
-            full_url.push_str("$filter=");
-            full_url.push_str(filter);
-            ampersand_necessary = true;
+        match query.version {
+            Some(version) => Ok(version),
+            None if query.count || has_nested_expand => detect_version(&query.entityset_url, authenticator.clone(), query.client.clone()).await,
+            // the response tells V2 services apart
+            None => Ok(ProtocolVersion::V4)
         }
+    }
 
-        if let Some(select) = &query.select
-        {
-            if ampersand_necessary
-            {
-                full_url.push('&');
-            }
+    fn build_full_url(query: &EntitySetQuery, version: ProtocolVersion) -> Result<Url, Error> {
+        let mut options = Vec::<(&str, String)>::new();
 
-            full_url.push_str("$select=");
-            full_url.push_str(select);
-            ampersand_necessary = true;
+        if let Some(filter) = &query.filters {
+            options.push(("$filter", filter.clone()));
         }
 
-        if let Some(order_by) = &query.order_by
-        {
-            if ampersand_necessary
-            {
-                full_url.push('&');
+        if let Some(search) = &query.search {
+            options.push(("$search", search.clone()));
+        }
+
+        if let Some(select) = &query.select {
+            options.push(("$select", select.clone()));
+        }
+
+        if let Some(order_by) = &query.order_by {
+            options.push(("$orderby", order_by.clone()));
+        }
+
+        if !query.expand.is_empty() {
+            options.push(("$expand", ExpandOption::format_for_version(&query.expand, version)?));
+        }
+
+        if let Some(top) = query.top {
+            options.push(("$top", top.to_string()));
+        }
+
+        if let Some(skip) = query.skip {
+            options.push(("$skip", skip.to_string()));
+        }
+
+        match version {
+            ProtocolVersion::V2 if query.count => options.push(("$inlinecount", "allpages".to_owned())),
+            ProtocolVersion::V4 if query.count => options.push(("$count", "true".to_owned())),
+            _ => ()
+        }
+
+        build_query_url(&query.entityset_url, &options)
+    }
+
+    fn stream_pages(&self, entity_set_query: EntitySetQuery, count_sender: Option<oneshot::Sender<u64>>, delta_sender: Option<oneshot::Sender<String>>, checkpoint_sender: Option<oneshot::Sender<Checkpoint>>) -> TokenStream {
+        TokenStream::new(|sender| Self::load_pages(entity_set_query, sender, count_sender, delta_sender, checkpoint_sender))
+    }
+
+    async fn load_pages(entity_set_query: EntitySetQuery, sender: Sender<Result<Token, Error>>, count_sender: Option<oneshot::Sender<u64>>, delta_sender: Option<oneshot::Sender<String>>, checkpoint_sender: Option<oneshot::Sender<Checkpoint>>) {
+        if let Some((collector, url_caller)) = Self::prepare_pages(&entity_set_query, EntityCollector::new(sender), None).await {
+            Self::collect_pages(collector, url_caller, count_sender, delta_sender, checkpoint_sender).await;
+        }
+    }
+
+    async fn load_page(mut entity_set_query: EntitySetQuery, checkpoint: Option<Checkpoint>, sender: Sender<Result<Token, Error>>, checkpoint_sender: oneshot::Sender<Checkpoint>) {
+        let first_index = checkpoint.as_ref().map(|checkpoint| checkpoint.entities).unwrap_or(0);
+        let mut collector = EntityCollector::starting_at(sender, first_index);
+        collector.pages = checkpoint.as_ref().map(|checkpoint| checkpoint.pages).unwrap_or(0);
+        entity_set_query.max_pages = Some(1);
+
+        let starting_link = match checkpoint {
+            Some(Checkpoint { next_link: Some(next_link), .. }) => Some(next_link),
+            Some(Checkpoint { truncated: true, .. }) => {
+                let error = Error::InvalidQuery("The checkpoint can not be continued, a row limit dropped entities of its last page".to_owned());
+                return collector.stream.fail(error).await;
+            },
+            // all pages were loaded already
+            Some(checkpoint) => {
+                collector.stream.begin_at(first_index).await;
+                let _ = checkpoint_sender.send(checkpoint);
+                return collector.stream.finish().await;
+            },
+            None => None
+        };
+
+        if let Some((collector, url_caller)) = Self::prepare_pages(&entity_set_query, collector, starting_link).await {
+            Self::collect_pages(collector, url_caller, None, None, Some(checkpoint_sender)).await;
+        }
+    }
+
+    /// the caller of the pages, starting with the link given or the URL of the query. Failures are sent to the stream of the collector
+    async fn prepare_pages(entity_set_query: &EntitySetQuery, mut collector: EntityCollector, starting_link: Option<String>) -> Option<(EntityCollector, MultiUrlCaller)> {
+        let authenticator = resolve_authenticator(entity_set_query.authenticator.clone(), entity_set_query.username.clone(), entity_set_query.password.clone());
+        let full_url = match Self::resolve_version(entity_set_query, &authenticator).await {
+            Ok(version) => {
+                collector.stream.use_protocol(version);
+                match starting_link {
+                    Some(link) => parse_url(&link),
+                    None => Self::build_full_url(entity_set_query, version)
+                }
+            },
+            Err(err) => Err(err)
+        };
+
+        let url_caller = match full_url {
+            Ok(url) => MultiUrlCaller::new(url, authenticator)
+                .tracking_changes(entity_set_query.track_changes)
+                .retrying(entity_set_query.retry)
+                .with_max_page_size(entity_set_query.max_page_size)
+                .with_client(entity_set_query.client.clone()),
+            Err(err) => {
+                collector.stream.fail(err).await;
+                return None;
             }
+        };
 
-            full_url.push_str("$orderby=");
-            full_url.push_str(order_by);
+        if entity_set_query.track_changes {
+            collector.stream.track_changes(ChangeType::Added);
         }
 
-        full_url
+        collector.limit(entity_set_query.max_pages, entity_set_query.max_rows);
+
+        Some((collector, url_caller))
     }
 
-    fn run_in_background(&self, url_caller: MultiUrlCaller, sender: Sender<Token>) {
-        tokio::spawn(async move {
-            let mut collector = EntityCollector::new(sender);
-            let mut next_url = Some(url_caller.starting_link_marker().clone());
+    async fn load_changes(delta_query: DeltaQuery, sender: Sender<Result<Token, Error>>, delta_sender: oneshot::Sender<String>) {
+        let mut collector = EntityCollector::new(sender);
+        collector.stream.track_changes(ChangeType::Changed);
 
-            while next_url.is_some() {
-                match url_caller.next(&next_url).await {
-                    Ok(Some(response)) => {
-                        match collector.stream_odata_objects(response).await {
-                            Ok(url) => next_url = url,
-                            Err(err) => {
-                                eprintln!("{}", err.message);
-                                return futures::future::ready(());
-                            }
+        let url_caller = match parse_url(&delta_query.delta_link) {
+            Ok(url) => MultiUrlCaller::new(url, resolve_authenticator(delta_query.authenticator, delta_query.username, delta_query.password))
+                .tracking_changes(true)
+                .retrying(delta_query.retry)
+                .with_client(delta_query.client),
+            Err(err) => return collector.stream.fail(err).await
+        };
+
+        Self::collect_pages(collector, url_caller, None, Some(delta_sender), None).await;
+    }
+
+    async fn load_windows(entity_set_query: EntitySetQuery, options: ParallelOptions, sender: Sender<Result<Token, Error>>) {
+        let merger = WindowMerger::new(sender);
+        let authenticator = resolve_authenticator(entity_set_query.authenticator.clone(), entity_set_query.username.clone(), entity_set_query.password.clone());
+        let retry = entity_set_query.retry;
+        let max_page_size = entity_set_query.max_page_size;
+        let client = entity_set_query.client.clone();
+        let (version, window_urls) = match Self::plan_windows(entity_set_query, options.windows, &authenticator).await {
+            Ok(plan) => plan,
+            Err(err) => return merger.fail(err).await
+        };
+
+        let (window_senders, window_receivers) : (Vec<_>, Vec<_>) = window_urls.iter()
+            .map(|_| channel::<Result<Token, Error>>(EntitySetIterator::WINDOW_BUFFER_SIZE))
+            .unzip();
+
+        // the windows are started in order, the merger never waits for a window which is not loading
+        let loading = stream::iter(window_urls.into_iter().zip(window_senders))
+            .for_each_concurrent(options.concurrency.max(1), |(window_url, window_sender)| {
+                let mut collector = EntityCollector::new(window_sender);
+                collector.stream.use_protocol(version);
+                let url_caller = MultiUrlCaller::new(window_url, authenticator.clone())
+                    .retrying(retry)
+                    .with_max_page_size(max_page_size)
+                    .with_client(client.clone());
+                Self::collect_pages(collector, url_caller, None, None, None)
+            });
+        let merging = merger.merge(window_receivers, options.ordered);
+
+        futures::pin_mut!(loading, merging);
+        // the loading of the windows is cancelled once the merger gave up
+        if let Either::Left((_, merging)) = future::select(loading, merging).await {
+            merging.await;
+        }
+    }
+
+    /// the URLs of the windows, splitting the entities matching the query (within its `$skip` and `$top`) into equal parts
+    async fn plan_windows(mut entity_set_query: EntitySetQuery, windows: usize, authenticator: &Option<SharedAuthenticator>) -> Result<(ProtocolVersion, Vec<Url>), Error> {
+        // the count is requested differently by the versions, it can not be left to the response
+        let version = match entity_set_query.version {
+            Some(version) => version,
+            None => detect_version(&entity_set_query.entityset_url, authenticator.clone(), entity_set_query.client.clone()).await?
+        };
+
+        let start = entity_set_query.skip.take().unwrap_or(0) as u64;
+        let top = entity_set_query.top.take();
+
+        entity_set_query.top = Some(0);
+        entity_set_query.count = true;
+        let total = Self::load_count(&entity_set_query, version, authenticator.clone()).await?;
+        entity_set_query.count = false;
+
+        let mut end = total.max(start);
+        if let Some(top) = top {
+            end = end.min(start + top as u64);
+        }
+
+        let windows = windows.max(1) as u64;
+        let window_size = (end - start).div_ceil(windows).max(1);
+
+        let mut window_urls = vec![];
+        let mut window_start = start;
+        while window_start < end {
+            let window_end = end.min(window_start + window_size);
+            entity_set_query.skip = Some(window_start as usize);
+            entity_set_query.top = Some((window_end - window_start) as usize);
+            window_urls.push(Self::build_full_url(&entity_set_query, version)?);
+            window_start = window_end;
+        }
+
+        Ok((version, window_urls))
+    }
+
+    /// streams the entities of all pages (up to the limits of the collector), reporting the count and the delta link once known,
+    /// and the checkpoint after the last page
+    async fn collect_pages(mut collector: EntityCollector, url_caller: MultiUrlCaller, mut count_sender: Option<oneshot::Sender<u64>>, delta_sender: Option<oneshot::Sender<String>>, checkpoint_sender: Option<oneshot::Sender<Checkpoint>>) {
+        let mut next_url = Some(url_caller.starting_link_marker());
+        collector.stream.begin_at(collector.first_index).await;
+
+        while next_url.is_some() {
+            let page = match url_caller.next(&next_url).await {
+                Ok(Some(response)) => collector.stream_response(response).await,
+                Ok(None) => Ok(None),
+                Err(err) => Err(err)
+            };
+
+            match page {
+                Ok(url) => {
+                    next_url = url;
+                    collector.pages += 1;
+
+                    if let Some(count) = collector.count {
+                        if let Some(count_sender) = count_sender.take() {
+                            let _ = count_sender.send(count);
                         }
-                    },
-                    Ok(None) => break,
-                    Err(err) => {
-                        eprintln!("{}", err.message);
-                        return futures::future::ready(());
                     }
+
+                    if collector.limit_reached() {
+                        break;
+                    }
+                },
+                Err(err) => return collector.stream.fail(err).await
+            }
+        }
+
+        if let Some(checkpoint_sender) = checkpoint_sender {
+            let next_link = match next_url.map(|link| url_caller.absolute_link(&link)).transpose() {
+                Ok(next_link) => next_link.map(|next_url| next_url.to_string()),
+                Err(err) => return collector.stream.fail(err).await
+            };
+
+            let checkpoint = if collector.dropped_entities() {
+                Checkpoint::truncated(collector.pages, collector.entities())
+            } else {
+                Checkpoint::new(next_link, collector.pages, collector.entities())
+            };
+            let _ = checkpoint_sender.send(checkpoint);
+        }
+
+        if let (Some(delta_link), Some(delta_sender)) = (collector.delta_link.take(), delta_sender) {
+            match url_caller.absolute_link(&delta_link) {
+                Ok(delta_url) => { let _ = delta_sender.send(delta_url.to_string()); },
+                Err(err) => return collector.stream.fail(err).await
+            }
+        }
+
+        collector.stream.finish().await;
+    }
+}
+
+/// joins the entities of the windows into one array, numbering them anew
+struct WindowMerger {
+    sender: Sender<Result<Token, Error>>,
+    /// the index of the next entity in the joined array
+    next_index: usize
+}
+
+impl WindowMerger {
+    fn new(sender: Sender<Result<Token, Error>>) -> Self {
+        WindowMerger { sender, next_index: 0 }
+    }
+
+    /// passes on the entities of the windows, one window after the other if ordered or as soon as loaded otherwise.
+    /// It stops at the first error of any window
+    async fn merge(mut self, windows: Vec<Receiver<Result<Token, Error>>>, ordered: bool) {
+        self.send(Ok(Token { path: ValuePath::new(), value: Value::StartArray })).await;
+
+        // the tokens of the entities which are not complete yet, per window
+        let mut entities : Vec<Vec<Token>> = windows.iter().map(|_| vec![]).collect();
+        let mut tokens = if ordered {
+            stream::iter(windows).enumerate().flat_map(|(window, receiver)| receiver.map(move |token| (window, token))).boxed()
+        } else {
+            stream::select_all(windows.into_iter().enumerate().map(|(window, receiver)| receiver.map(move |token| (window, token)).boxed())).boxed()
+        };
+
+        while let Some((window, token)) = tokens.next().await {
+            let token = match token {
+                Ok(token) => token,
+                Err(err) => return self.fail(err).await
+            };
+
+            // the arrays of the windows are replaced by the joined one
+            let level = token.path.current_level();
+            if level == 0 {
+                continue;
+            }
+
+            let completes_entity = level == 1 && !matches!(token.value, Value::StartObject | Value::StartArray);
+            entities[window].push(token);
+
+            if completes_entity {
+                for mut token in std::mem::take(&mut entities[window]) {
+                    token.path.replace(0, ValuePosition::Index(self.next_index));
+                    self.send(Ok(token)).await;
                 }
+
+                self.next_index += 1;
             }
+        }
+
+        self.send(Ok(Token { path: ValuePath::new(), value: Value::EndArray })).await;
+    }
+
+    async fn fail(mut self, error: Error) {
+        self.send(Err(error)).await;
+    }
 
-            futures::future::ready(())
-        });
+    async fn send(&mut self, message: Result<Token, Error>) {
+        // a failure means nobody is listening any more
+        let _ = self.sender.send(message).await;
     }
 }
 
 struct EntityCollector {
-    stream: EntityStreamer
+    stream: EntityStreamer,
+    count: Option<u64>,
+    /// the link to load the changes from later on, sent with the last page
+    delta_link: Option<String>,
+    /// for services responding with Atom feeds, it keeps counting the entities across the pages
+    atom: Option<AtomParser>,
+    /// the index of the first entity, when continuing an earlier load
+    first_index: usize,
+    /// the pages loaded so far, including those of an earlier load
+    pages: usize,
+    /// the number of pages after which no further ones are loaded
+    page_limit: Option<usize>
 }
 
 impl EntityCollector {
-    fn new(sender: Sender<Token>) -> Self {
-        EntityCollector { stream: EntityStreamer::new(sender, RootEntityType::Array) }
+    fn new(sender: Sender<Result<Token, Error>>) -> Self {
+        EntityCollector::starting_at(sender, 0)
     }
 
-    async fn stream_odata_objects<T>(&mut self, odata_response: T) -> Result<Option<String>, MyError>
+    fn starting_at(sender: Sender<Result<Token, Error>>, first_index: usize) -> Self {
+        EntityCollector { stream: EntityStreamer::new(sender, RootEntityType::Array), count: None, delta_link: None, atom: None, first_index, pages: 0, page_limit: None }
+    }
+
+    /// stops after the pages or entities of this load, not counting those of an earlier one
+    fn limit(&mut self, max_pages: Option<usize>, max_rows: Option<usize>) {
+        self.page_limit = max_pages.map(|max_pages| self.pages + max_pages);
+        self.stream.limit_entities(max_rows.map(|max_rows| self.first_index + max_rows));
+    }
+
+    fn limit_reached(&self) -> bool {
+        self.page_limit.is_some_and(|page_limit| self.pages >= page_limit)
+            || self.stream.entity_limit().is_some_and(|entity_limit| self.entities() >= entity_limit)
+    }
+
+    /// the number of entities streamed so far, including those of an earlier load
+    fn entities(&self) -> usize {
+        // the entities beyond the limit are read, but not streamed
+        self.stream.entity_limit().map_or(self.entities_read(), |entity_limit| self.entities_read().min(entity_limit))
+    }
+
+    /// the entity limit was reached within a page, the rest of it was read without being streamed
+    fn dropped_entities(&self) -> bool {
+        self.stream.entity_limit().is_some_and(|entity_limit| self.entities_read() > entity_limit)
+    }
+
+    fn entities_read(&self) -> usize {
+        match &self.atom {
+            Some(atom) => atom.next_index(),
+            None => self.stream.next_index()
+        }
+    }
+
+    /// streams the entities of one page, JSON or Atom. Returns the next link, if any
+    async fn stream_response(&mut self, response: reqwest::Response) -> Result<Option<String>, Error> {
+        if !is_atom(response.headers()) {
+            return self.stream_odata_objects(response.bytes_stream()).await;
+        }
+
+        let first_index = self.first_index;
+        let atom = self.atom.get_or_insert_with(|| AtomParser::starting_at(first_index));
+        let next = atom.stream_response(response.bytes_stream(), &mut self.stream).await?;
+        if atom.count.is_some() {
+            self.count = atom.count;
+        }
+
+        if atom.delta_link.is_some() {
+            self.delta_link = atom.delta_link.take();
+        }
+
+        Ok(next)
+    }
+
+    async fn stream_odata_objects<T>(&mut self, odata_response: T) -> Result<Option<String>, Error>
     where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
-        let mut error : Option<MyError> = None;
+        let mut error : Option<Error> = None;
         let mut next : Option<String> = None;
 
         let mut stream = crate::json_stream::stream::Stream::from_stream(odata_response)?;
@@ -121,7 +568,7 @@ impl EntityCollector {
                     JsonToken::JsKey(key_val) => {
                         match key_val.into_raw_str() {
 
-                            "@odata.nextLink" => {
+                            "@odata.nextLink" | "odata.nextLink" => {
                                 stream.advance().await?; 
                                 if let Some(next_link_token) = stream.get() {
 
@@ -129,15 +576,41 @@ impl EntityCollector {
                                         next = Some(next_link_value.into_raw_str().to_owned());
 
                                     } else {
-                                        error = Some(MyError { message: "Expected a string value for key '@odata.nextLink'".to_owned() });
+                                        error = Some(Error::Protocol("Expected a string value for key '@odata.nextLink'".to_owned()));
                                         break;
                                     };
 
                                 } else {
-                                    error = Some(MyError { message: "Expected a value after key '@odata.nextLink'".to_owned() });
+                                    error = Some(Error::Protocol("Expected a value after key '@odata.nextLink'".to_owned()));
                                     break;
                                 };
                             },
+                            "@odata.deltaLink" | "odata.deltaLink" => {
+                                stream.advance().await?;
+                                match stream.get() {
+                                    Some(JsonToken::JsString(delta_link_value)) => self.delta_link = Some(delta_link_value.into_raw_str().to_owned()),
+                                    _ => {
+                                        error = Some(Error::Protocol("Expected a string value for key '@odata.deltaLink'".to_owned()));
+                                        break;
+                                    }
+                                }
+                            },
+                            "@odata.count" | "odata.count" => {
+                                stream.advance().await?;
+                                // numbers are sent as strings with `IEEE754Compatible=true`
+                                let count = match stream.get() {
+                                    Some(JsonToken::JsNumber(count_value)) => count_value.parse::<u64>().ok(),
+                                    Some(JsonToken::JsString(count_value)) => count_value.into_raw_str().parse::<u64>().ok(),
+                                    _ => None
+                                };
+
+                                if count.is_none() {
+                                    error = Some(Error::Protocol("Expected a number for key '@odata.count'".to_owned()));
+                                    break;
+                                }
+
+                                self.count = count;
+                            },
                             "value" => {
                                 stream.advance().await?;
                                 if let Some(value_token) = stream.get() {
@@ -147,15 +620,32 @@ impl EntityCollector {
                                         self.stream.stream_content(&mut stream).await?;
 
                                     } else {
-                                        error = Some(MyError { message: "Expected an array for key 'value'".to_owned() });
+                                        error = Some(Error::Protocol("Expected an array for key 'value'".to_owned()));
                                         break;
                                     }
 
                                 } else {
-                                    error = Some(MyError { message: "Expected an array for key 'value'".to_owned() });
+                                    error = Some(Error::Protocol("Expected an array for key 'value'".to_owned()));
                                     break;
                                 };
                             },
+                            "d" => {
+                                // the verbose JSON of V2 (and V3) services
+                                self.stream.use_protocol(ProtocolVersion::V2);
+                                stream.advance().await?;
+                                match stream.get() {
+                                    Some(JsonToken::StartArray) => {
+                                        // V1 services return the entities without `results`
+                                        stream.advance().await?;
+                                        self.stream.stream_content(&mut stream).await?;
+                                    },
+                                    Some(JsonToken::StartObject) => next = self.stream_v2_results(&mut stream).await?,
+                                    _ => {
+                                        error = Some(Error::Protocol("Expected an object or an array for key 'd'".to_owned()));
+                                        break;
+                                    }
+                                }
+                            },
                             _ => {
                                 stream.advance().await?; // skip the value for that key
                             }
@@ -165,7 +655,7 @@ impl EntityCollector {
                         break;
                     },
                     _ => {
-                        error = Some(MyError { message: format!("Invalid top level JSON structure of response: {:?}", json_content) });
+                        error = Some(Error::Protocol(format!("Invalid top level JSON structure of response: {:?}", json_content)));
                         break;
                     }
                 }
@@ -182,4 +672,56 @@ impl EntityCollector {
 
         return Ok(next);
     }
+
+    /// the content of `d`: the entities in `results`, the next link in `__next`, the delta link in `__delta` and the count in `__count`
+    async fn stream_v2_results<T>(&mut self, stream: &mut crate::json_stream::stream::Stream<T>) -> Result<Option<String>, Error>
+    where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
+        let mut next : Option<String> = None;
+
+        stream.advance().await?;
+        loop {
+            let key = match stream.get() {
+                Some(JsonToken::JsKey(key)) => key.into_raw_str().to_owned(),
+                Some(JsonToken::EndObject) => return Ok(next),
+                other => return Err(Error::Protocol(format!("Invalid structure of key 'd' in response: {:?}", other)))
+            };
+
+            stream.advance().await?;
+            match key.as_str() {
+                "results" => {
+                    if stream.get() != Some(JsonToken::StartArray) {
+                        return Err(Error::Protocol("Expected an array for key 'results'".to_owned()));
+                    }
+
+                    stream.advance().await?;
+                    self.stream.stream_content(stream).await?;
+                },
+                "__next" => match stream.get() {
+                    Some(JsonToken::JsString(next_link_value)) => next = Some(next_link_value.into_raw_str().to_owned()),
+                    _ => return Err(Error::Protocol("Expected a string value for key '__next'".to_owned()))
+                },
+                "__delta" => match stream.get() {
+                    Some(JsonToken::JsString(delta_link_value)) => self.delta_link = Some(delta_link_value.into_raw_str().to_owned()),
+                    _ => return Err(Error::Protocol("Expected a string value for key '__delta'".to_owned()))
+                },
+                "__count" => {
+                    // V2 sends the count as string
+                    let count = match stream.get() {
+                        Some(JsonToken::JsString(count_value)) => count_value.into_raw_str().parse::<u64>().ok(),
+                        Some(JsonToken::JsNumber(count_value)) => count_value.parse::<u64>().ok(),
+                        _ => None
+                    };
+
+                    if count.is_none() {
+                        return Err(Error::Protocol("Expected a number for key '__count'".to_owned()));
+                    }
+
+                    self.count = count;
+                },
+                _ => skip_value(stream).await?
+            }
+
+            stream.advance().await?;
+        }
+    }
 }
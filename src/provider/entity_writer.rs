@@ -0,0 +1,147 @@
+use reqwest::Method;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE, ETAG, IF_MATCH, LOCATION};
+use url::Url;
+use crate::model::{Entity, EntityWriteQuery, WriteResult};
+use crate::error::Error;
+use crate::service::url::{build_query_url, parse_url, RequestContent, SingleUrlCaller};
+use crate::service::auth::resolve_authenticator;
+
+/// creates, updates and deletes entities, and changes the links between them
+#[derive(Default)]
+pub struct EntityWriter {}
+impl EntityWriter {
+    pub fn new() -> EntityWriter {
+        EntityWriter {}
+    }
+
+    /// creates the entity (`POST` to the entity set)
+    pub async fn create<T: Into<EntityWriteQuery>>(self, query: T) -> Result<WriteResult, Error> {
+        let query = query.into();
+        let url = parse_url(&query.entity_url)?;
+        let body = Self::payload(&query)?;
+
+        self.send(query, url, Method::POST, Some(body), false).await
+    }
+
+    /// changes the given properties of the entity (`PATCH`), others keep their values
+    pub async fn update<T: Into<EntityWriteQuery>>(self, query: T) -> Result<WriteResult, Error> {
+        let query = query.into();
+        let url = parse_url(&query.entity_url)?;
+        let body = Self::payload(&query)?;
+
+        self.send(query, url, Method::PATCH, Some(body), true).await
+    }
+
+    /// replaces the entity as a whole (`PUT`). Services supporting upserts create it, if it does not exist yet
+    pub async fn replace<T: Into<EntityWriteQuery>>(self, query: T) -> Result<WriteResult, Error> {
+        let query = query.into();
+        let url = parse_url(&query.entity_url)?;
+        let body = Self::payload(&query)?;
+
+        self.send(query, url, Method::PUT, Some(body), true).await
+    }
+
+    pub async fn delete<T: Into<EntityWriteQuery>>(self, query: T) -> Result<WriteResult, Error> {
+        let query = query.into();
+        let url = parse_url(&query.entity_url)?;
+
+        self.send(query, url, Method::DELETE, None, true).await
+    }
+
+    /// adds the target entity to a collection-valued navigation property (`POST .../Friends/$ref`)
+    pub async fn add_link<T: Into<EntityWriteQuery>>(self, query: T, target_url: &str) -> Result<WriteResult, Error> {
+        let query = query.into();
+        let url = Self::reference_url(&query, None)?;
+
+        self.send(query, url, Method::POST, Some(Self::reference(target_url)), false).await
+    }
+
+    /// sets the target entity for a single-valued navigation property (`PUT .../BestFriend/$ref`)
+    pub async fn set_link<T: Into<EntityWriteQuery>>(self, query: T, target_url: &str) -> Result<WriteResult, Error> {
+        let query = query.into();
+        let url = Self::reference_url(&query, None)?;
+
+        self.send(query, url, Method::PUT, Some(Self::reference(target_url)), false).await
+    }
+
+    /// removes the link: the target entity from a collection-valued navigation property, or the single-valued navigation property if no target is given
+    pub async fn remove_link<T: Into<EntityWriteQuery>>(self, query: T, target_url: Option<&str>) -> Result<WriteResult, Error> {
+        let query = query.into();
+        let url = Self::reference_url(&query, target_url)?;
+
+        self.send(query, url, Method::DELETE, None, false).await
+    }
+
+    fn payload(query: &EntityWriteQuery) -> Result<String, Error> {
+        match &query.payload {
+            Some(Entity::Object(properties)) => {
+                // the ETag is sent as `If-Match`, not as part of the entity
+                let payload : serde_json::Map<String, Entity> = properties.iter()
+                    .filter(|(key, _)| key.as_str() != "@odata.etag")
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+
+                Ok(Entity::Object(payload).to_string())
+            },
+            Some(payload) => Ok(payload.to_string()),
+            None => Err(Error::InvalidQuery(format!("No payload given for {}", query.entity_url)))
+        }
+    }
+
+    fn reference(target_url: &str) -> String {
+        serde_json::json!({ "@odata.id": target_url }).to_string()
+    }
+
+    fn reference_url(query: &EntityWriteQuery, target_url: Option<&str>) -> Result<Url, Error> {
+        let options = target_url.map(|target| vec![("$id", target.to_owned())]).unwrap_or_default();
+        let mut url = build_query_url(&query.entity_url, &options)?;
+
+        url.path_segments_mut()
+            .map_err(|_| Error::InvalidQuery(format!("Invalid navigation property URL '{}'", query.entity_url)))?
+            .pop_if_empty()
+            .push("$ref");
+
+        Ok(url)
+    }
+
+    async fn send(&self, query: EntityWriteQuery, url: Url, method: Method, body: Option<String>, conditional: bool) -> Result<WriteResult, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+        if body.is_some() {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+
+        if query.return_representation {
+            headers.insert("Prefer", HeaderValue::from_static("return=representation"));
+        }
+
+        if conditional {
+            if let Some(etag) = query.etag() {
+                let value = HeaderValue::from_str(&etag).map_err(|_| Error::InvalidQuery(format!("Invalid ETag '{}'", etag)))?;
+                headers.insert(IF_MATCH, value);
+            }
+        }
+
+        let url_caller = SingleUrlCaller::new(url, resolve_authenticator(query.authenticator, query.username, query.password)).with_client(query.client);
+        let response = url_caller.send(&RequestContent { method, headers, body }).await?;
+
+        let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok()).map(|value| value.to_owned());
+        let status = response.status();
+        let etag = header(ETAG.as_str());
+        let location = header(LOCATION.as_str()).or_else(|| header("OData-EntityId"));
+
+        let content = response.text().await?;
+        let entity = if content.trim().is_empty() {
+            None
+        } else {
+            let entity : Entity = serde_json::from_str(&content).map_err(|err| Error::decode(format!("Invalid JSON in response: {}", err), None))?;
+            Some(entity)
+        };
+
+        // the new ETag might only be part of the returned entity
+        let etag = etag.or_else(|| entity.as_ref().and_then(|entity| entity.get("@odata.etag")).and_then(|etag| etag.as_str()).map(|etag| etag.to_owned()));
+
+        Ok(WriteResult { status, etag, location, entity })
+    }
+}
@@ -1,9 +1,15 @@
-use futures::channel::mpsc::{ channel, Sender, Receiver};
+use futures::channel::mpsc::{Sender, Receiver};
 use futures::stream::Stream;
 use bytes::Bytes;
-use crate::model::{FunctionQuery, Token, MyError};
-use crate::service::url::SingleUrlCaller;
-use crate::service::entity_stream::{EntityStreamer, RootEntityType};
+use url::Url;
+use crate::model::{FunctionQuery, ProtocolVersion, Token};
+use crate::error::Error;
+use crate::filter::literal::Literal;
+use crate::service::url::{parse_url, RequestContent, SingleUrlCaller, JSON};
+use crate::service::protocol::version_from_headers;
+use crate::service::auth::resolve_authenticator;
+use crate::service::entity_stream::{skip_value, EntityStreamer, RootEntityType};
+use crate::service::token_stream::TokenStream;
 use crate::json_stream::token::JsonToken;
 use crate::json_stream::stream::TokenIterator;
 
@@ -15,106 +21,212 @@ impl FunctionCaller {
         FunctionCaller {}
     }
 
-    pub fn call_function<T: Into<FunctionQuery>>(self, query: T) -> Receiver<Token> {
+    /// streams the result of the function: the elements of a returned collection, or the single returned value, complex value or entity.
+    /// Errors are sent as last item of the stream
+    pub fn stream_function<T: Into<FunctionQuery>>(self, query: T) -> TokenStream {
         let function_query = query.into();
-        let url_caller = SingleUrlCaller::new(function_query.function_url, function_query.username, function_query.password);
-        let (sender, receiver) = channel::<Token>(FunctionCaller::BUFFER_SIZE);
+        let function_url = match function_url(&function_query.function_url, &function_query.parameters) {
+            Ok(url) => url,
+            Err(err) => return TokenStream::failed(err)
+        };
 
-        self.run_in_background(url_caller, sender);
-        
-        return receiver;
+        let url_caller = SingleUrlCaller::new(function_url, resolve_authenticator(function_query.authenticator, function_query.username, function_query.password))
+            .with_client(function_query.client);
+
+        TokenStream::new(|sender| Self::call(url_caller, sender))
     }
 
-    fn run_in_background(&self, url_caller: SingleUrlCaller, sender: Sender<Token>) {
-        tokio::spawn(async move {
-            let mut collector = FunctionResultCollector::new(sender);
-
-            match url_caller.call().await {
-                Ok(response) => {
-                    match collector.stream_odata_object(response).await {
-                        Ok(_) => (),
-                        Err(err) => {
-                            eprintln!("{}", err.message);
-                            return futures::future::ready(());
-                        }
-                    }
-                },
-                Err(err) => {
-                    eprintln!("{}", err.message);
-                    return futures::future::ready(());
-                }
-            }
+    /// `stream_function`, driven by a spawned task. Errors are sent as last item of the stream
+    pub fn call_function<T: Into<FunctionQuery>>(self, query: T) -> Receiver<Result<Token, Error>> {
+        self.stream_function(query).into_receiver(FunctionCaller::BUFFER_SIZE)
+    }
+
+    async fn call(url_caller: SingleUrlCaller, sender: Sender<Result<Token, Error>>) {
+        let result = match url_caller.send(&RequestContent::get_accepting(JSON)).await {
+            Ok(response) => {
+                let version = version_from_headers(response.headers()).unwrap_or_default();
+                FunctionResultCollector::new(sender.clone(), version).stream_odata_object(response.bytes_stream()).await
+            },
+            Err(err) => Err(err)
+        };
+
+        if let Err(err) = result {
+            EntityStreamer::new(sender, RootEntityType::Value).fail(err).await;
+        }
+    }
+}
+
+/// adds the parameters to the last segment of the URL, after the ones already given there: `Function(a=1)` + `b` → `Function(a=1,b='x')`
+fn function_url(base_url: &str, parameters: &[(String, Literal)]) -> Result<Url, Error> {
+    let mut url = parse_url(base_url)?;
+    if parameters.is_empty() {
+        return Ok(url);
+    }
+
+    let segment = url.path_segments().and_then(|mut segments| segments.rfind(|segment| !segment.is_empty())).unwrap_or_default();
+    let segment = percent_encoding::percent_decode_str(segment).decode_utf8_lossy().into_owned();
+
+    let (name, mut given) = match segment.strip_suffix(')').and_then(|segment| segment.split_once('(')) {
+        Some((name, given)) if !given.trim().is_empty() => (name.to_owned(), vec![given.to_owned()]),
+        Some((name, _)) => (name.to_owned(), vec![]),
+        None => (segment.clone(), vec![])
+    };
+
+    given.extend(parameters.iter().map(|(name, value)| format!("{}={}", name, value)));
 
-            futures::future::ready(())
-        });
+    url.path_segments_mut()
+        .map_err(|_| Error::InvalidQuery(format!("Invalid function URL '{}'", base_url)))?
+        .pop_if_empty()
+        .pop()
+        .push(&format!("{}({})", name, given.join(",")));
+
+    Ok(url)
+}
+
+/// the shape of an operation result, as announced by the `@odata.context`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultShape {
+    /// a collection or a single primitive value, wrapped into `value`
+    Wrapped,
+    /// an entity or a complex value, the response object itself
+    Inline
+}
+
+impl ResultShape {
+    /// `#Collection(...)`, `#People` and `#Edm.Int32` are wrapped, `#People/$entity` and `#NS.Address` are not
+    fn from_context(context: &str) -> Option<ResultShape> {
+        let fragment = context.rsplit_once('#').map(|(_, fragment)| fragment)?;
+        if fragment.ends_with("/$entity") {
+            return Some(ResultShape::Inline);
+        }
+
+        let type_name = fragment.split('(').next().unwrap_or_default();
+        if type_name == "Collection" || type_name.starts_with("Edm.") {
+            return Some(ResultShape::Wrapped);
+        }
+
+        if type_name.contains('.') {
+            return Some(ResultShape::Inline);
+        }
+
+        // an entity set
+        Some(ResultShape::Wrapped)
     }
 }
 
-struct FunctionResultCollector {
-    stream: EntityStreamer
+/// streams the result of a function or action, whatever it returns
+pub(crate) struct FunctionResultCollector {
+    sender: Sender<Result<Token, Error>>,
+    version: ProtocolVersion
 }
 
 impl FunctionResultCollector {
-    fn new(sender: Sender<Token>) -> Self {
-        FunctionResultCollector { stream: EntityStreamer::new(sender, RootEntityType::Array) }
+    pub(crate) fn new(sender: Sender<Result<Token, Error>>, version: ProtocolVersion) -> Self {
+        FunctionResultCollector { sender, version }
     }
 
-    async fn stream_odata_object<T>(&mut self, odata_response: T) -> Result<(), MyError>
+    /// collections and single primitive values are wrapped into `value`, complex values and entities are the response object itself.
+    /// The `@odata.context` tells them apart, otherwise the first property does
+    pub(crate) async fn stream_odata_object<T>(self, odata_response: T) -> Result<(), Error>
     where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
-        let mut error : Option<MyError> = None;
-
         let mut stream = crate::json_stream::stream::Stream::from_stream(odata_response)?;
-        stream.advance().await?; // start of object
-        stream.advance().await?; //hopefully a key
+        stream.advance().await?;
+        if stream.get() != Some(JsonToken::StartObject) {
+            return Err(Error::Protocol(format!("Invalid top level JSON structure of response: {:?}", stream.get())));
+        }
+
+        let mut shape : Option<ResultShape> = None;
+
+        stream.advance().await?;
         loop {
-            if let Some(json_content) = stream.get() {
-
-                match json_content {
-                    JsonToken::JsKey(key_val) => {
-                        match key_val.into_raw_str() {
-
-                            "value" => {
-                                stream.advance().await?;
-                                if let Some(value_token) = stream.get() {
-
-                                    if value_token == JsonToken::StartArray {
-                                        stream.advance().await?;
-                                        self.stream.stream_content(&mut stream).await?;
-
-                                    } else {
-                                        error = Some(MyError { message: "Expected an array for key 'value'".to_owned() });
-                                        break;
-                                    }
-
-                                } else {
-                                    error = Some(MyError { message: "Expected an array for key 'value'".to_owned() });
-                                    break;
-                                };
-                            },
-                            _ => {
-                                stream.advance().await?; // skip the value for that key
-                            }
-                        }
-                    },
-                    JsonToken::EndObject => {
-                        break;
-                    },
-                    _ => {
-                        error = Some(MyError { message: format!("Invalid top level JSON structure of response: {:?}", json_content) });
-                        break;
+            let key = match stream.get() {
+                Some(JsonToken::JsKey(key)) => key.into_raw_str().to_owned(),
+                // only annotations, nothing returned
+                Some(JsonToken::EndObject) => return Ok(()),
+                other => return Err(Error::Protocol(format!("Invalid top level JSON structure of response: {:?}", other)))
+            };
+
+            if self.version == ProtocolVersion::V2 {
+                if key != "d" {
+                    stream.advance().await?;
+                    skip_value(&mut stream).await?;
+                    stream.advance().await?;
+                    continue;
+                }
+
+                stream.advance().await?;
+                return self.stream_v2_result(&mut stream).await;
+            }
+
+            if key.starts_with('@') || key.starts_with("odata.") {
+                stream.advance().await?;
+                if key == "@odata.context" || key == "@context" || key == "odata.metadata" {
+                    if let Some(JsonToken::JsString(context)) = stream.get() {
+                        shape = ResultShape::from_context(context.into_raw_str());
                     }
                 }
-            } else {
-                break;
-            };
+
+                skip_value(&mut stream).await?;
+                stream.advance().await?;
+                continue;
+            }
+
+            if key != "value" || shape == Some(ResultShape::Inline) {
+                // a complex value or entity: the key is the first property
+                return self.stream_root(&mut stream, RootEntityType::Object, false).await;
+            }
 
             stream.advance().await?;
+            return match stream.get() {
+                Some(JsonToken::StartArray) => self.stream_root(&mut stream, RootEntityType::Array, true).await,
+                Some(JsonToken::StartObject) => self.stream_root(&mut stream, RootEntityType::Object, true).await,
+                _ => self.stream_root(&mut stream, RootEntityType::Value, false).await
+            };
+        }
+    }
+
+    /// the content of `d`: a collection (`[...]` or `{ "results": [...] }`), an entity (with `__metadata`),
+    /// or the complex or primitive value named after the operation (`{ "GetAddress": ... }`)
+    async fn stream_v2_result<T>(self, stream: &mut crate::json_stream::stream::Stream<T>) -> Result<(), Error>
+    where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
+        match stream.get() {
+            Some(JsonToken::StartArray) => return self.stream_root(stream, RootEntityType::Array, true).await,
+            Some(JsonToken::StartObject) => (),
+            _ => return self.stream_root(stream, RootEntityType::Value, false).await
+        }
+
+        stream.advance().await?;
+        let key = match stream.get() {
+            Some(JsonToken::JsKey(key)) => key.into_raw_str().to_owned(),
+            // nothing returned
+            _ => return Ok(())
+        };
+
+        if key == "__metadata" {
+            return self.stream_root(stream, RootEntityType::Object, false).await;
+        }
+
+        stream.advance().await?;
+        match stream.get() {
+            Some(JsonToken::StartArray) => self.stream_root(stream, RootEntityType::Array, true).await,
+            Some(JsonToken::StartObject) => self.stream_root(stream, RootEntityType::Object, true).await,
+            _ => self.stream_root(stream, RootEntityType::Value, false).await
         }
-        
-        if let Some(occurred_error) = error {
-            return Err(occurred_error);
+    }
+
+    async fn stream_root<T>(self, stream: &mut crate::json_stream::stream::Stream<T>, root_entity: RootEntityType, skip_start: bool) -> Result<(), Error>
+    where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
+        let mut streamer = EntityStreamer::new(self.sender, root_entity);
+        streamer.use_protocol(self.version);
+        streamer.begin().await;
+
+        if skip_start {
+            stream.advance().await?;
         }
 
-        return Ok(());
+        streamer.stream_content(stream).await?;
+        streamer.finish().await;
+
+        Ok(())
     }
 }
@@ -0,0 +1,32 @@
+use futures::stream::StreamExt;
+use crate::model::MetadataQuery;
+use crate::error::Error;
+use crate::service::url::SingleUrlCaller;
+use crate::service::auth::resolve_authenticator;
+use crate::metadata::csdl::ServiceMetadata;
+use crate::metadata::parser::MetadataParser;
+
+#[derive(Default)]
+pub struct MetadataLoader {}
+impl MetadataLoader {
+    pub fn new() -> MetadataLoader {
+        MetadataLoader {}
+    }
+
+    pub async fn load_metadata<T: Into<MetadataQuery>>(self, query: T) -> Result<ServiceMetadata, Error> {
+        let metadata_query = query.into();
+        let metadata_url = metadata_query.metadata_url()?;
+        let url_caller = SingleUrlCaller::new(metadata_url, resolve_authenticator(metadata_query.authenticator, metadata_query.username, metadata_query.password))
+            .with_client(metadata_query.client);
+
+        // the CSDL document is needed as a whole, there is no point in streaming it
+        let mut response = url_caller.call().await?;
+        let mut content = Vec::<u8>::new();
+        while let Some(chunk) = response.next().await {
+            content.extend_from_slice(&chunk?);
+        }
+
+        let document = String::from_utf8(content).map_err(|err| Error::decode("$metadata document is not valid UTF-8", Some(err.utf8_error().valid_up_to())))?;
+        MetadataParser::parse(&document)
+    }
+}
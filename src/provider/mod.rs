@@ -1,3 +1,7 @@
-﻿pub mod entity_individual;
+﻿pub mod action;
+pub mod entity_import;
+pub mod entity_individual;
 pub mod entity_set;
+pub mod entity_writer;
 pub mod function;
+pub mod metadata;
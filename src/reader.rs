@@ -0,0 +1,269 @@
+use std::io::Read;
+use std::path::Path;
+use serde_json::Map;
+use crate::model::Entity;
+use crate::error::Error;
+
+/// the formats entities are read from, the same as written by the JSON and CSV converters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Json,
+    Csv
+}
+
+impl InputFormat {
+    /// the explicitly given format, otherwise determined by the extension of the file (JSON by default)
+    pub fn determine(format: Option<&str>, in_file: &std::ffi::OsStr) -> Result<InputFormat, Error> {
+        match format.map(|format| format.to_lowercase()).as_deref() {
+            Some("json") => Ok(InputFormat::Json),
+            Some("csv") => Ok(InputFormat::Csv),
+            Some(other) => Err(Error::InvalidQuery(format!("Unknown input format '{}', expected json or csv", other))),
+            None => match Path::new(in_file).extension().and_then(|extension| extension.to_str()) {
+                Some(extension) if extension.eq_ignore_ascii_case("csv") => Ok(InputFormat::Csv),
+                _ => Ok(InputFormat::Json)
+            }
+        }
+    }
+}
+
+pub struct FileReader {
+    content: String
+}
+
+impl FileReader {
+    /// reads the whole file, `-` for stdin
+    pub fn new(in_file: &std::ffi::OsStr) -> Result<FileReader, Error> {
+        let mut content = String::new();
+        if in_file == std::ffi::OsStr::new("-") {
+            std::io::stdin().read_to_string(&mut content)?;
+        } else {
+            content = std::fs::read_to_string(Path::new(in_file))?;
+        }
+
+        Ok(FileReader { content })
+    }
+
+    pub fn content(&self) -> &str {
+        self.content.trim_start_matches('\u{feff}')
+    }
+
+    /// JSON files may contain a single entity, an array of entities or a collection (`{ "value": [...] }`),
+    /// CSV files one entity per line
+    pub fn read_entities(&self, format: InputFormat) -> Result<Vec<Entity>, Error> {
+        match format {
+            InputFormat::Json => {
+                let content : Entity = serde_json::from_str(self.content())
+                    .map_err(|err| Error::decode(format!("Invalid JSON: {}", err), None))?;
+
+                match content {
+                    Entity::Array(entities) => Ok(entities),
+                    Entity::Object(mut object) => match object.remove("value") {
+                        Some(Entity::Array(entities)) => Ok(entities),
+                        Some(other) => {
+                            object.insert("value".to_owned(), other);
+                            Ok(vec![Entity::Object(object)])
+                        },
+                        None => Ok(vec![Entity::Object(object)])
+                    },
+                    other => Err(Error::decode(format!("Expected an object or an array of objects, found '{}'", other), None))
+                }
+            },
+            InputFormat::Csv => Ok(CsvTable::parse(self.content(), ';')?.to_entities())
+        }
+    }
+}
+
+/// one cell of a CSV file. Quoted cells are strings, the type of others is inferred
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvField {
+    pub text: String,
+    pub quoted: bool
+}
+
+impl CsvField {
+    /// `null` for empty cells, booleans and numbers as they look like, everything else as string
+    pub fn infer_value(&self) -> Entity {
+        if self.quoted {
+            return Entity::String(self.text.clone());
+        }
+
+        match self.text.as_str() {
+            "" => Entity::Null,
+            "true" => Entity::Bool(true),
+            "false" => Entity::Bool(false),
+            text => match text.parse::<serde_json::Number>() {
+                Ok(number) => Entity::Number(number),
+                Err(_) => Entity::String(text.to_owned())
+            }
+        }
+    }
+}
+
+/// a CSV file as written by the CSV converter: a header line with the property names, `;` as delimiter and strings in quotes
+pub struct CsvTable {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<CsvField>>
+}
+
+impl CsvTable {
+    pub fn parse(content: &str, delimiter: char) -> Result<CsvTable, Error> {
+        let mut records = parse_records(content, delimiter)?.into_iter();
+        let header = match records.next() {
+            Some(header) => header.into_iter().map(|field| field.text.trim().to_owned()).collect(),
+            None => vec![]
+        };
+
+        let mut rows = vec![];
+        for (index, record) in records.enumerate() {
+            if record.len() != header.len() {
+                return Err(Error::decode(format!("Line {} has {} fields, the header {}", index + 2, record.len(), header.len()), None));
+            }
+
+            rows.push(record);
+        }
+
+        Ok(CsvTable { header, rows })
+    }
+
+    pub fn to_entities(&self) -> Vec<Entity> {
+        self.rows.iter().map(|row| {
+            let properties : Map<String, Entity> = self.header.iter().cloned().zip(row.iter().map(CsvField::infer_value)).collect();
+            Entity::Object(properties)
+        }).collect()
+    }
+}
+
+/// splits the content into records and fields. A quote only ends a quoted field if followed by the delimiter or the end of the line,
+/// as the converter does not escape quotes inside of strings. Doubled quotes are read as one quote
+fn parse_records(content: &str, delimiter: char) -> Result<Vec<Vec<CsvField>>, Error> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = CsvField { text: String::new(), quoted: false };
+    let mut in_quotes = false;
+    let mut characters = content.chars();
+
+    while let Some(character) = characters.next() {
+        if in_quotes {
+            if character != '"' {
+                field.text.push(character);
+                continue;
+            }
+
+            let mut lookahead = characters.clone();
+            match lookahead.next() {
+                Some('"') => {
+                    characters.next();
+                    field.text.push('"');
+
+                    // `""` right before the delimiter: the unescaped quote at the end of a string
+                    if lookahead.next().map(|next| next == delimiter || next == '\r' || next == '\n').unwrap_or(true) {
+                        in_quotes = false;
+                    }
+                },
+                Some(next) if next != delimiter && next != '\r' && next != '\n' => field.text.push('"'),
+                _ => in_quotes = false
+            }
+
+            continue;
+        }
+
+        match character {
+            '"' if field.text.is_empty() && !field.quoted => {
+                field.quoted = true;
+                in_quotes = true;
+            },
+            '\r' => (),
+            '\n' => {
+                record.push(std::mem::replace(&mut field, CsvField { text: String::new(), quoted: false }));
+                if !(record.len() == 1 && record[0].text.is_empty() && !record[0].quoted) {
+                    records.push(std::mem::take(&mut record));
+                }
+                record.clear();
+            },
+            _ if character == delimiter => record.push(std::mem::replace(&mut field, CsvField { text: String::new(), quoted: false })),
+            _ => field.text.push(character)
+        }
+    }
+
+    if in_quotes {
+        return Err(Error::decode(format!("Unterminated quoted field in line {}", records.len() + 1), None));
+    }
+
+    if !record.is_empty() || !field.text.is_empty() || field.quoted {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn field(text: &str, quoted: bool) -> CsvField {
+        CsvField { text: text.to_owned(), quoted }
+    }
+
+    #[test]
+    fn parses_the_csv_of_the_converter() {
+        let table = CsvTable::parse("Id;Name;Note\r\n1;\"Russell\";\r\n2;\"Scott; Ketchum\";\"\"\r\n", ';').expect("invalid CSV");
+
+        assert_eq!(table.header, vec!["Id", "Name", "Note"]);
+        assert_eq!(table.rows, vec![
+            vec![field("1", false), field("Russell", true), field("", false)],
+            vec![field("2", false), field("Scott; Ketchum", true), field("", true)]
+        ]);
+    }
+
+    #[test]
+    fn reads_quotes_inside_of_strings() {
+        // doubled as usual, or unescaped as the converter writes them
+        let table = CsvTable::parse("Name;Title\n\"Say \"\"hi\"\"\";\"The \"best\" one\"\n", ';').expect("invalid CSV");
+
+        assert_eq!(table.rows[0], vec![field("Say \"hi\"", true), field("The \"best\" one", true)]);
+    }
+
+    #[test]
+    fn reads_line_breaks_inside_of_strings() {
+        let table = CsvTable::parse("Id;Note\n1;\"first\nsecond\"\n\n", ';').expect("invalid CSV");
+
+        assert_eq!(table.rows, vec![vec![field("1", false), field("first\nsecond", true)]]);
+    }
+
+    #[test]
+    fn rejects_rows_not_matching_the_header() {
+        assert!(matches!(CsvTable::parse("Id;Name\n1\n", ';'), Err(Error::Decode { .. })));
+        assert!(matches!(CsvTable::parse("Id;Name\n1;\"open\n", ';'), Err(Error::Decode { .. })));
+    }
+
+    #[test]
+    fn infers_the_types_of_unquoted_cells() {
+        let table = CsvTable::parse("Id;Active;Rate;Name;Code;Missing\n7;true;1.5;\"007\";X1;\n", ';').expect("invalid CSV");
+
+        assert_eq!(table.to_entities(), vec![json!({ "Id": 7, "Active": true, "Rate": 1.5, "Name": "007", "Code": "X1", "Missing": null })]);
+    }
+
+    #[test]
+    fn reads_single_entities_arrays_and_collections() {
+        let read = |content: &str| FileReader { content: content.to_owned() }.read_entities(InputFormat::Json).expect("invalid JSON");
+
+        assert_eq!(read("{\"Id\": 1}"), vec![json!({ "Id": 1 })]);
+        assert_eq!(read("\u{feff}[{\"Id\": 1}, {\"Id\": 2}]"), vec![json!({ "Id": 1 }), json!({ "Id": 2 })]);
+        assert_eq!(read("{\"@odata.context\": \"$metadata#People\", \"value\": [{\"Id\": 1}]}"), vec![json!({ "Id": 1 })]);
+        // a property named `value`
+        assert_eq!(read("{\"Id\": 1, \"value\": 5}"), vec![json!({ "Id": 1, "value": 5 })]);
+    }
+
+    #[test]
+    fn determines_the_format_by_the_extension() {
+        let format = |format: Option<&str>, file: &str| InputFormat::determine(format, std::ffi::OsStr::new(file)).ok();
+
+        assert_eq!(format(None, "people.CSV"), Some(InputFormat::Csv));
+        assert_eq!(format(None, "people.json"), Some(InputFormat::Json));
+        assert_eq!(format(None, "-"), Some(InputFormat::Json));
+        assert_eq!(format(Some("csv"), "people.json"), Some(InputFormat::Csv));
+        assert_eq!(format(Some("xml"), "people.xml"), None);
+    }
+}
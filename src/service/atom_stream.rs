@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use futures::stream::{Stream, StreamExt};
+use bytes::Bytes;
+use quick_xml::Reader;
+use quick_xml::events::{Event, BytesStart};
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use crate::model::{Token, Value, ValuePath, ValuePosition};
+use crate::error::Error;
+use crate::service::entity_stream::EntityStreamer;
+use crate::json_stream::token::escape;
+
+/// the types written without quotes in JSON
+const NUMBER_TYPES: [&str; 9] = ["Edm.Byte", "Edm.SByte", "Edm.Int16", "Edm.Int32", "Edm.Int64", "Edm.Decimal", "Edm.Single", "Edm.Double", "Edm.Float"];
+
+/// whether the response is an Atom feed or entry (`application/atom+xml`, or plain `application/xml`)
+pub fn is_atom(headers: &HeaderMap) -> bool {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_lowercase();
+    content_type.starts_with("application/atom+xml") || content_type.starts_with("application/xml")
+}
+
+/// one XML event, detached from the buffer it was read from
+#[derive(Debug)]
+enum XmlEvent {
+    Start(String, HashMap<String, String>),
+    End,
+    Text(String)
+}
+
+/// reads XML events from a byte stream as the chunks arrive: only complete events are passed on,
+/// the rest waits in the buffer for the next chunk
+struct XmlEventStream<T> {
+    source: T,
+    buffer: Vec<u8>,
+    /// the bytes already read and removed from the buffer
+    offset: usize,
+    finished: bool
+}
+
+impl<T> XmlEventStream<T>
+where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
+    fn new(source: T) -> Self {
+        XmlEventStream { source, buffer: vec![], offset: 0, finished: false }
+    }
+
+    /// the next complete events, `None` at the end of the content
+    async fn next_events(&mut self) -> Result<Option<Vec<XmlEvent>>, Error> {
+        loop {
+            let events = self.read_buffered()?;
+            if !events.is_empty() {
+                return Ok(Some(events));
+            }
+
+            if self.finished {
+                return match self.buffer.iter().all(|byte| byte.is_ascii_whitespace()) {
+                    true => Ok(None),
+                    false => Err(Error::decode("Premature end of content", Some(self.offset)))
+                };
+            }
+
+            match self.source.next().await {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk?),
+                None => self.finished = true
+            }
+        }
+    }
+
+    fn read_buffered(&mut self) -> Result<Vec<XmlEvent>, Error> {
+        let mut reader = Reader::from_reader(&self.buffer[..]);
+        reader.expand_empty_elements(true).check_end_names(false);
+
+        let mut events = vec![];
+        let mut consumed = 0;
+        loop {
+            let event = match reader.read_event() {
+                Ok(Event::Eof) => break,
+                Ok(event) => event,
+                // most likely an element cut in half by the end of the chunk
+                Err(_) if !self.finished => break,
+                Err(err) => return Err(Self::xml_error(&reader, self.offset, err))
+            };
+
+            let position = reader.buffer_position();
+            let complete = match event {
+                // the text may continue in the next chunk, it is only complete with the next tag
+                Event::Text(_) => position > consumed && (self.finished || self.buffer.get(position) == Some(&b'<')),
+                // the closing `>` may only have been half of a `/>` or `?>`
+                _ => self.finished || position < self.buffer.len()
+            };
+
+            if !complete {
+                break;
+            }
+
+            let event = match event {
+                Event::Start(element) => Some(Self::start_event(&reader, self.offset, &element)?),
+                Event::End(_) => Some(XmlEvent::End),
+                Event::Text(text) => Some(XmlEvent::Text(text.unescape().map_err(|err| Self::xml_error(&reader, self.offset, err))?.into_owned())),
+                Event::CData(data) => Some(XmlEvent::Text(String::from_utf8_lossy(&data).into_owned())),
+                _ => None
+            };
+
+            events.extend(event);
+            consumed = position;
+        }
+
+        self.buffer.drain(..consumed);
+        self.offset += consumed;
+        Ok(events)
+    }
+
+    fn start_event(reader: &Reader<&[u8]>, offset: usize, element: &BytesStart<'_>) -> Result<XmlEvent, Error> {
+        let mut attributes = HashMap::<String, String>::new();
+        for attribute in element.attributes() {
+            let attribute = attribute.map_err(|err| Self::xml_error(reader, offset, err))?;
+            let key = reader.decoder().decode(attribute.key.local_name().as_ref()).map_err(|err| Self::xml_error(reader, offset, err))?.into_owned();
+            let value = attribute.decode_and_unescape_value(reader).map_err(|err| Self::xml_error(reader, offset, err))?.into_owned();
+            attributes.insert(key, value);
+        }
+
+        let name = reader.decoder().decode(element.local_name().as_ref()).map_err(|err| Self::xml_error(reader, offset, err))?.into_owned();
+        Ok(XmlEvent::Start(name, attributes))
+    }
+
+    fn xml_error<E: std::fmt::Display>(reader: &Reader<&[u8]>, offset: usize, err: E) -> Error {
+        Error::decode(format!("Invalid Atom document: {}", err), Some(offset + reader.buffer_position()))
+    }
+}
+
+/// the kind of value a property element turned out to contain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PropertyKind {
+    Complex,
+    Collection
+}
+
+/// one open element of the Atom document, while the parser is inside of it
+enum Context {
+    /// the entries are the elements of the array at the path
+    Feed { path: ValuePath, index: usize, top_level: bool },
+    /// the properties (and expanded navigation properties) are those of the object at the path
+    Entry { path: ValuePath, top_level: bool },
+    /// `<link rel=".../related/Trips">`, possibly with the expanded entities
+    Link { property: Option<ValuePath> },
+    /// `<m:inline>`, the expanded entities of a navigation property
+    Inline { path: ValuePath, empty: bool },
+    /// `<content>` of the entry at the path, containing the `<m:properties>`
+    Content { path: ValuePath },
+    /// `<m:properties>` of the entry at the path
+    Properties { path: ValuePath },
+    /// `<d:Name m:type="Edm.String">`, a primitive, complex or collection value
+    Property { path: ValuePath, type_name: Option<String>, null: bool, text: String, kind: Option<PropertyKind>, index: usize },
+    /// `<m:count>` of the feed
+    Count { text: String },
+    /// elements of no interest (`id`, `title`, `author`, deferred links, ...)
+    Other
+}
+
+/// turns the elements of an Atom feed (or single entry) into the tokens of the corresponding JSON response.
+/// The entries of a feed are the elements of the root array, the properties of a single entry those of the root object
+#[derive(Default)]
+pub(crate) struct AtomParser {
+    stack: Vec<Context>,
+    /// the index the next entry of the feed gets, across all pages
+    entity_index: usize,
+    next_link: Option<String>,
+    /// `<link rel="http://docs.oasis-open.org/odata/ns/delta">` of V4 feeds
+    pub(crate) delta_link: Option<String>,
+    pub(crate) count: Option<u64>
+}
+
+impl AtomParser {
+    pub(crate) fn new() -> Self {
+        AtomParser::default()
+    }
+
+    /// numbering the entries of the feed from `first_index` on, e.g. when continuing an earlier load
+    pub(crate) fn starting_at(first_index: usize) -> Self {
+        AtomParser { entity_index: first_index, ..Default::default() }
+    }
+
+    /// the index the next entry of the feed gets
+    pub(crate) fn next_index(&self) -> usize {
+        self.entity_index
+    }
+
+    /// parses one response (page), passing on the tokens as soon as they are complete. Returns the next link, if any
+    pub(crate) async fn stream_response<T>(&mut self, response: T, streamer: &mut EntityStreamer) -> Result<Option<String>, Error>
+    where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
+        self.next_link = None;
+        self.stack.clear();
+
+        let mut events = XmlEventStream::new(response);
+        while let Some(chunk) = events.next_events().await? {
+            for event in chunk {
+                for token in self.push(event)? {
+                    streamer.stream_token(token).await;
+                }
+            }
+        }
+
+        Ok(self.next_link.take())
+    }
+
+    fn push(&mut self, event: XmlEvent) -> Result<Vec<Token>, Error> {
+        match event {
+            XmlEvent::Start(name, attributes) => self.open_element(&name, &attributes),
+            XmlEvent::End => Ok(self.close_element()),
+            XmlEvent::Text(text) => {
+                match self.stack.last_mut() {
+                    Some(Context::Property { text: property_text, .. }) | Some(Context::Count { text: property_text }) => property_text.push_str(&text),
+                    _ => ()
+                }
+
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn open_element(&mut self, name: &str, attributes: &HashMap<String, String>) -> Result<Vec<Token>, Error> {
+        let attribute = |key: &str| attributes.get(key).cloned();
+        let mut tokens = vec![];
+
+        let context = match self.stack.last_mut() {
+            None => match name {
+                "feed" => Context::Feed { path: ValuePath::new(), index: self.entity_index, top_level: true },
+                "entry" => Context::Entry { path: ValuePath::new(), top_level: true },
+                "error" => return Err(Error::Protocol("The service responded with an error document".to_owned())),
+                _ => return Err(Error::Protocol(format!("Invalid top level element of Atom response: {}", name)))
+            },
+            Some(Context::Feed { path, index, top_level }) => match name {
+                "entry" => {
+                    let mut entry_path = path.clone();
+                    entry_path.push(ValuePosition::Index(*index));
+                    *index += 1;
+                    if *top_level {
+                        self.entity_index = *index;
+                    }
+
+                    tokens.push(Token { path: entry_path.clone(), value: Value::StartObject });
+                    Context::Entry { path: entry_path, top_level: false }
+                },
+                "link" if *top_level && attribute("rel").as_deref() == Some("next") => {
+                    self.next_link = attribute("href");
+                    Context::Other
+                },
+                "link" if *top_level && attribute("rel").map(|rel| rel.ends_with("/delta")).unwrap_or(false) => {
+                    self.delta_link = attribute("href");
+                    Context::Other
+                },
+                "count" if *top_level => Context::Count { text: String::new() },
+                _ => Context::Other
+            },
+            Some(Context::Entry { path, .. }) => match name {
+                "link" => {
+                    // `http://schemas.microsoft.com/ado/2007/08/dataservices/related/Trips`
+                    let property = attribute("rel")
+                        .and_then(|rel| rel.rsplit_once("/related/").map(|(_, property)| property.to_owned()))
+                        .map(|property| {
+                            let mut property_path = path.clone();
+                            property_path.push(ValuePosition::Key(property));
+                            property_path
+                        });
+
+                    Context::Link { property }
+                },
+                "content" => Context::Content { path: path.clone() },
+                // media link entries have their properties outside of the content
+                "properties" => Context::Properties { path: path.clone() },
+                _ => Context::Other
+            },
+            Some(Context::Link { property: Some(property) }) if name == "inline" => Context::Inline { path: property.clone(), empty: true },
+            Some(Context::Inline { path, empty }) => {
+                *empty = false;
+                match name {
+                    "feed" => {
+                        tokens.push(Token { path: path.clone(), value: Value::StartArray });
+                        Context::Feed { path: path.clone(), index: 0, top_level: false }
+                    },
+                    "entry" => {
+                        tokens.push(Token { path: path.clone(), value: Value::StartObject });
+                        Context::Entry { path: path.clone(), top_level: false }
+                    },
+                    _ => Context::Other
+                }
+            },
+            Some(Context::Content { path }) if name == "properties" => Context::Properties { path: path.clone() },
+            Some(Context::Properties { path }) => {
+                let mut property_path = path.clone();
+                property_path.push(ValuePosition::Key(name.to_owned()));
+                Self::property_context(property_path, attribute("type").map(normalize_type), attributes)
+            },
+            Some(Context::Property { path, type_name, kind, index, .. }) => {
+                let started = kind.is_some();
+                let element_kind = *kind.get_or_insert_with(|| {
+                    let is_collection = type_name.as_deref().map(|type_name| type_name.starts_with("Collection(")).unwrap_or(name == "element");
+                    if is_collection { PropertyKind::Collection } else { PropertyKind::Complex }
+                });
+
+                if !started {
+                    let value = if element_kind == PropertyKind::Collection { Value::StartArray } else { Value::StartObject };
+                    tokens.push(Token { path: path.clone(), value });
+                }
+
+                let mut element_path = path.clone();
+                let element_type = match element_kind {
+                    PropertyKind::Collection => {
+                        element_path.push(ValuePosition::Index(*index));
+                        *index += 1;
+
+                        // `Collection(Edm.Int32)` → `Edm.Int32`, unless the element names its own type
+                        attribute("type").map(normalize_type).or_else(|| type_name.as_deref()
+                            .and_then(|type_name| type_name.strip_prefix("Collection("))
+                            .and_then(|type_name| type_name.strip_suffix(')'))
+                            .map(|type_name| type_name.to_owned()))
+                    },
+                    PropertyKind::Complex => {
+                        element_path.push(ValuePosition::Key(name.to_owned()));
+                        attribute("type").map(normalize_type)
+                    }
+                };
+
+                Self::property_context(element_path, element_type, attributes)
+            },
+            Some(_) => Context::Other
+        };
+
+        self.stack.push(context);
+        Ok(tokens)
+    }
+
+    fn property_context(path: ValuePath, type_name: Option<String>, attributes: &HashMap<String, String>) -> Context {
+        let null = attributes.get("null").map(|null| null == "true").unwrap_or(false);
+        Context::Property { path, type_name, null, text: String::new(), kind: None, index: 0 }
+    }
+
+    fn close_element(&mut self) -> Vec<Token> {
+        match self.stack.pop() {
+            Some(Context::Feed { path, top_level: false, .. }) => vec![Token { path, value: Value::EndArray }],
+            Some(Context::Entry { path, top_level: false }) => vec![Token { path, value: Value::EndObject }],
+            Some(Context::Inline { path, empty: true }) => vec![Token { path, value: Value::None }],
+            Some(Context::Property { path, type_name, null, text, kind, .. }) => match kind {
+                Some(PropertyKind::Collection) => vec![Token { path, value: Value::EndArray }],
+                Some(PropertyKind::Complex) => vec![Token { path, value: Value::EndObject }],
+                None if null => vec![Token { path, value: Value::None }],
+                None => Self::primitive_tokens(path, type_name.as_deref(), text)
+            },
+            Some(Context::Count { text }) => {
+                self.count = text.trim().parse().ok();
+                vec![]
+            },
+            _ => vec![]
+        }
+    }
+
+    /// the JSON representation of the text of a property, by its type. Strings are JSON-escaped, like the ones read from JSON responses
+    fn primitive_tokens(path: ValuePath, type_name: Option<&str>, text: String) -> Vec<Token> {
+        let value = match type_name {
+            Some(type_name) if type_name.starts_with("Collection(") => return vec![
+                Token { path: path.clone(), value: Value::StartArray },
+                Token { path, value: Value::EndArray }
+            ],
+            Some("Edm.Boolean") => match text.trim() {
+                "true" | "1" => Value::Boolean(true),
+                "false" | "0" => Value::Boolean(false),
+                _ => Value::None
+            },
+            Some(type_name) if NUMBER_TYPES.contains(&type_name) => match text.trim() {
+                "" => Value::None,
+                // not representable as JSON number
+                "NaN" | "INF" | "-INF" => Value::String(text.trim().to_owned()),
+                number => Value::Number(number.to_owned())
+            },
+            Some("Edm.String") | None => Value::String(escape(&text)),
+            Some(_) if text.is_empty() => Value::None,
+            Some(_) => Value::String(escape(&text))
+        };
+
+        vec![Token { path, value }]
+    }
+}
+
+/// V4 omits the `Edm.` of primitive types and starts all others with `#`: `Int32`, `#NS.Address`, `#Collection(String)`
+fn normalize_type(type_name: String) -> String {
+    let type_name = type_name.trim_start_matches('#');
+    match type_name.strip_prefix("Collection(").and_then(|element_type| element_type.strip_suffix(')')) {
+        Some(element_type) => format!("Collection({})", normalize_type(element_type.to_owned())),
+        None if type_name.contains('.') => type_name.to_owned(),
+        None => format!("Edm.{}", type_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc::channel;
+    use futures::stream;
+    use reqwest::header::HeaderValue;
+    use serde_json::json;
+    use crate::model::Entity;
+    use crate::service::entity_stream::RootEntityType;
+    use crate::service::token_deserializer::from_tokens;
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:d="http://schemas.microsoft.com/ado/2007/08/dataservices" xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
+  <m:count>12</m:count>
+  <entry>
+    <link rel="http://schemas.microsoft.com/ado/2007/08/dataservices/related/Orders" href="People(1)/Orders"/>
+    <content type="application/xml">
+      <m:properties>
+        <d:Id m:type="Edm.Int32">1</d:Id>
+        <d:Name>Russell &amp; "Sons" \ Co</d:Name>
+        <d:Active m:type="Edm.Boolean">true</d:Active>
+        <d:Rating m:type="Edm.Double">INF</d:Rating>
+        <d:Born m:type="Edm.DateTime" m:null="true"/>
+        <d:Address m:type="Sample.Address">
+          <d:City>Bern</d:City>
+          <d:Zip>01234</d:Zip>
+        </d:Address>
+        <d:Emails m:type="Collection(Edm.String)">
+          <d:element>russell@example.com</d:element>
+          <d:element>r@example.com</d:element>
+        </d:Emails>
+      </m:properties>
+    </content>
+  </entry>
+  <entry>
+    <link rel="http://schemas.microsoft.com/ado/2007/08/dataservices/related/Orders" href="People(2)/Orders">
+      <m:inline>
+        <feed>
+          <entry><content type="application/xml"><m:properties><d:Number m:type="Edm.Int64">7</d:Number></m:properties></content></entry>
+        </feed>
+      </m:inline>
+    </link>
+    <content type="application/xml">
+      <m:properties>
+        <d:Id m:type="Edm.Int32">2</d:Id>
+        <d:Emails m:type="Collection(Edm.String)"/>
+      </m:properties>
+    </content>
+  </entry>
+  <link rel="next" href="People?$skiptoken=2"/>
+</feed>"#;
+
+    /// the tokens of the feed, sent in chunks of the given size
+    async fn stream_tokens(content: &str, chunk_size: usize) -> (Result<Option<String>, Error>, Option<u64>, Vec<Token>) {
+        let chunks : Vec<reqwest::Result<Bytes>> = content.as_bytes().chunks(chunk_size).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        let (sender, mut receiver) = channel(1024);
+        let mut streamer = EntityStreamer::new(sender, RootEntityType::Array);
+        let mut parser = AtomParser::new();
+
+        streamer.begin().await;
+        let next_link = parser.stream_response(stream::iter(chunks), &mut streamer).await;
+        streamer.finish().await;
+
+        let tokens = receiver.by_ref().map(|token| token.expect("failed token")).collect::<Vec<Token>>().await;
+        (next_link, parser.count, tokens)
+    }
+
+    /// the entities of the feed, sent in chunks of the given size
+    async fn parse(content: &str, chunk_size: usize) -> (Result<Option<String>, Error>, Option<u64>, Entity) {
+        let (next_link, count, tokens) = stream_tokens(content, chunk_size).await;
+        (next_link, count, from_tokens(tokens).expect("invalid tokens"))
+    }
+
+    #[tokio::test]
+    async fn parses_feeds() {
+        let (next_link, count, entities) = parse(FEED, 4096).await;
+
+        assert_eq!(next_link.expect("failed to parse").as_deref(), Some("People?$skiptoken=2"));
+        assert_eq!(count, Some(12));
+        assert_eq!(entities, json!([
+            {
+                "Id": 1,
+                "Name": "Russell & \"Sons\" \\ Co",
+                "Active": true,
+                "Rating": "INF",
+                "Born": null,
+                "Address": { "City": "Bern", "Zip": "01234" },
+                "Emails": ["russell@example.com", "r@example.com"]
+            },
+            { "Orders": [{ "Number": 7 }], "Id": 2, "Emails": [] }
+        ]));
+    }
+
+    #[tokio::test]
+    async fn converts_feeds_to_json() {
+        use futures::SinkExt;
+        use crate::convert::Converter;
+        use crate::convert::json::JsonConverter;
+        use crate::writer::FileWriter;
+
+        let (_, _, tokens) = stream_tokens(FEED, 4096).await;
+        let (mut sender, receiver) = channel(tokens.len());
+        for token in tokens {
+            sender.send(Ok(token)).await.expect("failed to send token");
+        }
+        drop(sender);
+
+        let (output_sender, output_receiver) = FileWriter::setup_channel();
+        JsonConverter::new().convert(receiver, output_sender);
+        let output = output_receiver.map(|text| text.expect("failed to convert").to_string()).collect::<Vec<String>>().await.concat();
+
+        let entities : Entity = serde_json::from_str(&output).expect("invalid JSON output");
+        assert_eq!(entities[0]["Name"], "Russell & \"Sons\" \\ Co");
+        assert_eq!(entities, parse(FEED, 4096).await.2);
+    }
+
+    #[tokio::test]
+    async fn parses_feeds_split_anywhere() {
+        let (_, _, entities) = parse(FEED, 4096).await;
+
+        for chunk_size in &[1, 7, 64] {
+            let (next_link, _, split_entities) = parse(FEED, *chunk_size).await;
+            assert!(next_link.is_ok());
+            assert_eq!(split_entities, entities, "chunks of {} bytes", chunk_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_error_documents() {
+        let (next_link, _, _) = parse("<?xml version=\"1.0\"?><error><code/><message>Invalid</message></error>", 4096).await;
+
+        assert!(matches!(next_link, Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn recognizes_atom_responses() {
+        let headers = |content_type: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers
+        };
+
+        assert!(is_atom(&headers("application/atom+xml;type=feed;charset=utf-8")));
+        assert!(is_atom(&headers("application/xml")));
+        assert!(!is_atom(&headers("application/json;odata.metadata=minimal")));
+        assert!(!is_atom(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn normalizes_v4_type_names() {
+        assert_eq!(normalize_type("Int32".to_owned()), "Edm.Int32");
+        assert_eq!(normalize_type("Edm.Int32".to_owned()), "Edm.Int32");
+        assert_eq!(normalize_type("#Sample.Address".to_owned()), "Sample.Address");
+        assert_eq!(normalize_type("#Collection(String)".to_owned()), "Collection(Edm.String)");
+    }
+}
@@ -0,0 +1,256 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use reqwest::RequestBuilder;
+use tokio::sync::Mutex;
+use crate::error::Error;
+use crate::service::client::ODataClient;
+
+#[async_trait]
+pub trait Authenticator: Send + Sync + std::fmt::Debug {
+    /// adds the authentication information (usually the `Authorization` header) to the request
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, Error>;
+
+    /// called when the service rejected a request with `401 Unauthorized`.
+    /// Returns `true` if the credentials have been renewed and the request should be sent again.
+    async fn refresh(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+pub type SharedAuthenticator = Arc<dyn Authenticator>;
+
+/// picks the authenticator to use for a query: an explicitly configured one wins over username/password
+pub fn resolve_authenticator(authenticator: Option<SharedAuthenticator>, username: Option<String>, password: Option<String>) -> Option<SharedAuthenticator> {
+    if authenticator.is_some() {
+        return authenticator;
+    }
+
+    username.map(|username| Arc::new(BasicAuthenticator::new(username, password)) as SharedAuthenticator)
+}
+
+/// wraps secrets so they don't show up in debug output
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret(value)
+    }
+
+    fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+#[derive(Debug)]
+pub struct BasicAuthenticator {
+    username: String,
+    password: Option<Secret>
+}
+
+impl BasicAuthenticator {
+    pub fn new(username: String, password: Option<String>) -> BasicAuthenticator {
+        BasicAuthenticator { username, password: password.map(Secret::new) }
+    }
+}
+
+#[async_trait]
+impl Authenticator for BasicAuthenticator {
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, Error> {
+        Ok(request.basic_auth(&self.username, self.password.as_ref().map(|password| password.expose())))
+    }
+}
+
+#[derive(Debug)]
+pub struct BearerTokenAuthenticator {
+    token: Secret
+}
+
+impl BearerTokenAuthenticator {
+    pub fn new(token: String) -> BearerTokenAuthenticator {
+        BearerTokenAuthenticator { token: Secret::new(token) }
+    }
+}
+
+#[async_trait]
+impl Authenticator for BearerTokenAuthenticator {
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, Error> {
+        Ok(request.bearer_auth(self.token.expose()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+    File(PathBuf),
+    Environment(String)
+}
+
+/// reads a bearer token from a file or an environment variable.
+/// The token is read again after a `401`, so tokens rotated by an external process are picked up
+#[derive(Debug)]
+pub struct TokenSourceAuthenticator {
+    source: TokenSource,
+    token: Mutex<Option<Secret>>
+}
+
+impl TokenSourceAuthenticator {
+    pub fn new(source: TokenSource) -> TokenSourceAuthenticator {
+        TokenSourceAuthenticator { source, token: Mutex::new(None) }
+    }
+
+    pub fn from_file<T: Into<PathBuf>>(path: T) -> TokenSourceAuthenticator {
+        Self::new(TokenSource::File(path.into()))
+    }
+
+    pub fn from_environment<T: Into<String>>(variable: T) -> TokenSourceAuthenticator {
+        Self::new(TokenSource::Environment(variable.into()))
+    }
+
+    async fn read_token(&self) -> Result<Secret, Error> {
+        let token = match &self.source {
+            TokenSource::File(path) => tokio::fs::read_to_string(path).await
+                .map_err(|err| Error::Authentication(format!("Could not read token file {}: {}", path.display(), err)))?,
+            TokenSource::Environment(variable) => std::env::var(variable)
+                .map_err(|_| Error::Authentication(format!("Environment variable {} does not contain a token", variable)))?
+        };
+
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(Error::Authentication(format!("No token found in {:?}", self.source)));
+        }
+
+        Ok(Secret::new(token.to_owned()))
+    }
+}
+
+#[async_trait]
+impl Authenticator for TokenSourceAuthenticator {
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, Error> {
+        let mut token = self.token.lock().await;
+        if token.is_none() {
+            *token = Some(self.read_token().await?);
+        }
+
+        let current = token.as_ref().expect("token has just been read");
+        Ok(request.bearer_auth(current.expose()))
+    }
+
+    async fn refresh(&self) -> Result<bool, Error> {
+        let mut token = self.token.lock().await;
+        let renewed = self.read_token().await?;
+        let changed = token.as_ref().map(|current| current.expose() != renewed.expose()).unwrap_or(true);
+        *token = Some(renewed);
+
+        Ok(changed)
+    }
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    access_token: Secret,
+    expires_at: Option<Instant>
+}
+
+impl CachedToken {
+    /// tokens are renewed a bit before they actually expire, to not have them expire mid-request
+    const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+    fn is_valid(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() + Self::EXPIRY_MARGIN < expires_at,
+            None => true
+        }
+    }
+}
+
+/// OAuth2 client credentials grant (e.g. against Azure AD or Keycloak).
+/// The token is fetched lazily, cached until it expires and renewed after a `401`
+#[derive(Debug)]
+pub struct ClientCredentialsAuthenticator {
+    token_url: String,
+    client_id: String,
+    client_secret: Secret,
+    scope: Option<String>,
+    token: Mutex<Option<CachedToken>>,
+    client: ODataClient
+}
+
+impl ClientCredentialsAuthenticator {
+    pub fn new(token_url: String, client_id: String, client_secret: String, scope: Option<String>) -> ClientCredentialsAuthenticator {
+        ClientCredentialsAuthenticator { token_url, client_id, client_secret: Secret::new(client_secret), scope, token: Mutex::new(None), client: ODataClient::shared() }
+    }
+
+    /// requests the tokens with the client, e.g. for a token endpoint behind the same proxy as the service
+    pub fn with_client(mut self, client: ODataClient) -> ClientCredentialsAuthenticator {
+        self.client = client;
+        self
+    }
+
+    async fn request_token(&self) -> Result<CachedToken, Error> {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", self.client_secret.expose())
+        ];
+
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+
+        let response = self.client.http().post(&self.token_url).form(&form).send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+
+        if !status.is_success() {
+            return Err(Error::Authentication(format!("Token endpoint {} responded with {}: {}", self.token_url, status, body)));
+        }
+
+        let content : serde_json::Value = serde_json::from_str(&body)
+            .map_err(|err| Error::Authentication(format!("Invalid response from token endpoint {}: {}", self.token_url, err)))?;
+
+        let access_token = content["access_token"].as_str()
+            .ok_or_else(|| Error::Authentication(format!("Response from token endpoint {} contains no access_token", self.token_url)))?;
+
+        // some identity providers send `expires_in` as string
+        let expires_in = content["expires_in"].as_u64()
+            .or_else(|| content["expires_in"].as_str().and_then(|value| value.parse::<u64>().ok()));
+
+        Ok(CachedToken {
+            access_token: Secret::new(access_token.to_owned()),
+            expires_at: expires_in.map(|seconds| Instant::now() + Duration::from_secs(seconds))
+        })
+    }
+}
+
+#[async_trait]
+impl Authenticator for ClientCredentialsAuthenticator {
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, Error> {
+        let mut token = self.token.lock().await;
+        let needs_token = match token.as_ref() {
+            Some(cached) => !cached.is_valid(),
+            None => true
+        };
+
+        if needs_token {
+            *token = Some(self.request_token().await?);
+        }
+
+        let current = token.as_ref().expect("token has just been requested");
+        Ok(request.bearer_auth(current.access_token.expose()))
+    }
+
+    async fn refresh(&self) -> Result<bool, Error> {
+        let mut token = self.token.lock().await;
+        *token = Some(self.request_token().await?);
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,680 @@
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+use reqwest::{Method, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use serde_json::{json, Map};
+use url::Url;
+use crate::model::{BatchFormat, BatchQuery, Entity};
+use crate::error::Error;
+use crate::service::auth::resolve_authenticator;
+use crate::service::error_response::parse_error_response;
+use crate::service::url::{parse_url, RequestContent, SingleUrlCaller};
+
+/// headers of the operations and responses, in the order they were given
+pub type Headers = Vec<(String, String)>;
+
+/// one request of a batch. The URL is relative to the service root (e.g. `People('russellwhyte')`),
+/// or refers to an entity created earlier in the same change set (`$1/Friends`)
+#[derive(Debug, Clone)]
+pub struct BatchOperation {
+    pub method: Method,
+    pub url: String,
+    pub headers: Headers,
+    pub body: Option<Entity>
+}
+
+impl BatchOperation {
+    pub fn new(method: Method, url: &str) -> BatchOperation {
+        BatchOperation { method, url: url.to_owned(), headers: vec![], body: None }
+    }
+
+    pub fn get(url: &str) -> BatchOperation {
+        BatchOperation::new(Method::GET, url)
+    }
+
+    /// creates the entity in the entity set (`POST`)
+    pub fn create(entityset_url: &str, entity: Entity) -> BatchOperation {
+        BatchOperation::new(Method::POST, entityset_url).with_payload(entity)
+    }
+
+    /// changes the given properties of the entity (`PATCH`)
+    pub fn update(entity_url: &str, entity: Entity) -> BatchOperation {
+        BatchOperation::new(Method::PATCH, entity_url).with_payload(entity)
+    }
+
+    /// replaces the entity as a whole (`PUT`)
+    pub fn replace(entity_url: &str, entity: Entity) -> BatchOperation {
+        BatchOperation::new(Method::PUT, entity_url).with_payload(entity)
+    }
+
+    pub fn delete(entity_url: &str) -> BatchOperation {
+        BatchOperation::new(Method::DELETE, entity_url)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> BatchOperation {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// the `@odata.etag` of the entity is sent as `If-Match`, like for single writes
+    fn with_payload(mut self, entity: Entity) -> BatchOperation {
+        let entity = match entity {
+            Entity::Object(mut properties) => {
+                if let Some(Entity::String(etag)) = properties.remove("@odata.etag") {
+                    self.headers.push(("If-Match".to_owned(), etag));
+                }
+
+                Entity::Object(properties)
+            },
+            other => other
+        };
+
+        self.body = Some(entity);
+        self
+    }
+
+    /// the headers of the operation, including the defaults for `Accept` and `Content-Type`
+    fn all_headers(&self) -> Headers {
+        let mut headers = self.headers.clone();
+        let has_header = |name: &str| self.headers.iter().any(|(header, _)| header.eq_ignore_ascii_case(name));
+
+        if !has_header("Accept") {
+            headers.push(("Accept".to_owned(), "application/json".to_owned()));
+        }
+
+        if self.body.is_some() && !has_header("Content-Type") {
+            headers.push(("Content-Type".to_owned(), "application/json".to_owned()));
+        }
+
+        headers
+    }
+}
+
+enum BatchPart {
+    Operation(String, BatchOperation),
+    /// operations which succeed or fail together
+    ChangeSet(Vec<(String, BatchOperation)>)
+}
+
+/// the response to one operation of the batch
+#[derive(Debug, Clone)]
+pub struct BatchResponse {
+    /// the id of the operation, as returned by the builder
+    pub id: String,
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: String
+}
+
+impl BatchResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// the body as JSON, `None` for empty responses (e.g. to a `DELETE`)
+    pub fn entity(&self) -> Result<Option<Entity>, Error> {
+        if self.body.trim().is_empty() {
+            return Ok(None);
+        }
+
+        serde_json::from_str(&self.body).map(Some).map_err(|err| Error::decode(format!("Invalid JSON in response to request {}: {}", self.id, err), None))
+    }
+}
+
+/// the outcome of one operation: its response, or the error the service responded with.
+/// If a change set fails, all of its operations report the error
+#[derive(Debug)]
+pub struct BatchResult {
+    pub id: String,
+    pub result: Result<BatchResponse, Error>
+}
+
+/// assembles reads and change sets into a single `$batch` request, and assigns the responses to them
+pub struct BatchBuilder {
+    query: BatchQuery,
+    parts: Vec<BatchPart>,
+    operation_count: usize
+}
+
+impl BatchBuilder {
+    pub fn new(query: BatchQuery) -> BatchBuilder {
+        BatchBuilder { query, parts: vec![], operation_count: 0 }
+    }
+
+    /// adds a single operation, returns its id (also its `Content-ID`)
+    pub fn add(&mut self, operation: BatchOperation) -> String {
+        let id = self.next_id();
+        self.parts.push(BatchPart::Operation(id.clone(), operation));
+        id
+    }
+
+    /// adds the operations as an atomic change set, returns their ids.
+    /// Later operations may refer to entities created by earlier ones with `$<id>`
+    pub fn add_change_set(&mut self, operations: Vec<BatchOperation>) -> Vec<String> {
+        if operations.is_empty() {
+            return vec![];
+        }
+
+        let operations : Vec<(String, BatchOperation)> = operations.into_iter().map(|operation| (self.next_id(), operation)).collect();
+        let ids = operations.iter().map(|(id, _)| id.clone()).collect();
+
+        self.parts.push(BatchPart::ChangeSet(operations));
+        ids
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    fn next_id(&mut self) -> String {
+        self.operation_count += 1;
+        self.operation_count.to_string()
+    }
+
+    /// the content type and the body of the `$batch` request
+    pub fn build(&self) -> (String, String) {
+        match self.query.format {
+            BatchFormat::Multipart => {
+                let boundary = boundary("batch");
+                (format!("multipart/mixed; boundary={}", boundary), self.build_multipart(&boundary))
+            },
+            BatchFormat::Json => ("application/json".to_owned(), self.build_json())
+        }
+    }
+
+    fn build_multipart(&self, boundary: &str) -> String {
+        let mut body = String::new();
+
+        for part in &self.parts {
+            body.push_str(&format!("--{}\r\n", boundary));
+            match part {
+                BatchPart::Operation(id, operation) => write_operation_part(&mut body, id, operation),
+                BatchPart::ChangeSet(operations) => {
+                    let change_set_boundary = boundary_for_change_set(boundary, &operations[0].0);
+                    body.push_str(&format!("Content-Type: multipart/mixed; boundary={}\r\n\r\n", change_set_boundary));
+
+                    for (id, operation) in operations {
+                        body.push_str(&format!("--{}\r\n", change_set_boundary));
+                        write_operation_part(&mut body, id, operation);
+                    }
+
+                    body.push_str(&format!("--{}--\r\n\r\n", change_set_boundary));
+                }
+            }
+        }
+
+        body.push_str(&format!("--{}--\r\n", boundary));
+        body
+    }
+
+    fn build_json(&self) -> String {
+        let mut requests = vec![];
+
+        for (index, part) in self.parts.iter().enumerate() {
+            match part {
+                BatchPart::Operation(id, operation) => requests.push(json_request(id, operation, None)),
+                BatchPart::ChangeSet(operations) => {
+                    let group = format!("g{}", index + 1);
+                    requests.extend(operations.iter().map(|(id, operation)| json_request(id, operation, Some(&group))));
+                }
+            }
+        }
+
+        json!({ "requests": requests }).to_string()
+    }
+
+    /// sends the batch to `<service root>/$batch`. Errors of single operations do not fail the batch,
+    /// they are part of the results (in the order of the operations)
+    pub async fn send(self) -> Result<Vec<BatchResult>, Error> {
+        let service_url = parse_url(&self.query.service_url)?;
+        let mut batch_url = service_url.clone();
+        batch_url.path_segments_mut()
+            .map_err(|_| Error::InvalidQuery(format!("Invalid service URL '{}'", self.query.service_url)))?
+            .pop_if_empty()
+            .push("$batch");
+
+        let (content_type, body) = self.build();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(&content_type).map_err(|_| Error::InvalidQuery(format!("Invalid content type '{}'", content_type)))?);
+        headers.insert(ACCEPT, HeaderValue::from_static(if self.query.format == BatchFormat::Json { "application/json" } else { "multipart/mixed" }));
+
+        let authenticator = resolve_authenticator(self.query.authenticator.clone(), self.query.username.clone(), self.query.password.clone());
+        let url_caller = SingleUrlCaller::new(batch_url, authenticator).with_client(self.query.client.clone());
+        let response = url_caller.send(&RequestContent { method: Method::POST, headers, body: Some(body) }).await?;
+
+        let response_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default().to_owned();
+        let content = response.text().await?;
+
+        let results = if response_type.to_lowercase().starts_with("multipart/") {
+            let boundary = content_type_boundary(&response_type).ok_or_else(|| Error::Protocol(format!("No boundary in batch response of type '{}'", response_type)))?;
+            self.assign_multipart(&service_url, parse_multipart(&content, &boundary)?)
+        } else {
+            self.assign_json(&service_url, parse_json_responses(&content)?)
+        };
+
+        Ok(results)
+    }
+
+    /// multipart responses are in the order of the requests, a failed change set is answered with a single response
+    fn assign_multipart(&self, service_url: &Url, mut response_parts: Vec<MultipartResponse>) -> Vec<BatchResult> {
+        let mut results = vec![];
+        response_parts.reverse();
+
+        for part in &self.parts {
+            let response_part = response_parts.pop();
+            match (part, response_part) {
+                (BatchPart::Operation(id, operation), Some(MultipartResponse::Single(response))) => {
+                    results.push(operation_result(service_url, id, operation, Some(response)));
+                },
+                (BatchPart::ChangeSet(operations), Some(MultipartResponse::ChangeSet(responses))) => {
+                    let mut responses : Vec<Option<BatchResponse>> = responses.into_iter().map(Some).collect();
+                    for (index, (id, operation)) in operations.iter().enumerate() {
+                        // matched by `Content-ID`, services not returning it respond in order
+                        let has_id = |position: usize, id: &str| responses[position].as_ref().map(|response| response.id == id).unwrap_or(false);
+                        let position = (0..responses.len()).find(|position| has_id(*position, id))
+                            .or_else(|| if index < responses.len() && has_id(index, "") { Some(index) } else { None });
+                        let response = position.and_then(|position| responses[position].take());
+
+                        results.push(operation_result(service_url, id, operation, response));
+                    }
+                },
+                (BatchPart::ChangeSet(operations), Some(MultipartResponse::Single(response))) => {
+                    for (id, operation) in operations {
+                        results.push(operation_result(service_url, id, operation, Some(response.clone())));
+                    }
+                },
+                (BatchPart::Operation(id, operation), _) => results.push(operation_result(service_url, id, operation, None)),
+                (BatchPart::ChangeSet(operations), _) => {
+                    for (id, operation) in operations {
+                        results.push(operation_result(service_url, id, operation, None));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+
+    /// JSON responses carry the id of their request. Operations of a failed atomicity group without own response get the error of the group
+    fn assign_json(&self, service_url: &Url, responses: Vec<BatchResponse>) -> Vec<BatchResult> {
+        let find = |id: &str| responses.iter().find(|response| response.id == id).cloned();
+        let mut results = vec![];
+
+        for part in &self.parts {
+            match part {
+                BatchPart::Operation(id, operation) => results.push(operation_result(service_url, id, operation, find(id))),
+                BatchPart::ChangeSet(operations) => {
+                    let group_failure = operations.iter()
+                        .filter_map(|(id, _)| find(id))
+                        .find(|response| !response.status.is_success());
+
+                    for (id, operation) in operations {
+                        let response = find(id).or_else(|| group_failure.clone());
+                        results.push(operation_result(service_url, id, operation, response));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+}
+
+fn boundary(prefix: &str) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_nanos()).unwrap_or_default();
+    format!("{}_{:x}", prefix, nanos)
+}
+
+fn boundary_for_change_set(batch_boundary: &str, first_id: &str) -> String {
+    format!("changeset_{}_{}", batch_boundary.trim_start_matches("batch_"), first_id)
+}
+
+fn write_operation_part(body: &mut String, id: &str, operation: &BatchOperation) {
+    body.push_str("Content-Type: application/http\r\nContent-Transfer-Encoding: binary\r\n");
+    body.push_str(&format!("Content-ID: {}\r\n\r\n", id));
+    body.push_str(&format!("{} {} HTTP/1.1\r\n", operation.method, operation.url));
+
+    for (name, value) in operation.all_headers() {
+        body.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    body.push_str("\r\n");
+    if let Some(payload) = &operation.body {
+        body.push_str(&payload.to_string());
+    }
+
+    body.push_str("\r\n");
+}
+
+fn json_request(id: &str, operation: &BatchOperation, atomicity_group: Option<&str>) -> Entity {
+    let headers : Map<String, Entity> = operation.all_headers().into_iter()
+        .map(|(name, value)| (name.to_lowercase(), Entity::String(value)))
+        .collect();
+
+    let mut request = json!({ "id": id, "method": operation.method.as_str(), "url": operation.url, "headers": headers });
+    if let Some(group) = atomicity_group {
+        request["atomicityGroup"] = Entity::String(group.to_owned());
+    }
+
+    if let Some(payload) = &operation.body {
+        request["body"] = payload.clone();
+    }
+
+    request
+}
+
+/// the response of the operation, errors converted like for single requests
+fn operation_result(service_url: &Url, id: &str, operation: &BatchOperation, response: Option<BatchResponse>) -> BatchResult {
+    let url = service_url.join(&operation.url).map(|url| url.to_string()).unwrap_or_else(|_| operation.url.clone());
+
+    let result = match response {
+        Some(response) if response.status.is_success() => Ok(BatchResponse { id: id.to_owned(), ..response }),
+        Some(response) => {
+            let service_error = parse_error_response(response.header("Content-Type"), &response.body);
+            Err(Error::Http { status: response.status, url, service_error: service_error.map(Box::new) })
+        },
+        None => Err(Error::Protocol(format!("No response for request {} ({} {}) in the batch", id, operation.method, url)))
+    };
+
+    BatchResult { id: id.to_owned(), result }
+}
+
+/// a part of a multipart batch response
+#[derive(Debug)]
+pub enum MultipartResponse {
+    Single(BatchResponse),
+    ChangeSet(Vec<BatchResponse>)
+}
+
+/// the `boundary` parameter of a `multipart/mixed` content type
+pub fn content_type_boundary(content_type: &str) -> Option<String> {
+    content_type.split(';')
+        .map(|parameter| parameter.trim())
+        .find(|parameter| parameter.to_lowercase().starts_with("boundary="))
+        .map(|parameter| parameter["boundary=".len()..].trim_matches('"').to_owned())
+}
+
+/// reads a `multipart/mixed` batch response. Line ends may be `\r\n` or `\n`
+pub fn parse_multipart(content: &str, boundary: &str) -> Result<Vec<MultipartResponse>, Error> {
+    let mut responses = vec![];
+
+    for (headers, body) in split_multipart(content, boundary)? {
+        let part_type = find_header(&headers, "Content-Type").unwrap_or_default();
+        let id = find_header(&headers, "Content-ID").unwrap_or_default();
+
+        if part_type.to_lowercase().starts_with("multipart/") {
+            let change_set_boundary = content_type_boundary(&part_type).ok_or_else(|| Error::Protocol(format!("No boundary in change set of type '{}'", part_type)))?;
+            let change_set = split_multipart(&body, &change_set_boundary)?.into_iter()
+                .map(|(headers, body)| parse_http_response(find_header(&headers, "Content-ID").unwrap_or_default(), &body))
+                .collect::<Result<Vec<BatchResponse>, Error>>()?;
+
+            responses.push(MultipartResponse::ChangeSet(change_set));
+        } else {
+            responses.push(MultipartResponse::Single(parse_http_response(id, &body)?));
+        }
+    }
+
+    Ok(responses)
+}
+
+/// the headers and the content of each part
+fn split_multipart(content: &str, boundary: &str) -> Result<Vec<(Headers, String)>, Error> {
+    let delimiter = format!("--{}", boundary);
+    if !content.contains(&delimiter) {
+        return Err(Error::Protocol(format!("Boundary '{}' not found in batch response", boundary)));
+    }
+
+    // everything before the first delimiter is a preamble
+    let mut segments = content.split(delimiter.as_str());
+    segments.next();
+
+    let mut parts = vec![];
+    for segment in segments {
+        if segment.starts_with("--") {
+            break;
+        }
+
+        // the line end before the next delimiter belongs to it
+        let segment = segment.trim_start_matches([' ', '\t']);
+        let segment = segment.strip_prefix("\r\n").or_else(|| segment.strip_prefix('\n')).unwrap_or(segment);
+        let segment = strip_line_end(segment);
+
+        let (head, body) = split_head(segment);
+        parts.push((parse_headers(head), body.to_owned()));
+    }
+
+    Ok(parts)
+}
+
+/// the response embedded in a part: `HTTP/1.1 201 Created`, headers and body
+fn parse_http_response(id: String, content: &str) -> Result<BatchResponse, Error> {
+    let (head, body) = split_head(content);
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default();
+
+    let status = status_line.split_whitespace().nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| Error::Protocol(format!("Invalid status line '{}' in batch response", status_line)))?;
+
+    let headers = parse_headers(&lines.collect::<Vec<&str>>().join("\n"));
+    Ok(BatchResponse { id, status, headers, body: body.to_owned() })
+}
+
+/// reads the responses of a JSON batch (`{ "responses": [...] }`)
+pub fn parse_json_responses(content: &str) -> Result<Vec<BatchResponse>, Error> {
+    let content : Entity = serde_json::from_str(content).map_err(|err| Error::decode(format!("Invalid JSON in batch response: {}", err), None))?;
+    let responses = content.get("responses").and_then(Entity::as_array)
+        .ok_or_else(|| Error::Protocol("Batch response without 'responses'".to_owned()))?;
+
+    responses.iter().map(|response| {
+        let id = match response.get("id") {
+            Some(Entity::String(id)) => id.clone(),
+            Some(Entity::Number(id)) => id.to_string(),
+            _ => String::new()
+        };
+
+        let status = response.get("status").and_then(Entity::as_u64)
+            .and_then(|status| u16::try_from(status).ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| Error::Protocol(format!("Response {} of the batch without a valid status", id)))?;
+
+        let headers = response.get("headers").and_then(Entity::as_object)
+            .map(|headers| headers.iter().map(|(name, value)| (name.clone(), value.as_str().map(|value| value.to_owned()).unwrap_or_else(|| value.to_string()))).collect())
+            .unwrap_or_default();
+
+        // bodies of other media types than JSON are embedded as string
+        let body = match response.get("body") {
+            None | Some(Entity::Null) => String::new(),
+            Some(Entity::String(text)) => text.clone(),
+            Some(body) => body.to_string()
+        };
+
+        Ok(BatchResponse { id, status, headers, body })
+    }).collect()
+}
+
+fn strip_line_end(text: &str) -> &str {
+    text.strip_suffix("\r\n").or_else(|| text.strip_suffix('\n')).unwrap_or(text)
+}
+
+/// splits at the first empty line
+fn split_head(content: &str) -> (&str, &str) {
+    let crlf = content.find("\r\n\r\n").map(|position| (position, 4));
+    let lf = content.find("\n\n").map(|position| (position, 2));
+
+    let separator = match (crlf, lf) {
+        (Some(crlf), Some(lf)) => if crlf.0 <= lf.0 { Some(crlf) } else { Some(lf) },
+        (crlf, lf) => crlf.or(lf)
+    };
+
+    match separator {
+        Some((position, length)) => (&content[..position], &content[position + length..]),
+        None => (content, "")
+    }
+}
+
+fn parse_headers(head: &str) -> Headers {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect()
+}
+
+fn find_header(headers: &[(String, String)], name: &str) -> Option<String> {
+    headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(format: BatchFormat) -> BatchBuilder {
+        let mut query = BatchQuery::new("https://example.com/svc/".to_owned());
+        query.format = format;
+
+        let mut builder = BatchBuilder::new(query);
+        builder.add(BatchOperation::get("People('russell')"));
+        builder.add_change_set(vec![
+            BatchOperation::create("People", json!({ "UserName": "scott" })),
+            BatchOperation::update("$2", json!({ "@odata.etag": "W/\"1\"", "FirstName": "Scott" }))
+        ]);
+        builder
+    }
+
+    #[test]
+    fn reads_the_boundary_of_the_content_type() {
+        assert_eq!(content_type_boundary("multipart/mixed; boundary=batch_1").as_deref(), Some("batch_1"));
+        assert_eq!(content_type_boundary("multipart/mixed;charset=utf-8;Boundary=\"batch 2\"").as_deref(), Some("batch 2"));
+        assert_eq!(content_type_boundary("application/json"), None);
+    }
+
+    #[test]
+    fn builds_multipart_batches() {
+        let (content_type, body) = builder(BatchFormat::Multipart).build();
+        let boundary = content_type_boundary(&content_type).expect("no boundary");
+        let change_set_boundary = boundary_for_change_set(&boundary, "2");
+
+        assert!(body.starts_with(&format!("--{}\r\nContent-Type: application/http\r\nContent-Transfer-Encoding: binary\r\nContent-ID: 1\r\n\r\nGET People('russell') HTTP/1.1\r\n", boundary)));
+        assert!(body.contains(&format!("Content-Type: multipart/mixed; boundary={}\r\n\r\n--{}\r\n", change_set_boundary, change_set_boundary)));
+        assert!(body.contains("PATCH $2 HTTP/1.1\r\nIf-Match: W/\"1\"\r\nAccept: application/json\r\nContent-Type: application/json\r\n\r\n{\"FirstName\":\"Scott\"}\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n\r\n--{}--\r\n", change_set_boundary, boundary)));
+    }
+
+    #[test]
+    fn builds_json_batches() {
+        let (content_type, body) = builder(BatchFormat::Json).build();
+        let body : Entity = serde_json::from_str(&body).expect("invalid JSON");
+
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body["requests"][0], json!({ "id": "1", "method": "GET", "url": "People('russell')", "headers": { "accept": "application/json" } }));
+        assert_eq!(body["requests"][1]["atomicityGroup"], "g2");
+        assert_eq!(body["requests"][1]["body"], json!({ "UserName": "scott" }));
+        assert_eq!(body["requests"][2]["atomicityGroup"], "g2");
+        assert_eq!(body["requests"][2]["headers"]["if-match"], "W/\"1\"");
+    }
+
+    #[test]
+    fn parses_multipart_responses() {
+        let content = "--batch_r\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: 1\r\n\
+            \r\n\
+            HTTP/1.1 200 OK\r\n\
+            Content-Type: application/json\r\n\
+            \r\n\
+            {\"UserName\":\"russell\"}\r\n\
+            --batch_r\r\n\
+            Content-Type: multipart/mixed; boundary=changeset_r\r\n\
+            \r\n\
+            --changeset_r\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: 2\r\n\
+            \r\n\
+            HTTP/1.1 201 Created\r\n\
+            \r\n\
+            {\"UserName\":\"scott\"}\r\n\
+            --changeset_r\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: 3\r\n\
+            \r\n\
+            HTTP/1.1 204 No Content\r\n\
+            \r\n\
+            \r\n\
+            --changeset_r--\r\n\
+            --batch_r--\r\n";
+
+        let responses = parse_multipart(content, "batch_r").expect("invalid batch response");
+
+        match responses.as_slice() {
+            [MultipartResponse::Single(read), MultipartResponse::ChangeSet(change_set)] => {
+                assert_eq!((read.id.as_str(), read.status), ("1", StatusCode::OK));
+                assert_eq!(read.header("content-type"), Some("application/json"));
+                assert_eq!(read.entity().expect("invalid body"), Some(json!({ "UserName": "russell" })));
+
+                let statuses : Vec<(&str, StatusCode, &str)> = change_set.iter().map(|response| (response.id.as_str(), response.status, response.body.as_str())).collect();
+                assert_eq!(statuses, vec![("2", StatusCode::CREATED, "{\"UserName\":\"scott\"}"), ("3", StatusCode::NO_CONTENT, "")]);
+            },
+            other => panic!("unexpected responses {:?}", other)
+        }
+    }
+
+    #[test]
+    fn parses_multipart_responses_with_lf() {
+        // a failed change set is answered with a single response
+        let content = "preamble\n--b\nContent-Type: application/http\n\nHTTP/1.1 400 Bad Request\nContent-Type: application/json\n\n{\"error\":{\"code\":\"1\",\"message\":\"Invalid\"}}\n--b--\n";
+
+        let responses = parse_multipart(content, "b").expect("invalid batch response");
+
+        assert!(matches!(responses.as_slice(), [MultipartResponse::Single(response)] if response.status == StatusCode::BAD_REQUEST && response.body.ends_with("\"Invalid\"}}")));
+    }
+
+    #[test]
+    fn rejects_invalid_status_lines() {
+        let content = "--b\r\nContent-Type: application/http\r\n\r\nHTTP/1.1 OK\r\n\r\n--b--\r\n";
+
+        assert!(matches!(parse_multipart(content, "b"), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn rejects_responses_without_the_boundary() {
+        let content = "--other\r\nContent-Type: application/http\r\n\r\nHTTP/1.1 200 OK\r\n\r\n{}\r\n--other--\r\n";
+
+        assert!(matches!(parse_multipart(content, "b"), Err(Error::Protocol(_))));
+        assert!(matches!(parse_multipart("", "b"), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn parses_json_responses() {
+        let content = r#"{ "responses": [
+            { "id": "1", "status": 200, "headers": { "content-type": "application/json" }, "body": { "UserName": "russell" } },
+            { "id": 2, "status": 204 },
+            { "id": "3", "status": 200, "headers": { "content-type": "text/plain" }, "body": "42" }
+        ] }"#;
+
+        let responses = parse_json_responses(content).expect("invalid batch response");
+        let responses : Vec<(&str, u16, &str)> = responses.iter().map(|response| (response.id.as_str(), response.status.as_u16(), response.body.as_str())).collect();
+
+        assert_eq!(responses, vec![("1", 200, "{\"UserName\":\"russell\"}"), ("2", 204, ""), ("3", 200, "42")]);
+        assert!(matches!(parse_json_responses("{ \"value\": [] }"), Err(Error::Protocol(_))));
+        assert!(matches!(parse_json_responses("{ \"responses\": [{ \"id\": \"1\" }] }"), Err(Error::Protocol(_))));
+        // 65736 would wrap around to 200
+        assert!(matches!(parse_json_responses("{ \"responses\": [{ \"id\": \"1\", \"status\": 65736 }] }"), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn assigns_a_failed_change_set_to_all_of_its_operations() {
+        let builder = builder(BatchFormat::Multipart);
+        let service_url = Url::parse("https://example.com/svc/").expect("invalid URL");
+        let content = "--b\r\nContent-Type: application/http\r\n\r\nHTTP/1.1 200 OK\r\n\r\n{}\r\n--b\r\nContent-Type: application/http\r\n\r\nHTTP/1.1 409 Conflict\r\n\r\n\r\n--b--\r\n";
+
+        let results = builder.assign_multipart(&service_url, parse_multipart(content, "b").expect("invalid batch response"));
+        let statuses : Vec<(&str, Option<u16>)> = results.iter().map(|result| (result.id.as_str(), match &result.result {
+            Ok(response) => Some(response.status.as_u16()),
+            Err(Error::Http { status, .. }) => Some(status.as_u16()),
+            Err(_) => None
+        })).collect();
+
+        assert_eq!(statuses, vec![("1", Some(200)), ("2", Some(409)), ("3", Some(409))]);
+    }
+}
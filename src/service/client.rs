@@ -0,0 +1,168 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use reqwest::{Certificate, Identity, NoProxy, Proxy};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::error::Error;
+
+/// how the connections to the services are made. Everything not given keeps the defaults of reqwest,
+/// e.g. the proxy of the environment (`HTTPS_PROXY`, `NO_PROXY`, ...)
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    /// the longest time to establish a connection
+    pub connect_timeout: Option<Duration>,
+    /// the longest time for a request, from sending it until the body of the response is received completely.
+    /// This includes streaming the body: a page loading longer fails, even if data keeps arriving
+    pub timeout: Option<Duration>,
+    /// the proxy for all requests, e.g. `http://proxy.example.org:3128`
+    pub proxy: Option<String>,
+    /// the hosts to connect to directly, comma separated as in `NO_PROXY` (`localhost,.example.org,10.0.0.0/8`).
+    /// Replaces `NO_PROXY`, for the proxy given as well as for those of the environment
+    pub no_proxy: Option<String>,
+    /// files with additional root certificates (PEM or DER), e.g. of a private CA
+    pub root_certificates: Vec<PathBuf>,
+    /// the certificate the client authenticates with (mTLS)
+    pub identity: Option<ClientIdentity>,
+    /// accepts any certificate and host name of the services. Only meant for development
+    pub accept_invalid_certificates: bool,
+    pub user_agent: Option<String>,
+    /// headers sent with every request, e.g. `sap-client`
+    pub headers: Vec<(String, String)>
+}
+
+/// a client certificate along with its private key
+#[derive(Debug, Clone)]
+pub enum ClientIdentity {
+    /// a PKCS#12 archive (`.p12`, `.pfx`)
+    Pkcs12 { path: PathBuf, password: Option<String> },
+    /// the PEM files of the certificate (chain) and the PKCS#8 key
+    Pem { certificate: PathBuf, key: PathBuf }
+}
+
+/// the HTTP client all requests are sent with. Connections are kept and reused, cloning it shares them
+#[derive(Debug, Clone)]
+pub struct ODataClient {
+    http: reqwest::Client
+}
+
+impl ODataClient {
+    pub fn new(config: &ClientConfig) -> Result<ODataClient, Error> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(connect_timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        let no_proxy = config.no_proxy.as_deref().and_then(NoProxy::from_string);
+        let proxies = match &config.proxy {
+            Some(proxy_url) => vec![Proxy::all(proxy_url).map_err(|err| Error::InvalidQuery(format!("Invalid proxy '{}': {}", proxy_url, err)))?],
+            // reqwest applies only `NO_PROXY` to the proxies it takes from the environment by itself
+            None if no_proxy.is_some() => environment_proxies()?,
+            None => vec![]
+        };
+
+        for proxy in proxies {
+            builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+        }
+
+        for path in &config.root_certificates {
+            for certificate in read_certificates(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some(identity) = &config.identity {
+            builder = builder.identity(identity.load()?);
+        }
+
+        if config.accept_invalid_certificates {
+            builder = builder.danger_accept_invalid_certs(true).danger_accept_invalid_hostnames(true);
+        }
+
+        if let Some(user_agent) = &config.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        builder = builder.default_headers(build_headers(&config.headers)?);
+
+        let http = builder.build().map_err(|err| Error::InvalidQuery(format!("Invalid client configuration: {}", err)))?;
+        Ok(ODataClient { http })
+    }
+
+    /// the client with the default configuration, shared by all queries not configuring their own
+    pub fn shared() -> ODataClient {
+        static SHARED: OnceLock<ODataClient> = OnceLock::new();
+        SHARED.get_or_init(|| ODataClient { http: reqwest::Client::new() }).clone()
+    }
+
+    pub(crate) fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+}
+
+impl ClientIdentity {
+    fn load(&self) -> Result<Identity, Error> {
+        let identity = match self {
+            ClientIdentity::Pkcs12 { path, password } => Identity::from_pkcs12_der(&read_file(path)?, password.as_deref().unwrap_or_default()),
+            ClientIdentity::Pem { certificate, key } => Identity::from_pkcs8_pem(&read_file(certificate)?, &read_file(key)?)
+        };
+
+        identity.map_err(|err| Error::InvalidQuery(format!("Invalid client certificate: {}", err)))
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    std::fs::read(path).map_err(|err| Error::Io(std::io::Error::new(err.kind(), format!("Could not read {}: {}", path.display(), err))))
+}
+
+/// the proxies of the environment variables, as reqwest would use them: `HTTP_PROXY`, `HTTPS_PROXY`
+/// and `ALL_PROXY` for both (also in lower case)
+fn environment_proxies() -> Result<Vec<Proxy>, Error> {
+    let variable = |names: [&str; 2]| names.iter().find_map(|name| std::env::var(name).ok()).filter(|value| !value.is_empty());
+    let invalid = |proxy_url: &str, err: reqwest::Error| Error::InvalidQuery(format!("Invalid proxy '{}' in the environment: {}", proxy_url, err));
+    let mut proxies = vec![];
+
+    // CGI scripts can't trust `HTTP_PROXY`, it may come from the `Proxy` header of the request
+    if std::env::var_os("REQUEST_METHOD").is_none() {
+        if let Some(proxy_url) = variable(["HTTP_PROXY", "http_proxy"]) {
+            proxies.push(Proxy::http(&proxy_url).map_err(|err| invalid(&proxy_url, err))?);
+        }
+    }
+
+    if let Some(proxy_url) = variable(["HTTPS_PROXY", "https_proxy"]) {
+        proxies.push(Proxy::https(&proxy_url).map_err(|err| invalid(&proxy_url, err))?);
+    }
+
+    if let Some(proxy_url) = variable(["ALL_PROXY", "all_proxy"]) {
+        proxies.push(Proxy::all(&proxy_url).map_err(|err| invalid(&proxy_url, err))?);
+    }
+
+    Ok(proxies)
+}
+
+/// the certificates of a PEM bundle or a single DER certificate
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let content = read_file(path)?;
+    let certificates = if content.starts_with(b"-----BEGIN") {
+        Certificate::from_pem_bundle(&content)
+    } else {
+        Certificate::from_der(&content).map(|certificate| vec![certificate])
+    };
+
+    certificates.map_err(|err| Error::InvalidQuery(format!("Invalid certificate in {}: {}", path.display(), err)))
+}
+
+fn build_headers(headers: &[(String, String)]) -> Result<HeaderMap, Error> {
+    let mut header_map = HeaderMap::new();
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| Error::InvalidQuery(format!("Invalid header name '{}'", name)))?;
+        let header_value = HeaderValue::from_str(value).map_err(|_| Error::InvalidQuery(format!("Invalid value for header '{}'", name)))?;
+        header_map.append(header_name, header_value);
+    }
+
+    Ok(header_map)
+}
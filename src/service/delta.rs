@@ -0,0 +1,110 @@
+use crate::model::{Token, Value, ValuePath, ValuePosition};
+
+/// the field telling how each entity changed, added when tracking changes
+pub const CHANGE_FIELD: &str = "@change";
+
+/// how an entity (or a relation between two entities) changed since the delta link was returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeType {
+    /// part of the initial load
+    Added,
+    /// added or changed since the last load, V4 does not tell them apart
+    Changed,
+    /// deleted, or not matching the query any more (`@removed`, `$deletedEntity`)
+    Removed,
+    /// a relation added (`$link`)
+    LinkAdded,
+    /// a relation removed (`$deletedLink`)
+    LinkRemoved
+}
+
+impl ChangeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeType::Added => "added",
+            ChangeType::Changed => "changed",
+            ChangeType::Removed => "removed",
+            ChangeType::LinkAdded => "link-added",
+            ChangeType::LinkRemoved => "link-removed"
+        }
+    }
+
+    /// the kind of entry announced by its `@odata.context`, e.g. `$metadata#People/$deletedEntity`
+    fn from_context(context: &str) -> Option<ChangeType> {
+        let kind = context.rsplit_once('/').map(|(_, kind)| kind)?;
+        match kind {
+            "$deletedEntity" => Some(ChangeType::Removed),
+            "$link" => Some(ChangeType::LinkAdded),
+            "$deletedLink" => Some(ChangeType::LinkRemoved),
+            _ => None
+        }
+    }
+}
+
+impl std::fmt::Display for ChangeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// adds the `@change` field to the entities of the root array, at their end.
+/// The `@removed` annotation of removed entities is dropped, the field replaces it
+pub(crate) struct ChangeMarker {
+    /// the change of entities without any marking
+    default_change: ChangeType,
+    /// the change of the current entity, if marked
+    change: Option<ChangeType>,
+    /// the `@removed` annotation currently skipped
+    skipping: Option<ValuePath>
+}
+
+impl ChangeMarker {
+    pub(crate) fn new(default_change: ChangeType) -> Self {
+        ChangeMarker { default_change, change: None, skipping: None }
+    }
+
+    /// the tokens to pass on for the token of the response
+    pub(crate) fn push(&mut self, token: Token) -> Vec<Token> {
+        if let Some(skipped) = &self.skipping {
+            if token.path.starts_with(skipped) {
+                if token.path == *skipped && (token.value == Value::EndObject || token.value == Value::EndArray) {
+                    self.skipping = None;
+                }
+
+                return vec![];
+            }
+
+            self.skipping = None;
+        }
+
+        match (token.path.current_level(), &token.value) {
+            (1, Value::StartObject) => self.change = None,
+            (1, Value::EndObject) => {
+                let mut change_path = token.path.clone();
+                change_path.push(ValuePosition::Key(CHANGE_FIELD.to_owned()));
+
+                let change = self.change.take().unwrap_or(self.default_change);
+                return vec![Token { path: change_path, value: Value::String(change.as_str().to_owned()) }, token];
+            },
+            (2, value) => match token.path.top_most() {
+                Some(ValuePosition::Key(key)) if key == "@removed" => {
+                    self.change = Some(ChangeType::Removed);
+                    if *value == Value::StartObject || *value == Value::StartArray {
+                        self.skipping = Some(token.path);
+                    }
+
+                    return vec![];
+                },
+                Some(ValuePosition::Key(key)) if key == "@odata.context" || key == "@context" => {
+                    if let Value::String(context) = value {
+                        self.change = ChangeType::from_context(context).or(self.change);
+                    }
+                },
+                _ => ()
+            },
+            _ => ()
+        }
+
+        vec![token]
+    }
+}
@@ -1,10 +1,13 @@
-use std::{thread, time};
 use futures::stream::Stream;
+use futures::sink::SinkExt;
 use futures::channel::mpsc::Sender;
 use bytes::Bytes;
 use crate::json_stream::token::JsonToken;
 use crate::json_stream::stream::TokenIterator;
-use crate::model::{Token, Value, ValuePath, ValuePosition, MyError};
+use crate::model::{ProtocolVersion, Token, Value, ValuePath, ValuePosition};
+use crate::error::Error;
+use crate::service::protocol::V2Normalizer;
+use crate::service::delta::{ChangeMarker, ChangeType};
 
 pub enum RootEntityType {
     Array,
@@ -16,30 +19,76 @@ pub enum RootEntityType {
 pub struct EntityStreamer {
     path: ValuePath,
     index : Option<usize>,
-    sender: Sender<Token>,
-    root_entity : RootEntityType
+    sender: Sender<Result<Token, Error>>,
+    root_entity : RootEntityType,
+    /// reshapes V2 entities into V4 ones
+    normalizer: Option<V2Normalizer>,
+    /// marks the entities with their change, when tracking changes
+    change_marker: Option<ChangeMarker>,
+    /// the index of the first element of the root array which is dropped
+    entity_limit: Option<usize>
 }
 
 impl EntityStreamer {
-    pub fn new(sender: Sender<Token>, root_entity : RootEntityType) -> Self {
-        let mut instance = EntityStreamer { sender, root_entity, path: ValuePath::new(), index: None};
-        instance.begin();
+    pub fn new(sender: Sender<Result<Token, Error>>, root_entity : RootEntityType) -> Self {
+        EntityStreamer { sender, root_entity, path: ValuePath::new(), index: None, normalizer: None, change_marker: None, entity_limit: None }
+    }
+
+    /// the protocol of the streamed content. V2 entities are passed on in the shape of V4 ones
+    pub fn use_protocol(&mut self, version: ProtocolVersion) {
+        match version {
+            ProtocolVersion::V2 if self.normalizer.is_none() => self.normalizer = Some(V2Normalizer::default()),
+            ProtocolVersion::V2 => (),
+            ProtocolVersion::V4 => self.normalizer = None
+        }
+    }
+
+    /// adds the `@change` field to the entities, `default_change` if the entity is not marked otherwise
+    pub fn track_changes(&mut self, default_change: ChangeType) {
+        self.change_marker = Some(ChangeMarker::new(default_change));
+    }
+
+    /// drops the elements of the root array from `entity_limit` on, the content is still read
+    pub fn limit_entities(&mut self, entity_limit: Option<usize>) {
+        self.entity_limit = entity_limit;
+    }
+
+    pub fn entity_limit(&self) -> Option<usize> {
+        self.entity_limit
+    }
 
-        return instance;
+    /// opens the root entity, before any content is streamed
+    pub async fn begin(&mut self) {
+        self.begin_at(0).await;
     }
 
-    fn begin(&mut self) {
+    /// like `begin`, numbering the elements of a root array from `first_index` on, e.g. when continuing an earlier load
+    pub async fn begin_at(&mut self, first_index: usize) {
         match self.root_entity {
             RootEntityType::Array => {
-                self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::StartArray });
-                self.start_index();
+                self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::StartArray }).await;
+                self.index = Some(first_index);
             }
-            RootEntityType::Object => self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::StartObject }),
+            RootEntityType::Object => self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::StartObject }).await,
             RootEntityType::Value => ()
         }        
     }
 
-    pub async fn stream_content<T>(&mut self, stream: &mut crate::json_stream::stream::Stream<T>) -> Result<(), MyError>
+    /// the index the next element of the root array gets
+    pub fn next_index(&self) -> usize {
+        self.index.unwrap_or(0)
+    }
+
+    /// closes the root entity, after all content was streamed successfully
+    pub async fn finish(mut self) {
+        match self.root_entity {
+            RootEntityType::Array => self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::EndArray }).await,
+            RootEntityType::Object => self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::EndObject }).await,
+            RootEntityType::Value => ()
+        }
+    }
+
+    pub async fn stream_content<T>(&mut self, stream: &mut crate::json_stream::stream::Stream<T>) -> Result<(), Error>
     where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
         loop {
             if let Some(json_content) =  stream.get() {
@@ -49,33 +98,33 @@ impl EntityStreamer {
                     },
                     JsonToken::StartArray => {
                         self.apply_index();
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::StartArray });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::StartArray }).await;
                         self.start_index();
                         
                     },
                     JsonToken::StartObject => {
                         self.apply_index();
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::StartObject });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::StartObject }).await;
                         self.index = None;
                     },
                     JsonToken::JsNull => {
                         self.apply_index();
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::None });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::None }).await;
                         self.leave_nesting();
                     },
                     JsonToken::JsNumber(value) => {
                         self.apply_index();
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::Number(value.to_owned()) });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::Number(value.to_owned()) }).await;
                         self.leave_nesting();
                     },
                     JsonToken::JsString(value) => {
                         self.apply_index();
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::String(value.into_raw_str().to_owned()) });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::String(value.into_raw_str().to_owned()) }).await;
                         self.leave_nesting();
                     },
                     JsonToken::JsBoolean(value) => {
                         self.apply_index();
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::Boolean(value) });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::Boolean(value) }).await;
                         self.leave_nesting();
                     },
                     JsonToken::EndObject => {
@@ -83,7 +132,7 @@ impl EntityStreamer {
                             break
                         }
 
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::EndObject });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::EndObject }).await;
                         self.leave_nesting();
                     },
                     JsonToken::EndArray => {
@@ -91,12 +140,12 @@ impl EntityStreamer {
                             break
                         }
 
-                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::EndArray });
+                        self.send_message_into_stream(Token { path: self.path.clone(), value: Value::EndArray }).await;
                         self.leave_nesting();
                     },
                 }
             } else {
-                return Err(MyError { message: format!("Premature end of content at position {}", self.path.get_path_string()) });
+                return Err(Error::decode(format!("Premature end of content at {}", self.path.get_path_string()), Some(stream.position())));
             }
 
             stream.advance().await?;
@@ -105,6 +154,11 @@ impl EntityStreamer {
         Ok(())
     }
 
+    /// hands the error to the consumer of the stream. The stream ends with it, it is not closed properly any more
+    pub async fn fail(mut self, error: Error) {
+        self.force_send_into_stream(Err(error)).await;
+    }
+
     fn end_of_scope(&self) -> bool {
         self.path.is_empty()
     }
@@ -136,8 +190,8 @@ impl EntityStreamer {
         self.index = Some(0);
     }
 
-    fn should_send(&mut self) -> bool {
-        let has_odata_key = self.path.iter().any(|path_part| {
+    fn should_send(path: &ValuePath) -> bool {
+        let has_odata_key = path.iter().any(|path_part| {
             if let ValuePosition::Key(key_value) = path_part {
                 if key_value.starts_with("@odata.") {
                     return true;
@@ -150,35 +204,74 @@ impl EntityStreamer {
         !has_odata_key
     }
 
-    fn send_message_into_stream(&mut self, message: Token) {
-        if !self.should_send() {
-            return;
+    async fn send_message_into_stream(&mut self, message: Token) {
+        // the change marker needs the `@odata.context` of the entities
+        let marked = match self.change_marker.as_mut() {
+            Some(change_marker) => change_marker.push(message),
+            None => vec![message]
+        };
+
+        for message in marked {
+            if !Self::should_send(&message.path) {
+                continue;
+            }
+
+            let messages = match self.normalizer.as_mut() {
+                Some(normalizer) => normalizer.push(message),
+                None => vec![message]
+            };
+
+            for message in messages {
+                self.force_send_message_into_stream(message).await;
+            }
         }
+    }
 
-        self.force_send_message_into_stream(message);
+    /// passes on a token read from another format than JSON (e.g. Atom), the path already being complete
+    pub(crate) async fn stream_token(&mut self, message: Token) {
+        let marked = match self.change_marker.as_mut() {
+            Some(change_marker) => change_marker.push(message),
+            None => vec![message]
+        };
+
+        for message in marked {
+            self.force_send_message_into_stream(message).await;
+        }
     }
 
-    fn force_send_message_into_stream(&mut self, message: Token) {
-        let mut message = Some(message);
-        while message.is_some() {
-            match self.sender.try_send(message.take().unwrap()) {
-                Ok(_) => (),
-                Err(e) => {
-                    message = Some(e.into_inner());
-                    thread::sleep(time::Duration::from_millis(50));
-                }
+    async fn force_send_message_into_stream(&mut self, message: Token) {
+        if let (Some(entity_limit), Some(ValuePosition::Index(index))) = (self.entity_limit, message.path.iter().next()) {
+            if *index >= entity_limit {
+                return;
             }
         }
+
+        self.force_send_into_stream(Ok(message)).await;
+    }
+
+    /// waits while the consumer is busy, to not buffer the whole response
+    async fn force_send_into_stream(&mut self, message: Result<Token, Error>) {
+        // a failure means nobody is listening any more
+        let _ = self.sender.send(message).await;
     }
 }
 
-impl Drop for EntityStreamer {
-    fn drop(&mut self) {
-        match self.root_entity {
-            RootEntityType::Array => self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::EndArray }),
-            RootEntityType::Object => self.force_send_message_into_stream(Token { path: ValuePath::new(), value: Value::EndObject }),
-            RootEntityType::Value => ()
+/// moves to the last token of the current value, e.g. the end of an annotation's object
+pub(crate) async fn skip_value<T>(stream: &mut crate::json_stream::stream::Stream<T>) -> Result<(), Error>
+where T: Stream<Item = reqwest::Result<Bytes>> + Send + Unpin {
+    let mut depth = 0usize;
+    loop {
+        match stream.get() {
+            Some(JsonToken::StartObject) | Some(JsonToken::StartArray) => depth += 1,
+            Some(JsonToken::EndObject) | Some(JsonToken::EndArray) => depth = depth.saturating_sub(1),
+            Some(_) => (),
+            None => return Err(Error::decode("Premature end of content", Some(stream.position())))
+        }
+
+        if depth == 0 {
+            return Ok(());
         }
-        self.sender.disconnect();
+
+        stream.advance().await?;
     }
 }
@@ -0,0 +1,179 @@
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use serde_json::{Map, Value};
+use crate::error::ServiceError;
+
+/// plain text bodies (e.g. from a proxy) are only taken over up to this length
+const MAX_TEXT_LENGTH: usize = 500;
+
+/// reads the error a service sent along with an error status code.
+/// Understands the OData JSON format (V4 `error`, V2/V3 `error` and `odata.error`) and the XML format (V2 and V4),
+/// including the `errordetails` SAP services put into the `innererror`
+pub fn parse_error_response(content_type: Option<&str>, body: &str) -> Option<ServiceError> {
+    let body = body.trim_start_matches('\u{feff}').trim();
+    if body.is_empty() {
+        return None;
+    }
+
+    let content_type = content_type.unwrap_or_default().to_lowercase();
+    let parsed = if body.starts_with('{') {
+        parse_json_error(body)
+    } else if body.starts_with('<') {
+        parse_xml_error(body)
+    } else {
+        None
+    };
+
+    if parsed.is_some() || !content_type.starts_with("text/plain") {
+        return parsed;
+    }
+
+    Some(ServiceError { message: body.chars().take(MAX_TEXT_LENGTH).collect(), ..Default::default() })
+}
+
+fn parse_json_error(body: &str) -> Option<ServiceError> {
+    let content : Value = serde_json::from_str(body).ok()?;
+    let error = content.get("error").or_else(|| content.get("odata.error"))?;
+
+    Some(json_service_error(error))
+}
+
+fn json_service_error(error: &Value) -> ServiceError {
+    // V2/V3 send the message as `{ "lang": "en", "value": "..." }`
+    let message = match error.get("message") {
+        Some(Value::String(message)) => message.clone(),
+        Some(message) => message.get("value").and_then(Value::as_str).unwrap_or_default().to_owned(),
+        None => String::new()
+    };
+
+    let inner_error = error.get("innererror").cloned();
+    let mut details : Vec<ServiceError> = error.get("details").and_then(Value::as_array)
+        .map(|details| details.iter().map(json_service_error).collect())
+        .unwrap_or_default();
+
+    if details.is_empty() {
+        details = inner_error.as_ref()
+            .and_then(|inner| inner.get("errordetails"))
+            .and_then(Value::as_array)
+            .map(|details| details.iter().map(json_service_error).collect())
+            .unwrap_or_default();
+    }
+
+    ServiceError {
+        code: json_text(error.get("code")),
+        message,
+        target: error.get("target").map(|target| json_text(Some(target))).filter(|target| !target.is_empty()),
+        details,
+        inner_error
+    }
+}
+
+/// codes are strings in the spec, but some services send numbers
+fn json_text(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string()
+    }
+}
+
+/// a generic XML element, namespaces stripped
+#[derive(Default)]
+struct XmlElement {
+    name: String,
+    text: String,
+    children: Vec<XmlElement>
+}
+
+impl XmlElement {
+    fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn child_text(&self, name: &str) -> Option<String> {
+        self.child(name).map(|child| child.text.clone())
+    }
+
+    fn to_value(&self) -> Value {
+        if self.children.is_empty() {
+            return Value::String(self.text.clone());
+        }
+
+        let mut object = Map::new();
+        for child in &self.children {
+            let value = child.to_value();
+            match object.get_mut(&child.name) {
+                Some(Value::Array(values)) => values.push(value),
+                Some(existing) => {
+                    let first = existing.take();
+                    *existing = Value::Array(vec![first, value]);
+                },
+                None => {
+                    object.insert(child.name.clone(), value);
+                }
+            }
+        }
+
+        Value::Object(object)
+    }
+}
+
+fn parse_xml_document(body: &str) -> Option<XmlElement> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+    let mut stack = vec![XmlElement::default()];
+
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(element) => {
+                let name = reader.decoder().decode(element.local_name().as_ref()).ok()?.into_owned();
+                stack.push(XmlElement { name, ..Default::default() });
+            },
+            Event::Empty(element) => {
+                let name = reader.decoder().decode(element.local_name().as_ref()).ok()?.into_owned();
+                stack.last_mut()?.children.push(XmlElement { name, ..Default::default() });
+            },
+            Event::End(_) => {
+                let finished = stack.pop()?;
+                stack.last_mut()?.children.push(finished);
+            },
+            Event::Text(text) => stack.last_mut()?.text.push_str(&text.unescape().ok()?),
+            Event::CData(data) => stack.last_mut()?.text.push_str(&reader.decoder().decode(data.as_ref()).ok()?),
+            Event::Eof => break,
+            _ => ()
+        }
+    }
+
+    stack.pop()?.children.into_iter().next()
+}
+
+fn parse_xml_error(body: &str) -> Option<ServiceError> {
+    let document = parse_xml_document(body)?;
+    if document.name != "error" {
+        return None;
+    }
+
+    Some(xml_service_error(&document))
+}
+
+fn xml_service_error(error: &XmlElement) -> ServiceError {
+    let inner_error = error.child("innererror");
+    let mut details : Vec<ServiceError> = error.child("details")
+        .map(|details| details.children.iter().map(xml_service_error).collect())
+        .unwrap_or_default();
+
+    if details.is_empty() {
+        details = inner_error
+            .and_then(|inner| inner.child("errordetails"))
+            .map(|details| details.children.iter().map(xml_service_error).collect())
+            .unwrap_or_default();
+    }
+
+    ServiceError {
+        code: error.child_text("code").unwrap_or_default(),
+        message: error.child_text("message").unwrap_or_default(),
+        target: error.child_text("target").filter(|target| !target.is_empty()),
+        details,
+        inner_error: inner_error.map(XmlElement::to_value)
+    }
+}
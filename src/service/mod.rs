@@ -1,2 +1,12 @@
+pub mod auth;
+pub mod client;
 pub mod url;
-pub mod entity_stream;
\ No newline at end of file
+pub mod entity_stream;
+pub mod atom_stream;
+pub mod delta;
+pub mod retry;
+pub mod error_response;
+pub mod token_stream;
+pub mod token_deserializer;
+pub mod batch;
+pub mod protocol;
\ No newline at end of file
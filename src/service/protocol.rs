@@ -0,0 +1,214 @@
+use reqwest::header::HeaderMap;
+use url::Url;
+use crate::model::{ProtocolVersion, Token, Value, ValuePath, ValuePosition};
+use crate::error::Error;
+use crate::service::auth::SharedAuthenticator;
+use crate::service::client::ODataClient;
+use crate::service::url::{build_query_url, RequestContent, SingleUrlCaller, JSON_OR_ATOM};
+
+/// the version announced by the response headers: `OData-Version` (V4) or `DataServiceVersion` (V2/V3)
+pub fn version_from_headers(headers: &HeaderMap) -> Option<ProtocolVersion> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(version) = header("OData-Version") {
+        return version.trim().parse().ok();
+    }
+
+    header("DataServiceVersion").map(|_| ProtocolVersion::V2)
+}
+
+/// asks the service for its version, with a request for no entities (`$top=0`). Services not announcing any are treated as V4
+pub async fn detect_version(url: &str, authenticator: Option<SharedAuthenticator>, client: Option<ODataClient>) -> Result<ProtocolVersion, Error> {
+    let probe_url : Url = build_query_url(url, &[("$top", "0".to_owned())])?;
+    let response = SingleUrlCaller::new(probe_url, authenticator).with_client(client).send(&RequestContent::get_accepting(JSON_OR_ATOM)).await?;
+
+    Ok(version_from_headers(response.headers()).unwrap_or_default())
+}
+
+/// converts the V2 JSON format of dates (`/Date(1617192000000)/`, `/Date(1617192000000+0120)/`)
+/// into ISO 8601 (`2021-03-31T12:00:00Z`, `2021-03-31T14:00:00+02:00`)
+pub fn convert_v2_date(text: &str) -> Option<String> {
+    let content = text.strip_prefix("\\/Date(").or_else(|| text.strip_prefix("/Date("))?;
+    let content = content.strip_suffix(")\\/").or_else(|| content.strip_suffix(")/"))?;
+
+    // the offset (in minutes) follows the milliseconds, both may be negative
+    let offset_start = content.char_indices().skip(1).find(|(_, character)| *character == '+' || *character == '-').map(|(index, _)| index);
+    let (milliseconds, offset_minutes) = match offset_start {
+        Some(index) => (content[..index].parse::<i64>().ok()?, Some(content[index..].parse::<i64>().ok()?)),
+        None => (content.parse::<i64>().ok()?, None)
+    };
+
+    let local_milliseconds = milliseconds + offset_minutes.unwrap_or_default() * 60_000;
+    let days = local_milliseconds.div_euclid(86_400_000);
+    let millisecond_of_day = local_milliseconds.rem_euclid(86_400_000);
+    let (year, month, day) = civil_from_days(days);
+
+    let seconds_of_day = millisecond_of_day / 1000;
+    let mut formatted = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, seconds_of_day / 3600, seconds_of_day % 3600 / 60, seconds_of_day % 60);
+    if millisecond_of_day % 1000 != 0 {
+        formatted.push_str(&format!(".{:03}", millisecond_of_day % 1000));
+    }
+
+    match offset_minutes {
+        Some(offset) if offset != 0 => formatted.push_str(&format!("{}{:02}:{:02}", if offset < 0 { '-' } else { '+' }, offset.abs() / 60, offset.abs() % 60)),
+        _ => formatted.push('Z')
+    }
+
+    Some(formatted)
+}
+
+/// year, month and day of the days since 1970-01-01 (proleptic Gregorian calendar)
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// turns the tokens of V2 entities into the shape of V4 entities:
+/// `__metadata` and deferred navigation properties (`{ "__deferred": ... }`) are dropped,
+/// expanded collections (`{ "results": [...] }`) become arrays and dates are converted to ISO 8601
+#[derive(Default)]
+pub(crate) struct V2Normalizer {
+    /// an object, held back until its first property tells whether it is a wrapper
+    pending: Option<Token>,
+    /// the deferred navigation property currently skipped
+    skipping: Option<ValuePath>,
+    /// the `{ "results": [...] }` objects currently passed through as arrays
+    wrappers: Vec<ValuePath>
+}
+
+impl V2Normalizer {
+    /// the tokens to pass on for the token of the V2 response
+    pub(crate) fn push(&mut self, token: Token) -> Vec<Token> {
+        if let Some(skipped) = &self.skipping {
+            if token.path.starts_with(skipped) {
+                if token.path == *skipped && token.value == Value::EndObject {
+                    self.skipping = None;
+                }
+
+                return vec![];
+            }
+
+            self.skipping = None;
+        }
+
+        let mut tokens = vec![];
+        if let Some(pending) = self.pending.take() {
+            let is_property = token.path.parent().as_ref() == Some(&pending.path);
+            match token.path.top_most() {
+                Some(ValuePosition::Key(key)) if is_property && key == "__deferred" => {
+                    self.skipping = Some(pending.path);
+                    return self.push(token);
+                },
+                Some(ValuePosition::Key(key)) if is_property && key == "results" => {
+                    // the array itself is passed on in place of the wrapping object
+                    self.wrappers.push(pending.path);
+                },
+                Some(ValuePosition::Key(key)) if is_property && key.starts_with("__") && key != "__metadata" => {
+                    // e.g. `__count` before `results`, the kind of object is not known yet
+                    self.pending = Some(pending);
+                    return vec![];
+                },
+                _ => tokens.push(self.rewrite(pending))
+            }
+        }
+
+        if self.is_v2_annotation(&token.path) {
+            return tokens;
+        }
+
+        if token.value == Value::EndObject && self.wrappers.last() == Some(&token.path) {
+            self.wrappers.pop();
+            return tokens;
+        }
+
+        if token.value == Value::StartObject {
+            self.pending = Some(token);
+            return tokens;
+        }
+
+        let token = match token.value {
+            Value::String(text) => match convert_v2_date(&text) {
+                Some(date) => Token { path: token.path, value: Value::String(date) },
+                None => Token { path: token.path, value: Value::String(text) }
+            },
+            value => Token { path: token.path, value }
+        };
+
+        tokens.push(self.rewrite(token));
+        tokens
+    }
+
+    /// `__metadata`, `__count`, ... and everything inside of them
+    fn is_v2_annotation(&self, path: &ValuePath) -> bool {
+        path.iter().any(|position| matches!(position, ValuePosition::Key(key) if key.starts_with("__")))
+    }
+
+    /// removes the `results` steps of the wrappers from the path, the innermost first
+    fn rewrite(&self, mut token: Token) -> Token {
+        for wrapper in self.wrappers.iter().rev() {
+            let level = wrapper.current_level();
+            if token.path.starts_with(wrapper) && matches!(token.path.get(level), Some(ValuePosition::Key(key)) if key == "results") {
+                token.path.remove(level);
+            }
+        }
+
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn converts_v2_dates_to_iso_8601() {
+        assert_eq!(convert_v2_date("/Date(1617192000000)/").as_deref(), Some("2021-03-31T12:00:00Z"));
+        // escaped as in the JSON text
+        assert_eq!(convert_v2_date("\\/Date(1617192000000)\\/").as_deref(), Some("2021-03-31T12:00:00Z"));
+        assert_eq!(convert_v2_date("/Date(1617192000123)/").as_deref(), Some("2021-03-31T12:00:00.123Z"));
+        assert_eq!(convert_v2_date("/Date(0)/").as_deref(), Some("1970-01-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn converts_v2_dates_with_offset() {
+        assert_eq!(convert_v2_date("/Date(1617192000000+0120)/").as_deref(), Some("2021-03-31T14:00:00+02:00"));
+        assert_eq!(convert_v2_date("/Date(1617192000000-0330)/").as_deref(), Some("2021-03-31T06:30:00-05:30"));
+        assert_eq!(convert_v2_date("/Date(1617192000000+0000)/").as_deref(), Some("2021-03-31T12:00:00Z"));
+    }
+
+    #[test]
+    fn converts_v2_dates_before_1970() {
+        assert_eq!(convert_v2_date("/Date(-86400000)/").as_deref(), Some("1969-12-31T00:00:00Z"));
+        assert_eq!(convert_v2_date("/Date(-1)/").as_deref(), Some("1969-12-31T23:59:59.999Z"));
+        assert_eq!(convert_v2_date("/Date(951782400000)/").as_deref(), Some("2000-02-29T00:00:00Z"));
+    }
+
+    #[test]
+    fn keeps_other_texts() {
+        assert_eq!(convert_v2_date("2021-03-31T12:00:00Z"), None);
+        assert_eq!(convert_v2_date("/Date(soon)/"), None);
+        assert_eq!(convert_v2_date("/Date(1617192000000"), None);
+    }
+
+    #[test]
+    fn reads_the_version_from_the_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(version_from_headers(&headers), None);
+
+        headers.insert("DataServiceVersion", HeaderValue::from_static("2.0"));
+        assert_eq!(version_from_headers(&headers), Some(ProtocolVersion::V2));
+
+        headers.insert("OData-Version", HeaderValue::from_static("4.0"));
+        assert_eq!(version_from_headers(&headers), Some(ProtocolVersion::V4));
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use crate::error::Error;
+
+/// how failed requests are repeated: on overload (`429`, `503`), gateway errors (`502`, `504`),
+/// timeouts and connections lost before the response arrived. The body of a response is streamed, failures while receiving it are not repeated.
+/// The waits grow exponentially (with jitter), a `Retry-After` of the service is honored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// how often a failed request is repeated, `0` to never repeat it
+    pub retries: u32,
+    /// the wait before the first repetition, doubled for every further one
+    pub initial_wait: Duration,
+    /// the longest wait before a repetition, also limiting `Retry-After`
+    pub max_wait: Duration
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32) -> RetryPolicy {
+        RetryPolicy { retries, ..Default::default() }
+    }
+
+    /// no repetitions at all
+    pub fn none() -> RetryPolicy {
+        RetryPolicy::new(0)
+    }
+
+    /// the wait before repeating the request after the failed attempt (counted from 0), `None` if it is not repeated
+    pub(crate) fn wait_before_retry(&self, attempt: u32, error: &Error, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.retries || !is_transient(error) {
+            return None;
+        }
+
+        let wait = match retry_after {
+            Some(retry_after) => retry_after,
+            None => {
+                let backoff = self.initial_wait.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_wait);
+                // "equal jitter": between half and the full backoff, so parallel clients do not retry in lockstep
+                backoff / 2 + backoff.mul_f64(random_fraction() / 2.0)
+            }
+        };
+
+        Some(wait.min(self.max_wait))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { retries: 0, initial_wait: Duration::from_secs(1), max_wait: Duration::from_secs(60) }
+    }
+}
+
+/// errors which may be gone when trying again
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Http { status, .. } => matches!(*status, StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT),
+        Error::Transport(err) => err.is_connect() || err.is_timeout() || err.is_request() || err.is_body(),
+        _ => false
+    }
+}
+
+/// the wait requested by the service, in seconds (`Retry-After: 120`) or as date (`Retry-After: Wed, 21 Oct 2015 07:28:00 GMT`)
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let retry_at = UNIX_EPOCH + Duration::from_secs(parse_http_date(value)?);
+    Some(retry_at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// the seconds since 1970-01-01 of a date in the format of HTTP (IMF-fixdate): `Wed, 21 Oct 2015 07:28:00 GMT`
+fn parse_http_date(date: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let parts : Vec<&str> = date.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+
+    let day = parts[1].parse::<u64>().ok()?;
+    let month = MONTHS.iter().position(|month| *month == parts[2])? as u64 + 1;
+    let year = parts[3].parse::<u64>().ok()?;
+    let time : Vec<u64> = parts[4].split(':').map(|part| part.parse::<u64>().ok()).collect::<Option<_>>()?;
+    if time.len() != 3 || year < 1970 {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86_400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+/// the days since 1970-01-01 of a date (proleptic Gregorian calendar), from 1970 on
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// a number between 0 and 1, random enough to spread the retries
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,408 @@
+use std::iter::Peekable;
+use std::str::FromStr;
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
+use serde::de::value::StringDeserializer;
+use serde::forward_to_deserialize_any;
+use crate::model::{Token, Value, ValuePath, ValuePosition};
+use crate::error::Error;
+use crate::json_stream::token::JsonString;
+
+/// deserializes the tokens of one entity (see `EntityStream`) into `T`
+pub fn from_tokens<T: DeserializeOwned>(tokens: Vec<Token>) -> Result<T, Error> {
+    let mut deserializer = TokenDeserializer::new(tokens);
+
+    T::deserialize(&mut deserializer).map_err(|err| match err {
+        Error::Deserialize(message) => Error::Deserialize(format!("{} at {}", message, deserializer.current_path)),
+        other => other
+    })
+}
+
+/// the text of a string token or key, which carries the JSON-escaped text as read from the response
+fn unescape(raw: String) -> Result<String, Error> {
+    if !raw.contains('\\') {
+        return Ok(raw);
+    }
+
+    JsonString::from_str_ref(&raw)
+        .map(Into::into)
+        .map_err(|err| Error::Deserialize(format!("Invalid escape sequence in '{}': {:?}", raw, err)))
+}
+
+/// a `serde::Deserializer` reading directly from the tokens, without an intermediate JSON document.
+/// Numbers are also accepted as strings (as sent with `IEEE754Compatible=true`), strings are deserialized into enums by the member name
+pub struct TokenDeserializer {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+    /// the position of the last read token, to report errors
+    current_path: ValuePath
+}
+
+impl TokenDeserializer {
+    pub fn new(tokens: Vec<Token>) -> TokenDeserializer {
+        TokenDeserializer { tokens: tokens.into_iter().peekable(), current_path: ValuePath::new() }
+    }
+
+    fn next_value(&mut self) -> Result<Value, Error> {
+        match self.tokens.next() {
+            Some(token) => {
+                self.current_path = token.path;
+                Ok(token.value)
+            },
+            None => Err(Error::Deserialize("Unexpected end of entity".to_owned()))
+        }
+    }
+
+    fn peek_value(&mut self) -> Option<&Value> {
+        self.tokens.peek().map(|token| &token.value)
+    }
+
+    fn parse_number<T: FromStr>(&mut self) -> Result<Option<T>, Error> {
+        let parsed = match self.peek_value() {
+            Some(Value::Number(number)) | Some(Value::String(number)) => number.parse::<T>().ok(),
+            _ => None
+        };
+
+        if parsed.is_some() {
+            self.next_value()?;
+        }
+
+        Ok(parsed)
+    }
+}
+
+macro_rules! deserialize_number {
+    ($($method:ident => $visit:ident: $number_type:ty),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self.parse_number::<$number_type>()? {
+                    Some(number) => visitor.$visit(number),
+                    None => self.deserialize_any(visitor)
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut TokenDeserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.next_value()? {
+            Value::StartObject => visitor.visit_map(TokenMapAccess { deserializer: self }),
+            Value::StartArray => {
+                let mut seq = TokenSeqAccess { deserializer: self, finished: false };
+                let value = visitor.visit_seq(&mut seq)?;
+
+                // tuples stop reading after their last element, the end of the array is still ahead
+                if seq.finished {
+                    return Ok(value);
+                }
+
+                match seq.deserializer.next_value()? {
+                    Value::EndArray => Ok(value),
+                    _ => Err(Error::Deserialize("Expected the end of an array, found more elements".to_owned()))
+                }
+            },
+            Value::None => visitor.visit_unit(),
+            Value::Boolean(value) => visitor.visit_bool(value),
+            Value::Number(number) => {
+                if let Ok(value) = number.parse::<i64>() {
+                    visitor.visit_i64(value)
+                } else if let Ok(value) = number.parse::<u64>() {
+                    visitor.visit_u64(value)
+                } else {
+                    match number.parse::<f64>() {
+                        Ok(value) if value.is_finite() => visitor.visit_f64(value),
+                        // out of range for every number type, keep the text
+                        _ => visitor.visit_string(number)
+                    }
+                }
+            },
+            Value::String(value) => visitor.visit_string(unescape(value)?),
+            Value::EndObject | Value::EndArray => Err(Error::Deserialize("Expected a value, found the end of an object or array".to_owned()))
+        }
+    }
+
+    deserialize_number! {
+        deserialize_i8 => visit_i8: i8,
+        deserialize_i16 => visit_i16: i16,
+        deserialize_i32 => visit_i32: i32,
+        deserialize_i64 => visit_i64: i64,
+        deserialize_u8 => visit_u8: u8,
+        deserialize_u16 => visit_u16: u16,
+        deserialize_u32 => visit_u32: u32,
+        deserialize_u64 => visit_u64: u64,
+        deserialize_f32 => visit_f32: f32,
+        deserialize_f64 => visit_f64: f64
+    }
+
+    /// numbers are kept as they were sent, e.g. to not lose the precision of decimals
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.peek_value() {
+            Some(Value::Number(_)) | Some(Value::String(_)) => match self.next_value()? {
+                Value::Number(value) => visitor.visit_string(value),
+                Value::String(value) => visitor.visit_string(unescape(value)?),
+                _ => unreachable!()
+            },
+            _ => self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if let Some(Value::None) = self.peek_value() {
+            self.next_value()?;
+            return visitor.visit_none();
+        }
+
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        match self.next_value()? {
+            // OData enum members are sent by name, e.g. `"Gender": "Female"`
+            Value::String(member) => visitor.visit_enum(unescape(member)?.into_deserializer()),
+            // externally tagged: `{ "Variant": content }`
+            Value::StartObject => {
+                let value = visitor.visit_enum(TokenEnumAccess { deserializer: &mut *self })?;
+                match self.next_value()? {
+                    Value::EndObject => Ok(value),
+                    _ => Err(Error::Deserialize("Expected an object with a single key for an enum".to_owned()))
+                }
+            },
+            other => Err(Error::Deserialize(format!("Expected a string or an object for an enum, found '{}'", other)))
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool char bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct TokenMapAccess<'a> {
+    deserializer: &'a mut TokenDeserializer
+}
+
+impl<'de, 'a> MapAccess<'de> for TokenMapAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let key = match self.deserializer.tokens.peek() {
+            Some(Token { value: Value::EndObject, .. }) => {
+                self.deserializer.next_value()?;
+                return Ok(None);
+            },
+            // the key is the last step in the path of the value
+            Some(Token { path, .. }) => match path.top_most() {
+                Some(ValuePosition::Key(key)) => key,
+                _ => return Err(Error::Deserialize(format!("Expected a key at {}", path)))
+            },
+            None => return Err(Error::Deserialize("Unexpected end of entity in an object".to_owned()))
+        };
+
+        seed.deserialize(unescape(key)?.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.deserializer)
+    }
+}
+
+struct TokenSeqAccess<'a> {
+    deserializer: &'a mut TokenDeserializer,
+    /// whether the end of the array was read
+    finished: bool
+}
+
+impl<'de, 'a> SeqAccess<'de> for &mut TokenSeqAccess<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        match self.deserializer.peek_value() {
+            Some(Value::EndArray) => {
+                self.deserializer.next_value()?;
+                self.finished = true;
+                Ok(None)
+            },
+            Some(_) => seed.deserialize(&mut *self.deserializer).map(Some),
+            None => Err(Error::Deserialize("Unexpected end of entity in an array".to_owned()))
+        }
+    }
+}
+
+struct TokenEnumAccess<'a> {
+    deserializer: &'a mut TokenDeserializer
+}
+
+impl<'de, 'a> EnumAccess<'de> for TokenEnumAccess<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = match self.deserializer.tokens.peek().and_then(|token| token.path.top_most()) {
+            Some(ValuePosition::Key(key)) => key,
+            _ => return Err(Error::Deserialize("Expected an object with a single key for an enum".to_owned()))
+        };
+
+        let variant : StringDeserializer<Error> = unescape(variant)?.into_deserializer();
+        Ok((seed.deserialize(variant)?, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for TokenEnumAccess<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Deserialize::deserialize(self.deserializer)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.deserializer)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self.deserializer, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self.deserializer, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::ops::Bound;
+    use serde_json::json;
+    use crate::model::Entity;
+
+    /// strings and keys are JSON-escaped, as read from the response
+    fn escaped(text: &str) -> String {
+        let quoted = Entity::String(text.to_owned()).to_string();
+        quoted[1..quoted.len() - 1].to_owned()
+    }
+
+    /// the tokens of the value, as the entity stream passes them on
+    fn tokens(value: &Entity) -> Vec<Token> {
+        fn push(value: &Entity, path: &ValuePath, tokens: &mut Vec<Token>) {
+            let token = |value: Value| Token { path: path.clone(), value };
+            let child = |position: ValuePosition| {
+                let mut child = path.clone();
+                child.push(position);
+                child
+            };
+
+            match value {
+                Entity::Object(properties) => {
+                    tokens.push(token(Value::StartObject));
+                    for (name, property) in properties {
+                        push(property, &child(ValuePosition::Key(escaped(name))), tokens);
+                    }
+                    tokens.push(token(Value::EndObject));
+                },
+                Entity::Array(items) => {
+                    tokens.push(token(Value::StartArray));
+                    for (index, item) in items.iter().enumerate() {
+                        push(item, &child(ValuePosition::Index(index)), tokens);
+                    }
+                    tokens.push(token(Value::EndArray));
+                },
+                Entity::String(text) => tokens.push(token(Value::String(escaped(text)))),
+                Entity::Number(number) => tokens.push(token(Value::Number(number.to_string()))),
+                Entity::Bool(boolean) => tokens.push(token(Value::Boolean(*boolean))),
+                Entity::Null => tokens.push(token(Value::None))
+            }
+        }
+
+        let mut tokens = vec![];
+        push(value, &ValuePath::new(), &mut tokens);
+        tokens
+    }
+
+    #[test]
+    fn deserializes_nested_values() {
+        let entity = json!({ "Id": 7, "Name": "Russell", "Emails": ["r@example.com"], "Address": { "City": "Bern" }, "Note": null });
+
+        assert_eq!(from_tokens::<Entity>(tokens(&entity)).expect("failed to deserialize"), entity);
+
+        let lists : Vec<(u32, Option<bool>)> = from_tokens(tokens(&json!([[1, true], [2, null]]))).expect("failed to deserialize");
+        assert_eq!(lists, vec![(1, Some(true)), (2, None)]);
+        assert!(matches!(from_tokens::<(u32, bool)>(tokens(&json!([1, true, 2]))), Err(Error::Deserialize(_))));
+    }
+
+    #[tokio::test]
+    async fn unescapes_strings_of_responses() {
+        use bytes::Bytes;
+        use futures::StreamExt;
+        use futures::channel::mpsc::channel;
+        use crate::json_stream::stream::TokenIterator;
+        use crate::service::entity_stream::{EntityStreamer, RootEntityType};
+
+        let response = r#"{ "Name": "say \"hi\" \\ \u00e9\n", "Tags": ["a\/b"], "\"Quoted\"": "x" }"#;
+        let mut stream = crate::json_stream::stream::Stream::from_stream(futures::stream::iter(vec![Ok::<Bytes, reqwest::Error>(Bytes::from(response))])).expect("no stream");
+        let (sender, receiver) = channel(64);
+        let mut streamer = EntityStreamer::new(sender, RootEntityType::Object);
+
+        streamer.begin().await;
+        stream.advance().await.expect("invalid JSON");
+        stream.advance().await.expect("invalid JSON");
+        streamer.stream_content(&mut stream).await.expect("invalid JSON");
+        streamer.finish().await;
+
+        let tokens : Vec<Token> = receiver.map(|token| token.expect("failed token")).collect().await;
+        let entity : Entity = from_tokens(tokens).expect("failed to deserialize");
+        assert_eq!(entity, json!({ "Name": "say \"hi\" \\ é\n", "Tags": ["a/b"], "\"Quoted\"": "x" }));
+    }
+
+    #[test]
+    fn accepts_numbers_as_strings() {
+        // as sent with `IEEE754Compatible=true`
+        let numbers : HashMap<String, i64> = from_tokens(tokens(&json!({ "Id": "9007199254740993", "Count": 3 }))).expect("failed to deserialize");
+        assert_eq!(numbers["Id"], 9_007_199_254_740_993);
+        assert_eq!(numbers["Count"], 3);
+
+        let rate : f64 = from_tokens(vec![Token { path: ValuePath::new(), value: Value::String("1.5".to_owned()) }]).expect("failed to deserialize");
+        assert!((rate - 1.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn keeps_the_text_of_numbers_read_as_string() {
+        let decimal : String = from_tokens(vec![Token { path: ValuePath::new(), value: Value::Number("1234567890.1234567890123".to_owned()) }]).expect("failed to deserialize");
+
+        assert_eq!(decimal, "1234567890.1234567890123");
+    }
+
+    #[test]
+    fn deserializes_enums() {
+        // members by name, variants with content as object with a single key
+        let bounds : Vec<Bound<u32>> = from_tokens(tokens(&json!(["Unbounded", { "Included": 5 }, { "Excluded": "6" }]))).expect("failed to deserialize");
+
+        assert_eq!(bounds, vec![Bound::Unbounded, Bound::Included(5), Bound::Excluded(6)]);
+        assert!(matches!(from_tokens::<Bound<u32>>(tokens(&json!(7))), Err(Error::Deserialize(_))));
+    }
+
+    #[test]
+    fn reports_the_path_of_errors() {
+        let result = from_tokens::<HashMap<String, Vec<u32>>>(tokens(&json!({ "Scores": [1, "many"] })));
+
+        match result {
+            Err(Error::Deserialize(message)) => assert!(message.ends_with(" at Scores[1]"), "{}", message),
+            other => panic!("unexpected result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn fails_on_incomplete_entities() {
+        let mut incomplete = tokens(&json!({ "Id": 1 }));
+        incomplete.pop();
+
+        assert!(matches!(from_tokens::<HashMap<String, u32>>(incomplete), Err(Error::Deserialize(_))));
+    }
+}
@@ -0,0 +1,140 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::ready;
+use futures::sink::SinkExt;
+use futures::stream::{Stream, StreamExt};
+use futures::channel::mpsc::{channel, Sender, Receiver};
+use std::marker::PhantomData;
+use serde::de::DeserializeOwned;
+use crate::model::{Entity, Token, Value};
+use crate::error::Error;
+use crate::service::token_deserializer::from_tokens;
+
+type Producer = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// the tokens of a response, as they are read from the service.
+/// The loading is driven by polling the stream, no task gets spawned. Dropping the stream cancels the request.
+/// Errors are sent as last item of the stream
+pub struct TokenStream {
+    producer: Option<Producer>,
+    receiver: Receiver<Result<Token, Error>>
+}
+
+impl TokenStream {
+    /// tokens buffered between the response parser and the consumer
+    const BUFFER_SIZE: usize = 1_000;
+
+    pub(crate) fn new<P, F>(produce: P) -> TokenStream
+    where P: FnOnce(Sender<Result<Token, Error>>) -> F, F: Future<Output = ()> + Send + 'static {
+        let (sender, receiver) = channel::<Result<Token, Error>>(TokenStream::BUFFER_SIZE);
+        TokenStream { producer: Some(Box::pin(produce(sender))), receiver }
+    }
+
+    /// a stream only reporting the error, e.g. when the request could not be built
+    pub(crate) fn failed(error: Error) -> TokenStream {
+        TokenStream::new(|mut sender| async move {
+            let _ = sender.send(Err(error)).await;
+        })
+    }
+
+    /// combines the tokens into whole entities
+    pub fn entities(self) -> EntityStream<TokenStream> {
+        EntityStream::new(self)
+    }
+
+    /// deserializes the entities into `T`, e.g. a struct deriving `serde::Deserialize`
+    pub fn deserialize<T: DeserializeOwned>(self) -> EntityStream<TokenStream, T> {
+        EntityStream::new(self)
+    }
+
+    /// the channel based API: the stream is driven by a spawned task, until it ends or the receiver is dropped
+    pub fn into_receiver(self, buffer_size: usize) -> Receiver<Result<Token, Error>> {
+        let (sender, receiver) = channel::<Result<Token, Error>>(buffer_size);
+        tokio::spawn(async move {
+            let _ = self.map(Ok).forward(sender).await;
+        });
+
+        receiver
+    }
+}
+
+impl Stream for TokenStream {
+    type Item = Result<Token, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // the producer runs until the buffer is full or it waits for the service
+        if let Some(producer) = &mut this.producer {
+            if producer.as_mut().poll(cx).is_ready() {
+                this.producer = None;
+            }
+        }
+
+        this.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// entities (or values, for functions returning primitives) read from a stream of tokens:
+/// every element of the top level array, or the object itself for single entities.
+/// By default they are combined into JSON values, any other `DeserializeOwned` type can be used instead
+pub struct EntityStream<S, T = Entity> {
+    tokens: S,
+    grouper: TokenGrouper,
+    entity_type: PhantomData<fn() -> T>
+}
+
+impl<S, T> EntityStream<S, T>
+where S: Stream<Item = Result<Token, Error>> + Unpin, T: DeserializeOwned {
+    pub fn new(tokens: S) -> EntityStream<S, T> {
+        EntityStream { tokens, grouper: TokenGrouper::default(), entity_type: PhantomData }
+    }
+}
+
+impl<S, T> Stream for EntityStream<S, T>
+where S: Stream<Item = Result<Token, Error>> + Unpin, T: DeserializeOwned {
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(self.tokens.poll_next_unpin(cx)) {
+                Some(Ok(token)) => {
+                    if let Some(entity_tokens) = self.grouper.push(token) {
+                        return Poll::Ready(Some(from_tokens(entity_tokens)));
+                    }
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None)
+            }
+        }
+    }
+}
+
+/// collects the tokens belonging to one entity
+#[derive(Default)]
+struct TokenGrouper {
+    /// nesting level of the entities: 1 for the elements of a top level array, 0 for a single entity
+    entity_level: Option<usize>,
+    tokens: Vec<Token>
+}
+
+impl TokenGrouper {
+    /// returns the tokens of the entity completed by the token, if any
+    fn push(&mut self, token: Token) -> Option<Vec<Token>> {
+        let level = token.path.current_level();
+        let entity_level = *self.entity_level.get_or_insert(if level == 0 && token.value == Value::StartArray { 1 } else { 0 });
+        if level < entity_level {
+            return None;
+        }
+
+        let completes_entity = level == entity_level && !matches!(token.value, Value::StartObject | Value::StartArray);
+        self.tokens.push(token);
+
+        if completes_entity {
+            return Some(std::mem::take(&mut self.tokens));
+        }
+
+        None
+    }
+}
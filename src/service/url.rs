@@ -1,58 +1,270 @@
 ﻿use bytes::Bytes;
-use crate::model::MyError;
+use reqwest::{Method, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use url::Url;
+use percent_encoding::{utf8_percent_encode, percent_decode_str, AsciiSet, CONTROLS};
+use crate::error::Error;
+use crate::service::auth::SharedAuthenticator;
+use crate::service::client::ODataClient;
+use crate::service::error_response::parse_error_response;
+use crate::service::retry::{retry_after, RetryPolicy};
 
-async fn call_url(url: &str, _username: &Option<String>, _password: &Option<String>) -> Result<impl futures::stream::Stream<Item = reqwest::Result<Bytes>>, MyError> {
-    let url = String::from(url);
+/// characters to encode in the values of query options. OData expressions keep their readability,
+/// as `'`, `(`, `)`, `,`, `$`, `/`, `:`, `;`, `=` and `@` are allowed in a query, while
+/// everything changing the meaning of the query string (`&`, `#`, `+`, `%`, ...) and all non-ASCII characters get encoded
+const QUERY_VALUE: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'&').add(b'+').add(b'<').add(b'>')
+    .add(b'[').add(b'\\').add(b']').add(b'^').add(b'`').add(b'{').add(b'|').add(b'}');
 
-    let result = reqwest::get(&url).await?.bytes_stream();
-    Ok(result)
+/// the media type of OData JSON responses
+pub const JSON: &str = "application/json";
+/// JSON preferred, Atom for the services which only speak that
+pub const JSON_OR_ATOM: &str = "application/json, application/atom+xml;q=0.9, application/xml;q=0.8";
+
+pub fn parse_url(url: &str) -> Result<Url, Error> {
+    Url::parse(url).map_err(|err| Error::InvalidQuery(format!("Invalid URL '{}': {}", url, err)))
+}
+
+/// adds the (not yet encoded) query options to the URL.
+/// Query parameters already present in the URL are kept (e.g. `sap-client=100`), unless they are overridden by one of the options
+pub fn build_query_url(base_url: &str, options: &[(&str, String)]) -> Result<Url, Error> {
+    let mut url = parse_url(base_url)?;
+    if options.is_empty() {
+        return Ok(url);
+    }
+
+    let mut query_parts : Vec<String> = url.query()
+        .map(|query| query.split('&')
+            .filter(|part| !part.is_empty())
+            .filter(|part| {
+                let name = part.split('=').next().unwrap_or_default();
+                let decoded_name = percent_decode_str(name).decode_utf8_lossy();
+                !options.iter().any(|(option_name, _)| *option_name == decoded_name)
+            })
+            .map(|part| part.to_owned())
+            .collect())
+        .unwrap_or_default();
+
+    for (name, value) in options {
+        query_parts.push(format!("{}={}", utf8_percent_encode(name, QUERY_VALUE), utf8_percent_encode(value, QUERY_VALUE)));
+    }
+
+    url.set_query(Some(&query_parts.join("&")));
+    Ok(url)
+}
+
+/// method, additional headers and body of a request. Kept to be able to repeat the request
+pub(crate) struct RequestContent {
+    pub method: Method,
+    pub headers: HeaderMap,
+    pub body: Option<String>
+}
+
+impl RequestContent {
+    pub fn get() -> RequestContent {
+        RequestContent { method: Method::GET, headers: HeaderMap::new(), body: None }
+    }
+
+    /// a `GET` asking for the media type (`Accept`), e.g. for JSON, which V2 services only send on request
+    pub fn get_accepting(accept: &'static str) -> RequestContent {
+        let mut content = RequestContent::get();
+        content.headers.insert(ACCEPT, HeaderValue::from_static(accept));
+        content
+    }
+}
+
+async fn send_request(client: &reqwest::Client, url: &Url, content: &RequestContent, authenticator: &Option<SharedAuthenticator>) -> Result<reqwest::Response, Error> {
+    let mut request = client.request(content.method.clone(), url.clone()).headers(content.headers.clone());
+    if let Some(body) = &content.body {
+        request = request.body(body.clone());
+    }
+
+    if let Some(authenticator) = authenticator {
+        request = authenticator.authenticate(request).await?;
+    }
+
+    Ok(request.send().await?)
+}
+
+async fn send_authenticated_request(client: &reqwest::Client, url: &Url, content: &RequestContent, authenticator: &Option<SharedAuthenticator>) -> Result<reqwest::Response, Error> {
+    let mut response = send_request(client, url, content, authenticator).await?;
+
+    // expired or rotated tokens: give the authenticator one chance to renew them
+    if response.status() == StatusCode::UNAUTHORIZED {
+        if let Some(active_authenticator) = authenticator {
+            if active_authenticator.refresh().await? {
+                response = send_request(client, url, content, authenticator).await?;
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+async fn execute_request(client: &ODataClient, url: &Url, content: &RequestContent, authenticator: &Option<SharedAuthenticator>) -> Result<reqwest::Response, Error> {
+    let response = send_authenticated_request(client.http(), url, content, authenticator).await?;
+
+    if !response.status().is_success() {
+        return Err(read_error_response(url, response).await);
+    }
+
+    Ok(response)
+}
+
+/// like `execute_request`, repeating the request as long as the policy allows.
+/// Only the request itself is repeated: the body of a successful response is streamed, a connection lost while receiving it is not repeated
+async fn execute_request_with_retries(client: &ODataClient, url: &Url, content: &RequestContent, authenticator: &Option<SharedAuthenticator>, retry: &RetryPolicy) -> Result<reqwest::Response, Error> {
+    let mut attempt = 0;
+    loop {
+        let (error, requested_wait) = match send_authenticated_request(client.http(), url, content, authenticator).await {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) => {
+                let requested_wait = retry_after(response.headers());
+                (read_error_response(url, response).await, requested_wait)
+            },
+            Err(err) => (err, None)
+        };
+
+        match retry.wait_before_retry(attempt, &error, requested_wait) {
+            Some(wait) => tokio::time::sleep(wait).await,
+            None => return Err(error)
+        }
+
+        attempt += 1;
+    }
+}
+
+async fn call_url(client: &ODataClient, url: &Url, authenticator: &Option<SharedAuthenticator>) -> Result<impl futures::stream::Stream<Item = reqwest::Result<Bytes>>, Error> {
+    let response = execute_request(client, url, &RequestContent::get(), authenticator).await?;
+
+    Ok(response.bytes_stream())
+}
+
+/// turns a response with an error status into an error, including the OData error the service sent (if any)
+async fn read_error_response(url: &Url, response: reqwest::Response) -> Error {
+    let status = response.status();
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(|value| value.to_owned());
+    let service_error = match response.text().await {
+        Ok(body) => parse_error_response(content_type.as_deref(), &body),
+        Err(_) => None
+    };
+
+    Error::Http { status, url: url.to_string(), service_error: service_error.map(Box::new) }
 }
 
 #[derive(Clone)]
 pub struct SingleUrlCaller {
-    url: String,
-    username: Option<String>,
-    password: Option<String>
+    url: Url,
+    authenticator: Option<SharedAuthenticator>,
+    client: ODataClient
 }
 
 impl SingleUrlCaller {
-    pub fn new(url: String, username: Option<String>, password: Option<String>) -> SingleUrlCaller {
-        SingleUrlCaller { url, username, password }
+    pub fn new(url: Url, authenticator: Option<SharedAuthenticator>) -> SingleUrlCaller {
+        SingleUrlCaller { url, authenticator, client: ODataClient::shared() }
+    }
+
+    /// sends the request with the client, instead of the shared one
+    pub fn with_client(mut self, client: Option<ODataClient>) -> SingleUrlCaller {
+        if let Some(client) = client {
+            self.client = client;
+        }
+
+        self
     }
 
-    pub(crate) async fn call(&self) -> Result<impl futures::stream::Stream<Item = reqwest::Result<Bytes>>, MyError> {
-        let content  = call_url(&self.url, &self.username, &self.password).await?;
+    pub(crate) async fn call(&self) -> Result<impl futures::stream::Stream<Item = reqwest::Result<Bytes>>, Error> {
+        let content  = call_url(&self.client, &self.url, &self.authenticator).await?;
 
         Ok(content)
     }
+
+    /// sends the request as it is, e.g. to modify the entity. Error status codes are returned as `Error::Http`
+    pub(crate) async fn send(&self, content: &RequestContent) -> Result<reqwest::Response, Error> {
+        execute_request(&self.client, &self.url, content, &self.authenticator).await
+    }
 }
 
 #[derive(Clone)]
 pub struct MultiUrlCaller {
-    starting_url: String,
-    username: Option<String>,
-    password: Option<String>
+    starting_url: Url,
+    authenticator: Option<SharedAuthenticator>,
+    track_changes: bool,
+    max_page_size: Option<usize>,
+    retry: RetryPolicy,
+    client: ODataClient
 }
 
 impl MultiUrlCaller{
-    pub fn new(starting_url: String, username: Option<String>, password: Option<String>) -> MultiUrlCaller {
-        MultiUrlCaller { starting_url, username, password }
+    pub fn new(starting_url: Url, authenticator: Option<SharedAuthenticator>) -> MultiUrlCaller {
+        MultiUrlCaller { starting_url, authenticator, track_changes: false, max_page_size: None, retry: RetryPolicy::none(), client: ODataClient::shared() }
     }
 
-    pub(crate) fn starting_link_marker(&self) -> &String {
-        &self.starting_url
+    /// requests the pages with the client, instead of the shared one
+    pub fn with_client(mut self, client: Option<ODataClient>) -> MultiUrlCaller {
+        if let Some(client) = client {
+            self.client = client;
+        }
+
+        self
+    }
+
+    /// repeats the request of a page when it failed temporarily, continuing with the pages loaded so far
+    pub fn retrying(mut self, retry: RetryPolicy) -> MultiUrlCaller {
+        self.retry = retry;
+        self
+    }
+
+    /// asks for a delta link at the end of the pages (`Prefer: odata.track-changes`)
+    pub fn tracking_changes(mut self, track_changes: bool) -> MultiUrlCaller {
+        self.track_changes = track_changes;
+        self
+    }
+
+    /// asks for pages of at most this many entities (`Prefer: odata.maxpagesize`)
+    pub fn with_max_page_size(mut self, max_page_size: Option<usize>) -> MultiUrlCaller {
+        self.max_page_size = max_page_size;
+        self
+    }
+
+    pub(crate) fn starting_link_marker(&self) -> String {
+        self.starting_url.to_string()
+    }
+
+    /// next and delta links may be relative to the initial request
+    pub(crate) fn absolute_link(&self, link: &str) -> Result<Url, Error> {
+        self.starting_url.join(link).map_err(|err| Error::Protocol(format!("Invalid link '{}': {}", link, err)))
     }
 
-    pub(crate) async fn next(&self, odata_next_link: &Option<String>) -> Result<Option<impl futures::stream::Stream<Item = reqwest::Result<Bytes>>>, MyError> {
+    /// the response for the link, its content type tells JSON and Atom apart
+    pub(crate) async fn next(&self, odata_next_link: &Option<String>) -> Result<Option<reqwest::Response>, Error> {
         return match odata_next_link {
             Some(link) => {
-                let content  = call_url(&link, &self.username, &self.password).await?;
+                let next_url = self.absolute_link(link)?;
+
+                // every page is requested with the same authentication as the first one
+                let mut content = RequestContent::get_accepting(JSON_OR_ATOM);
+                let mut preferences = vec![];
+                if self.track_changes {
+                    preferences.push("odata.track-changes".to_owned());
+                }
 
-                Ok(Some(content))
+                if let Some(max_page_size) = self.max_page_size {
+                    preferences.push(format!("odata.maxpagesize={}", max_page_size));
+                }
+
+                if !preferences.is_empty() {
+                    let prefer = HeaderValue::from_str(&preferences.join(", ")).map_err(|err| Error::InvalidQuery(format!("Invalid Prefer header: {}", err)))?;
+                    content.headers.insert("Prefer", prefer);
+                }
+
+                let response = execute_request_with_retries(&self.client, &next_url, &content, &self.authenticator, &self.retry).await?;
+
+                Ok(Some(response))
             },
             None =>  {
                 Ok(None)
             }
         };
     }
-}
+}
\ No newline at end of file
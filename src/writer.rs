@@ -1,51 +1,86 @@
-use std::fs::File;
-use std::io::{prelude::*, Write, stdout, BufWriter};
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, Write, stdout, BufWriter, ErrorKind, SeekFrom};
 use std::path::Path;
 use futures::channel::mpsc::{channel, Sender, Receiver};
 use futures::stream::StreamExt;
 use futures::executor::block_on;
+use crate::error::Error;
+
+/// channel carrying converted output (or the error that ended it) to the writer
+pub type OutputSender = Sender<Result<String, Error>>;
+pub type OutputReceiver = Receiver<Result<String, Error>>;
 
 pub struct FileWriter {
-    output: Box<dyn Write>
+    output: Box<dyn Write>,
+    /// the bytes in the output so far
+    position: u64
 }
 
 impl FileWriter {
     const CHANNEL_BUFFER_SIZE: usize = 1_000_000;
     const WRITE_BUFFER_SIZE: usize = 1_048_576;
 
-    pub fn setup_channel() -> (Sender<Box<String>>, Receiver<Box<String>>) {
-        channel::<Box<String>>(FileWriter::CHANNEL_BUFFER_SIZE)
+    pub fn setup_channel() -> (OutputSender, OutputReceiver) {
+        channel::<Result<String, Error>>(FileWriter::CHANNEL_BUFFER_SIZE)
     }
 
-    pub fn new(out_file: &std::ffi::OsStr) -> FileWriter {
+    pub fn new(out_file: &std::ffi::OsStr) -> Result<FileWriter, Error> {
         let output : Box<dyn Write> = if out_file == std::ffi::OsStr::new("-") {
             Box::new(BufWriter::with_capacity(FileWriter::WRITE_BUFFER_SIZE, stdout()))
 
         } else { 
-            Box::new(BufWriter::with_capacity(FileWriter::WRITE_BUFFER_SIZE, File::create(Path::new(out_file)).expect("Could not create output file")))
+            Box::new(BufWriter::with_capacity(FileWriter::WRITE_BUFFER_SIZE, File::create(Path::new(out_file))?))
         };
 
-        FileWriter { output }
+        Ok(FileWriter { output, position: 0 })
+    }
+
+    /// writes on after the first `offset` bytes of the file, anything behind them (e.g. of an interrupted run) is dropped
+    pub fn continuing(out_file: &std::ffi::OsStr, offset: u64) -> Result<FileWriter, Error> {
+        let mut file = OpenOptions::new().write(true).open(Path::new(out_file))?;
+        if file.metadata()?.len() < offset {
+            let message = format!("{} is shorter than {} bytes", Path::new(out_file).display(), offset);
+            return Err(Error::Io(std::io::Error::new(ErrorKind::UnexpectedEof, message)));
+        }
+
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(FileWriter { output: Box::new(BufWriter::with_capacity(FileWriter::WRITE_BUFFER_SIZE, file)), position: offset })
+    }
+
+    /// the number of bytes in the output, including those before continuing it
+    pub fn position(&self) -> u64 {
+        self.position
     }
 
-    pub async fn write(&mut self, receiver: Receiver<Box<String>>) {
-        let mut failed = false;
-        let running_foreach = receiver.for_each(move |next_object| {
-            if failed {
+    /// writes everything received, until the first error. That error (or an error while writing) is returned
+    pub async fn write(&mut self, receiver: OutputReceiver) -> Result<(), Error> {
+        let mut result = Ok(());
+        let output = &mut self.output;
+        let position = &mut self.position;
+        let running_foreach = receiver.for_each(|next_object| {
+            if result.is_err() {
                 return futures::future::ready(());
             }
 
-            match self.output.write((*next_object).as_bytes()) {
-                Ok(_) => (),
-                Err(e) => {
-                    failed = true;
-                    eprintln!("{}", e);
-                    return futures::future::ready(());
-                }
+            match next_object {
+                Ok(content) => {
+                    match output.write_all(content.as_bytes()) {
+                        Ok(()) => *position += content.len() as u64,
+                        Err(e) => result = Err(Error::Io(e))
+                    }
+                },
+                Err(err) => result = Err(err)
             }
             futures::future::ready(())
         });
 
-        block_on(running_foreach)
+        block_on(running_foreach);
+
+        // everything received before the error is kept
+        let flushed = self.output.flush();
+        result?;
+        Ok(flushed?)
     }
 }
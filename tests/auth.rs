@@ -0,0 +1,136 @@
+mod common;
+
+use std::sync::Arc;
+use futures::StreamExt;
+use serde_json::json;
+use rodata::error::Error;
+use rodata::model::{Entity, EntitySetQuery};
+use rodata::provider::entity_set::EntitySetIterator;
+use rodata::service::auth::{ClientCredentialsAuthenticator, SharedAuthenticator, TokenSourceAuthenticator};
+use common::{MockRequest, MockResponse, MockServer};
+
+/// two pages of people, only for requests with the token
+fn people(request: &MockRequest, token: &str) -> MockResponse {
+    if request.header("Authorization") != Some(&format!("Bearer {}", token)) {
+        return MockResponse::status(401);
+    }
+
+    match request.path.as_str() {
+        "/svc/People" => MockResponse::json(json!({ "value": [{ "Id": 1 }], "@odata.nextLink": "People?page=2" })),
+        _ => MockResponse::json(json!({ "value": [{ "Id": 2 }] }))
+    }
+}
+
+fn token_response(access_token: &str, expires_in: serde_json::Value) -> MockResponse {
+    MockResponse::json(json!({ "access_token": access_token, "token_type": "Bearer", "expires_in": expires_in }))
+}
+
+async fn load(server: &MockServer, authenticator: SharedAuthenticator) -> Vec<Result<Entity, Error>> {
+    let mut query = EntitySetQuery::new(server.url("/svc/People"));
+    query.authenticator = Some(authenticator);
+
+    EntitySetIterator::new().stream_entity_set(query).entities().collect().await
+}
+
+fn client_credentials(server: &MockServer) -> SharedAuthenticator {
+    Arc::new(ClientCredentialsAuthenticator::new(server.url("/token"), "roc".to_owned(), "s3cret".to_owned(), Some("api://svc/.default".to_owned())))
+}
+
+fn assert_loaded(entities: &[Result<Entity, Error>]) {
+    let ids : Vec<Option<u64>> = entities.iter().map(|entity| entity.as_ref().ok().and_then(|entity| entity["Id"].as_u64())).collect();
+    assert_eq!(ids, vec![Some(1), Some(2)], "{:?}", entities);
+}
+
+#[tokio::test]
+async fn requests_a_client_credentials_token_once() {
+    let server = MockServer::start(|request| match request.path.as_str() {
+        "/token" => token_response("token-1", json!(3600)),
+        _ => people(request, "token-1")
+    }).await;
+
+    let entities = load(&server, client_credentials(&server)).await;
+
+    assert_loaded(&entities);
+    let token_requests : Vec<MockRequest> = server.requests().into_iter().filter(|request| request.path == "/token").collect();
+    assert_eq!(token_requests.len(), 1);
+    assert_eq!(token_requests[0].method, "POST");
+    assert_eq!(token_requests[0].body, "grant_type=client_credentials&client_id=roc&client_secret=s3cret&scope=api%3A%2F%2Fsvc%2F.default");
+}
+
+#[tokio::test]
+async fn renews_tokens_about_to_expire() {
+    // valid for less than the margin, every request needs a new one. Some identity providers send the seconds as string
+    let server = MockServer::start(|request| match request.path.as_str() {
+        "/token" => token_response("token-1", json!("10")),
+        _ => people(request, "token-1")
+    }).await;
+
+    let entities = load(&server, client_credentials(&server)).await;
+
+    assert_loaded(&entities);
+    assert_eq!(server.requests_of("/token"), 2);
+}
+
+#[tokio::test]
+async fn requests_a_new_token_after_401() {
+    // the first token is revoked before it expires
+    let server = MockServer::start(|request| match (request.path.as_str(), request.attempt) {
+        ("/token", 0) => token_response("revoked", json!(3600)),
+        ("/token", _) => token_response("token-2", json!(3600)),
+        _ => people(request, "token-2")
+    }).await;
+
+    let entities = load(&server, client_credentials(&server)).await;
+
+    assert_loaded(&entities);
+    assert_eq!(server.requests_of("/token"), 2);
+    assert_eq!(server.requests_of("/svc/People"), 2);
+    assert_eq!(server.requests_of("/svc/People?page=2"), 1);
+}
+
+#[tokio::test]
+async fn fails_if_the_token_endpoint_rejects_the_client() {
+    let server = MockServer::start(|request| match request.path.as_str() {
+        "/token" => MockResponse::status(400).with_body("invalid_client"),
+        _ => people(request, "token-1")
+    }).await;
+
+    let entities = load(&server, client_credentials(&server)).await;
+
+    assert!(matches!(entities.as_slice(), [Err(Error::Authentication(message))] if message.contains("invalid_client")), "{:?}", entities);
+    assert_eq!(server.requests_of("/svc/People"), 0);
+}
+
+#[tokio::test]
+async fn reads_the_token_again_after_401() {
+    let token_file = std::env::temp_dir().join(format!("rodata-token-{}", std::process::id()));
+    std::fs::write(&token_file, "old-token\n").expect("could not write token file");
+
+    // another process rotates the token while the old one is in use
+    let rotated_file = token_file.clone();
+    let server = MockServer::start(move |request| {
+        if request.header("Authorization") == Some("Bearer old-token") {
+            std::fs::write(&rotated_file, "new-token").expect("could not rotate token");
+        }
+
+        people(request, "new-token")
+    }).await;
+
+    let entities = load(&server, Arc::new(TokenSourceAuthenticator::from_file(&token_file))).await;
+    let _ = std::fs::remove_file(&token_file);
+
+    assert_loaded(&entities);
+    assert_eq!(server.requests_of("/svc/People"), 2);
+}
+
+#[tokio::test]
+async fn does_not_repeat_the_request_with_the_same_token() {
+    let variable = format!("RODATA_TEST_TOKEN_{}", std::process::id());
+    std::env::set_var(&variable, "unknown-token");
+
+    let server = MockServer::start(|request| people(request, "token-1")).await;
+    let entities = load(&server, Arc::new(TokenSourceAuthenticator::from_environment(variable.as_str()))).await;
+
+    assert!(matches!(entities.as_slice(), [Err(Error::Http { status, .. })] if status.as_u16() == 401), "{:?}", entities);
+    assert_eq!(server.requests_of("/svc/People"), 1);
+}
@@ -0,0 +1,167 @@
+//! a minimal HTTP/1.1 server on a local port, answering every request with the response of a handler
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    /// path and query, as sent
+    pub path: String,
+    /// the names in lower case
+    pub headers: HashMap<String, String>,
+    pub body: String,
+    /// how often the same method and path were requested before
+    pub attempt: usize
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|value| value.as_str())
+    }
+}
+
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    /// closes the connection after this many bytes of the body
+    cut_after: Option<usize>
+}
+
+impl MockResponse {
+    pub fn status(status: u16) -> MockResponse {
+        MockResponse { status, headers: vec![], body: String::new(), cut_after: None }
+    }
+
+    pub fn json(body: serde_json::Value) -> MockResponse {
+        MockResponse::status(200)
+            .with_header("Content-Type", "application/json")
+            .with_body(&body.to_string())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn with_body(mut self, body: &str) -> MockResponse {
+        self.body = body.to_owned();
+        self
+    }
+
+    /// announces the whole body, but loses the connection after the first bytes of it
+    pub fn cut_after(mut self, bytes: usize) -> MockResponse {
+        self.cut_after = Some(bytes);
+        self
+    }
+}
+
+type Handler = dyn Fn(&MockRequest) -> MockResponse + Send + Sync;
+
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>
+}
+
+impl MockServer {
+    pub async fn start<H>(handler: H) -> MockServer
+    where H: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("no local port");
+        let url = format!("http://{}", listener.local_addr().expect("no local address"));
+        let requests = Arc::new(Mutex::new(Vec::<MockRequest>::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((connection, _)) = listener.accept().await {
+                tokio::spawn(answer(connection, handler.clone(), received.clone()));
+            }
+        });
+
+        MockServer { url, requests }
+    }
+
+    /// the URL of the path on the server
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// how often the path (including the query) was requested
+    pub fn requests_of(&self, path: &str) -> usize {
+        self.requests().iter().filter(|request| request.path == path).count()
+    }
+}
+
+async fn answer(mut connection: TcpStream, handler: Arc<Handler>, requests: Arc<Mutex<Vec<MockRequest>>>) {
+    let request = match read_request(&mut connection).await {
+        Some(request) => request,
+        None => return
+    };
+
+    let request = {
+        let mut requests = requests.lock().unwrap();
+        let attempt = requests.iter().filter(|earlier| earlier.method == request.method && earlier.path == request.path).count();
+        let request = MockRequest { attempt, ..request };
+        requests.push(request.clone());
+        request
+    };
+
+    let response = handler(&request);
+    let mut head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n", response.status, response.body.len());
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let body = response.body.as_bytes();
+    let sent = &body[..response.cut_after.unwrap_or(body.len()).min(body.len())];
+    let _ = connection.write_all(head.as_bytes()).await;
+    let _ = connection.write_all(sent).await;
+    let _ = connection.shutdown().await;
+}
+
+async fn read_request(connection: &mut TcpStream) -> Option<MockRequest> {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let head_end = loop {
+        if let Some(position) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position;
+        }
+
+        let read = connection.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        received.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&received[..head_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let path = request_line.next()?.to_owned();
+    let headers : HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+        .collect();
+
+    let length = headers.get("content-length").and_then(|length| length.parse::<usize>().ok()).unwrap_or(0);
+    let mut body = received[head_end + 4..].to_vec();
+    while body.len() < length {
+        let read = connection.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&buffer[..read]);
+    }
+
+    Some(MockRequest { method, path, headers, body: String::from_utf8_lossy(&body).to_string(), attempt: 0 })
+}
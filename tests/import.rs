@@ -0,0 +1,163 @@
+mod common;
+
+use futures::StreamExt;
+use serde_json::json;
+use rodata::convert::Converter;
+use rodata::convert::csv::CsvConverter;
+use rodata::model::{EntityImportQuery, EntitySetQuery, ImportMode};
+use rodata::provider::entity_import::{EntityImporter, ImportInput};
+use rodata::provider::entity_set::EntitySetIterator;
+use rodata::reader::CsvTable;
+use rodata::writer::FileWriter;
+use common::{MockRequest, MockResponse, MockServer};
+
+const METADATA: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<edmx:Edmx Version="4.0" xmlns:edmx="http://docs.oasis-open.org/odata/ns/edmx">
+  <edmx:DataServices>
+    <Schema Namespace="Sample" xmlns="http://docs.oasis-open.org/odata/ns/edm">
+      <EntityType Name="Person">
+        <Key><PropertyRef Name="Id"/></Key>
+        <Property Name="Id" Type="Edm.Int64" Nullable="false"/>
+        <Property Name="Name" Type="Edm.String"/>
+        <Property Name="Address" Type="Sample.Address"/>
+        <Property Name="Emails" Type="Collection(Edm.String)"/>
+      </EntityType>
+      <ComplexType Name="Address">
+        <Property Name="City" Type="Edm.String"/>
+        <Property Name="Zip" Type="Edm.String"/>
+      </ComplexType>
+      <EntityContainer Name="Container">
+        <EntitySet Name="People" EntityType="Sample.Person"/>
+      </EntityContainer>
+    </Schema>
+  </edmx:DataServices>
+</edmx:Edmx>"#;
+
+/// answers every operation of a `$batch` with `201`, except the ones creating `"Invalid"`
+fn service(request: &MockRequest) -> MockResponse {
+    match request.path.as_str() {
+        "/svc/$metadata" => MockResponse::status(200).with_header("Content-Type", "application/xml").with_body(METADATA),
+        "/svc/$batch" => {
+            let responses : Vec<_> = request.body.split("Content-ID: ").skip(1)
+                .map(|operation| {
+                    let id = operation.lines().next().unwrap_or_default().trim();
+                    let status = if operation.contains("\"Invalid\"") { 400 } else { 201 };
+                    json!({ "id": id, "status": status })
+                })
+                .collect();
+
+            MockResponse::json(json!({ "responses": responses }))
+        },
+        _ => MockResponse::status(201)
+    }
+}
+
+fn people() -> ImportInput {
+    ImportInput::Entities(vec![
+        json!({ "Id": 1, "Name": "Russell" }),
+        json!({ "Id": 2, "Name": "Invalid" }),
+        json!({ "Id": 3, "Name": "Scott" })
+    ])
+}
+
+#[tokio::test]
+async fn sends_the_rows_in_batches() {
+    let server = MockServer::start(service).await;
+    let mut query = EntityImportQuery::new(server.url("/svc/People"));
+    query.batch_size = Some(2);
+
+    let importer = EntityImporter::new();
+    let (requests, errors) = importer.prepare(&query, people()).await.expect("failed to prepare");
+    let report = importer.execute(&query, requests).await;
+
+    assert!(errors.is_empty());
+    assert_eq!(report.imported, 2);
+    assert_eq!(report.failed.iter().map(|row_error| row_error.row).collect::<Vec<_>>(), vec![2]);
+
+    let batches : Vec<MockRequest> = server.requests().into_iter().filter(|request| request.path == "/svc/$batch").collect();
+    assert_eq!(batches.len(), 2);
+    // every row is a change set of its own, referring to the entity set relative to the service
+    assert_eq!(batches[0].body.matches("multipart/mixed; boundary=changeset_").count(), 2);
+    assert!(batches[0].body.contains("POST People HTTP/1.1"));
+}
+
+#[tokio::test]
+async fn sends_single_requests_without_batch_size() {
+    let server = MockServer::start(service).await;
+    let query = EntityImportQuery::new(server.url("/svc/People"));
+
+    let importer = EntityImporter::new();
+    let (requests, _) = importer.prepare(&query, people()).await.expect("failed to prepare");
+    let report = importer.execute(&query, requests).await;
+
+    assert_eq!(report.imported, 3);
+    assert_eq!(server.requests_of("/svc/People"), 3);
+    assert_eq!(server.requests_of("/svc/$batch"), 0);
+}
+
+/// the entity set as CSV, exported like `roc entityset` does
+async fn export_csv(server: &MockServer) -> String {
+    let tokens = EntitySetIterator::new().iterate_entity_set(EntitySetQuery::new(server.url("/svc/People")));
+
+    let (output_sender, output_receiver) = FileWriter::setup_channel();
+    CsvConverter::new().convert(tokens, output_sender);
+    output_receiver.map(|line| line.expect("failed to convert").to_string()).collect::<Vec<String>>().await.concat()
+}
+
+// the converter blocks its thread while the entity set is loaded, like in `roc`
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn imports_complex_values_as_exported() {
+    let person = json!({
+        "Id": 1,
+        "Name": "Russell",
+        "Address": { "City": "Bern; \"Old\" Town \\ Center é", "Zip": "01234" },
+        "Emails": ["russell@example.com", "", "r / w"]
+    });
+    let response = json!({ "value": [person] });
+    let server = MockServer::start(move |request| match request.path.as_str() {
+        "/svc/People" if request.method == "GET" => MockResponse::json(response.clone()),
+        _ => service(request)
+    }).await;
+
+    let csv = export_csv(&server).await;
+    let table = CsvTable::parse(&csv, ';').expect("invalid CSV");
+
+    let (requests, errors) = EntityImporter::new().prepare(&EntityImportQuery::new(server.url("/svc/People")), ImportInput::Csv(table)).await.expect("failed to prepare");
+
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(requests[0].payload, person);
+}
+
+const V2_METADATA: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<edmx:Edmx Version="1.0" xmlns:edmx="http://schemas.microsoft.com/ado/2007/06/edmx" xmlns:m="http://schemas.microsoft.com/ado/2007/08/dataservices/metadata">
+  <edmx:DataServices m:DataServiceVersion="2.0">
+    <Schema Namespace="Sample" xmlns="http://schemas.microsoft.com/ado/2008/09/edm">
+      <EntityType Name="Order">
+        <Key><PropertyRef Name="Number"/><PropertyRef Name="Customer"/></Key>
+        <Property Name="Number" Type="Edm.Int64" Nullable="false"/>
+        <Property Name="Customer" Type="Edm.Guid" Nullable="false"/>
+        <Property Name="Note" Type="Edm.String"/>
+      </EntityType>
+      <EntityContainer Name="Container" m:IsDefaultEntityContainer="true">
+        <EntitySet Name="Orders" EntityType="Sample.Order"/>
+      </EntityContainer>
+    </Schema>
+  </edmx:DataServices>
+</edmx:Edmx>"#;
+
+#[tokio::test]
+async fn writes_v2_key_literals() {
+    let server = MockServer::start(|request| match request.path.as_str() {
+        "/v2/$metadata" => MockResponse::status(200).with_header("Content-Type", "application/xml").with_body(V2_METADATA),
+        _ => MockResponse::status(204)
+    }).await;
+
+    let mut query = EntityImportQuery::new(server.url("/v2/Orders"));
+    query.mode = ImportMode::Update;
+    let order = json!({ "Number": 5, "Customer": "01234567-89ab-cdef-0123-456789abcdef", "Note": "rush" });
+
+    let (requests, errors) = EntityImporter::new().prepare(&query, ImportInput::Entities(vec![order])).await.expect("failed to prepare");
+
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(requests[0].url.ends_with("/v2/Orders(Customer=guid'01234567-89ab-cdef-0123-456789abcdef',Number=5L)"), "{}", requests[0].url);
+}
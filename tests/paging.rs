@@ -0,0 +1,75 @@
+mod common;
+
+use futures::StreamExt;
+use serde_json::json;
+use rodata::error::Error;
+use rodata::model::{Checkpoint, Entity, EntitySetQuery};
+use rodata::provider::entity_set::EntitySetIterator;
+use common::{MockRequest, MockResponse, MockServer};
+
+/// pages of three entities, numbered by the page, up to page 3
+fn page(request: &MockRequest) -> MockResponse {
+    let page = request.path.split("page=").nth(1).and_then(|page| page.parse::<u64>().ok()).unwrap_or(1);
+    let mut body = json!({ "value": (0..3).map(|index| json!({ "Id": page * 10 + index })).collect::<Vec<_>>() });
+    if page < 3 {
+        body["@odata.nextLink"] = json!(format!("People?page={}", page + 1));
+    }
+
+    MockResponse::json(body)
+}
+
+async fn load_rows(server: &MockServer, max_rows: usize) -> (Vec<Result<Entity, Error>>, Checkpoint) {
+    let mut query = EntitySetQuery::new(server.url("/People"));
+    query.max_rows = Some(max_rows);
+
+    let (tokens, _count, checkpoint) = EntitySetIterator::new().stream_entity_set_with_progress(query);
+    let entities = tokens.entities().collect().await;
+    (entities, checkpoint.await.expect("no checkpoint"))
+}
+
+#[tokio::test]
+async fn continues_after_a_row_limit_at_the_end_of_a_page() {
+    let server = MockServer::start(page).await;
+
+    let (entities, checkpoint) = load_rows(&server, 3).await;
+
+    assert_eq!(entities.len(), 3);
+    assert_eq!(checkpoint, Checkpoint::new(Some(server.url("/People?page=2")), 1, 3));
+}
+
+#[tokio::test]
+async fn does_not_continue_after_a_row_limit_within_a_page() {
+    let server = MockServer::start(page).await;
+
+    let (entities, checkpoint) = load_rows(&server, 4).await;
+
+    assert_eq!(entities.len(), 4);
+    assert_eq!(checkpoint, Checkpoint::truncated(2, 4));
+    assert!(!checkpoint.is_complete());
+
+    let (tokens, _checkpoint) = EntitySetIterator::new().stream_page(EntitySetQuery::new(server.url("/People")), Some(checkpoint));
+    let entities : Vec<Result<Entity, Error>> = tokens.entities().collect().await;
+    assert!(matches!(entities.last(), Some(Err(Error::InvalidQuery(_)))));
+}
+
+#[tokio::test]
+async fn continues_page_by_page() {
+    let server = MockServer::start(page).await;
+    let mut checkpoint = None;
+    let mut ids = vec![];
+
+    loop {
+        let (tokens, next_checkpoint) = EntitySetIterator::new().stream_page(EntitySetQuery::new(server.url("/People")), checkpoint);
+        let entities : Vec<Result<Entity, Error>> = tokens.entities().collect().await;
+        ids.extend(entities.into_iter().map(|entity| entity.expect("failed page")["Id"].as_u64().unwrap_or_default()));
+
+        let next_checkpoint = next_checkpoint.await.expect("no checkpoint");
+        if next_checkpoint.is_complete() {
+            assert_eq!(next_checkpoint, Checkpoint::new(None, 3, 9));
+            break;
+        }
+        checkpoint = Some(next_checkpoint);
+    }
+
+    assert_eq!(ids, vec![10, 11, 12, 20, 21, 22, 30, 31, 32]);
+}
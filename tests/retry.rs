@@ -0,0 +1,94 @@
+mod common;
+
+use std::time::Duration;
+use futures::StreamExt;
+use serde_json::json;
+use rodata::error::Error;
+use rodata::model::{Entity, EntitySetQuery};
+use rodata::provider::entity_set::EntitySetIterator;
+use rodata::service::retry::RetryPolicy;
+use common::{MockRequest, MockResponse, MockServer};
+
+/// pages of three entities, numbered by the page, up to page 3
+fn page(request: &MockRequest) -> MockResponse {
+    let page = request.path.split("page=").nth(1).and_then(|page| page.parse::<u64>().ok()).unwrap_or(1);
+    let mut body = json!({ "value": (0..3).map(|index| json!({ "Id": page * 10 + index })).collect::<Vec<_>>() });
+    if page < 3 {
+        body["@odata.nextLink"] = json!(format!("People?page={}", page + 1));
+    }
+
+    MockResponse::json(body)
+}
+
+fn quick_retries(retries: u32) -> RetryPolicy {
+    RetryPolicy { retries, initial_wait: Duration::from_millis(10), max_wait: Duration::from_millis(50) }
+}
+
+async fn load(server: &MockServer, retry: RetryPolicy) -> Vec<Result<Entity, Error>> {
+    let mut query = EntitySetQuery::new(server.url("/People"));
+    query.retry = retry;
+
+    EntitySetIterator::new().stream_entity_set(query).entities().collect().await
+}
+
+fn ids(entities: &[Result<Entity, Error>]) -> Vec<u64> {
+    entities.iter().filter_map(|entity| entity.as_ref().ok()).filter_map(|entity| entity["Id"].as_u64()).collect()
+}
+
+#[tokio::test]
+async fn repeats_pages_failing_temporarily() {
+    let server = MockServer::start(|request| match (request.path.as_str(), request.attempt) {
+        ("/People?page=2", 0) => MockResponse::status(503).with_header("Retry-After", "1"),
+        ("/People?page=3", 0) | ("/People?page=3", 1) => MockResponse::status(429),
+        _ => page(request)
+    }).await;
+
+    let entities = load(&server, quick_retries(3)).await;
+
+    assert_eq!(ids(&entities), vec![10, 11, 12, 20, 21, 22, 30, 31, 32]);
+    assert_eq!(server.requests_of("/People"), 1);
+    assert_eq!(server.requests_of("/People?page=2"), 2);
+    assert_eq!(server.requests_of("/People?page=3"), 3);
+}
+
+#[tokio::test]
+async fn gives_up_after_the_retries() {
+    let server = MockServer::start(|request| match request.path.as_str() {
+        "/People?page=2" => MockResponse::status(503),
+        _ => page(request)
+    }).await;
+
+    let entities = load(&server, quick_retries(2)).await;
+
+    assert_eq!(ids(&entities), vec![10, 11, 12]);
+    assert!(matches!(entities.last(), Some(Err(Error::Http { status, .. })) if status.as_u16() == 503));
+    assert_eq!(server.requests_of("/People?page=2"), 3);
+}
+
+#[tokio::test]
+async fn does_not_repeat_permanent_failures() {
+    let server = MockServer::start(|request| match request.path.as_str() {
+        "/People?page=2" => MockResponse::status(500),
+        _ => page(request)
+    }).await;
+
+    let entities = load(&server, quick_retries(3)).await;
+
+    assert!(matches!(entities.last(), Some(Err(Error::Http { status, .. })) if status.as_u16() == 500));
+    assert_eq!(server.requests_of("/People?page=2"), 1);
+}
+
+#[tokio::test]
+async fn streams_the_body_without_repeating_it() {
+    // the entities of the page are passed on as received, so a connection lost within the body can not be repeated
+    let server = MockServer::start(|request| match (request.path.as_str(), request.attempt) {
+        ("/People?page=2", 0) => page(request).cut_after(30),
+        _ => page(request)
+    }).await;
+
+    let entities = load(&server, quick_retries(3)).await;
+
+    assert!(ids(&entities).starts_with(&[10, 11, 12]));
+    assert!(matches!(entities.last(), Some(Err(_))));
+    assert_eq!(server.requests_of("/People?page=2"), 1);
+}